@@ -1,16 +1,12 @@
-use clap::{Arg, Command};
-use idevice::{
-    IdeviceService,
-    lockdown::LockdownClient,
-    usbmuxd::{Connection, UsbmuxdAddr, UsbmuxdConnection},
-};
+use clap::{Arg, ArgAction, Command};
+use idevice::usbmuxd::UsbmuxdAddr;
+use imonitor_lib::enroll::{enroll_device, find_usb_device};
+use log::LevelFilter;
 
 const CONNECTION_LABEL: &str = "test";
 
 #[tokio::main]
 async fn main() {
-    env_logger::init();
-
     let matches = Command::new("pair")
         .about("Pair with the device")
         .arg(
@@ -19,73 +15,68 @@ async fn main() {
                 .help("UDID of the device (overrides host/pairing file)")
                 .index(1),
         )
+        .arg(
+            Arg::new("verbose")
+                .short('v')
+                .long("verbose")
+                .help("Increase logging verbosity (-v warn, -vv info, -vvv debug, -vvvv trace)")
+                .action(ArgAction::Count),
+        )
         .get_matches();
 
-    let udid = matches.get_one::<String>("udid");
+    init_logger(matches.get_count("verbose"));
 
-    let mut u = UsbmuxdConnection::default()
-        .await
-        .expect("Failed to connect to usbmuxd");
+    let udid = matches.get_one::<String>("udid");
 
-    let dev = match udid {
-        Some(udid) => u
-            .get_device(udid)
-            .await
-            .expect("Failed to get device with specific udid"),
-        None => u
-            .get_devices()
-            .await
-            .expect("Failed to get devices")
-            .into_iter()
-            .find(|x| x.connection_type == Connection::Usb)
-            .expect("No devices connected via USB"),
+    let dev = match find_usb_device(udid.map(|udid| udid.as_str())).await {
+        Ok(dev) => dev,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
     };
 
     let provider = dev.to_provider(UsbmuxdAddr::default(), CONNECTION_LABEL);
 
-    let mut lockdown_client = match LockdownClient::connect(&provider).await {
-        Ok(l) => l,
+    println!("Pairing and enabling lockdownd wifi connection");
+
+    let pairing_file = match enroll_device(&provider, &dev.udid).await {
+        Ok(pairing_file) => pairing_file,
         Err(e) => {
-            eprintln!("Unable to connect to lockdown: {e:?}");
-            return;
+            eprintln!("Failed to enroll device {}: {e}", dev.udid);
+            std::process::exit(1);
         }
     };
-    let id = uuid::Uuid::new_v4().to_string().to_uppercase();
-
-    let mut pairing_file = lockdown_client
-        .pair(id, u.get_buid().await.unwrap(), None)
-        .await
-        .expect("Failed to pair");
-
-    // Test the pairing file
-    lockdown_client
-        .start_session(&pairing_file)
-        .await
-        .expect("Pairing file test failed");
 
-    println!("Enabling lockdownd wifi connection");
-
-    lockdown_client
-        .set_value(
-            "EnableWifiConnections",
-            true.into(),
-            Some("com.apple.mobile.wireless_lockdown"),
-        )
-        .await
-        .expect("Failed to enable lockdownd wifi connection");
-
-    // Add the UDID
-    pairing_file.udid = Some(dev.udid.clone());
+    let pairing_file_bytes = match pairing_file.serialize() {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("Failed to serialize pairing file: {e}");
+            std::process::exit(1);
+        }
+    };
 
     let pairing_file_name = format!("{}_pairing_file.plist", dev.udid);
-    tokio::fs::write(
-        pairing_file_name.clone(),
-        pairing_file
-            .serialize()
-            .expect("Failed to serialize pairing file"),
-    )
-    .await
-    .expect("Failed to write pairing file");
+    if let Err(e) = tokio::fs::write(pairing_file_name.clone(), pairing_file_bytes).await {
+        eprintln!("Failed to write pairing file {pairing_file_name}: {e}");
+        std::process::exit(1);
+    }
 
     println!("Pairing file generated at: {pairing_file_name}");
 }
+
+/// Each `-v` raises the log level, `RUST_LOG` wins if set.
+fn init_logger(verbosity: u8) {
+    let level = match verbosity {
+        0 => LevelFilter::Error,
+        1 => LevelFilter::Warn,
+        2 => LevelFilter::Info,
+        3 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+
+    env_logger::Builder::new()
+        .filter_level(level)
+        .parse_default_env()
+        .init();
+}
@@ -0,0 +1,265 @@
+use crate::observer::MonitorObserver;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::mpsc::{Sender, channel};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+// Enough to hold the last record when resuming the chain
+const TAIL_READ_BYTES: u64 = 64 * 1024;
+
+/// One line of the audit log.
+///
+/// With the hash chain, `hash` is the hex SHA-256 of the line serialized without it, which
+/// is the line with its trailing `"hash"` member removed. `prev_hash` is the hash of the
+/// previous line, so that removing or altering a line breaks the chain.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub seq: u64,
+    pub ts: DateTime<Utc>,
+    pub udid: String,
+    /// "heartbeat", "crash_pulled", "archive_created" or "service_error"
+    pub event: String,
+    /// "connected", "disconnected", "ok" or "error"
+    pub outcome: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prev_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+/// Observer appending every monitoring event to an NDJSON file, see `[audit_log]` in the
+/// config. Lines are written by a dedicated thread, so that the services never wait on
+/// the file.
+#[derive(Debug)]
+pub struct AuditLog {
+    // Behind a mutex so that the observer is Sync
+    sender: Mutex<Sender<AuditRecord>>,
+}
+
+struct AuditWriter {
+    file: File,
+    hash_chain: bool,
+    next_seq: u64,
+    prev_hash: Option<String>,
+}
+
+impl AuditLog {
+    /// Opens the log in append mode. An existing chain is continued from its last line.
+    pub fn open(path: &Path, hash_chain: bool) -> io::Result<Arc<AuditLog>> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(path)?;
+        let last = last_record(&mut file)?;
+
+        let mut writer = AuditWriter {
+            file,
+            hash_chain,
+            next_seq: last.as_ref().map_or(0, |last| last.seq + 1),
+            prev_hash: last.and_then(|last| last.hash),
+        };
+
+        let (sender, receiver) = channel::<AuditRecord>();
+        std::thread::Builder::new()
+            .name("audit-log".to_string())
+            .spawn(move || {
+                for record in receiver {
+                    if let Err(e) = writer.append(record) {
+                        eprintln!("Failed to write audit log: {e}");
+                    }
+                }
+            })?;
+
+        Ok(Arc::new(AuditLog {
+            sender: Mutex::new(sender),
+        }))
+    }
+
+    fn record(&self, udid: &str, event: &str, outcome: &str, details: Option<serde_json::Value>) {
+        let record = AuditRecord {
+            // Set by the writer
+            seq: 0,
+            ts: Utc::now(),
+            udid: udid.to_string(),
+            event: event.to_string(),
+            outcome: outcome.to_string(),
+            details,
+            prev_hash: None,
+            hash: None,
+        };
+        if let Ok(sender) = self.sender.lock() {
+            let _ = sender.send(record);
+        }
+    }
+}
+
+impl AuditWriter {
+    fn append(&mut self, mut record: AuditRecord) -> io::Result<()> {
+        record.seq = self.next_seq;
+        if self.hash_chain {
+            record.prev_hash = self.prev_hash.clone();
+            let unhashed = serde_json::to_string(&record)?;
+            record.hash = Some(format!("{:x}", Sha256::digest(unhashed.as_bytes())));
+        }
+
+        let mut line = serde_json::to_string(&record)?;
+        line.push('\n');
+        // A single write, so that lines are never interleaved
+        self.file.write_all(line.as_bytes())?;
+        self.file.flush()?;
+
+        self.next_seq += 1;
+        self.prev_hash = record.hash;
+        Ok(())
+    }
+}
+
+/// Last parsable record of the log, None if empty.
+fn last_record(file: &mut File) -> io::Result<Option<AuditRecord>> {
+    let len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(len.saturating_sub(TAIL_READ_BYTES)))?;
+    let mut tail = String::new();
+    file.read_to_string(&mut tail)?;
+
+    Ok(tail
+        .lines()
+        .rev()
+        .find_map(|line| serde_json::from_str::<AuditRecord>(line).ok()))
+}
+
+/// Checks that every line hashes to its `hash` and links to the previous one. Returns the
+/// number of the first line breaking the chain, counted from 1.
+pub fn verify_chain(content: &str) -> Result<(), usize> {
+    let mut prev_hash = None;
+    for (index, line) in content.lines().enumerate() {
+        let mut record = serde_json::from_str::<AuditRecord>(line).map_err(|_| index + 1)?;
+        let Some(hash) = record.hash.take() else {
+            return Err(index + 1);
+        };
+        let unhashed = serde_json::to_string(&record).map_err(|_| index + 1)?;
+        if format!("{:x}", Sha256::digest(unhashed.as_bytes())) != hash
+            || (index > 0 && record.prev_hash != prev_hash)
+        {
+            return Err(index + 1);
+        }
+        prev_hash = Some(hash);
+    }
+    Ok(())
+}
+
+impl MonitorObserver for AuditLog {
+    fn on_heartbeat(&self, udid: &str, connected: bool) {
+        let outcome = if connected {
+            "connected"
+        } else {
+            "disconnected"
+        };
+        self.record(udid, "heartbeat", outcome, None);
+    }
+
+    fn on_crash_pulled(&self, udid: &str, file: &str, bytes: u64) {
+        let details = serde_json::json!({ "file": file, "bytes": bytes });
+        self.record(udid, "crash_pulled", "ok", Some(details));
+    }
+
+    fn on_archive_created(&self, udid: &str, range: &Range<SystemTime>) {
+        let details = serde_json::json!({
+            "start": DateTime::<Utc>::from(range.start),
+            "end": DateTime::<Utc>::from(range.end),
+        });
+        self.record(udid, "archive_created", "ok", Some(details));
+    }
+
+    fn on_service_error(&self, udid: &str, service: &str, error: &dyn Error) {
+        let details = serde_json::json!({ "service": service, "error": error.to_string() });
+        self.record(udid, "service_error", "error", Some(details));
+    }
+
+    fn on_pairing_invalid(&self, udid: &str, error: &dyn Error) {
+        let details = serde_json::json!({ "error": error.to_string() });
+        self.record(udid, "pairing", "invalid", Some(details));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(event: &str) -> AuditRecord {
+        AuditRecord {
+            seq: 0,
+            ts: DateTime::<Utc>::from_timestamp(1_700_000_000, 0).unwrap(),
+            udid: "00008030-TEST".to_string(),
+            event: event.to_string(),
+            outcome: "ok".to_string(),
+            details: Some(serde_json::json!({ "file": "a.ips" })),
+            prev_hash: None,
+            hash: None,
+        }
+    }
+
+    /// Writer continuing the log, as opened by `AuditLog::open`.
+    fn open_writer(path: &Path) -> AuditWriter {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(path)
+            .unwrap();
+        let last = last_record(&mut file).unwrap();
+        AuditWriter {
+            file,
+            hash_chain: true,
+            next_seq: last.as_ref().map_or(0, |last| last.seq + 1),
+            prev_hash: last.and_then(|last| last.hash),
+        }
+    }
+
+    #[test]
+    fn hash_chain_links_every_event() {
+        let dir = std::env::temp_dir().join(format!("imonitor-audit-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.ndjson");
+
+        let mut writer = open_writer(&path);
+        writer.append(event("heartbeat")).unwrap();
+        writer.append(event("crash_pulled")).unwrap();
+        drop(writer);
+        // The chain goes on after a restart
+        let mut writer = open_writer(&path);
+        writer.append(event("archive_created")).unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(verify_chain(&content), Ok(()));
+        let records: Vec<AuditRecord> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            records.iter().map(|record| record.seq).collect::<Vec<_>>(),
+            [0, 1, 2]
+        );
+        assert_eq!(records[0].prev_hash, None);
+        assert_eq!(records[1].prev_hash, records[0].hash);
+        assert_eq!(records[2].prev_hash, records[1].hash);
+
+        // Altered and removed lines break the chain
+        let lines: Vec<&str> = content.lines().collect();
+        let altered = content.replace("crash_pulled", "heartbeat");
+        assert_eq!(verify_chain(&altered), Err(2));
+        let removed = format!("{}\n{}\n", lines[0], lines[2]);
+        assert_eq!(verify_chain(&removed), Err(2));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
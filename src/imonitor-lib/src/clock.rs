@@ -0,0 +1,80 @@
+use chrono::{DateTime, Utc};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// Time source used by the services, so that time dependent logic can be driven in tests.
+pub trait Clock: Send + Sync + std::fmt::Debug {
+    fn now(&self) -> SystemTime;
+
+    fn now_utc(&self) -> DateTime<Utc> {
+        self.now().into()
+    }
+}
+
+/// Clock reading the system time, used by default.
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Clock only moving when told to.
+#[derive(Debug)]
+pub struct MockClock {
+    now: Mutex<SystemTime>,
+}
+
+impl MockClock {
+    pub fn new(now: SystemTime) -> Self {
+        Self {
+            now: Mutex::new(now),
+        }
+    }
+
+    pub fn set(&self, now: SystemTime) {
+        match self.now.lock() {
+            Ok(mut current) => *current = now,
+            Err(poisoned) => *poisoned.into_inner() = now,
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let now = self.now();
+        self.set(now + duration);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        match self.now.lock() {
+            Ok(now) => *now,
+            Err(poisoned) => *poisoned.into_inner(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mock_clock_only_moves_when_told_to() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let clock = MockClock::new(start);
+        assert_eq!(clock.now(), start);
+        assert_eq!(clock.now(), start);
+
+        clock.advance(Duration::from_secs(90));
+        assert_eq!(clock.now(), start + Duration::from_secs(90));
+        assert_eq!(
+            clock.now_utc(),
+            DateTime::<Utc>::from(start + Duration::from_secs(90))
+        );
+
+        clock.set(start);
+        assert_eq!(clock.now(), start);
+    }
+}
@@ -1,44 +1,1727 @@
+use crate::state_store::StateFormat;
+use crate::util::fnv1a;
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::error::Error;
-use std::fs::read_to_string;
-use std::path::Path;
+use std::fs::{create_dir_all, read_to_string};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
-/// Configuration values.
+/// Configuration values. Unknown keys are rejected, so that a misspelled key does not
+/// silently leave the default in place.
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     /// Configuration settings.
     #[serde(rename = "config")]
     pub settings: Settings,
     /// Encryption configuration
     pub encryption: EncryptionConfig,
+    /// Crashes service configuration
+    #[serde(default)]
+    pub crashes: CrashesConfig,
+    /// Syslog service configuration
+    #[serde(default)]
+    pub syslog: SyslogConfig,
+    /// Enabled device services
+    #[serde(default)]
+    pub services: ServicesConfig,
+    /// Low-power mode of idle devices
+    #[serde(default)]
+    pub idle: IdleConfig,
+    /// Heartbeat service configuration
+    #[serde(default)]
+    pub heartbeat: HeartbeatConfig,
+    /// Os trace services configuration
+    #[serde(default)]
+    pub os_trace: OsTraceConfig,
+    /// Installed apps service configuration
+    #[serde(default)]
+    pub installed_apps: InstalledAppsConfig,
+    /// Pairing check service configuration
+    #[serde(default)]
+    pub pairing_check: PairingCheckConfig,
+    /// Models and iOS versions of the devices monitored
+    #[serde(default)]
+    pub device_filter: DeviceFilterConfig,
+    /// Daemon log file, in addition to stderr. Device logs are not affected.
+    #[serde(default)]
+    pub log: Option<LogFileConfig>,
+    /// Append-only log of monitoring events
+    #[serde(default)]
+    pub audit_log: Option<AuditLogConfig>,
+}
+
+/// Rolling file the daemon log is copied to.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogFileConfig {
+    pub path: String,
+    #[serde(default)]
+    pub rotation: LogRotation,
+    /// Rotated files kept, all if not set.
+    #[serde(default)]
+    pub max_files: Option<usize>,
+}
+
+/// NDJSON file every heartbeat, pulled crash, archive and service error is appended to.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuditLogConfig {
+    pub path: String,
+    /// Chain each line to the previous one with a SHA-256 hash, so that tampering shows.
+    #[serde(default)]
+    pub hash_chain: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Never,
+    Hourly,
+    #[default]
+    Daily,
+}
+
+impl From<LogRotation> for logger::Rotation {
+    fn from(rotation: LogRotation) -> Self {
+        match rotation {
+            LogRotation::Never => logger::Rotation::Never,
+            LogRotation::Hourly => logger::Rotation::Hourly,
+            LogRotation::Daily => logger::Rotation::Daily,
+        }
+    }
 }
 
 /// General settings for configuration.
-#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Settings {
-    /// Refresh rate of the configuration file.
-    #[serde(with = "humantime_serde")]
+    /// Refresh rate of the configuration file, and base polling interval of the services.
+    /// At least one second.
+    #[serde(default = "default_refresh_rate", with = "humantime_serde")]
     pub refresh_rate: Duration,
-    pub base_dir: String,
+    /// Dir holding the device dirs, or a list of dirs to spread them across volumes.
+    pub base_dir: BaseDir,
+    /// Maximum time streamed syslog and os trace lines stay buffered before being written
+    /// to disk.
+    #[serde(default = "default_flush_interval", with = "humantime_serde")]
+    pub flush_interval: Duration,
+    /// Timeout of the connection probing a device at startup. Unreachable devices only
+    /// start their services once a later probe succeeds.
+    #[serde(default = "default_reachability_timeout", with = "humantime_serde")]
+    pub reachability_timeout: Duration,
+    /// Time without any data on a syslog or os trace stream, or beyond the heartbeat
+    /// interval, after which the connection is considered dead and reopened. Guards against
+    /// half-open connections.
+    #[serde(default = "default_read_timeout", with = "humantime_serde")]
+    pub read_timeout: Duration,
+    /// Maximum number of service connections being established at the same time, across
+    /// all devices. Unlimited if not set, zero is rejected. See
+    /// [`crate::connection::ConnectionPriority`].
+    #[serde(default)]
+    pub max_global_concurrent_connections: Option<NonZeroUsize>,
+    /// Maximum number of heartbeat connections being established at the same time, across
+    /// all devices. Unlimited if not set, zero is rejected.
+    #[serde(default)]
+    pub max_concurrent_heartbeat_connects: Option<NonZeroUsize>,
+    /// Maximum download rate for crash files and os trace archives, shared by all devices.
+    /// Unlimited if not set.
+    #[serde(default)]
+    pub max_download_bytes_per_sec: Option<u64>,
+    /// Maximum number of devices in the monitored devices file, each of which holds file
+    /// descriptors and connections. Startup fails beyond it. Unlimited if not set.
+    #[serde(default)]
+    pub max_devices: Option<usize>,
+    /// Pairs again over USB with devices whose pairing file is missing or invalid at startup.
+    #[serde(default)]
+    pub auto_repair: bool,
+    /// Path of a read-only Unix socket answering `status`, `coverage` and `crashes`
+    /// commands about a device. Disabled if not set.
+    #[serde(default)]
+    pub control_socket: Option<String>,
+    /// Proxy all device connections go through. Direct connections if not set.
+    #[serde(default)]
+    pub proxy: Option<ProxyConfig>,
+    /// File rewritten on every heartbeat of any device, for external watchdogs. It goes
+    /// stale when no device is connected. Disabled if not set.
+    #[serde(default)]
+    pub liveness_file: Option<String>,
+    /// Format of the state files such as `activity_coverage.json`. Existing files are read
+    /// whatever their format, so that it can be changed at any time.
+    #[serde(default)]
+    pub state_format: StateFormat,
+    /// Gathers the bookkeeping files of each device, such as `known_crashes.json`, in its
+    /// `state` dir instead of the service dirs. Existing files are moved at startup.
+    #[serde(default)]
+    pub state_dir: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            refresh_rate: default_refresh_rate(),
+            base_dir: BaseDir::default(),
+            flush_interval: default_flush_interval(),
+            reachability_timeout: default_reachability_timeout(),
+            read_timeout: default_read_timeout(),
+            max_global_concurrent_connections: None,
+            max_concurrent_heartbeat_connects: None,
+            max_download_bytes_per_sec: None,
+            max_devices: None,
+            auto_repair: false,
+            control_socket: None,
+            proxy: None,
+            liveness_file: None,
+            state_format: StateFormat::default(),
+            state_dir: false,
+        }
+    }
+}
+
+/// One or several dirs holding the device dirs.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+pub enum BaseDir {
+    Single(String),
+    Sharded(Vec<String>),
+}
+
+impl Default for BaseDir {
+    fn default() -> Self {
+        BaseDir::Single(String::new())
+    }
+}
+
+impl BaseDir {
+    pub fn dirs(&self) -> Vec<String> {
+        match self {
+            BaseDir::Single(dir) => vec![dir.clone()],
+            BaseDir::Sharded(dirs) => dirs.clone(),
+        }
+    }
+
+    /// Same dirs, created and made canonical, see [`canonical_base_dir`].
+    pub fn canonical(&self) -> Result<BaseDir, Box<dyn Error>> {
+        Ok(match self {
+            BaseDir::Single(dir) => BaseDir::Single(canonical_base_dir(dir)?),
+            BaseDir::Sharded(dirs) => BaseDir::Sharded(
+                dirs.iter()
+                    .map(|dir| canonical_base_dir(dir))
+                    .collect::<Result<_, _>>()?,
+            ),
+        })
+    }
+}
+
+/// Proxy reaching the devices.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyConfig {
+    pub kind: ProxyKind,
+    /// `host:port` of the proxy.
+    pub address: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyKind {
+    Socks5,
+    /// HTTP CONNECT
+    Http,
 }
 
 /// Encryption configuration.
 #[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct EncryptionConfig {
     /// Public keys listing
     pub public_keys: Vec<String>,
 }
 
+const DEFAULT_REFRESH_RATE_SECS: u64 = 60;
+const DEFAULT_FLUSH_INTERVAL_SECS: u64 = 5;
+const DEFAULT_REACHABILITY_TIMEOUT_SECS: u64 = 2;
+const DEFAULT_READ_TIMEOUT_SECS: u64 = 10 * 60;
+const DEFAULT_CRASH_POLL_INTERVAL_SECS: u64 = 15;
+const DEFAULT_CRASH_RETRY_WAIT_SECS: u64 = 15;
+const DEFAULT_CRASH_MAX_DIR_DEPTH: usize = 8;
+const DEFAULT_CRASH_PERMANENT_FAILURE_RETRY_SECS: u64 = 24 * 60 * 60;
+const DEFAULT_CRASH_DEAD_LETTER_AFTER: u32 = 5;
+const DEFAULT_CRASH_DEAD_LETTER_RETRY_SECS: u64 = 7 * 24 * 60 * 60;
+const DEFAULT_CRASH_LIST_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_CRASH_PULL_TIMEOUT_SECS: u64 = 5 * 60;
+const DEFAULT_ARCHIVE_MIN_DEVICE_FREE_MB: u64 = 1024;
+const DEFAULT_ARCHIVE_LOW_STORAGE_WAIT_SECS: u64 = 30 * 60;
+const DEFAULT_ARCHIVE_RETRY_WAIT_SECS: u64 = 60;
+const DEFAULT_ARCHIVE_SIZE_LIMIT: u64 = 5000;
+const DEFAULT_ARCHIVE_AGE_LIMIT: u64 = 1;
+const MAX_ARCHIVE_START_MARGIN_SECS: u64 = 24 * 60 * 60;
+const DEFAULT_IDLE_AFTER_SECS: u64 = 30 * 60;
+const DEFAULT_IDLE_INTERVAL_SECS: u64 = 5 * 60;
+const DEFAULT_HEARTBEAT_RECENTLY_SEEN_SECS: u64 = 60 * 60;
+const DEFAULT_HEARTBEAT_MIN_INTERVAL_SECS: u64 = 5;
+const DEFAULT_INSTALLED_APPS_INTERVAL_SECS: u64 = 6 * 60 * 60;
+const DEFAULT_PAIRING_CHECK_INTERVAL_SECS: u64 = 6 * 60 * 60;
+const DEFAULT_HEARTBEAT_MAX_INTERVAL_SECS: u64 = 5 * 60;
+const DEFAULT_HEARTBEAT_HISTORY_RETENTION_SECS: u64 = 30 * 24 * 60 * 60;
+/// Base dir volumes with less free space than this ratio are reported as near full.
+pub const VOLUME_NEAR_FULL_FREE_RATIO: f64 = 0.1;
+
+/// Crashes service configuration.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CrashesConfig {
+    /// Glob patterns of device crash paths that are never downloaded (e.g. `Retired/*`).
+    #[serde(default)]
+    pub exclude_globs: Vec<String>,
+    /// How device crash paths are mapped to local files.
+    #[serde(default)]
+    pub path_mode: CrashPathMode,
+    /// Records size and mtime of pulled crash files and re-pulls them when they change.
+    #[serde(default)]
+    pub track_changes: bool,
+    /// Crash files larger than this are not pulled, as they are held in memory while pulled.
+    #[serde(default)]
+    pub max_file_bytes: Option<u64>,
+    /// Stores identical crash files once, hard linked to a blob named after their hash.
+    #[serde(default)]
+    pub dedup: bool,
+    /// Wait between two listings of the device crash files.
+    #[serde(default = "default_crash_poll_interval", with = "humantime_serde")]
+    pub poll_interval: Duration,
+    /// Wait before reconnecting to the crash service after a failure.
+    #[serde(default = "default_crash_retry_wait", with = "humantime_serde")]
+    pub retry_wait: Duration,
+    /// Device crash dirs nested deeper than this are not listed.
+    #[serde(default = "default_crash_max_dir_depth")]
+    pub max_dir_depth: usize,
+    /// Wait before pulling again a crash file that failed with a permanent error, such as
+    /// a permission denied.
+    #[serde(
+        default = "default_crash_permanent_failure_retry",
+        with = "humantime_serde"
+    )]
+    pub permanent_failure_retry: Duration,
+    /// Failed local writes of a pulled crash file before it is dead-lettered.
+    #[serde(default = "default_crash_dead_letter_after")]
+    pub dead_letter_after: u32,
+    /// Wait before pulling again a dead-lettered crash file.
+    #[serde(default = "default_crash_dead_letter_retry", with = "humantime_serde")]
+    pub dead_letter_retry: Duration,
+    /// Listing a device crash dir taking longer is abandoned. A slow sub dir is skipped on
+    /// the next cycle.
+    #[serde(default = "default_crash_list_timeout", with = "humantime_serde")]
+    pub list_timeout: Duration,
+    /// Pulling a crash file taking longer is abandoned, the file is retried after
+    /// `permanent_failure_retry`.
+    #[serde(default = "default_crash_pull_timeout", with = "humantime_serde")]
+    pub pull_timeout: Duration,
+    /// Where pulled crash files are stored.
+    #[serde(default)]
+    pub store: CrashStore,
+    /// Store the crash files are sent to with `store = "remote"`.
+    #[serde(default)]
+    pub remote: Option<RemoteStoreConfig>,
+}
+
+impl Default for CrashesConfig {
+    fn default() -> Self {
+        Self {
+            exclude_globs: Vec::new(),
+            path_mode: CrashPathMode::default(),
+            track_changes: false,
+            max_file_bytes: None,
+            dedup: false,
+            poll_interval: default_crash_poll_interval(),
+            retry_wait: default_crash_retry_wait(),
+            max_dir_depth: default_crash_max_dir_depth(),
+            permanent_failure_retry: default_crash_permanent_failure_retry(),
+            dead_letter_after: default_crash_dead_letter_after(),
+            dead_letter_retry: default_crash_dead_letter_retry(),
+            list_timeout: default_crash_list_timeout(),
+            pull_timeout: default_crash_pull_timeout(),
+            store: CrashStore::default(),
+            remote: None,
+        }
+    }
+}
+
+fn default_refresh_rate() -> Duration {
+    Duration::from_secs(DEFAULT_REFRESH_RATE_SECS)
+}
+
+fn default_reachability_timeout() -> Duration {
+    Duration::from_secs(DEFAULT_REACHABILITY_TIMEOUT_SECS)
+}
+
+fn default_read_timeout() -> Duration {
+    Duration::from_secs(DEFAULT_READ_TIMEOUT_SECS)
+}
+
+fn default_flush_interval() -> Duration {
+    Duration::from_secs(DEFAULT_FLUSH_INTERVAL_SECS)
+}
+
+fn default_crash_poll_interval() -> Duration {
+    Duration::from_secs(DEFAULT_CRASH_POLL_INTERVAL_SECS)
+}
+
+fn default_crash_retry_wait() -> Duration {
+    Duration::from_secs(DEFAULT_CRASH_RETRY_WAIT_SECS)
+}
+
+fn default_crash_permanent_failure_retry() -> Duration {
+    Duration::from_secs(DEFAULT_CRASH_PERMANENT_FAILURE_RETRY_SECS)
+}
+
+fn default_crash_dead_letter_after() -> u32 {
+    DEFAULT_CRASH_DEAD_LETTER_AFTER
+}
+
+fn default_crash_dead_letter_retry() -> Duration {
+    Duration::from_secs(DEFAULT_CRASH_DEAD_LETTER_RETRY_SECS)
+}
+
+fn default_crash_list_timeout() -> Duration {
+    Duration::from_secs(DEFAULT_CRASH_LIST_TIMEOUT_SECS)
+}
+
+fn default_crash_pull_timeout() -> Duration {
+    Duration::from_secs(DEFAULT_CRASH_PULL_TIMEOUT_SECS)
+}
+
+fn default_crash_max_dir_depth() -> usize {
+    DEFAULT_CRASH_MAX_DIR_DEPTH
+}
+
+/// Local layout of crash files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CrashPathMode {
+    /// Keep the device directory structure.
+    #[default]
+    Preserve,
+    /// Store every file directly in the crash files dir, with a collision-free name.
+    Flatten,
+}
+
+/// Destination of pulled crash files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CrashStore {
+    /// Written to the crash files dir, for `imonitor-send` or other tooling to ship.
+    #[default]
+    Local,
+    /// Sent straight to `crashes.remote`, only the known crashes are kept locally.
+    Remote,
+}
+
+/// Store of `crashes.store = "remote"`, either a dir or an S3 bucket.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RemoteStoreConfig {
+    /// Dir the crash files are written under, e.g. a network share.
+    #[serde(default)]
+    pub dir: Option<String>,
+    /// Bucket the crash files are uploaded to, needs the `s3` feature.
+    #[serde(default)]
+    pub s3: Option<S3Config>,
+}
+
+/// S3 compatible object store, shared with `imonitor-send`. The credentials are read from
+/// the `S3_ACCESS_KEY` and `S3_SECRET_KEY` environment variables.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct S3Config {
+    pub bucket: String,
+    /// Prepended to the key of every object.
+    #[serde(default)]
+    pub prefix: String,
+    pub endpoint: String,
+    /// Region of the bucket. Defaults to the one of the AWS environment variables or
+    /// config files.
+    #[serde(default)]
+    pub region: Option<String>,
+    /// Addresses the bucket as `<endpoint>/<bucket>` instead of `<bucket>.<endpoint>`, as
+    /// most self-hosted stores expect.
+    #[serde(default)]
+    pub force_path_style: bool,
+    /// PEM file of certificates trusted for the endpoint, in addition to the system ones.
+    #[serde(default)]
+    pub ca_bundle: Option<String>,
+    /// Accepts any endpoint certificate. Test labs only.
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+}
+
+/// Device services to run. Every other service waits for the heartbeat, which cannot be
+/// disabled.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServicesConfig {
+    #[serde(default = "enabled")]
+    pub heartbeat: bool,
+    #[serde(default)]
+    pub syslog: bool,
+    #[serde(default = "enabled")]
+    pub crashes: bool,
+    #[serde(default = "enabled")]
+    pub os_trace_log: bool,
+    /// Still under development, not recommended in production.
+    #[serde(default)]
+    pub os_trace_archive: bool,
+    /// Snapshot of the installed apps and their versions, see `[installed_apps]`.
+    #[serde(default)]
+    pub installed_apps: bool,
+    /// Periodic check that the device still accepts the pairing, see `[pairing_check]`.
+    #[serde(default)]
+    pub pairing_check: bool,
+    /// Developer mode, passcode and battery level, checked every 5 minutes.
+    #[serde(default = "enabled")]
+    pub device_state: bool,
+}
+
+impl Default for ServicesConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat: true,
+            syslog: false,
+            crashes: true,
+            os_trace_log: true,
+            os_trace_archive: false,
+            installed_apps: false,
+            pairing_check: false,
+            device_state: true,
+        }
+    }
+}
+
+/// Optional device service, spawned when enabled in `[services]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Service {
+    Syslog,
+    Crashes,
+    OsTraceLog,
+    OsTraceArchive,
+    InstalledApps,
+    PairingCheck,
+    DeviceState,
+}
+
+impl ServicesConfig {
+    /// Services to spawn along with the heartbeat, which always runs: a config disabling
+    /// it is rejected by [`Config::validate`].
+    pub fn enabled_services(&self) -> Vec<Service> {
+        [
+            (self.syslog, Service::Syslog),
+            (self.crashes, Service::Crashes),
+            (self.os_trace_log, Service::OsTraceLog),
+            (self.os_trace_archive, Service::OsTraceArchive),
+            (self.installed_apps, Service::InstalledApps),
+            (self.pairing_check, Service::PairingCheck),
+            (self.device_state, Service::DeviceState),
+        ]
+        .into_iter()
+        .filter(|(enabled, _)| *enabled)
+        .map(|(_, service)| service)
+        .collect()
+    }
+}
+
+fn enabled() -> bool {
+    true
+}
+
+/// Idle mode: once a device sent no new crash nor os trace log and its heartbeat did not
+/// change for `after`, crashes are polled and lost connections retried every `interval`.
+/// Saves device battery at the cost of a later detection of the first new crash.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IdleConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_idle_after", with = "humantime_serde")]
+    pub after: Duration,
+    #[serde(default = "default_idle_interval", with = "humantime_serde")]
+    pub interval: Duration,
+}
+
+impl Default for IdleConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            after: default_idle_after(),
+            interval: default_idle_interval(),
+        }
+    }
+}
+
+fn default_idle_after() -> Duration {
+    Duration::from_secs(DEFAULT_IDLE_AFTER_SECS)
+}
+
+fn default_idle_interval() -> Duration {
+    Duration::from_secs(DEFAULT_IDLE_INTERVAL_SECS)
+}
+
+/// Os trace services configuration.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OsTraceConfig {
+    /// No archive is requested while the device has less free storage, as it builds the
+    /// archive on its own storage first.
+    #[serde(default = "default_archive_min_device_free_mb")]
+    pub archive_min_device_free_mb: u64,
+    /// Wait before checking the device storage again once it was too low.
+    #[serde(default = "default_archive_low_storage_wait", with = "humantime_serde")]
+    pub archive_low_storage_wait: Duration,
+    /// Wait before reconnecting once an archive failed to be created, written or verified.
+    /// The remaining gaps are retried then.
+    #[serde(default = "default_archive_retry_wait", with = "humantime_serde")]
+    pub archive_retry_wait: Duration,
+    /// Checks that each archive holds a readable Info.plist before covering its gap. A
+    /// truncated archive is deleted and its gap retried.
+    #[serde(default = "enabled")]
+    pub verify_archives: bool,
+    /// `SizeLimit` of the archive request, passed as is to the device which bounds the
+    /// archive size with it.
+    #[serde(default = "default_archive_size_limit")]
+    pub archive_size_limit: u64,
+    /// `AgeLimit` of the archive request, passed as is to the device which bounds the age
+    /// of the logs archived with it.
+    #[serde(default = "default_archive_age_limit")]
+    pub archive_age_limit: u64,
+    /// Archives start this long before their gap, to also pull logs the device recorded
+    /// late. At most a day.
+    #[serde(default, with = "humantime_serde")]
+    pub archive_start_margin: Duration,
+    /// Writes `{archive}.meta.json` next to each archive, with the UDID, requested gap,
+    /// coverage read from the archive, creation time and size.
+    #[serde(default)]
+    pub archive_metadata: bool,
+}
+
+impl Default for OsTraceConfig {
+    fn default() -> Self {
+        Self {
+            archive_min_device_free_mb: default_archive_min_device_free_mb(),
+            archive_low_storage_wait: default_archive_low_storage_wait(),
+            archive_retry_wait: default_archive_retry_wait(),
+            verify_archives: true,
+            archive_size_limit: default_archive_size_limit(),
+            archive_age_limit: default_archive_age_limit(),
+            archive_start_margin: Duration::ZERO,
+            archive_metadata: false,
+        }
+    }
+}
+
+fn default_archive_min_device_free_mb() -> u64 {
+    DEFAULT_ARCHIVE_MIN_DEVICE_FREE_MB
+}
+
+fn default_archive_low_storage_wait() -> Duration {
+    Duration::from_secs(DEFAULT_ARCHIVE_LOW_STORAGE_WAIT_SECS)
+}
+
+fn default_archive_retry_wait() -> Duration {
+    Duration::from_secs(DEFAULT_ARCHIVE_RETRY_WAIT_SECS)
+}
+
+fn default_archive_size_limit() -> u64 {
+    DEFAULT_ARCHIVE_SIZE_LIMIT
+}
+
+fn default_archive_age_limit() -> u64 {
+    DEFAULT_ARCHIVE_AGE_LIMIT
+}
+
+/// Installed apps service configuration.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InstalledAppsConfig {
+    /// Time between two listings. `info/installed_apps.json` is only rewritten when the
+    /// list changed.
+    #[serde(default = "default_installed_apps_interval", with = "humantime_serde")]
+    pub interval: Duration,
+}
+
+impl Default for InstalledAppsConfig {
+    fn default() -> Self {
+        Self {
+            interval: default_installed_apps_interval(),
+        }
+    }
+}
+
+fn default_installed_apps_interval() -> Duration {
+    Duration::from_secs(DEFAULT_INSTALLED_APPS_INTERVAL_SECS)
+}
+
+/// Pairing check service configuration.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PairingCheckConfig {
+    /// Time between two lockdown sessions started to check the pairing.
+    #[serde(default = "default_pairing_check_interval", with = "humantime_serde")]
+    pub interval: Duration,
+}
+
+impl Default for PairingCheckConfig {
+    fn default() -> Self {
+        Self {
+            interval: default_pairing_check_interval(),
+        }
+    }
+}
+
+fn default_pairing_check_interval() -> Duration {
+    Duration::from_secs(DEFAULT_PAIRING_CHECK_INTERVAL_SECS)
+}
+
+/// Models and iOS versions of the devices monitored, as glob patterns. A deny list wins
+/// over an allow list, an empty allow list allows everything.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceFilterConfig {
+    /// `ProductType` patterns, such as "iPhone15,*"
+    #[serde(default)]
+    pub allow_models: Vec<String>,
+    #[serde(default)]
+    pub deny_models: Vec<String>,
+    /// `ProductVersion` patterns, such as "17.*"
+    #[serde(default)]
+    pub allow_versions: Vec<String>,
+    #[serde(default)]
+    pub deny_versions: Vec<String>,
+}
+
+impl DeviceFilterConfig {
+    /// No device is filtered out, the product info need not be queried.
+    pub fn is_empty(&self) -> bool {
+        self.allow_models.is_empty()
+            && self.deny_models.is_empty()
+            && self.allow_versions.is_empty()
+            && self.deny_versions.is_empty()
+    }
+
+    /// Why a device of this model and iOS version is not monitored, None if it is.
+    /// Unknown values match no pattern.
+    pub fn skip_reason(
+        &self,
+        product_type: Option<&str>,
+        product_version: Option<&str>,
+    ) -> Option<String> {
+        let filters = [
+            ("model", &self.allow_models, &self.deny_models, product_type),
+            (
+                "iOS version",
+                &self.allow_versions,
+                &self.deny_versions,
+                product_version,
+            ),
+        ];
+
+        for (kind, allow, deny, value) in filters {
+            let shown = value.unwrap_or("unknown");
+            if matches_any(deny, value) {
+                return Some(format!("{kind} {shown} is denied by device_filter"));
+            }
+            if !allow.is_empty() && !matches_any(allow, value) {
+                return Some(format!("{kind} {shown} is not allowed by device_filter"));
+            }
+        }
+        None
+    }
+
+    /// Every pattern, with the name of its list.
+    fn patterns(&self) -> Vec<(&'static str, &String)> {
+        [
+            ("allow_models", &self.allow_models),
+            ("deny_models", &self.deny_models),
+            ("allow_versions", &self.allow_versions),
+            ("deny_versions", &self.deny_versions),
+        ]
+        .into_iter()
+        .flat_map(|(name, patterns)| patterns.iter().map(move |pattern| (name, pattern)))
+        .collect()
+    }
+}
+
+// Invalid patterns are reported by `Config::validate` and match nothing
+fn matches_any(patterns: &[String], value: Option<&str>) -> bool {
+    value.is_some_and(|value| {
+        patterns
+            .iter()
+            .any(|pattern| glob::Pattern::new(pattern).is_ok_and(|pattern| pattern.matches(value)))
+    })
+}
+
+/// Heartbeat service configuration.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeartbeatConfig {
+    /// Whether the other services run when the heartbeat connection hangs.
+    #[serde(default)]
+    pub timeout_alive: HeartbeatTimeoutMode,
+    /// How recently the heartbeat must have been established for `recently_seen`.
+    #[serde(default = "default_heartbeat_recently_seen", with = "humantime_serde")]
+    pub recently_seen: Duration,
+    /// Bounds of the interval announced by the device in each marco, which a faulty
+    /// device may set to zero or to hours. A shorter interval is raised to `min_interval`,
+    /// a longer one than `max_interval` is reported but still waited for.
+    #[serde(default = "default_heartbeat_min_interval", with = "humantime_serde")]
+    pub min_interval: Duration,
+    #[serde(default = "default_heartbeat_max_interval", with = "humantime_serde")]
+    pub max_interval: Duration,
+    /// Whether connected and disconnected transitions are appended to
+    /// `heartbeat/history.ndjson`.
+    #[serde(default = "enabled")]
+    pub history: bool,
+    /// Age beyond which transitions are dropped from the history.
+    #[serde(
+        default = "default_heartbeat_history_retention",
+        with = "humantime_serde"
+    )]
+    pub history_retention: Duration,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            timeout_alive: HeartbeatTimeoutMode::default(),
+            recently_seen: default_heartbeat_recently_seen(),
+            min_interval: default_heartbeat_min_interval(),
+            max_interval: default_heartbeat_max_interval(),
+            history: true,
+            history_retention: default_heartbeat_history_retention(),
+        }
+    }
+}
+
+fn default_heartbeat_recently_seen() -> Duration {
+    Duration::from_secs(DEFAULT_HEARTBEAT_RECENTLY_SEEN_SECS)
+}
+
+fn default_heartbeat_min_interval() -> Duration {
+    Duration::from_secs(DEFAULT_HEARTBEAT_MIN_INTERVAL_SECS)
+}
+
+fn default_heartbeat_max_interval() -> Duration {
+    Duration::from_secs(DEFAULT_HEARTBEAT_MAX_INTERVAL_SECS)
+}
+
+fn default_heartbeat_history_retention() -> Duration {
+    Duration::from_secs(DEFAULT_HEARTBEAT_HISTORY_RETENTION_SECS)
+}
+
+/// Device state assumed when the heartbeat connection times out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HeartbeatTimeoutMode {
+    /// Retry the connection, the device stays disconnected.
+    Off,
+    /// Consider the device alive.
+    On,
+    /// Consider the device alive if its heartbeat was established within `recently_seen`.
+    #[default]
+    RecentlySeen,
+}
+
+/// Syslog service configuration.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SyslogConfig {
+    /// How syslog lines are stored.
+    #[serde(default)]
+    pub format: SyslogFormat,
+    /// Escapes control characters, such as NUL or embedded newlines, so that every line
+    /// stays a single parseable line.
+    #[serde(default = "default_syslog_sanitize")]
+    pub sanitize: bool,
+}
+
+impl Default for SyslogConfig {
+    fn default() -> Self {
+        Self {
+            format: SyslogFormat::default(),
+            sanitize: default_syslog_sanitize(),
+        }
+    }
+}
+
+fn default_syslog_sanitize() -> bool {
+    true
+}
+
+/// Storage format of syslog lines.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogFormat {
+    /// Lines as received.
+    #[default]
+    Raw,
+    /// Newline-delimited JSON objects with the line fields.
+    Json,
+}
+
+/// Per-device values shadowing the global configuration. Unset values keep the global one.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigOverrides {
+    #[serde(
+        default,
+        with = "humantime_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub refresh_rate: Option<Duration>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crashes: Option<CrashesOverrides>,
+}
+
+/// Per-device crashes service values, see [`CrashesConfig`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CrashesOverrides {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exclude_globs: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path_mode: Option<CrashPathMode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub track_changes: Option<bool>,
+    #[serde(
+        default,
+        with = "humantime_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub poll_interval: Option<Duration>,
+    #[serde(
+        default,
+        with = "humantime_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub retry_wait: Option<Duration>,
+}
+
 impl Config {
     /// Parses the config file and returns the values.
+    /// `base_dir` is created and made canonical, see [`canonical_base_dir`].
     pub fn parse(path: &Path) -> Result<Config, Box<dyn Error>> {
-        let config_str = read_to_string(path)?;
-        let config: Config = toml::from_str(&config_str)?;
+        Self::parse_layered(&[path])
+    }
+
+    /// Parses config files layered in order, such as a base config then an environment
+    /// overlay. Tables are merged key by key, any other value, lists included, is replaced
+    /// by the one of the later file. Relative paths are resolved against the dir of the file
+    /// setting them, see [`resolve_path`].
+    pub fn parse_layered(paths: &[&Path]) -> Result<Config, Box<dyn Error>> {
+        let mut merged = toml::Table::new();
+        for path in paths {
+            let config_str =
+                read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+            let mut layer: toml::Table =
+                toml::from_str(&config_str).map_err(|e| format!("{}: {e}", path.display()))?;
+
+            let config_dir = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir.canonicalize()?,
+                _ => std::env::current_dir()?,
+            };
+            resolve_layer_paths(&mut layer, &config_dir)?;
+            merge_tables(&mut merged, layer);
+        }
+
+        // The error names the offending key, such as an unknown one
+        let mut config: Config = toml::Value::Table(merged).try_into().map_err(|e| {
+            let paths = paths
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect::<Vec<String>>();
+            format!("{}: {e}", paths.join(", "))
+        })?;
+
+        // Only the dirs of the merged config, not the ones a later file replaced
+        config.settings.base_dir = config.settings.base_dir.canonical()?;
         Ok(config)
     }
 
-    pub fn get_base_dir(&self) -> String {
-        self.settings.base_dir.clone()
+    pub fn get_base_dirs(&self) -> Vec<String> {
+        self.settings.base_dir.dirs()
+    }
+
+    /// Base dir of a device, see [`device_base_dir`].
+    pub fn get_base_dir(&self, udid: &str) -> String {
+        device_base_dir(&self.get_base_dirs(), udid)
+    }
+
+    /// Checks values serde cannot, including that `base_dir` is writable.
+    /// Returns the problems found, empty if the config is valid.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if self
+            .log
+            .as_ref()
+            .is_some_and(|log| log.max_files == Some(0))
+        {
+            problems.push("log.max_files must not be zero".to_string());
+        }
+        if self.settings.refresh_rate < Duration::from_secs(1) {
+            problems.push("refresh_rate must be at least 1s".to_string());
+        }
+        if self.settings.reachability_timeout.is_zero() {
+            problems.push("reachability_timeout must not be zero".to_string());
+        }
+        if self.settings.read_timeout.is_zero() {
+            problems.push("read_timeout must not be zero".to_string());
+        }
+        if self.settings.flush_interval.is_zero() {
+            problems.push("flush_interval must not be zero".to_string());
+        }
+        if self.settings.max_download_bytes_per_sec == Some(0) {
+            problems.push("max_download_bytes_per_sec must not be zero".to_string());
+        }
+        if self.settings.max_devices == Some(0) {
+            problems.push("max_devices must not be zero".to_string());
+        }
+        if !self.services.heartbeat {
+            problems.push("services.heartbeat cannot be disabled".to_string());
+        }
+        if self.heartbeat.min_interval.as_secs() == 0 {
+            problems.push("heartbeat.min_interval must be at least one second".to_string());
+        }
+        if self.heartbeat.min_interval > self.heartbeat.max_interval {
+            problems.push("heartbeat.min_interval must not exceed max_interval".to_string());
+        }
+        if self.heartbeat.history && self.heartbeat.history_retention.is_zero() {
+            problems.push("heartbeat.history_retention must not be zero".to_string());
+        }
+        if self.idle.enabled && self.idle.interval.is_zero() {
+            problems.push("idle.interval must not be zero".to_string());
+        }
+        if self.crashes.max_file_bytes == Some(0) {
+            problems.push("crashes.max_file_bytes must not be zero".to_string());
+        }
+        if self.crashes.dead_letter_after == 0 {
+            problems.push("crashes.dead_letter_after must not be zero".to_string());
+        }
+        if self.services.installed_apps && self.installed_apps.interval.is_zero() {
+            problems.push("installed_apps.interval must not be zero".to_string());
+        }
+        if self.os_trace.archive_retry_wait.is_zero() {
+            problems.push("os_trace.archive_retry_wait must not be zero".to_string());
+        }
+        if self.os_trace.archive_size_limit == 0 {
+            problems.push("os_trace.archive_size_limit must not be zero".to_string());
+        }
+        if self.os_trace.archive_age_limit == 0 {
+            problems.push("os_trace.archive_age_limit must not be zero".to_string());
+        }
+        if self.os_trace.archive_start_margin > Duration::from_secs(MAX_ARCHIVE_START_MARGIN_SECS) {
+            problems.push("os_trace.archive_start_margin must not exceed a day".to_string());
+        }
+        if self.services.pairing_check && self.pairing_check.interval.is_zero() {
+            problems.push("pairing_check.interval must not be zero".to_string());
+        }
+        if self.crashes.list_timeout.is_zero() {
+            problems.push("crashes.list_timeout must not be zero".to_string());
+        }
+        if self.crashes.pull_timeout.is_zero() {
+            problems.push("crashes.pull_timeout must not be zero".to_string());
+        }
+        if self.crashes.store == CrashStore::Remote {
+            match &self.crashes.remote {
+                None => problems
+                    .push("crashes.store = \"remote\" needs a [crashes.remote] table".to_string()),
+                Some(remote) => match (&remote.dir, &remote.s3) {
+                    (Some(_), Some(_)) | (None, None) => problems.push(
+                        "crashes.remote needs either a dir or a [crashes.remote.s3] table"
+                            .to_string(),
+                    ),
+                    (None, Some(_)) if !cfg!(feature = "s3") => problems.push(
+                        "crashes.remote.s3 needs imonitor built with the s3 feature".to_string(),
+                    ),
+                    _ => {}
+                },
+            }
+            if self.crashes.dedup {
+                problems.push("crashes.dedup needs crashes.store = \"local\"".to_string());
+            }
+        }
+        if self.crashes.poll_interval.is_zero() {
+            problems.push("crashes.poll_interval must not be zero".to_string());
+        }
+        for glob in &self.crashes.exclude_globs {
+            if let Err(e) = glob::Pattern::new(glob) {
+                problems.push(format!(
+                    "Invalid crashes.exclude_globs pattern \"{glob}\": {e}"
+                ));
+            }
+        }
+        for (name, pattern) in self.device_filter.patterns() {
+            if let Err(e) = glob::Pattern::new(pattern) {
+                problems.push(format!(
+                    "Invalid device_filter.{name} pattern \"{pattern}\": {e}"
+                ));
+            }
+        }
+
+        let base_dirs = self.get_base_dirs();
+        if base_dirs.is_empty() {
+            problems.push("base_dir must not be an empty list".to_string());
+        }
+        for base_dir in &base_dirs {
+            if let Err(e) = check_writable(Path::new(base_dir)) {
+                problems.push(format!("base_dir {base_dir} is not writable: {e}"));
+            }
+        }
+        let unique_dirs = base_dirs.iter().collect::<HashSet<_>>();
+        if unique_dirs.len() != base_dirs.len() {
+            problems.push("base_dir lists a dir more than once".to_string());
+        }
+
+        problems
+    }
+
+    /// Returns a copy of the config with the device overrides applied.
+    pub fn with_overrides(&self, overrides: &ConfigOverrides) -> Config {
+        let mut config = self.clone();
+
+        if let Some(refresh_rate) = overrides.refresh_rate {
+            config.settings.refresh_rate = refresh_rate;
+        }
+
+        if let Some(crashes) = &overrides.crashes {
+            if let Some(exclude_globs) = &crashes.exclude_globs {
+                config.crashes.exclude_globs = exclude_globs.clone();
+            }
+            if let Some(path_mode) = crashes.path_mode {
+                config.crashes.path_mode = path_mode;
+            }
+            if let Some(track_changes) = crashes.track_changes {
+                config.crashes.track_changes = track_changes;
+            }
+            if let Some(poll_interval) = crashes.poll_interval {
+                config.crashes.poll_interval = poll_interval;
+            }
+            if let Some(retry_wait) = crashes.retry_wait {
+                config.crashes.retry_wait = retry_wait;
+            }
+        }
+
+        config
+    }
+}
+
+/// Expands a leading `~` to the home directory and resolves relative paths against the
+/// config file directory rather than the working directory.
+pub fn resolve_path(path: &str, config_dir: &Path) -> Result<String, Box<dyn Error>> {
+    let expanded = match path.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+            let home = std::env::var("HOME").map_err(|_| "HOME is not set, cannot expand ~")?;
+            PathBuf::from(home).join(rest.trim_start_matches('/'))
+        }
+        _ => PathBuf::from(path),
+    };
+
+    let resolved = if expanded.is_absolute() {
+        expanded
+    } else {
+        config_dir.join(expanded)
+    };
+
+    Ok(resolved.to_string_lossy().to_string())
+}
+
+/// Creates a resolved base dir if needed and returns its canonical path, so that a dir
+/// listed twice through a symlink or `..` is caught, and a device keeps its shard whatever
+/// the spelling of the dir. It must exist to be canonical.
+pub fn canonical_base_dir(base_dir: &str) -> Result<String, Box<dyn Error>> {
+    create_dir_all(base_dir).map_err(|e| format!("base_dir {base_dir}: {e}"))?;
+    let canonical = Path::new(base_dir)
+        .canonicalize()
+        .map_err(|e| format!("base_dir {base_dir}: {e}"))?;
+    Ok(canonical.to_string_lossy().to_string())
+}
+
+/// Merges `overlay` into `base`: nested tables are merged, other values replaced.
+fn merge_tables(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base_table)), toml::Value::Table(overlay_table)) => {
+                merge_tables(base_table, overlay_table)
+            }
+            (Some(base_value), value) => *base_value = value,
+            (None, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Resolves the paths set by one config file, see [`resolve_path`]. Values of the wrong
+/// type are left for deserialization to report.
+fn resolve_layer_paths(layer: &mut toml::Table, config_dir: &Path) -> Result<(), Box<dyn Error>> {
+    let resolve = |value: &mut toml::Value| -> Result<(), Box<dyn Error>> {
+        if let toml::Value::String(path) = value {
+            *path = resolve_path(path, config_dir)?;
+        }
+        Ok(())
+    };
+
+    if let Some(toml::Value::Table(settings)) = layer.get_mut("config") {
+        match settings.get_mut("base_dir") {
+            Some(toml::Value::Array(dirs)) => dirs.iter_mut().try_for_each(resolve)?,
+            Some(dir) => resolve(dir)?,
+            None => {}
+        }
+        if let Some(liveness_file) = settings.get_mut("liveness_file") {
+            resolve(liveness_file)?;
+        }
+    }
+    for section in ["log", "audit_log"] {
+        if let Some(toml::Value::Table(section)) = layer.get_mut(section)
+            && let Some(path) = section.get_mut("path")
+        {
+            resolve(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Base dir of a device among `dirs`: the one already holding its dir, else the one picked
+/// by [`shard_base_dir`], so that a device keeps its volume across restarts. Empty if there
+/// is no dir.
+pub fn device_base_dir(dirs: &[String], udid: &str) -> String {
+    if let [dir] = dirs {
+        return dir.clone();
+    }
+    if let Some(dir) = dirs.iter().find(|dir| Path::new(dir).join(udid).is_dir()) {
+        return dir.clone();
+    }
+    shard_base_dir(dirs, udid).unwrap_or_default()
+}
+
+/// Dir assigned to a device from a stable hash of its UDID. None if there is no dir.
+pub fn shard_base_dir(dirs: &[String], udid: &str) -> Option<String> {
+    if dirs.is_empty() {
+        return None;
+    }
+    let index = fnv1a(udid.as_bytes()) % dirs.len() as u64;
+    dirs.get(index as usize).cloned()
+}
+
+/// Space left on the volume holding a base dir.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct VolumeUsage {
+    pub base_dir: String,
+    pub available_bytes: u64,
+    pub total_bytes: u64,
+}
+
+impl VolumeUsage {
+    pub fn new(base_dir: &str) -> std::io::Result<VolumeUsage> {
+        Ok(VolumeUsage {
+            base_dir: base_dir.to_string(),
+            available_bytes: fs2::available_space(base_dir)?,
+            total_bytes: fs2::total_space(base_dir)?,
+        })
+    }
+
+    /// Less than `min_free_ratio` of the volume is available.
+    pub fn is_near_full(&self, min_free_ratio: f64) -> bool {
+        self.total_bytes > 0
+            && (self.available_bytes as f64) < self.total_bytes as f64 * min_free_ratio
+    }
+}
+
+/// Creates the dir if needed and writes then removes a probe file in it.
+fn check_writable(dir: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let probe_path = dir.join(".imonitor_write_test");
+    std::fs::write(&probe_path, b"")?;
+    std::fs::remove_file(&probe_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINIMAL_CONFIG: &str = r#"
+[config]
+base_dir = "/var/lib/imonitor"
+
+[encryption]
+public_keys = []
+"#;
+
+    /// Default config whose base dir is writable, so that only the tested problem shows.
+    fn test_config() -> Config {
+        let mut config = Config::default();
+        config.settings.base_dir =
+            BaseDir::Single(std::env::temp_dir().to_string_lossy().to_string());
+        config
+    }
+
+    #[test]
+    fn disabled_services_are_not_spawned() {
+        let services = ServicesConfig {
+            syslog: false,
+            os_trace_archive: true,
+            ..Default::default()
+        };
+
+        let enabled = services.enabled_services();
+        assert!(!enabled.contains(&Service::Syslog));
+        assert_eq!(
+            enabled,
+            vec![
+                Service::Crashes,
+                Service::OsTraceLog,
+                Service::OsTraceArchive,
+                Service::DeviceState
+            ]
+        );
+    }
+
+    #[test]
+    fn disabled_heartbeat_is_rejected() {
+        let mut config = test_config();
+        config.services.heartbeat = false;
+
+        let problems = config.validate();
+        assert!(
+            problems
+                .iter()
+                .any(|problem| problem == "services.heartbeat cannot be disabled")
+        );
+    }
+
+    #[test]
+    fn archive_request_settings_are_parsed_and_bounded() {
+        let config = toml::from_str::<Config>(&format!(
+            "{MINIMAL_CONFIG}\n[os_trace]\narchive_size_limit = 200\narchive_start_margin = \"25h\"\n"
+        ))
+        .unwrap();
+        assert_eq!(config.os_trace.archive_size_limit, 200);
+        assert_eq!(config.os_trace.archive_age_limit, 1);
+        assert_eq!(
+            config.os_trace.archive_start_margin,
+            Duration::from_secs(25 * 60 * 60)
+        );
+
+        let mut config = test_config();
+        config.os_trace = OsTraceConfig {
+            archive_start_margin: Duration::from_secs(25 * 60 * 60),
+            archive_age_limit: 0,
+            ..Default::default()
+        };
+        let problems = config.validate();
+        assert!(
+            problems.contains(&"os_trace.archive_start_margin must not exceed a day".to_string())
+        );
+        assert!(problems.contains(&"os_trace.archive_age_limit must not be zero".to_string()));
+    }
+
+    #[test]
+    fn zero_max_devices_is_rejected() {
+        let mut config = test_config();
+        assert_eq!(config.settings.max_devices, None);
+        config.settings.max_devices = Some(0);
+        assert!(
+            config
+                .validate()
+                .iter()
+                .any(|problem| problem == "max_devices must not be zero")
+        );
+    }
+
+    #[test]
+    fn default_settings_match_an_empty_config_section() {
+        let parsed = toml::from_str::<Config>(MINIMAL_CONFIG).unwrap().settings;
+        let settings = Settings::default();
+        assert_eq!(settings.refresh_rate, parsed.refresh_rate);
+        assert_eq!(settings.flush_interval, parsed.flush_interval);
+        assert_eq!(settings.reachability_timeout, parsed.reachability_timeout);
+        assert_eq!(settings.read_timeout, parsed.read_timeout);
+
+        let problems = test_config().validate();
+        for setting in [
+            "refresh_rate",
+            "reachability_timeout",
+            "read_timeout",
+            "flush_interval",
+        ] {
+            assert!(
+                !problems.iter().any(|problem| problem.starts_with(setting)),
+                "{setting}: {problems:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn crash_poll_interval_and_retry_wait_override_the_defaults() {
+        let config = toml::from_str::<Config>(MINIMAL_CONFIG).unwrap();
+        assert_eq!(config.crashes.poll_interval, Duration::from_secs(15));
+        assert_eq!(config.crashes.retry_wait, Duration::from_secs(15));
+
+        let content =
+            format!("{MINIMAL_CONFIG}\n[crashes]\npoll_interval = \"5m\"\nretry_wait = \"30s\"\n");
+        let config = toml::from_str::<Config>(&content).unwrap();
+        assert_eq!(config.crashes.poll_interval, Duration::from_secs(300));
+        assert_eq!(config.crashes.retry_wait, Duration::from_secs(30));
+
+        // A device override wins over both
+        let overrides = ConfigOverrides {
+            crashes: Some(CrashesOverrides {
+                poll_interval: Some(Duration::from_secs(60)),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let config = config.with_overrides(&overrides);
+        assert_eq!(config.crashes.poll_interval, Duration::from_secs(60));
+        assert_eq!(config.crashes.retry_wait, Duration::from_secs(30));
+    }
+
+    #[test]
+    fn remote_crash_store_needs_its_table_and_no_dedup() {
+        let mut config = test_config();
+        config.crashes.store = CrashStore::Remote;
+        config.crashes.dedup = true;
+
+        let problems = config.validate();
+        assert!(
+            problems
+                .iter()
+                .any(|problem| problem.contains("[crashes.remote]"))
+        );
+        assert!(
+            problems
+                .iter()
+                .any(|problem| problem.contains("crashes.dedup"))
+        );
+
+        let content = format!(
+            "{MINIMAL_CONFIG}\n[crashes]\nstore = \"remote\"\n\n[crashes.remote.s3]\nbucket = \"crashes\"\nendpoint = \"https://s3.example.com\"\n"
+        );
+        let config = toml::from_str::<Config>(&content).unwrap();
+        assert_eq!(config.crashes.store, CrashStore::Remote);
+        let s3 = config.crashes.remote.and_then(|remote| remote.s3).unwrap();
+        assert_eq!(s3.prefix, "");
+        assert!(!s3.force_path_style);
+        assert!(!s3.insecure_skip_verify);
+    }
+
+    #[test]
+    fn remote_crash_store_needs_a_dir_or_a_bucket() {
+        let mut config = test_config();
+        config.crashes.store = CrashStore::Remote;
+        config.crashes.remote = Some(RemoteStoreConfig::default());
+        assert!(
+            config
+                .validate()
+                .iter()
+                .any(|problem| problem.contains("either a dir"))
+        );
+
+        config.crashes.remote = Some(RemoteStoreConfig {
+            dir: Some("/mnt/crashes".to_string()),
+            s3: None,
+        });
+        assert!(
+            !config
+                .validate()
+                .iter()
+                .any(|problem| problem.contains("crashes.remote"))
+        );
+    }
+
+    #[test]
+    fn device_filter_deny_wins_over_allow() {
+        let filter = DeviceFilterConfig {
+            allow_models: vec!["iPhone15,*".to_string()],
+            deny_models: vec!["iPhone15,3".to_string()],
+            deny_versions: vec!["16.*".to_string()],
+            ..Default::default()
+        };
+
+        assert_eq!(filter.skip_reason(Some("iPhone15,2"), Some("17.4")), None);
+        assert_eq!(
+            filter
+                .skip_reason(Some("iPhone15,3"), Some("17.4"))
+                .as_deref(),
+            Some("model iPhone15,3 is denied by device_filter")
+        );
+        assert_eq!(
+            filter
+                .skip_reason(Some("iPhone14,2"), Some("17.4"))
+                .as_deref(),
+            Some("model iPhone14,2 is not allowed by device_filter")
+        );
+        assert_eq!(
+            filter
+                .skip_reason(Some("iPhone15,2"), Some("16.7"))
+                .as_deref(),
+            Some("iOS version 16.7 is denied by device_filter")
+        );
+    }
+
+    #[test]
+    fn device_filter_unknown_values_match_no_pattern() {
+        let filter = DeviceFilterConfig {
+            allow_models: vec!["*".to_string()],
+            deny_versions: vec!["*".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            filter.skip_reason(None, None).as_deref(),
+            Some("model unknown is not allowed by device_filter")
+        );
+
+        assert!(DeviceFilterConfig::default().is_empty());
+        assert_eq!(DeviceFilterConfig::default().skip_reason(None, None), None);
+    }
+
+    #[test]
+    fn overrides_only_replace_the_values_set() {
+        let mut config = test_config();
+        config.crashes.exclude_globs = vec!["Retired/*".to_string()];
+        config.crashes.track_changes = true;
+
+        let overrides = toml::from_str::<ConfigOverrides>(
+            "refresh_rate = \"5s\"\n\n[crashes]\npath_mode = \"flatten\"\npoll_interval = \"1m\"\n",
+        )
+        .unwrap();
+        let overridden = config.with_overrides(&overrides);
+
+        assert_eq!(overridden.settings.refresh_rate, Duration::from_secs(5));
+        assert_eq!(overridden.crashes.path_mode, CrashPathMode::Flatten);
+        assert_eq!(overridden.crashes.poll_interval, Duration::from_secs(60));
+        assert_eq!(overridden.crashes.exclude_globs, ["Retired/*"]);
+        assert!(overridden.crashes.track_changes);
+        assert_eq!(overridden.crashes.retry_wait, config.crashes.retry_wait);
+        // The global config is left as is
+        assert_eq!(config.crashes.path_mode, CrashPathMode::Preserve);
+    }
+
+    #[test]
+    fn empty_overrides_keep_the_config() {
+        let config = test_config();
+        let overridden = config.with_overrides(&ConfigOverrides::default());
+        assert_eq!(
+            overridden.settings.refresh_rate,
+            config.settings.refresh_rate
+        );
+        assert_eq!(
+            overridden.crashes.poll_interval,
+            config.crashes.poll_interval
+        );
+    }
+
+    #[test]
+    fn refresh_rate_defaults_to_a_minute() {
+        let mut config = toml::from_str::<Config>(MINIMAL_CONFIG).unwrap();
+        assert_eq!(config.settings.refresh_rate, Duration::from_secs(60));
+
+        config.settings.base_dir = test_config().settings.base_dir;
+        assert_eq!(config.validate(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn sub_second_refresh_rate_is_rejected() {
+        let mut config = test_config();
+        config.settings.refresh_rate = Duration::from_millis(500);
+        assert!(
+            config
+                .validate()
+                .contains(&"refresh_rate must be at least 1s".to_string())
+        );
+
+        let content = MINIMAL_CONFIG.replace("[config]\n", "[config]\nrefresh_rate = \"soon\"\n");
+        let error = toml::from_str::<Config>(&content).unwrap_err().to_string();
+        assert!(error.contains("refresh_rate"), "{error}");
+    }
+
+    #[test]
+    fn layered_files_are_merged_field_by_field() {
+        let dir = std::env::temp_dir().join(format!("imonitor-layers-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let base = dir.join("base.toml");
+        let overlay = dir.join("overlay.toml");
+        std::fs::write(
+            &base,
+            format!(
+                "{MINIMAL_CONFIG}\n[crashes]\nexclude_globs = [\"Retired/*\", \"*.synced\"]\ntrack_changes = true\npoll_interval = \"30s\"\n"
+            ),
+        )
+        .unwrap();
+        std::fs::write(
+            &overlay,
+            "[config]\nbase_dir = \"devices\"\n\n[crashes]\nexclude_globs = [\"Logs/*\"]\npoll_interval = \"1m\"\n",
+        )
+        .unwrap();
+
+        let config = Config::parse_layered(&[&base, &overlay]).unwrap();
+        let devices_dir = dir.canonicalize().unwrap().join("devices");
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // Relative paths are resolved against the file setting them
+        assert_eq!(
+            config.get_base_dirs(),
+            [devices_dir.to_string_lossy().to_string()]
+        );
+        // Lists are replaced, not appended to
+        assert_eq!(config.crashes.exclude_globs, ["Logs/*"]);
+        assert_eq!(config.crashes.poll_interval, Duration::from_secs(60));
+        // Values the overlay does not set are kept
+        assert!(config.crashes.track_changes);
+    }
+
+    #[test]
+    fn paths_are_resolved_against_the_config_dir() {
+        let config_dir = Path::new("/etc/imonitor");
+        assert_eq!(
+            resolve_path("/var/log/imonitor.log", config_dir).unwrap(),
+            "/var/log/imonitor.log"
+        );
+        assert_eq!(
+            resolve_path("logs/imonitor.log", config_dir).unwrap(),
+            "/etc/imonitor/logs/imonitor.log"
+        );
+        let home = std::env::var("HOME").unwrap();
+        assert_eq!(
+            resolve_path("~/imonitor", config_dir).unwrap(),
+            Path::new(&home).join("imonitor").to_string_lossy()
+        );
+        // Only a whole `~` component is expanded
+        assert_eq!(
+            resolve_path("~user/imonitor", config_dir).unwrap(),
+            "/etc/imonitor/~user/imonitor"
+        );
+    }
+
+    #[test]
+    fn base_dirs_are_created_and_canonical() {
+        let dir = std::env::temp_dir().join(format!("imonitor-base-dirs-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(dir.join("config")).unwrap();
+        let dir = dir.canonicalize().unwrap();
+        std::os::unix::fs::symlink(dir.join("config"), dir.join("link")).unwrap();
+        let home_dir_name = format!(".imonitor-base-dir-{}", uuid::Uuid::new_v4());
+        let home_dir = Path::new(&std::env::var("HOME").unwrap())
+            .canonicalize()
+            .unwrap()
+            .join(&home_dir_name);
+
+        let path = dir.join("config/imonitor.toml");
+        let base_dirs = format!(
+            "base_dir = [\"devices/../relative\", \"{}/link/absolute\", \"~/{home_dir_name}\"]",
+            dir.display()
+        );
+        std::fs::write(
+            &path,
+            MINIMAL_CONFIG.replace("base_dir = \"/var/lib/imonitor\"", &base_dirs),
+        )
+        .unwrap();
+
+        let config = Config::parse(&path).unwrap();
+        let expected = [
+            dir.join("config/relative"),
+            dir.join("config/absolute"),
+            home_dir.clone(),
+        ];
+        let is_dir = expected.iter().all(|dir| dir.is_dir());
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_dir_all(&home_dir).unwrap();
+
+        assert_eq!(
+            config.get_base_dirs(),
+            expected.map(|dir| dir.to_string_lossy().to_string())
+        );
+        assert!(is_dir);
+    }
+
+    #[test]
+    fn devices_are_sharded_by_udid() {
+        let dirs = [
+            "/mnt/a".to_string(),
+            "/mnt/b".to_string(),
+            "/mnt/c".to_string(),
+        ];
+        assert_eq!(shard_base_dir(&[], "udid"), None);
+        assert_eq!(
+            shard_base_dir(&dirs[..1], "udid").as_deref(),
+            Some("/mnt/a")
+        );
+
+        // Stable, and spread over the dirs
+        let shards = (0..30)
+            .map(|n| shard_base_dir(&dirs, &format!("0000{n}-UDID")).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(shards[7], shard_base_dir(&dirs, "00007-UDID").unwrap());
+        assert_eq!(shards.iter().collect::<HashSet<_>>().len(), dirs.len());
+    }
+
+    #[test]
+    fn device_keeps_the_base_dir_holding_its_dir() {
+        let root = std::env::temp_dir().join(format!("imonitor-shards-{}", uuid::Uuid::new_v4()));
+        let dirs = ["a", "b"]
+            .map(|dir| root.join(dir).to_string_lossy().to_string())
+            .to_vec();
+        let udid = "00008030-SHARD";
+        let shard = shard_base_dir(&dirs, udid).unwrap();
+        assert_eq!(device_base_dir(&dirs, udid), shard);
+
+        // Moved to the other dir, e.g. before a dir was added
+        let other = dirs.iter().find(|dir| **dir != shard).unwrap();
+        std::fs::create_dir_all(Path::new(other).join(udid)).unwrap();
+        assert_eq!(&device_base_dir(&dirs, udid), other);
+        assert_eq!(device_base_dir(&[], udid), "");
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn merge_replaces_scalars_and_merges_tables() {
+        let mut base = toml::from_str::<toml::Table>("a = 1\n[t]\nb = 2\nc = 3\n").unwrap();
+        let overlay = toml::from_str::<toml::Table>("a = 4\n[t]\nc = 5\nd = 6\n").unwrap();
+
+        merge_tables(&mut base, overlay);
+        assert_eq!(
+            base,
+            toml::from_str::<toml::Table>("a = 4\n[t]\nb = 2\nc = 5\nd = 6\n").unwrap()
+        );
+    }
+
+    #[test]
+    fn valid_config_parses() {
+        let config = toml::from_str::<Config>(MINIMAL_CONFIG).unwrap();
+        assert_eq!(
+            config.get_base_dirs(),
+            vec!["/var/lib/imonitor".to_string()]
+        );
+    }
+
+    #[test]
+    fn unknown_key_is_rejected_by_name() {
+        for (section, key) in [
+            ("config", "refrsh_rate = \"5s\""),
+            ("crashes", "poll_intervall = \"5s\""),
+            ("services", "os_trace_archives = true"),
+            ("heartbeat", "min_intervl = \"5s\""),
+        ] {
+            // [config] is already in the minimal config, a table cannot be opened twice
+            let content = match section {
+                "config" => MINIMAL_CONFIG.replace("[config]\n", &format!("[config]\n{key}\n")),
+                _ => format!("{MINIMAL_CONFIG}\n[{section}]\n{key}\n"),
+            };
+            let error = toml::from_str::<Config>(&content).unwrap_err().to_string();
+            let name = key.split(' ').next().unwrap();
+            assert!(error.contains(name), "{error}");
+        }
     }
 }
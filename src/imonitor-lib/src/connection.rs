@@ -0,0 +1,234 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use tokio::sync::oneshot;
+
+/// Priority of a service connection. Queued connections are granted a slot by decreasing
+/// priority, then in arrival order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ConnectionPriority {
+    /// os trace log and archive, syslog, device state
+    Low,
+    /// Crash collection
+    Normal,
+    /// Heartbeat, which every other service waits for
+    High,
+}
+
+/// Limits the number of service connections being established at the same time, shared by
+/// all devices. Unlike a plain semaphore, a waiting high priority connection gets the next
+/// free slot before lower priority ones queued earlier.
+#[derive(Debug)]
+pub struct ConnectionManager {
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    available: usize,
+    waiters: BinaryHeap<Waiter>,
+    next_sequence: u64,
+}
+
+#[derive(Debug)]
+struct Waiter {
+    priority: ConnectionPriority,
+    sequence: u64,
+    grant: oneshot::Sender<()>,
+}
+
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Waiter {}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Waiter {
+    // Max-heap: highest priority first, then lowest sequence
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+/// Connection slot, given back to the manager when dropped.
+#[derive(Debug)]
+pub struct ConnectionPermit<'a> {
+    manager: &'a ConnectionManager,
+}
+
+impl Drop for ConnectionPermit<'_> {
+    fn drop(&mut self) {
+        self.manager.release();
+    }
+}
+
+/// Waiting acquire. Passes the slot on if it is granted after the acquire was cancelled.
+struct PendingGrant<'a> {
+    manager: &'a ConnectionManager,
+    granted: oneshot::Receiver<()>,
+}
+
+impl Drop for PendingGrant<'_> {
+    fn drop(&mut self) {
+        if self.granted.try_recv().is_ok() {
+            self.manager.release();
+        }
+    }
+}
+
+impl ConnectionManager {
+    /// Non-zero, a manager without any slot would never grant one.
+    pub fn new(limit: NonZeroUsize) -> Self {
+        Self {
+            state: Mutex::new(State {
+                available: limit.get(),
+                waiters: BinaryHeap::new(),
+                next_sequence: 0,
+            }),
+        }
+    }
+
+    /// Waits for a connection slot.
+    pub async fn acquire(&self, priority: ConnectionPriority) -> ConnectionPermit<'_> {
+        let granted = {
+            let mut state = self.lock();
+            if state.available > 0 && state.waiters.is_empty() {
+                state.available -= 1;
+                None
+            } else {
+                let (grant, granted) = oneshot::channel();
+                let sequence = state.next_sequence;
+                state.next_sequence += 1;
+                state.waiters.push(Waiter {
+                    priority,
+                    sequence,
+                    grant,
+                });
+                Some(granted)
+            }
+        };
+
+        if let Some(granted) = granted {
+            let mut pending = PendingGrant {
+                manager: self,
+                granted,
+            };
+            // The sender is only dropped once the slot is handed over
+            let _ = (&mut pending.granted).await;
+        }
+
+        ConnectionPermit { manager: self }
+    }
+
+    /// Hands the slot over to the next waiter still waiting, or makes it available.
+    fn release(&self) {
+        let mut state = self.lock();
+        while let Some(waiter) = state.waiters.pop() {
+            // Fails if the waiter gave up, e.g. on a timeout
+            if waiter.grant.send(()).is_ok() {
+                return;
+            }
+        }
+        state.available += 1;
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::task::yield_now;
+    use tokio::time::timeout;
+
+    const WAIT: Duration = Duration::from_millis(50);
+
+    #[tokio::test]
+    async fn slots_are_granted_by_priority_then_arrival() {
+        let manager = ConnectionManager::new(NonZeroUsize::MIN);
+        let order = Mutex::new(Vec::new());
+        let first = manager.acquire(ConnectionPriority::High).await;
+
+        let waiter = |priority, name: &'static str| {
+            let manager = &manager;
+            let order = &order;
+            async move {
+                let _permit = manager.acquire(priority).await;
+                order.lock().unwrap().push(name);
+                // Holds the slot until the others had a chance to be polled
+                yield_now().await;
+            }
+        };
+
+        tokio::join!(
+            waiter(ConnectionPriority::Low, "low"),
+            waiter(ConnectionPriority::Normal, "normal 1"),
+            waiter(ConnectionPriority::High, "high"),
+            waiter(ConnectionPriority::Normal, "normal 2"),
+            async move {
+                yield_now().await;
+                drop(first);
+            },
+        );
+
+        assert_eq!(
+            order.into_inner().unwrap(),
+            ["high", "normal 1", "normal 2", "low"]
+        );
+    }
+
+    #[tokio::test]
+    async fn abandoned_acquire_does_not_keep_the_slot() {
+        let manager = ConnectionManager::new(NonZeroUsize::MIN);
+        let first = manager.acquire(ConnectionPriority::Low).await;
+
+        // Gives up while queued, the slot goes back to the manager
+        assert!(
+            timeout(WAIT, manager.acquire(ConnectionPriority::High))
+                .await
+                .is_err()
+        );
+        drop(first);
+
+        assert!(
+            timeout(WAIT, manager.acquire(ConnectionPriority::Low))
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn grant_after_cancel_is_passed_on() {
+        let manager = ConnectionManager::new(NonZeroUsize::MIN);
+        let first = manager.acquire(ConnectionPriority::Low).await;
+
+        let mut pending = Box::pin(manager.acquire(ConnectionPriority::High));
+        assert!(timeout(WAIT, &mut pending).await.is_err());
+        // Granted to the pending acquire, which is dropped before noticing it
+        drop(first);
+        drop(pending);
+
+        assert!(
+            timeout(WAIT, manager.acquire(ConnectionPriority::Low))
+                .await
+                .is_ok()
+        );
+    }
+}
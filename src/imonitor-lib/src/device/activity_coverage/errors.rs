@@ -1,11 +1,13 @@
+use crate::state_store::StateStoreError;
+
 #[derive(Debug)]
 pub enum ActivityCoverageError {
     CreateFile(std::io::Error, String),
     ReadFile(std::io::Error, String),
     FileExists(std::io::Error, String),
     WriteToFile(std::io::Error, String),
-    Serialize(serde_json::Error),
-    Deserialize(serde_json::Error),
+    Serialize(StateStoreError),
+    Deserialize(StateStoreError),
 }
 
 impl std::error::Error for ActivityCoverageError {}
@@ -26,10 +28,10 @@ impl std::fmt::Display for ActivityCoverageError {
                 write!(f, "Failed to write to file {file_name}: {e}")
             }
             ActivityCoverageError::Serialize(e) => {
-                write!(f, "Failed to serialize known crashes: {e}")
+                write!(f, "Failed to serialize activity coverage: {e}")
             }
             ActivityCoverageError::Deserialize(e) => {
-                write!(f, "Failed to deserialize known crashes: {e}")
+                write!(f, "Failed to deserialize activity coverage: {e}")
             }
         }
     }
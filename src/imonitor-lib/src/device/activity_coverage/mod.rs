@@ -1,5 +1,6 @@
 pub mod errors;
 
+use crate::state_store::{StateFormat, StateStore, decode_state};
 use chrono::{DateTime, Utc};
 use errors::ActivityCoverageError;
 use serde::de::{self, Deserializer};
@@ -11,7 +12,7 @@ use std::{
     path::Path,
     time::{Duration, SystemTime},
 };
-use tokio::fs::{File, read_to_string, try_exists};
+use tokio::fs::{File, read, try_exists};
 use tokio::io::{AsyncWriteExt, BufWriter};
 
 pub const ACTIVITY_COVERAGE_FILE_NAME: &str = "activity_coverage.json";
@@ -38,6 +39,24 @@ impl Ord for TimeRange {
     }
 }
 
+/// Coverage within a time window, see [`ActivityCoverage::coverage_in`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct WindowCoverage {
+    pub covered: Duration,
+    pub gaps: Vec<Range<SystemTime>>,
+    /// Covered time over the window duration, `None` for an empty window.
+    pub ratio: Option<f64>,
+}
+
+impl WindowCoverage {
+    pub fn largest_gap(&self) -> Option<Range<SystemTime>> {
+        self.gaps
+            .iter()
+            .max_by_key(|gap| gap.end.duration_since(gap.start).unwrap_or_default())
+            .cloned()
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ActivityCoverage {
     covered: BTreeSet<TimeRange>,
@@ -56,29 +75,31 @@ impl ActivityCoverage {
         }
     }
 
+    /// Adds a range, merged with the covered ranges it overlaps or touches. An inverted
+    /// range, e.g. measured across a backward clock step, is ignored.
     pub fn add_range(&mut self, new_range: Range<SystemTime>) {
+        if new_range.end < new_range.start {
+            return;
+        }
         let mut new_start = new_range.start;
         let mut new_end = new_range.end;
 
-        // Trouver les plages existantes qui chevauchent ou touchent celle à insérer
-        let mut to_remove = vec![];
-
-        for existing in &self.covered {
-            if new_end < existing.0.start || new_start > existing.0.end {
-                continue;
-            }
-            // Fusionner
+        // Covered ranges are disjoint, so their ends are sorted like their starts: the ranges
+        // to merge are the last ones starting before the new end, found in O(log n + k)
+        let to_remove = self
+            .covered
+            .range(..=TimeRange(new_end..new_end))
+            .rev()
+            .take_while(|existing| existing.0.end >= new_start)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for existing in to_remove {
             new_start = new_start.min(existing.0.start);
             new_end = new_end.max(existing.0.end);
-            to_remove.push(existing.clone());
-        }
-
-        // Supprimer les anciennes plages fusionnées
-        for r in to_remove {
-            self.covered.remove(&r);
+            self.covered.remove(&existing);
         }
 
-        // Insérer la nouvelle plage fusionnée
         self.covered.insert(TimeRange(new_start..new_end));
     }
 
@@ -112,12 +133,109 @@ impl ActivityCoverage {
         self.missing_ranges().into_iter().next()
     }
 
+    /// Returns true if `t` falls in a covered range (start inclusive, end exclusive).
+    pub fn is_covered(&self, t: SystemTime) -> bool {
+        self.covering_range(t).is_some()
+    }
+
+    /// Returns the covered range containing `t`, clamped to `t - window..t + window`.
+    pub fn covered_around(&self, t: SystemTime, window: Duration) -> Option<Range<SystemTime>> {
+        let range = self.covering_range(t)?;
+        let start = t
+            .checked_sub(window)
+            .map_or(range.0.start, |start| start.max(range.0.start));
+        let end = t
+            .checked_add(window)
+            .map_or(range.0.end, |end| end.min(range.0.end));
+        Some(start..end)
+    }
+
+    fn covering_range(&self, t: SystemTime) -> Option<&TimeRange> {
+        // Ranges are ordered by start and never overlap: only the last range starting
+        // before t can contain it.
+        self.covered
+            .range(..=TimeRange(t..t))
+            .next_back()
+            .filter(|range| range.contains(t))
+    }
+
+    pub fn largest_gap(&self) -> Option<Range<SystemTime>> {
+        self.missing_ranges()
+            .into_iter()
+            .max_by_key(|gap| gap.end.duration_since(gap.start).unwrap_or_default())
+    }
+
+    /// Time between the start of the first covered range and the end of the last one.
+    pub fn span(&self) -> Option<Range<SystemTime>> {
+        let first = self.covered.first()?;
+        let last = self.covered.last()?;
+        Some(first.0.start..last.0.end)
+    }
+
+    pub fn covered_duration(&self) -> Duration {
+        self.covered
+            .iter()
+            .map(|r| r.0.end.duration_since(r.0.start).unwrap_or_default())
+            .sum()
+    }
+
+    /// Ratio of covered time over the whole span, between 0 and 1.
+    pub fn coverage_ratio(&self) -> Option<f64> {
+        let span = self.span()?;
+        let span_duration = span.end.duration_since(span.start).ok()?;
+        if span_duration.is_zero() {
+            return None;
+        }
+        Some(self.covered_duration().as_secs_f64() / span_duration.as_secs_f64())
+    }
+
+    /// Coverage clamped to `window`. Any uncovered time within the window is a gap,
+    /// including before the first covered range and after the last one.
+    pub fn coverage_in(&self, window: Range<SystemTime>) -> WindowCoverage {
+        let mut covered = Duration::ZERO;
+        let mut gaps = vec![];
+        let mut cursor = window.start;
+
+        for r in &self.covered {
+            if r.0.end <= window.start {
+                continue;
+            }
+            if r.0.start >= window.end {
+                break;
+            }
+
+            let start = r.0.start.max(window.start);
+            let end = r.0.end.min(window.end);
+            if start > cursor {
+                gaps.push(cursor..start);
+            }
+            covered += end.duration_since(start).unwrap_or_default();
+            cursor = cursor.max(end);
+        }
+
+        if cursor < window.end {
+            gaps.push(cursor..window.end);
+        }
+
+        let window_duration = window.end.duration_since(window.start).unwrap_or_default();
+        let ratio = (!window_duration.is_zero())
+            .then(|| covered.as_secs_f64() / window_duration.as_secs_f64());
+
+        WindowCoverage {
+            covered,
+            gaps,
+            ratio,
+        }
+    }
+
     pub async fn write_to_fs(
         &self,
         output_path: impl AsRef<Path>,
+        format: StateFormat,
     ) -> Result<(), ActivityCoverageError> {
-        let coverage =
-            serde_json::to_string_pretty(&self).map_err(ActivityCoverageError::Serialize)?;
+        let coverage = format
+            .encode(self)
+            .map_err(ActivityCoverageError::Serialize)?;
         let output_path_string = output_path.as_ref().to_string_lossy().to_string();
 
         let file_h = File::create(output_path)
@@ -127,7 +245,7 @@ impl ActivityCoverage {
         let mut writer = BufWriter::new(file_h);
 
         writer
-            .write_all(coverage.as_bytes())
+            .write_all(&coverage)
             .await
             .map_err(|e| ActivityCoverageError::WriteToFile(e, output_path_string.clone()))?;
 
@@ -147,20 +265,31 @@ pub async fn load_from_fs(
         .await
         .map_err(|e| ActivityCoverageError::FileExists(e, path_string.clone()))?
     {
-        let content = read_to_string(path_string.clone())
+        let content = read(path_string.clone())
             .await
             .map_err(|e| ActivityCoverageError::ReadFile(e, path_string.clone()))?;
 
         let coverage: ActivityCoverage =
-            serde_json::from_str(&content).map_err(ActivityCoverageError::Deserialize)?;
+            decode_state(&content).map_err(ActivityCoverageError::Deserialize)?;
 
-        Ok(coverage)
+        // add_range relies on disjoint ranges, which an edited file may not have
+        let mut merged = ActivityCoverage::new();
+        for range in coverage.covered {
+            merged.add_range(range.0);
+        }
+
+        Ok(merged)
     } else {
         Ok(ActivityCoverage::default())
     }
 }
 
 impl TimeRange {
+    /// Start inclusive, end exclusive.
+    pub fn contains(&self, t: SystemTime) -> bool {
+        self.0.contains(&t)
+    }
+
     fn to_rfc3339_range(&self) -> (String, String) {
         (to_rfc3339(self.0.start), to_rfc3339(self.0.end))
     }
@@ -203,3 +332,170 @@ impl<'de> Deserialize<'de> for TimeRange {
         TimeRange::from_rfc3339_range(&start, &end).map_err(de::Error::custom)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn t(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn ranges(coverage: &ActivityCoverage) -> Vec<Range<SystemTime>> {
+        coverage
+            .covered
+            .iter()
+            .map(|range| range.0.clone())
+            .collect()
+    }
+
+    /// The scan of every covered range `add_range` used to do, as a reference.
+    fn add_range_by_scan(covered: &mut Vec<Range<SystemTime>>, new_range: Range<SystemTime>) {
+        if new_range.end < new_range.start {
+            return;
+        }
+        let mut merged = new_range;
+        covered.retain(|existing| {
+            let overlaps = existing.start <= merged.end && existing.end >= merged.start;
+            if overlaps {
+                merged = merged.start.min(existing.start)..merged.end.max(existing.end);
+            }
+            !overlaps
+        });
+        // Merging may reach ranges skipped before it grew
+        if covered
+            .iter()
+            .any(|existing| existing.start <= merged.end && existing.end >= merged.start)
+        {
+            add_range_by_scan(covered, merged);
+            return;
+        }
+        covered.push(merged);
+        covered.sort_by_key(|range| range.start);
+    }
+
+    #[test]
+    fn overlapping_and_touching_ranges_are_merged() {
+        let mut coverage = ActivityCoverage::new();
+        coverage.add_range(t(10)..t(20));
+        coverage.add_range(t(30)..t(40));
+        coverage.add_range(t(20)..t(25));
+        assert_eq!(ranges(&coverage), [t(10)..t(25), t(30)..t(40)]);
+
+        // Spanning both, contained and inverted ranges
+        coverage.add_range(t(5)..t(35));
+        coverage.add_range(t(12)..t(13));
+        coverage.add_range(t(50)..t(45));
+        assert_eq!(ranges(&coverage), [t(5)..t(40)]);
+    }
+
+    #[test]
+    fn gaps_and_coverage_queries() {
+        let mut coverage = ActivityCoverage::new();
+        coverage.add_range(t(10)..t(20));
+        coverage.add_range(t(30)..t(60));
+
+        assert_eq!(coverage.missing_ranges(), [t(20)..t(30)]);
+        assert!(coverage.is_covered(t(10)));
+        assert!(!coverage.is_covered(t(20)));
+        assert_eq!(
+            coverage.covered_around(t(40), Duration::from_secs(5)),
+            Some(t(35)..t(45))
+        );
+        assert_eq!(coverage.coverage_ratio(), Some(0.8));
+
+        let window = coverage.coverage_in(t(5)..t(40));
+        assert_eq!(window.covered, Duration::from_secs(20));
+        assert_eq!(window.gaps, [t(5)..t(10), t(20)..t(30)]);
+        assert_eq!(window.largest_gap(), Some(t(20)..t(30)));
+    }
+
+    #[test]
+    fn merge_matches_a_full_scan() {
+        let mut coverage = ActivityCoverage::new();
+        let mut reference = Vec::new();
+        // Deterministic pseudo-random ranges, many overlapping
+        let mut seed: u64 = 42;
+        for _ in 0..2000 {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let start = (seed >> 33) % 10_000;
+            let len = (seed >> 20) % 50;
+            coverage.add_range(t(start)..t(start + len));
+            add_range_by_scan(&mut reference, t(start)..t(start + len));
+        }
+        assert_eq!(ranges(&coverage), reference);
+    }
+
+    #[test]
+    fn hundred_thousand_ranges() {
+        // Quadratic with the former scan of every covered range on each insert
+        let mut disjoint = ActivityCoverage::new();
+        for i in (0..100_000).rev() {
+            disjoint.add_range(t(i * 10)..t(i * 10 + 5));
+        }
+        assert_eq!(disjoint.covered.len(), 100_000);
+
+        let mut contiguous = ActivityCoverage::new();
+        for i in 0..100_000 {
+            contiguous.add_range(t(i * 10)..t(i * 10 + 10));
+        }
+        assert_eq!(ranges(&contiguous), [t(0)..t(1_000_000)]);
+
+        // Filling every gap of the disjoint coverage folds it into a single range
+        for i in 0..100_000 {
+            disjoint.add_range(t(i * 10 + 5)..t(i * 10 + 10));
+        }
+        assert_eq!(ranges(&disjoint), [t(0)..t(1_000_000)]);
+    }
+
+    #[test]
+    fn inverted_range_is_ignored() {
+        let mut coverage = ActivityCoverage::new();
+        coverage.add_range(t(0)..t(10));
+        coverage.add_range(t(20)..t(30));
+
+        coverage.add_range(t(25)..t(5));
+        assert_eq!(coverage.missing_ranges(), [t(10)..t(20)]);
+    }
+
+    #[test]
+    fn window_inside_one_covered_range() {
+        let mut coverage = ActivityCoverage::new();
+        coverage.add_range(t(10)..t(100));
+        coverage.add_range(t(200)..t(300));
+
+        let window = coverage.coverage_in(t(20)..t(50));
+        assert_eq!(window.covered, Duration::from_secs(30));
+        assert!(window.gaps.is_empty());
+        assert_eq!(window.largest_gap(), None);
+        assert_eq!(window.ratio, Some(1.0));
+    }
+
+    #[test]
+    fn window_spanning_several_gaps() {
+        let mut coverage = ActivityCoverage::new();
+        coverage.add_range(t(0)..t(10));
+        coverage.add_range(t(20)..t(30));
+        coverage.add_range(t(50)..t(60));
+        coverage.add_range(t(100)..t(110));
+
+        // Clamped on both ends, ranges outside the window ignored
+        let window = coverage.coverage_in(t(5)..t(55));
+        assert_eq!(window.covered, Duration::from_secs(20));
+        assert_eq!(window.gaps, [t(10)..t(20), t(30)..t(50)]);
+        assert_eq!(window.largest_gap(), Some(t(30)..t(50)));
+        assert_eq!(window.ratio, Some(0.4));
+
+        // Nothing covered
+        let window = coverage.coverage_in(t(70)..t(90));
+        assert_eq!(window.covered, Duration::ZERO);
+        assert_eq!(window.gaps, [t(70)..t(90)]);
+        assert_eq!(window.ratio, Some(0.0));
+
+        let window = coverage.coverage_in(t(25)..t(25));
+        assert!(window.gaps.is_empty());
+        assert_eq!(window.ratio, None);
+    }
+}
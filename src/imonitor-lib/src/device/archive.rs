@@ -0,0 +1,233 @@
+use super::Device;
+use super::errors::DeviceError;
+use chrono::{DateTime, Utc};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use serde::Serialize;
+use std::fs::{File, read_dir};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tar::{Builder, Header};
+
+const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+/// Content of a device data archive, stored in the archive and next to it.
+#[derive(Debug, Clone, Serialize)]
+pub struct DataManifest {
+    pub udid: String,
+    pub created: DateTime<Utc>,
+    pub total_bytes: u64,
+    pub files: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ManifestEntry {
+    /// Path relative to the device dir
+    pub path: String,
+    pub size: u64,
+}
+
+impl Device {
+    /// Packs the device dir into a single archive in `dest`, see [`archive_device_dir`].
+    pub async fn archive_data(&self, dest: &Path, compress: bool) -> Result<PathBuf, DeviceError> {
+        archive_device_dir(&self.get_shard_dir(), &self.info.udid, dest, compress).await
+    }
+}
+
+/// Packs `<base_dir>/<udid>` into `<dest>/<udid>_<timestamp>.tar` (`.tar.gz` if `compress`)
+/// with a `manifest.json` listing every file, also written next to the archive. The device
+/// dir is left untouched: delete it once the archive is offloaded.
+///
+/// Files must not change while archived, the device should not be monitored anymore.
+pub async fn archive_device_dir(
+    base_dir: &str,
+    udid: &str,
+    dest: &Path,
+    compress: bool,
+) -> Result<PathBuf, DeviceError> {
+    let device_dir = Path::new(base_dir).join(udid);
+    let created = Utc::now();
+    let extension = if compress { "tar.gz" } else { "tar" };
+    let archive_name = format!("{udid}_{}", created.timestamp());
+    let archive_path = dest.join(format!("{archive_name}.{extension}"));
+
+    let udid = udid.to_string();
+    let task_archive_path = archive_path.clone();
+    let manifest = tokio::task::spawn_blocking(move || {
+        write_archive(&device_dir, &udid, created, &task_archive_path, compress)
+    })
+    .await??;
+
+    let manifest_path = dest.join(format!("{archive_name}.{MANIFEST_FILE_NAME}"));
+    let manifest_path_string = manifest_path.to_string_lossy().to_string();
+    let content = serde_json::to_string_pretty(&manifest)
+        .map_err(|e| DeviceError::SerializeFile(e, manifest_path_string.clone()))?;
+    tokio::fs::write(&manifest_path, content)
+        .await
+        .map_err(|e| DeviceError::WriteToFile(e, manifest_path_string))?;
+
+    Ok(archive_path)
+}
+
+fn write_archive(
+    device_dir: &Path,
+    udid: &str,
+    created: DateTime<Utc>,
+    archive_path: &Path,
+    compress: bool,
+) -> Result<DataManifest, DeviceError> {
+    let mut files = Vec::new();
+    list_files(device_dir, device_dir, &mut files)?;
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let manifest = DataManifest {
+        udid: udid.to_string(),
+        created,
+        total_bytes: files.iter().map(|file| file.size).sum(),
+        files,
+    };
+
+    let archive_path_string = archive_path.to_string_lossy().to_string();
+    let file = File::create(archive_path)
+        .map_err(|e| DeviceError::CreateFile(e, archive_path_string.clone()))?;
+
+    let manifest_content = serde_json::to_vec_pretty(&manifest)
+        .map_err(|e| DeviceError::SerializeFile(e, MANIFEST_FILE_NAME.to_string()))?;
+
+    let res = if compress {
+        let mut builder = Builder::new(GzEncoder::new(file, Compression::default()));
+        append_entries(&mut builder, device_dir, &manifest, &manifest_content)
+            .and_then(|_| builder.into_inner())
+            // Writes the gzip trailer
+            .and_then(|encoder| encoder.finish())
+            .and_then(|file| file.sync_all())
+    } else {
+        let mut builder = Builder::new(file);
+        append_entries(&mut builder, device_dir, &manifest, &manifest_content)
+            .and_then(|_| builder.into_inner())
+            .and_then(|file| file.sync_all())
+    };
+    res.map_err(|e| DeviceError::WriteToFile(e, archive_path_string))?;
+
+    Ok(manifest)
+}
+
+fn append_entries<W: Write>(
+    builder: &mut Builder<W>,
+    device_dir: &Path,
+    manifest: &DataManifest,
+    manifest_content: &[u8],
+) -> std::io::Result<()> {
+    // First entry, so that the content is known without reading the whole archive
+    let mut header = Header::new_gnu();
+    header.set_size(manifest_content.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(manifest.created.timestamp().max(0) as u64);
+    header.set_cksum();
+    builder.append_data(&mut header, MANIFEST_FILE_NAME, manifest_content)?;
+
+    for entry in &manifest.files {
+        builder.append_path_with_name(
+            device_dir.join(&entry.path),
+            Path::new(&manifest.udid).join(&entry.path),
+        )?;
+    }
+
+    Ok(())
+}
+
+fn list_files(root: &Path, dir: &Path, files: &mut Vec<ManifestEntry>) -> Result<(), DeviceError> {
+    let dir_string = dir.to_string_lossy().to_string();
+    let entries = read_dir(dir).map_err(|e| DeviceError::ReadFile(e, dir_string.clone()))?;
+
+    for entry in entries {
+        let entry = entry.map_err(|e| DeviceError::ReadFile(e, dir_string.clone()))?;
+        let metadata = entry
+            .metadata()
+            .map_err(|e| DeviceError::ReadFile(e, dir_string.clone()))?;
+        let path = entry.path();
+
+        if metadata.is_dir() {
+            list_files(root, &path, files)?;
+        } else if metadata.is_file() {
+            let relative_path = path.strip_prefix(root).unwrap_or(&path);
+            files.push(ManifestEntry {
+                path: relative_path.to_string_lossy().to_string(),
+                size: metadata.len(),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::test_support::{TEST_UDID, test_device};
+    use flate2::read::GzDecoder;
+    use std::collections::BTreeMap;
+    use std::io::Read;
+    use tar::Archive;
+
+    #[tokio::test]
+    async fn archive_holds_the_manifest_and_every_file() {
+        let (device, base_dir) = test_device("archive-data");
+        std::fs::write(device.get_syslog_file_path(), "line\n").unwrap();
+        let crash_file = Path::new(&device.get_crash_files_dir()).join("App.ips");
+        std::fs::write(&crash_file, "{}").unwrap();
+        let dest = base_dir.join("dest");
+        std::fs::create_dir(&dest).unwrap();
+
+        let archive_path = device.archive_data(&dest, true).await.unwrap();
+        assert!(archive_path.to_string_lossy().ends_with(".tar.gz"));
+
+        let mut archive = Archive::new(GzDecoder::new(File::open(&archive_path).unwrap()));
+        let mut entries = BTreeMap::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_string_lossy().to_string();
+            let mut content = String::new();
+            entry.read_to_string(&mut content).unwrap();
+            entries.insert(path, content);
+        }
+
+        let syslog_path = Path::new(&device.get_syslog_file_path())
+            .strip_prefix(device.get_shard_dir())
+            .unwrap()
+            .to_string_lossy()
+            .to_string();
+        assert_eq!(entries[&syslog_path], "line\n");
+        assert_eq!(entries[&format!("{TEST_UDID}/crashes/files/App.ips")], "{}");
+
+        let manifest: serde_json::Value =
+            serde_json::from_str(&entries[MANIFEST_FILE_NAME]).unwrap();
+        assert_eq!(manifest["udid"], TEST_UDID);
+        let manifest_paths: Vec<_> = manifest["files"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|file| format!("{TEST_UDID}/{}", file["path"].as_str().unwrap()))
+            .collect();
+        for path in &manifest_paths {
+            assert!(entries.contains_key(path), "{path}");
+        }
+        assert_eq!(entries.len(), manifest_paths.len() + 1);
+
+        // Also written next to the archive
+        let manifest_next_to = std::fs::read_dir(&dest)
+            .unwrap()
+            .filter(|entry| {
+                entry
+                    .as_ref()
+                    .unwrap()
+                    .file_name()
+                    .to_string_lossy()
+                    .ends_with(MANIFEST_FILE_NAME)
+            })
+            .count();
+        assert_eq!(manifest_next_to, 1);
+
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+}
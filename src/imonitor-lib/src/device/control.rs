@@ -0,0 +1,158 @@
+use super::Device;
+use super::errors::DeviceError;
+use logger::{HasLogger, error, info};
+use tokio::fs::{File, read_to_string, try_exists};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::time::{Duration, sleep};
+
+const PAUSED_FILE_NAME: &str = "paused.json";
+const PAUSED_POLL_SECS: u64 = 5;
+
+impl Device {
+    /// Whether collection is paused. The heartbeat keeps running while paused.
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    /// Waits until collection is resumed. Returns immediately if not paused.
+    pub async fn wait_unpaused(&self) {
+        let mut paused_rx = self.paused.subscribe();
+        let _ = paused_rx.wait_for(|paused| !*paused).await;
+    }
+
+    /// Pauses collection. The state is persisted and survives restarts.
+    pub async fn pause(&self) -> Result<(), DeviceError> {
+        self.set_paused(true).await
+    }
+
+    /// Resumes collection.
+    pub async fn resume(&self) -> Result<(), DeviceError> {
+        self.set_paused(false).await
+    }
+
+    async fn set_paused(&self, paused: bool) -> Result<(), DeviceError> {
+        let paused_file_path = self.get_paused_file_path();
+
+        let content = serde_json::to_string_pretty(&paused)
+            .map_err(|e| DeviceError::SerializeFile(e, paused_file_path.clone()))?;
+
+        let dst_file = File::create(paused_file_path.clone())
+            .await
+            .map_err(|e| DeviceError::CreateFile(e, paused_file_path.clone()))?;
+
+        let mut writer = BufWriter::new(dst_file);
+
+        writer
+            .write_all(content.as_bytes())
+            .await
+            .map_err(|e| DeviceError::WriteToFile(e, paused_file_path.clone()))?;
+
+        writer
+            .flush()
+            .await
+            .map_err(|e| DeviceError::WriteToFile(e, paused_file_path.clone()))?;
+
+        self.paused.send_replace(paused);
+        Ok(())
+    }
+
+    pub fn get_paused_file_path(&self) -> String {
+        self.get_state_file_path(self.get_info_dir(), PAUSED_FILE_NAME)
+    }
+
+    /// Reads the persisted paused state. Not paused if never set.
+    pub async fn load_paused(&self) -> Result<bool, DeviceError> {
+        let paused_file_path = self.get_paused_file_path();
+
+        if !try_exists(&paused_file_path)
+            .await
+            .map_err(|e| DeviceError::ReadFile(e, paused_file_path.clone()))?
+        {
+            return Ok(false);
+        }
+
+        let content = read_to_string(&paused_file_path)
+            .await
+            .map_err(|e| DeviceError::ReadFile(e, paused_file_path.clone()))?;
+
+        serde_json::from_str(&content)
+            .map_err(|e| DeviceError::DeserializeFile(e, paused_file_path))
+    }
+
+    /// Follows the persisted paused state, so that it can be changed from another process
+    /// (e.g. `imonitor pause`) while monitoring.
+    pub async fn watch_paused_file(&self) -> Result<(), DeviceError> {
+        loop {
+            match self.load_paused().await {
+                Ok(paused) => {
+                    let changed = self.paused.send_if_modified(|current| {
+                        let changed = *current != paused;
+                        *current = paused;
+                        changed
+                    });
+                    if changed {
+                        info!(
+                            self,
+                            "Collection {}",
+                            if paused { "paused" } else { "resumed" }
+                        );
+                    }
+                }
+                Err(e) => error!(self, "Failed to read paused state: {e}"),
+            }
+            sleep(Duration::from_secs(PAUSED_POLL_SECS)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::test_support::{test_device, test_device_in};
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn paused_state_survives_a_restart() {
+        let (device, base_dir) = test_device("paused-restart");
+        assert!(!device.load_paused().await.unwrap());
+
+        device.pause().await.unwrap();
+        assert!(device.is_paused());
+
+        // A fresh device over the same dirs reads the persisted state
+        let restarted = test_device_in(&base_dir);
+        assert!(restarted.load_paused().await.unwrap());
+
+        device.resume().await.unwrap();
+        assert!(!device.is_paused());
+        assert!(!restarted.load_paused().await.unwrap());
+
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn collection_waits_while_paused() {
+        let (device, base_dir) = test_device("paused-wait");
+        // The service loops wait for this before each pull
+        timeout(Duration::from_millis(100), device.wait_unpaused())
+            .await
+            .unwrap();
+
+        device.pause().await.unwrap();
+        assert!(
+            timeout(Duration::from_millis(100), device.wait_unpaused())
+                .await
+                .is_err()
+        );
+
+        let (waited, resumed) = tokio::join!(
+            timeout(Duration::from_secs(1), device.wait_unpaused()),
+            device.resume()
+        );
+        waited.unwrap();
+        resumed.unwrap();
+        assert!(!device.is_paused());
+
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+}
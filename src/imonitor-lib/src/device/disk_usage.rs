@@ -0,0 +1,165 @@
+use super::Device;
+use super::errors::DeviceError;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fs::read_dir;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Bytes used on disk by a device, by category.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DiskUsage {
+    /// Crash files and crash bookkeeping
+    pub crashes: u64,
+    pub os_trace_log: u64,
+    pub os_trace_archives: u64,
+    pub syslog: u64,
+    /// Device log files
+    pub logs: u64,
+    /// Everything else, such as heartbeat state and activity coverage
+    pub other: u64,
+    pub total: u64,
+}
+
+impl DiskUsage {
+    fn add(&mut self, category: Category, bytes: u64) {
+        let counter = match category {
+            Category::Crashes => &mut self.crashes,
+            Category::OsTraceLog => &mut self.os_trace_log,
+            Category::OsTraceArchives => &mut self.os_trace_archives,
+            Category::Syslog => &mut self.syslog,
+            Category::Logs => &mut self.logs,
+            Category::Other => &mut self.other,
+        };
+        *counter += bytes;
+        self.total += bytes;
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Category {
+    Crashes,
+    OsTraceLog,
+    OsTraceArchives,
+    Syslog,
+    Logs,
+    Other,
+}
+
+impl Device {
+    /// Walks the device dir once and sums the file sizes by category. Symlinks are not
+    /// followed nor counted, hard linked files such as deduplicated crashes count once.
+    pub fn total_disk_usage(&self) -> Result<DiskUsage, DeviceError> {
+        let base_dir = PathBuf::from(self.base_dir());
+        let categories = [
+            (self.get_crashes_dir(), Category::Crashes),
+            (self.get_os_trace_log_dir(), Category::OsTraceLog),
+            (self.get_os_trace_archive_dir(), Category::OsTraceArchives),
+            (self.get_syslog_dir(), Category::Syslog),
+        ]
+        .map(|(dir, category)| (PathBuf::from(dir), category));
+
+        let mut usage = DiskUsage::default();
+        let mut linked_files = HashSet::new();
+        let mut dirs = vec![(base_dir.clone(), Category::Other)];
+        while let Some((dir, dir_category)) = dirs.pop() {
+            let dir_string = dir.to_string_lossy().to_string();
+            let entries = match read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(DeviceError::ReadFile(e, dir_string)),
+            };
+
+            for entry in entries {
+                let entry = entry.map_err(|e| DeviceError::ReadFile(e, dir_string.clone()))?;
+                let path = entry.path();
+                // Does not follow symlinks
+                let file_type = entry
+                    .file_type()
+                    .map_err(|e| DeviceError::ReadFile(e, dir_string.clone()))?;
+
+                if file_type.is_dir() {
+                    let category = categories
+                        .iter()
+                        .find(|(category_dir, _)| *category_dir == path)
+                        .map_or(dir_category, |(_, category)| *category);
+                    dirs.push((path, category));
+                } else if file_type.is_file() {
+                    let metadata = entry.metadata().map_err(|e| {
+                        DeviceError::ReadFile(e, path.to_string_lossy().to_string())
+                    })?;
+                    if metadata.nlink() > 1
+                        && !linked_files.insert((metadata.dev(), metadata.ino()))
+                    {
+                        continue;
+                    }
+                    let size = metadata.len();
+                    let category = if dir == base_dir && is_log_file(&path) {
+                        Category::Logs
+                    } else {
+                        dir_category
+                    };
+                    usage.add(category, size);
+                }
+            }
+        }
+
+        Ok(usage)
+    }
+}
+
+fn is_log_file(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "log")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::test_support::test_device;
+
+    fn write_bytes(path: impl AsRef<Path>, len: usize) {
+        std::fs::write(path, vec![b'x'; len]).unwrap();
+    }
+
+    #[test]
+    fn sizes_are_summed_by_category() {
+        let (device, base_dir) = test_device("disk-usage");
+        let crash_files_dir = Path::new(&device.get_crash_files_dir()).to_path_buf();
+        write_bytes(crash_files_dir.join("a.ips"), 100);
+        // Deduplicated crash, counted once
+        std::fs::hard_link(crash_files_dir.join("a.ips"), crash_files_dir.join("b.ips")).unwrap();
+        write_bytes(Path::new(&device.get_crashes_dir()).join("known.json"), 20);
+        write_bytes(
+            Path::new(&device.get_os_trace_log_dir()).join("log.json"),
+            200,
+        );
+        write_bytes(
+            Path::new(&device.get_os_trace_archive_dir()).join("a.tar"),
+            300,
+        );
+        write_bytes(Path::new(&device.get_syslog_dir()).join("syslog.log"), 50);
+        write_bytes(Path::new(&device.base_dir()).join("device.log"), 10);
+        write_bytes(Path::new(&device.get_heartbeat_dir()).join("state.json"), 7);
+        // Not followed
+        std::os::unix::fs::symlink(
+            crash_files_dir.join("a.ips"),
+            Path::new(&device.get_syslog_dir()).join("link.ips"),
+        )
+        .unwrap();
+
+        assert_eq!(
+            device.total_disk_usage().unwrap(),
+            DiskUsage {
+                crashes: 120,
+                os_trace_log: 200,
+                os_trace_archives: 300,
+                syslog: 50,
+                logs: 10,
+                other: 7,
+                total: 687,
+            }
+        );
+
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+}
@@ -1,9 +1,13 @@
 use super::activity_coverage::errors::ActivityCoverageError;
 use crate::services::crashes::errors::CrashError;
+use crate::services::device_state::errors::DeviceStateError;
 use crate::services::heartbeat::errors::HeartbeatError;
+use crate::services::installed_apps::errors::InstalledAppsError;
 use crate::services::os_trace::errors::OsTraceError;
 use crate::services::syslog::errors::SyslogError;
+use crate::state_store::StateStoreError;
 use idevice::IdeviceError;
+use idevice::pairing_file::PairingFile;
 
 #[derive(Debug)]
 pub enum DeviceError {
@@ -16,13 +20,27 @@ pub enum DeviceError {
     RemoveFile(std::io::Error, String),
     Syslog(SyslogError),
     Crash(CrashError),
+    DeviceState(DeviceStateError),
+    InstalledApps(InstalledAppsError),
     OsTrace(OsTraceError),
     CreateDir(std::io::Error, String),
+    /// A file is where a dir should be, and the dir to create
+    PathConflict(String, String),
     CreateFile(std::io::Error, String),
+    MoveFile(std::io::Error, String, String),
+    ReadFile(std::io::Error, String),
+    DeserializeFile(serde_json::Error, String),
+    SerializeFile(serde_json::Error, String),
+    DecodeState(StateStoreError, String),
     Task(tokio::task::JoinError),
     ActivityCoverage(ActivityCoverageError),
     TaskFailed,
     ConfigReadLock,
+    LoggerInit(std::io::Error, String),
+    InstanceLock(std::io::Error, String),
+    AlreadyMonitored(Option<u32>, String),
+    /// Not a failure: the services stop to restart with the new pairing
+    PairingRepaired(Box<PairingFile>),
 }
 
 impl std::error::Error for DeviceError {}
@@ -45,17 +63,61 @@ impl std::fmt::Display for DeviceError {
             }
             DeviceError::Syslog(e) => write!(f, "Syslog task failed: {e}"),
             DeviceError::Crash(e) => write!(f, "Crash task failed: {e}"),
+            DeviceError::DeviceState(e) => write!(f, "Device state task failed: {e}"),
+            DeviceError::InstalledApps(e) => write!(f, "Installed apps task failed: {e}"),
             DeviceError::OsTrace(e) => write!(f, "Os trace failed: {e}"),
             DeviceError::Task(e) => write!(f, "Tokio task failed: {e}"),
             DeviceError::ActivityCoverage(e) => write!(f, "Activity coverage error: {e}"),
+            DeviceError::PairingRepaired(_) => {
+                write!(f, "Device paired again after its pairing was rejected")
+            }
+            DeviceError::MoveFile(e, from, to) => {
+                write!(f, "Failed to move file {from} to {to}: {e}")
+            }
+            DeviceError::PathConflict(file_name, dir_name) => {
+                write!(
+                    f,
+                    "Cannot create directory {dir_name}: {file_name} exists and is not a directory. Move it away or change the base dir"
+                )
+            }
             DeviceError::CreateDir(e, dir_name) => {
                 write!(f, "Failed to create directory {dir_name}: {e}")
             }
             DeviceError::CreateFile(e, file_name) => {
                 write!(f, "Failed to create file {file_name}: {e}")
             }
+            DeviceError::ReadFile(e, file_name) => {
+                write!(f, "Failed to read file {file_name}: {e}")
+            }
+            DeviceError::DeserializeFile(e, file_name) => {
+                write!(f, "Failed to deserialize file {file_name}: {e}")
+            }
+            DeviceError::SerializeFile(e, file_name) => {
+                write!(f, "Failed to serialize file {file_name}: {e}")
+            }
+            DeviceError::InstanceLock(e, file_name) => {
+                write!(f, "Failed to take instance lock {file_name}: {e}")
+            }
+            DeviceError::AlreadyMonitored(Some(pid), file_name) => {
+                write!(
+                    f,
+                    "Device already monitored by process {pid}, see {file_name}. Stop it first"
+                )
+            }
+            DeviceError::AlreadyMonitored(None, file_name) => {
+                write!(
+                    f,
+                    "Device already monitored by another process, see {file_name}. Stop it first"
+                )
+            }
+            DeviceError::DecodeState(e, file_name) => {
+                write!(f, "Failed to decode state file {file_name}: {e}")
+            }
             DeviceError::ConfigReadLock => write!(f, "Failed to get config read lock"),
             DeviceError::TaskFailed => write!(f, "Spawned task failed"),
+            DeviceError::LoggerInit(e, file_name) => {
+                write!(f, "Failed to open log file {file_name}: {e}")
+            }
         }
     }
 }
@@ -78,6 +140,18 @@ impl From<CrashError> for DeviceError {
     }
 }
 
+impl From<DeviceStateError> for DeviceError {
+    fn from(error: DeviceStateError) -> Self {
+        DeviceError::DeviceState(error)
+    }
+}
+
+impl From<InstalledAppsError> for DeviceError {
+    fn from(error: InstalledAppsError) -> Self {
+        DeviceError::InstalledApps(error)
+    }
+}
+
 impl From<OsTraceError> for DeviceError {
     fn from(error: OsTraceError) -> Self {
         DeviceError::OsTrace(error)
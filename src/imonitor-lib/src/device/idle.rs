@@ -0,0 +1,92 @@
+use super::Device;
+use crate::clock::Clock;
+use crate::config::IdleConfig;
+use chrono::TimeDelta;
+use logger::{HasLogger, debug};
+use std::sync::Arc;
+use tokio::time::{Duration, sleep};
+
+// Bounds the wake ups of idle services while logs stream in
+const ACTIVITY_RESOLUTION_SECS: i64 = 1;
+
+impl Device {
+    /// Replaces the time source, e.g. with a [`crate::clock::MockClock`]. The last activity
+    /// is reset to its current time, as if the device had just been set up.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.last_activity.send_replace(clock.now_utc());
+        self.clock = clock;
+    }
+
+    /// Records new data or a heartbeat change. Leaves idle mode.
+    pub fn record_activity(&self) {
+        let now = self.clock.now_utc();
+        self.last_activity.send_if_modified(|last_activity| {
+            if now - *last_activity >= TimeDelta::seconds(ACTIVITY_RESOLUTION_SECS) {
+                *last_activity = now;
+                true
+            } else {
+                false
+            }
+        });
+    }
+
+    /// Whether nothing happened on the device for `idle.after`.
+    pub fn is_idle(&self, idle: &IdleConfig) -> bool {
+        if !idle.enabled {
+            return false;
+        }
+        let last_activity = *self.last_activity.borrow();
+        let since_activity = (self.clock.now_utc() - last_activity)
+            .to_std()
+            .unwrap_or_default();
+        since_activity >= idle.after
+    }
+
+    /// Sleeps `interval`, or `idle.interval` in idle mode. An idle sleep ends early on
+    /// activity, so that the normal cadence resumes right away.
+    pub async fn idle_aware_sleep(&self, interval: Duration, idle: &IdleConfig) {
+        if !self.is_idle(idle) {
+            sleep(interval).await;
+            return;
+        }
+
+        debug!(self, "Idle, waiting up to {}s", idle.interval.as_secs());
+        let mut activity_rx = self.last_activity.subscribe();
+        tokio::select! {
+            _ = sleep(idle.interval.max(interval)) => {}
+            _ = activity_rx.changed() => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::device::test_support::test_device;
+    use std::time::SystemTime;
+
+    #[test]
+    fn device_is_idle_without_activity_for_a_while() {
+        let (mut device, base_dir) = test_device("idle");
+        // Long before the system time, activity recorded at setup would not count
+        let clock = Arc::new(MockClock::new(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000),
+        ));
+        device.set_clock(clock.clone());
+        let idle = IdleConfig {
+            enabled: true,
+            after: Duration::from_secs(60),
+            ..Default::default()
+        };
+
+        assert!(!device.is_idle(&idle));
+        clock.advance(Duration::from_secs(60));
+        assert!(device.is_idle(&idle));
+        assert!(!device.is_idle(&IdleConfig::default()));
+
+        device.record_activity();
+        assert!(!device.is_idle(&idle));
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+}
@@ -0,0 +1,107 @@
+use super::Device;
+use super::errors::DeviceError;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+const INSTANCE_LOCK_FILE_NAME: &str = "monitor.lock";
+
+/// Advisory lock on a device base dir, held by the process monitoring the device so that a
+/// second process on the same base dir is refused. The file holds the PID of the holder.
+///
+/// The lock is released by the OS when its holder dies, the file left behind is reclaimed by
+/// the next process.
+#[derive(Debug)]
+pub struct InstanceLock {
+    file: File,
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+    }
+}
+
+impl Device {
+    pub fn get_instance_lock_file_path(&self) -> String {
+        let base_dir = PathBuf::from(self.base_dir());
+        let file_path = base_dir.join(INSTANCE_LOCK_FILE_NAME);
+        file_path.to_string_lossy().to_string()
+    }
+
+    /// Takes the instance lock of the device, held until every clone of the device is
+    /// dropped. Fails if a live process holds it. Returns the PID of the dead process whose
+    /// stale lock was reclaimed, if any.
+    pub fn acquire_instance_lock(&mut self) -> Result<Option<u32>, DeviceError> {
+        let file_path = self.get_instance_lock_file_path();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(&file_path)
+            .map_err(|e| DeviceError::InstanceLock(e, file_path.clone()))?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                return Err(DeviceError::AlreadyMonitored(
+                    read_pid(&mut file),
+                    file_path,
+                ));
+            }
+            Err(TryLockError::Error(e)) => return Err(DeviceError::InstanceLock(e, file_path)),
+        }
+
+        let own_pid = std::process::id();
+        let stale_pid = read_pid(&mut file).filter(|pid| *pid != own_pid);
+
+        file.set_len(0)
+            .and_then(|_| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| writeln!(file, "{own_pid}"))
+            .and_then(|_| file.flush())
+            .map_err(|e| DeviceError::InstanceLock(e, file_path))?;
+
+        self.instance_lock = Some(Arc::new(InstanceLock { file }));
+        Ok(stale_pid)
+    }
+}
+
+/// PID written in the lock file, None if empty or unreadable.
+fn read_pid(file: &mut File) -> Option<u32> {
+    let mut content = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut content).ok()?;
+    content.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::test_support::{test_device, test_device_in};
+
+    #[test]
+    fn live_lock_is_refused_and_stale_lock_reclaimed() {
+        let (mut device, base_dir) = test_device("instance-lock");
+        // Left behind by a dead process
+        std::fs::write(device.get_instance_lock_file_path(), "999999999\n").unwrap();
+
+        assert_eq!(device.acquire_instance_lock().unwrap(), Some(999_999_999));
+        let content = std::fs::read_to_string(device.get_instance_lock_file_path()).unwrap();
+        assert_eq!(content.trim(), std::process::id().to_string());
+
+        // Locks of separate opens conflict, even within a process
+        let mut second = test_device_in(&base_dir);
+        let result = second.acquire_instance_lock();
+        assert!(
+            matches!(&result, Err(DeviceError::AlreadyMonitored(Some(pid), _)) if *pid == std::process::id()),
+            "{result:?}"
+        );
+
+        drop(device);
+        assert_eq!(second.acquire_instance_lock().unwrap(), None);
+
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+}
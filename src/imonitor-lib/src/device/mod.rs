@@ -1,32 +1,65 @@
 pub mod activity_coverage;
+pub mod archive;
+pub mod control;
+pub mod disk_usage;
 pub mod errors;
-
-use crate::config::Config;
+pub mod idle;
+pub mod instance_lock;
+pub mod pairing_check;
+pub mod state_dir;
+pub mod summary;
+#[cfg(test)]
+pub(crate) mod test_support;
+
+use crate::clock::{Clock, SystemClock};
+use crate::config::{Config, ConfigOverrides, ProxyConfig, Service, device_base_dir};
+use crate::connection::ConnectionManager;
+use crate::liveness::LivenessFile;
+use crate::object_store::CrashBackend;
+use crate::observer::{MonitorObserver, NoopObserver};
+use crate::proxy::ProxyProvider;
+use crate::services::crashes::client::CrashFileMeta;
+use crate::services::crashes::dead_letter::DeadLetterCrash;
+use crate::services::crashes::failed::FailedCrash;
+use crate::services::device_state::client::DeviceState;
+use crate::services::heartbeat::CIRCUIT_OPEN_FAILURES;
+use crate::services::heartbeat::latency::LatencyWindow;
+use crate::services::os_trace::client::OsTraceSink;
+use crate::state_store::StateFormat;
+use crate::throttle::BandwidthLimiter;
 use activity_coverage::ACTIVITY_COVERAGE_FILE_NAME;
 use activity_coverage::ActivityCoverage;
 use chrono::{DateTime, Utc};
 use errors::DeviceError;
 use idevice::pairing_file::PairingFile;
 use idevice::provider::{IdeviceProvider, TcpProvider};
-use logger::{HasLogger, Logger};
+use instance_lock::InstanceLock;
+use logger::{HasLogger, Logger, error, info};
 use phf::phf_map;
-use std::collections::HashSet;
-use std::fs::create_dir_all;
+use state_dir::STATE_DIR_NAME;
+use std::collections::{HashMap, HashSet};
+use std::fs::{create_dir_all, metadata};
 use std::net::IpAddr;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
 use tokio::fs::File;
 use tokio::io::{AsyncWriteExt, BufWriter};
-use tokio::sync::watch;
+use tokio::sync::{Semaphore, watch};
+use tokio::time::{Duration, sleep};
 use tokio::{task::JoinHandle, try_join};
 
+const DIRS_CHECK_INTERVAL_SECS: u64 = 600;
+
 static SUB_DIRS: phf::Map<&'static str, &'static str> = phf_map! {
     "info" => "info",
     "connection" => "connection",
     "heartbeat" => "heartbeat",
     "crashes" => "crashes",
     "crash_files" => "crashes/files",
+    "sysdiagnose" => "crashes/sysdiagnose",
+    "crash_blobs" => "crashes/blobs",
     "syslog" => "syslog",
     "os_trace" => "os_trace",
     "os_trace_log" => "os_trace/log",
@@ -35,6 +68,17 @@ static SUB_DIRS: phf::Map<&'static str, &'static str> = phf_map! {
     "activity_coverage" => "activity_coverage",
 };
 
+/// Why `Device::monitor` returned, for the caller to restart, alert or quarantine the device.
+#[derive(Debug)]
+pub enum MonitorOutcome {
+    /// Every service stopped on its own
+    ShutdownRequested,
+    /// Monitoring cannot go on as configured, restarting it does not help
+    Fatal(DeviceError),
+    /// A service failed while the heartbeat kept failing: the device seems offline
+    Unhealthy { consecutive_failures: u32 },
+}
+
 #[derive(Debug, Clone)]
 pub struct Device {
     pub info: Info,
@@ -43,18 +87,75 @@ pub struct Device {
     pub crashes: Crashes,
     pub logger: Option<Arc<Logger>>,
     pub activity_coverage: Arc<RwLock<ActivityCoverage>>,
-    pub base_dir: String,
+    /// Notified when a range is added to the activity coverage, which may open a new gap
+    pub coverage_changed: Arc<watch::Sender<()>>,
+    pub device_state: Arc<RwLock<Option<DeviceState>>>,
+    pub connection_limiter: Option<Arc<ConnectionManager>>,
+    pub heartbeat_limiter: Option<Arc<Semaphore>>,
+    pub download_limiter: Option<Arc<BandwidthLimiter>>,
+    /// Shared by all devices with `crashes.store = "remote"`, none to write crash files locally
+    pub crash_backend: Option<Arc<CrashBackend>>,
+    /// Shared by all devices, rewritten on each heartbeat
+    pub liveness: Option<Arc<LivenessFile>>,
+    pub observer: Arc<dyn MonitorObserver>,
+    /// Last state sent on the heartbeat connected channel, see [`Device::is_connected`]
+    pub connected: Arc<AtomicBool>,
+    /// Cleared when the device rejects the pairing, see [`Device::is_pairing_valid`]
+    pub pairing_valid: Arc<AtomicBool>,
+    pub clock: Arc<dyn Clock>,
+    /// Format the state files are written in, they are read in any
+    pub state_format: StateFormat,
+    /// Whether the state files are gathered in the state dir, see [`Device::get_state_dir`]
+    pub state_dir: bool,
+    pub config_overrides: ConfigOverrides,
+    /// Collection paused state, see [`Device::pause`]
+    pub paused: Arc<watch::Sender<bool>>,
+    /// Last new data or heartbeat change, see [`Device::is_idle`]
+    pub last_activity: Arc<watch::Sender<DateTime<Utc>>>,
+    /// Optional tee of the os trace log stream
+    pub os_trace_sink: Option<OsTraceSink>,
+    /// Proxy service connections go through, direct connections if not set
+    pub proxy: Option<ProxyConfig>,
+    /// Base dirs the device dir is in, one of them for a sharded base dir, see
+    /// [`Device::base_dir`]
+    pub base_dirs: Vec<String>,
+    /// Held while monitoring, see [`Device::acquire_instance_lock`]
+    pub instance_lock: Option<Arc<InstanceLock>>,
 }
 
 #[derive(Debug, Clone)]
 pub struct Info {
     pub udid: String,
+    /// Readable name from the monitored devices file, the UDID if not set
+    pub name: String,
 }
 
 #[derive(Debug, Clone)]
 pub struct Crashes {
     pub crash_files: Arc<RwLock<HashSet<String>>>,
     pub crash_dirs: Arc<RwLock<HashSet<String>>>,
+    /// Size and mtime of known crash files, only filled when changes are tracked
+    pub crash_files_meta: Arc<RwLock<HashMap<String, CrashFileMeta>>>,
+    /// Dirs whose listing timed out, skipped on the next listing
+    pub slow_dirs: Arc<RwLock<HashSet<String>>>,
+    /// Identity of the dirs discovered since startup, used to detect loops
+    pub crash_dir_ids: Arc<RwLock<HashMap<String, CrashDirIdentity>>>,
+    /// Symlinks, looping and too deep dirs, never listed nor pulled
+    pub ignored_paths: Arc<RwLock<HashSet<String>>>,
+    /// Files failing with a permanent error, persisted and retried after a long backoff
+    pub permanently_failed: Arc<RwLock<HashMap<String, FailedCrash>>>,
+    /// Failed local writes per file since startup, until the file is dead-lettered
+    pub write_failures: Arc<RwLock<HashMap<String, u32>>>,
+    /// Files failing to be written locally, persisted and retried after a long backoff
+    pub dead_letter: Arc<RwLock<HashMap<String, DeadLetterCrash>>>,
+}
+
+/// Attributes telling a device crash dir apart from its parents, AFC exposing no inode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrashDirIdentity {
+    pub creation: i64,
+    pub modified: i64,
+    pub nlink: String,
 }
 
 #[derive(Debug, Clone)]
@@ -67,12 +168,15 @@ pub struct Connection {
 #[derive(Debug, Clone)]
 pub struct HeartBeat {
     pub last_established: Arc<RwLock<DateTime<Utc>>>,
+    /// Rolling marco/polo round trip latency
+    pub latency: Arc<RwLock<LatencyWindow>>,
 }
 
 impl Info {
     pub fn new(udid: &str) -> Info {
         Info {
             udid: udid.to_string(),
+            name: udid.to_string(),
         }
     }
 }
@@ -82,6 +186,13 @@ impl Crashes {
         Crashes {
             crash_files: Arc::new(RwLock::new(HashSet::new())),
             crash_dirs: Arc::new(RwLock::new(HashSet::new())),
+            crash_files_meta: Arc::new(RwLock::new(HashMap::new())),
+            slow_dirs: Arc::new(RwLock::new(HashSet::new())),
+            crash_dir_ids: Arc::new(RwLock::new(HashMap::new())),
+            ignored_paths: Arc::new(RwLock::new(HashSet::new())),
+            permanently_failed: Arc::new(RwLock::new(HashMap::new())),
+            write_failures: Arc::new(RwLock::new(HashMap::new())),
+            dead_letter: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 }
@@ -96,6 +207,7 @@ impl HeartBeat {
     pub fn new() -> HeartBeat {
         HeartBeat {
             last_established: Arc::new(RwLock::new(DateTime::<Utc>::MIN_UTC)),
+            latency: Arc::new(RwLock::new(LatencyWindow::default())),
         }
     }
 }
@@ -122,9 +234,10 @@ impl Device {
         pairing_file: &PairingFile,
         ip_addr: &IpAddr,
         label: &str,
-        base_dir: impl AsRef<Path>,
+        base_dirs: &[String],
     ) -> Device {
         let connection = Connection::new(pairing_file, ip_addr, label);
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
 
         Device {
             connection: connection.clone(),
@@ -133,10 +246,54 @@ impl Device {
             crashes: Crashes::new(),
             logger: None,
             activity_coverage: Arc::new(RwLock::new(ActivityCoverage::new())),
-            base_dir: base_dir.as_ref().to_string_lossy().to_string(),
+            coverage_changed: Arc::new(watch::channel(()).0),
+            device_state: Arc::new(RwLock::new(None)),
+            connection_limiter: None,
+            heartbeat_limiter: None,
+            download_limiter: None,
+            crash_backend: None,
+            liveness: None,
+            observer: Arc::new(NoopObserver),
+            connected: Arc::new(AtomicBool::new(false)),
+            pairing_valid: Arc::new(AtomicBool::new(true)),
+            last_activity: Arc::new(watch::channel(clock.now_utc()).0),
+            clock,
+            state_format: StateFormat::default(),
+            state_dir: false,
+            config_overrides: ConfigOverrides::default(),
+            paused: Arc::new(watch::channel(false).0),
+            os_trace_sink: None,
+            proxy: None,
+            base_dirs: base_dirs.to_vec(),
+            instance_lock: None,
         }
     }
 
+    /// Name and UDID for logs, the UDID alone if the device has no name.
+    pub fn display_name(&self) -> String {
+        if self.info.name == self.info.udid {
+            self.info.udid.clone()
+        } else {
+            format!("{} ({})", self.info.name, self.info.udid)
+        }
+    }
+
+    /// Whether the heartbeat last considered the device connected. Cheap to call from
+    /// anywhere, the heartbeat watch channel is for waiting on changes.
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    /// Records the connected state, then notifies the channel waiters.
+    pub fn set_connected(
+        &self,
+        connected_sender: &watch::Sender<bool>,
+        connected: bool,
+    ) -> Result<(), watch::error::SendError<bool>> {
+        self.connected.store(connected, Ordering::Relaxed);
+        connected_sender.send(connected)
+    }
+
     pub async fn load_activity_coverage(&mut self) -> Result<(), DeviceError> {
         let activity_coverage =
             activity_coverage::load_from_fs(&self.get_activity_coverage_file_path()).await?;
@@ -144,10 +301,24 @@ impl Device {
         Ok(())
     }
 
+    /// Dir of the device, in the base dir holding it or else the one its UDID is sharded
+    /// to, see [`device_base_dir`].
     pub fn base_dir(&self) -> String {
-        let general_base_dir = PathBuf::from(&self.base_dir);
-        general_base_dir
-            .join(self.info.udid.clone())
+        PathBuf::from(self.get_shard_dir())
+            .join(&self.info.udid)
+            .to_string_lossy()
+            .to_string()
+    }
+
+    /// Base dir holding the device dir.
+    pub fn get_shard_dir(&self) -> String {
+        device_base_dir(&self.base_dirs, &self.info.udid)
+    }
+
+    pub fn get_info_dir(&self) -> String {
+        let base_path = PathBuf::from(self.base_dir());
+        base_path
+            .join(SUB_DIRS.get("info").unwrap_or(&""))
             .to_string_lossy()
             .to_string()
     }
@@ -206,6 +377,22 @@ impl Device {
             .to_string()
     }
 
+    pub fn get_crash_blobs_dir(&self) -> String {
+        let base_path = PathBuf::from(self.base_dir());
+        base_path
+            .join(SUB_DIRS.get("crash_blobs").unwrap_or(&""))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    pub fn get_sysdiagnose_dir(&self) -> String {
+        let base_path = PathBuf::from(self.base_dir());
+        base_path
+            .join(SUB_DIRS.get("sysdiagnose").unwrap_or(&""))
+            .to_string_lossy()
+            .to_string()
+    }
+
     pub fn get_log_file_name(&self) -> String {
         format!("{}.log", self.info.udid)
     }
@@ -235,22 +422,65 @@ impl Device {
     }
 
     pub fn get_activity_coverage_file_path(&self) -> String {
-        let dir = PathBuf::from(self.get_activity_coverage_dir());
-        dir.join(ACTIVITY_COVERAGE_FILE_NAME)
-            .to_string_lossy()
-            .to_string()
+        self.get_state_file_path(
+            self.get_activity_coverage_dir(),
+            ACTIVITY_COVERAGE_FILE_NAME,
+        )
     }
 
+    /// Creates the device dirs, which hold every state file.
     pub fn create_dirs(&self) -> Result<(), DeviceError> {
-        for dir in SUB_DIRS.values() {
+        let state_dir = self.state_dir.then_some(STATE_DIR_NAME);
+        for dir in SUB_DIRS.values().copied().chain(state_dir) {
             let base_path = PathBuf::from(self.base_dir());
-            let path = base_path.join(dir).to_string_lossy().to_string();
-            create_dir_all(&path).map_err(|e| DeviceError::CreateDir(e, path.clone()))?;
+            let path = base_path.join(dir);
+            if let Err(e) = create_dir_all(&path) {
+                let path_string = path.to_string_lossy().to_string();
+                return Err(match file_in_the_way(&path) {
+                    Some(file) => {
+                        DeviceError::PathConflict(file.to_string_lossy().to_string(), path_string)
+                    }
+                    None => DeviceError::CreateDir(e, path_string),
+                });
+            }
         }
 
         Ok(())
     }
 
+    /// Returns the device dirs that are missing or are not directories. Creates nothing.
+    pub fn verify_dirs(&self) -> Result<Vec<String>, DeviceError> {
+        let base_path = PathBuf::from(self.base_dir());
+        let mut damaged = Vec::new();
+
+        let state_dir = self.state_dir.then_some(&STATE_DIR_NAME);
+        for dir in SUB_DIRS.values().chain(state_dir) {
+            let path = base_path.join(dir);
+            match metadata(&path) {
+                Ok(meta) if meta.is_dir() => {}
+                Ok(_) => damaged.push(path.to_string_lossy().to_string()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    damaged.push(path.to_string_lossy().to_string())
+                }
+                Err(e) => return Err(DeviceError::ReadFile(e, path.to_string_lossy().to_string())),
+            }
+        }
+
+        Ok(damaged)
+    }
+
+    /// Periodically checks the device dirs, e.g. to detect a volume remounted without them.
+    pub async fn watch_dirs(&self) -> Result<(), DeviceError> {
+        loop {
+            sleep(Duration::from_secs(DIRS_CHECK_INTERVAL_SECS)).await;
+            match self.verify_dirs() {
+                Ok(damaged) if damaged.is_empty() => {}
+                Ok(damaged) => error!(self, "Damaged device dirs: {}", damaged.join(", ")),
+                Err(e) => error!(self, "Failed to verify device dirs: {e}"),
+            }
+        }
+    }
+
     pub async fn write_pairing_file(&self, _source_file_path: &str) -> Result<(), DeviceError> {
         let pairing_file_bytes = self
             .connection
@@ -288,28 +518,88 @@ impl Device {
         Ok(())
     }
 
-    pub async fn monitor(&mut self, config: Arc<RwLock<Config>>) -> Result<(), DeviceError> {
+    /// Runs the device services until one of them fails or all of them stop.
+    pub async fn monitor(&mut self, config: Arc<RwLock<Config>>) -> MonitorOutcome {
+        info!(self, "Monitoring device {}", self.display_name());
+        let result = loop {
+            match self.run_services(config.clone()).await {
+                // The services held the rejected pairing, they restart with the new one
+                Err(DeviceError::PairingRepaired(pairing_file)) => {
+                    self.connection.pairing_file = *pairing_file;
+                }
+                result => break result,
+            }
+        };
+
+        self.monitor_outcome(result).await
+    }
+
+    /// Tells apart why the services stopped, see [`MonitorOutcome`].
+    async fn monitor_outcome(&self, result: Result<(), DeviceError>) -> MonitorOutcome {
+        let error = match result {
+            Ok(_) => return MonitorOutcome::ShutdownRequested,
+            Err(e) => e,
+        };
+
+        // The failure count is persisted by the heartbeat service
+        let consecutive_failures = self.load_hb_failures().await;
+        if consecutive_failures >= CIRCUIT_OPEN_FAILURES {
+            error!(
+                self,
+                "Monitoring stopped after {consecutive_failures} heartbeat failures: {error}"
+            );
+            MonitorOutcome::Unhealthy {
+                consecutive_failures,
+            }
+        } else {
+            error!(self, "Monitoring stopped: {error}");
+            MonitorOutcome::Fatal(error)
+        }
+    }
+
+    async fn run_services(&mut self, config: Arc<RwLock<Config>>) -> Result<(), DeviceError> {
         let refresh_rate;
-        {
-            refresh_rate = config
+        let crashes_config;
+        let syslog_config;
+        let services;
+        let idle_config;
+        let os_trace_config;
+        let installed_apps_config;
+        let pairing_check_config;
+        let auto_repair;
+        let flush_interval;
+        let read_timeout;
+        let config = {
+            let config = config
                 .read()
                 .map_err(|_| DeviceError::ConfigReadLock)?
-                .settings
-                .clone()
-                .refresh_rate;
-        }
-
-        let (tx, mut rx) = watch::channel(false);
+                .with_overrides(&self.config_overrides);
+            refresh_rate = config.settings.clone().refresh_rate;
+            crashes_config = config.crashes.clone();
+            syslog_config = config.syslog.clone();
+            services = config.services.clone();
+            idle_config = config.idle.clone();
+            os_trace_config = config.os_trace.clone();
+            installed_apps_config = config.installed_apps.clone();
+            pairing_check_config = config.pairing_check.clone();
+            auto_repair = config.settings.auto_repair;
+            flush_interval = config.settings.flush_interval;
+            read_timeout = config.settings.read_timeout;
+            // Device services only see the config with the device overrides applied
+            Arc::new(RwLock::new(config))
+        };
+
+        // Restore the persisted paused state before services start
+        let paused = self.load_paused().await?;
+        self.paused.send_replace(paused);
+
+        // Restarted monitoring starts disconnected, like the channel
+        self.connected.store(false, Ordering::Relaxed);
+        let (tx, rx) = watch::channel(false);
 
         let device_hb = self.clone();
-        //let device_syslog = self.clone();
-        let device_crashes = self.clone();
-        let device_os_trace_log = self.clone();
-        let device_os_trace_archive = self.clone();
-
-        //let mut syslog_hb_rx = rx.clone();
-        let mut os_trace_log_hb_rx = rx.clone();
-        let mut os_trace_archive_hb_rx = rx.clone();
+        let device_control = self.clone();
+        let device_dirs = self.clone();
 
         /*
         // Not parallelized version
@@ -319,33 +609,130 @@ impl Device {
         let _ = tokio::join!(hb, syslog, crashes);
         */
 
+        let enabled_services = services.enabled_services();
+        info!(self, "Starting heartbeat and {enabled_services:?}");
+        let enabled = |service| enabled_services.contains(&service);
+
+        // Other services gate on the heartbeat, which cannot be disabled
         let hb = tokio::spawn(async move { device_hb.maintain_heartbeat(config, &tx).await });
-        
-        /*
+
         // os_trace service seems more useful than syslog: formatted as json.
         // TODO: Do a thorough comparison of the data delivered by the 2 services
-        let _syslog =
-            tokio::spawn(async move { device_syslog.stream_syslog(refresh_rate, &mut syslog_hb_rx).await });
-        */
+        let syslog = enabled(Service::Syslog).then(|| {
+            let device_syslog = self.clone();
+            let mut syslog_hb_rx = rx.clone();
+            tokio::spawn(async move {
+                device_syslog
+                    .stream_syslog(
+                        refresh_rate,
+                        syslog_config,
+                        flush_interval,
+                        read_timeout,
+                        &mut syslog_hb_rx,
+                    )
+                    .await
+            })
+        });
 
-        let crashes =
-            tokio::spawn(async move { device_crashes.get_crashes(refresh_rate, &mut rx).await });
+        let control = tokio::spawn(async move { device_control.watch_paused_file().await });
 
-        let os_trace_log = tokio::spawn(async move {
-            device_os_trace_log
-                .stream_os_trace_logs(refresh_rate, &mut os_trace_log_hb_rx)
-                .await
+        let dirs = tokio::spawn(async move { device_dirs.watch_dirs().await });
+
+        let device_state = enabled(Service::DeviceState).then(|| {
+            let device_device_state = self.clone();
+            let mut device_state_hb_rx = rx.clone();
+            tokio::spawn(async move {
+                device_device_state
+                    .check_device_state(&mut device_state_hb_rx)
+                    .await
+            })
+        });
+
+        let crashes = enabled(Service::Crashes).then(|| {
+            let device_crashes = self.clone();
+            let mut crashes_hb_rx = rx.clone();
+            let idle_config = idle_config.clone();
+            tokio::spawn(async move {
+                device_crashes
+                    .get_crashes(
+                        refresh_rate,
+                        crashes_config,
+                        idle_config,
+                        &mut crashes_hb_rx,
+                    )
+                    .await
+            })
+        });
+
+        let os_trace_log = enabled(Service::OsTraceLog).then(|| {
+            let device_os_trace_log = self.clone();
+            let mut os_trace_log_hb_rx = rx.clone();
+            let idle_config = idle_config.clone();
+            tokio::spawn(async move {
+                device_os_trace_log
+                    .stream_os_trace_logs(
+                        refresh_rate,
+                        idle_config,
+                        flush_interval,
+                        read_timeout,
+                        &mut os_trace_log_hb_rx,
+                    )
+                    .await
+            })
         });
 
-        /*
         // Service still under development. Using it in production is not recommended.
-        let os_trace_archive = tokio::spawn(async move {
-            device_os_trace_archive
-                .create_os_trace_archive(refresh_rate, &mut os_trace_archive_hb_rx)
-                .await
+        let os_trace_archive = enabled(Service::OsTraceArchive).then(|| {
+            let device_os_trace_archive = self.clone();
+            let mut os_trace_archive_hb_rx = rx.clone();
+            tokio::spawn(async move {
+                device_os_trace_archive
+                    .create_os_trace_archive(
+                        refresh_rate,
+                        os_trace_config,
+                        &mut os_trace_archive_hb_rx,
+                    )
+                    .await
+            })
         });
-        */
 
+        let installed_apps = enabled(Service::InstalledApps).then(|| {
+            let device_installed_apps = self.clone();
+            let mut installed_apps_hb_rx = rx.clone();
+            tokio::spawn(async move {
+                device_installed_apps
+                    .snapshot_installed_apps(installed_apps_config, &mut installed_apps_hb_rx)
+                    .await
+            })
+        });
+
+        let pairing_check = enabled(Service::PairingCheck).then(|| {
+            let device_pairing_check = self.clone();
+            let mut pairing_check_hb_rx = rx.clone();
+            tokio::spawn(async move {
+                device_pairing_check
+                    .check_pairing_periodically(
+                        pairing_check_config,
+                        auto_repair,
+                        &mut pairing_check_hb_rx,
+                    )
+                    .await
+            })
+        });
+
+        // Monitoring stopped by a failed service must not leave the others running
+        let mut abort_handles = vec![
+            hb.abort_handle(),
+            control.abort_handle(),
+            dirs.abort_handle(),
+        ];
+        abort_handles.extend(syslog.as_ref().map(JoinHandle::abort_handle));
+        abort_handles.extend(crashes.as_ref().map(JoinHandle::abort_handle));
+        abort_handles.extend(os_trace_log.as_ref().map(JoinHandle::abort_handle));
+        abort_handles.extend(os_trace_archive.as_ref().map(JoinHandle::abort_handle));
+        abort_handles.extend(installed_apps.as_ref().map(JoinHandle::abort_handle));
+        abort_handles.extend(pairing_check.as_ref().map(JoinHandle::abort_handle));
+        abort_handles.extend(device_state.as_ref().map(JoinHandle::abort_handle));
 
         /*
         // Test: await services individually
@@ -355,24 +742,55 @@ impl Device {
         */
 
         // Fire all services at once. They run concurrently.
-        try_join!(
+        let res = try_join!(
             flatten(hb),
-            //flatten(syslog),
-            flatten(crashes),
-            flatten(os_trace_log),
-            //flatten(os_trace_archive),
-        )?;
+            flatten(control),
+            flatten(dirs),
+            flatten_optional(syslog),
+            flatten_optional(device_state),
+            flatten_optional(crashes),
+            flatten_optional(os_trace_log),
+            flatten_optional(os_trace_archive),
+            flatten_optional(installed_apps),
+            flatten_optional(pairing_check),
+        );
+        if res.is_err() {
+            for abort_handle in abort_handles {
+                abort_handle.abort();
+            }
+        }
+        res?;
 
         Ok(())
     }
 
+    /// Sets up the device log file. If it cannot be written to, logs go to stderr and
+    /// `LoggerInit` is returned: the device can still be monitored.
     pub fn init_logger(&mut self) -> Result<(), DeviceError> {
-        let logger = Logger::new(&self.base_dir(), &self.get_log_file_name());
-        self.logger = Some(Arc::new(logger));
-        Ok(())
+        match Logger::try_new(&self.base_dir(), &self.get_log_file_name()) {
+            Ok(logger) => {
+                self.logger = Some(Arc::new(logger));
+                Ok(())
+            }
+            Err(e) => {
+                self.logger = Some(Arc::new(Logger::stderr()));
+                let log_file_path = Path::new(&self.base_dir())
+                    .join(self.get_log_file_name())
+                    .to_string_lossy()
+                    .to_string();
+                Err(DeviceError::LoggerInit(e, log_file_path))
+            }
+        }
     }
 }
 
+/// The path, or one of its ancestors, existing as something else than a directory, such as
+/// a file named after the UDID in the base dir.
+fn file_in_the_way(path: &Path) -> Option<&Path> {
+    path.ancestors()
+        .find(|ancestor| metadata(ancestor).is_ok_and(|meta| !meta.is_dir()))
+}
+
 // Inspired from https://docs.rs/tokio/latest/tokio/macro.try_join.html
 async fn flatten(
     handle: JoinHandle<Result<(), impl Into<DeviceError>>>,
@@ -384,6 +802,16 @@ async fn flatten(
     }
 }
 
+// Disabled services never fail
+async fn flatten_optional(
+    handle: Option<JoinHandle<Result<(), impl Into<DeviceError>>>>,
+) -> Result<(), DeviceError> {
+    match handle {
+        Some(handle) => flatten(handle).await,
+        None => Ok(()),
+    }
+}
+
 impl HasLogger for Device {
     fn logger(&self) -> Option<&Logger> {
         self.logger.as_deref()
@@ -403,6 +831,175 @@ impl From<&Device> for TcpProvider {
 impl From<&Device> for Box<dyn IdeviceProvider> {
     fn from(device: &Device) -> Self {
         let provider: TcpProvider = device.into();
-        Box::new(provider)
+        match &device.proxy {
+            Some(proxy) => Box::new(ProxyProvider {
+                addr: provider.addr,
+                pairing_file: provider.pairing_file,
+                label: provider.label,
+                proxy: proxy.clone(),
+            }),
+            None => Box::new(provider),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::shard_base_dir;
+    use test_support::test_device;
+
+    #[test]
+    fn device_dir_follows_the_shard() {
+        let (mut device, base_dir) = test_device("device-shard");
+        device.base_dirs = ["a", "b"]
+            .map(|dir| base_dir.join(dir).to_string_lossy().to_string())
+            .to_vec();
+        let shard = shard_base_dir(&device.base_dirs, &device.info.udid).unwrap();
+
+        assert_eq!(device.get_shard_dir(), shard);
+        assert_eq!(
+            device.base_dir(),
+            Path::new(&shard).join(&device.info.udid).to_string_lossy()
+        );
+        device.create_dirs().unwrap();
+        assert!(Path::new(&device.get_crashes_dir()).starts_with(&shard));
+        assert!(Path::new(&device.get_crashes_dir()).is_dir());
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    #[test]
+    fn display_name_adds_the_udid_to_a_set_name() {
+        let (mut device, base_dir) = test_device("display-name");
+        assert_eq!(device.display_name(), device.info.udid);
+
+        device.info.name = "QA iPhone 12".to_string();
+        assert_eq!(
+            device.display_name(),
+            format!("QA iPhone 12 ({})", device.info.udid)
+        );
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    #[test]
+    fn file_in_the_way_of_a_dir_is_reported() {
+        let (device, base_dir) = test_device("path-conflict");
+        let crashes_dir = device.get_crashes_dir();
+        std::fs::remove_dir_all(&crashes_dir).unwrap();
+        std::fs::write(&crashes_dir, b"").unwrap();
+
+        // Either the crashes dir or one below it, depending on the creation order
+        match device.create_dirs() {
+            Err(DeviceError::PathConflict(file, dir)) => {
+                assert_eq!(file, crashes_dir);
+                assert!(Path::new(&dir).starts_with(&crashes_dir), "{dir}");
+            }
+            result => panic!("{result:?}"),
+        }
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    #[test]
+    fn verify_dirs_reports_missing_dirs_and_files_in_the_way() {
+        let (device, base_dir) = test_device("verify-dirs");
+        assert!(device.verify_dirs().unwrap().is_empty());
+
+        let syslog_dir = device.get_syslog_dir();
+        std::fs::remove_dir(&syslog_dir).unwrap();
+        let archive_dir = device.get_os_trace_archive_dir();
+        std::fs::remove_dir(&archive_dir).unwrap();
+        std::fs::write(&archive_dir, b"").unwrap();
+
+        let mut damaged = device.verify_dirs().unwrap();
+        damaged.sort();
+        let mut expected = vec![syslog_dir.clone(), archive_dir];
+        expected.sort();
+        assert_eq!(damaged, expected);
+        // Nothing is created
+        assert!(!Path::new(&syslog_dir).exists());
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    #[test]
+    fn verify_dirs_includes_the_state_dir_when_enabled() {
+        let (mut device, base_dir) = test_device("verify-state-dir");
+        device.state_dir = true;
+        assert_eq!(device.verify_dirs().unwrap(), [device.get_state_dir()]);
+
+        device.create_dirs().unwrap();
+        assert!(device.verify_dirs().unwrap().is_empty());
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    #[test]
+    fn logger_falls_back_to_stderr_when_the_log_file_is_not_writable() {
+        let (mut device, base_dir) = test_device("logger-init");
+        device.init_logger().unwrap();
+        assert_ne!(device.logger.as_ref().unwrap().file_path, "<stderr>");
+
+        // Unlike permissions, a dir in the way cannot be written to even as root
+        let log_file_path = Path::new(&device.base_dir()).join(device.get_log_file_name());
+        std::fs::remove_file(&log_file_path).unwrap();
+        std::fs::create_dir(&log_file_path).unwrap();
+
+        let result = device.init_logger();
+        assert!(
+            matches!(&result, Err(DeviceError::LoggerInit(_, path)) if Path::new(path) == log_file_path),
+            "{result:?}"
+        );
+        assert_eq!(device.logger.as_ref().unwrap().file_path, "<stderr>");
+
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn monitor_outcome_of_each_stop() {
+        let (device, base_dir) = test_device("monitor-outcome");
+
+        assert!(matches!(
+            device.monitor_outcome(Ok(())).await,
+            MonitorOutcome::ShutdownRequested
+        ));
+        assert!(matches!(
+            device.monitor_outcome(Err(DeviceError::TaskFailed)).await,
+            MonitorOutcome::Fatal(DeviceError::TaskFailed)
+        ));
+
+        // Persisted by the heartbeat service while the device is offline
+        device
+            .update_hb_failures(CIRCUIT_OPEN_FAILURES)
+            .await
+            .unwrap();
+        assert!(matches!(
+            device.monitor_outcome(Err(DeviceError::TaskFailed)).await,
+            MonitorOutcome::Unhealthy {
+                consecutive_failures: CIRCUIT_OPEN_FAILURES
+            }
+        ));
+
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    #[test]
+    fn connected_flag_reflects_the_last_sent_state() {
+        let (device, base_dir) = test_device("connected-flag");
+        // Services hold clones of the device
+        let service_device = device.clone();
+        let (connected_sender, connected_rx) = watch::channel(false);
+        assert!(!service_device.is_connected());
+
+        device.set_connected(&connected_sender, true).unwrap();
+        assert!(service_device.is_connected());
+        assert!(*connected_rx.borrow());
+
+        device.set_connected(&connected_sender, false).unwrap();
+        assert!(!service_device.is_connected());
+
+        // Recorded even without anyone waiting on the channel
+        drop(connected_rx);
+        assert!(device.set_connected(&connected_sender, true).is_err());
+        assert!(service_device.is_connected());
+
+        std::fs::remove_dir_all(base_dir).unwrap();
     }
 }
@@ -0,0 +1,125 @@
+use super::Device;
+use super::errors::DeviceError;
+use crate::config::PairingCheckConfig;
+use crate::enroll::errors::EnrollError;
+use crate::enroll::{check_pairing, enroll_usb_device};
+use logger::{HasLogger, debug, error, info, warn};
+use std::sync::atomic::Ordering;
+use tokio::sync::watch;
+use tokio::time::{Duration, sleep};
+
+const RETRY_CONNECT_WAIT_SECS: u64 = 30;
+
+impl Device {
+    /// Whether the last pairing check succeeded. True until a check is rejected.
+    pub fn is_pairing_valid(&self) -> bool {
+        self.pairing_valid.load(Ordering::Relaxed)
+    }
+
+    /// Periodically starts a lockdown session with the pairing file. A rejected session,
+    /// e.g. after an iOS update or a reset, marks the pairing invalid. With `auto_repair`
+    /// the device is paired again over USB and `PairingRepaired` stops the services, which
+    /// [`Device::monitor`] restarts with the new pairing.
+    pub async fn check_pairing_periodically(
+        &self,
+        pairing_check_config: PairingCheckConfig,
+        auto_repair: bool,
+        hb_connected_rx: &mut watch::Receiver<bool>,
+    ) -> Result<(), DeviceError> {
+        loop {
+            // A session can only be rejected by a reachable device
+            if hb_connected_rx.wait_for(|val| *val).await.is_err() {
+                sleep(Duration::from_secs(RETRY_CONNECT_WAIT_SECS)).await;
+                continue;
+            }
+
+            let provider = self.get_provider("pairing_check");
+            let checked = check_pairing(&*provider, &self.connection.pairing_file).await;
+            match self.record_pairing_check(checked) {
+                Ok(true) => {}
+                Ok(false) => {
+                    if auto_repair {
+                        match enroll_usb_device(&self.info.udid, &self.connection.label).await {
+                            Ok(pairing_file) => {
+                                let mut repaired = self.clone();
+                                repaired.connection.pairing_file = pairing_file.clone();
+                                repaired
+                                    .write_pairing_file(&self.get_pairing_file_path())
+                                    .await?;
+                                info!(self, "Paired again, restarting the services");
+                                return Err(DeviceError::PairingRepaired(Box::new(pairing_file)));
+                            }
+                            Err(e) => warn!(self, "Failed to pair again: {e}"),
+                        }
+                    }
+                }
+                // The device may just be offline, not an invalid pairing
+                Err(e) => {
+                    debug!(self, "Pairing check skipped: {e}");
+                    sleep(Duration::from_secs(RETRY_CONNECT_WAIT_SECS)).await;
+                    continue;
+                }
+            }
+
+            sleep(pairing_check_config.interval).await;
+        }
+    }
+
+    /// Updates [`Device::is_pairing_valid`] from a pairing check, only a rejected session
+    /// invalidates the pairing. Returns whether the pairing is valid, or the error of a
+    /// check that could not be made.
+    fn record_pairing_check(&self, checked: Result<(), EnrollError>) -> Result<bool, EnrollError> {
+        match checked {
+            Ok(()) => {
+                if !self.pairing_valid.swap(true, Ordering::Relaxed) {
+                    info!(self, "Pairing valid again");
+                }
+                Ok(true)
+            }
+            Err(EnrollError::StartSession(e)) => {
+                if self.pairing_valid.swap(false, Ordering::Relaxed) {
+                    error!(self, "Pairing rejected by the device: {e}");
+                    self.observer.on_pairing_invalid(&self.info.udid, &e);
+                }
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::test_support::test_device;
+    use crate::observer::CountingObserver;
+    use idevice::IdeviceError;
+    use std::sync::Arc;
+
+    #[test]
+    fn only_a_rejected_session_invalidates_the_pairing() {
+        let (mut device, base_dir) = test_device("pairing-check");
+        let observer = Arc::new(CountingObserver::default());
+        device.observer = observer.clone();
+        let rejected = || EnrollError::StartSession(IdeviceError::HeartbeatTimeout);
+
+        // An unreachable device says nothing of the pairing
+        assert!(matches!(
+            device.record_pairing_check(Err(EnrollError::Timeout)),
+            Err(EnrollError::Timeout)
+        ));
+        assert!(device.is_pairing_valid());
+
+        assert!(!device.record_pairing_check(Err(rejected())).unwrap());
+        assert!(!device.is_pairing_valid());
+        // Reported once per invalidation
+        assert!(!device.record_pairing_check(Err(rejected())).unwrap());
+        assert_eq!(observer.pairings_invalid.load(Ordering::Relaxed), 1);
+
+        assert!(device.record_pairing_check(Ok(())).unwrap());
+        assert!(device.is_pairing_valid());
+        assert!(!device.record_pairing_check(Err(rejected())).unwrap());
+        assert_eq!(observer.pairings_invalid.load(Ordering::Relaxed), 2);
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+}
@@ -0,0 +1,130 @@
+use super::Device;
+use super::errors::DeviceError;
+use logger::{HasLogger, info};
+use std::fs::rename;
+use std::path::{Path, PathBuf};
+
+pub(crate) const STATE_DIR_NAME: &str = "state";
+
+/// Bookkeeping files moved to the state dir when enabled. Collected data, such as crash
+/// files, logs, archives and the crash index, stays in the service dirs.
+const STATE_FILE_PATHS: [fn(&Device) -> String; 10] = [
+    Device::get_known_crashes_file_path,
+    Device::get_known_crash_dirs_file_path,
+    Device::get_permanently_failed_file_path,
+    Device::get_dead_letter_file_path,
+    Device::get_force_pull_requests_file_path,
+    Device::get_activity_coverage_file_path,
+    Device::get_hb_last_established_file_path,
+    Device::get_hb_failures_file_path,
+    Device::get_hb_history_file_path,
+    Device::get_paused_file_path,
+];
+
+impl Device {
+    pub fn get_state_dir(&self) -> String {
+        let base_path = PathBuf::from(self.base_dir());
+        base_path.join(STATE_DIR_NAME).to_string_lossy().to_string()
+    }
+
+    /// Path of a state file kept in `service_dir`, or in the state dir if `state_dir` is set.
+    pub fn get_state_file_path(&self, service_dir: String, file_name: &str) -> String {
+        let dir = if self.state_dir {
+            PathBuf::from(self.get_state_dir())
+        } else {
+            PathBuf::from(service_dir)
+        };
+        dir.join(file_name).to_string_lossy().to_string()
+    }
+
+    /// Moves the state files left in the other layout, e.g. on the first run after
+    /// `state_dir` was set or unset, and the crash index left under its former name. A file
+    /// already in place is never overwritten. Returns the number of files moved.
+    pub fn migrate_state_files(&self) -> Result<usize, DeviceError> {
+        let mut other_layout = self.clone();
+        other_layout.state_dir = !self.state_dir;
+
+        let mut moves = STATE_FILE_PATHS
+            .iter()
+            .map(|state_file_path| (state_file_path(&other_layout), state_file_path(self)))
+            .collect::<Vec<_>>();
+        moves.push((
+            self.get_legacy_crash_index_file_path(),
+            self.get_crash_index_file_path(),
+        ));
+
+        let mut moved = 0;
+        for (from, to) in moves {
+            if !Path::new(&from).exists() || Path::new(&to).exists() {
+                continue;
+            }
+            rename(&from, &to).map_err(|e| DeviceError::MoveFile(e, from.clone(), to.clone()))?;
+            info!(self, "Moved state file {from} to {to}");
+            moved += 1;
+        }
+
+        Ok(moved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::test_support::test_device;
+
+    #[test]
+    fn state_files_follow_the_layout() {
+        let (mut device, base_dir) = test_device("state-layout");
+        assert_eq!(
+            device.get_paused_file_path(),
+            Path::new(&device.get_info_dir())
+                .join("paused.json")
+                .to_string_lossy()
+        );
+
+        device.state_dir = true;
+        assert_eq!(
+            device.get_paused_file_path(),
+            Path::new(&device.get_state_dir())
+                .join("paused.json")
+                .to_string_lossy()
+        );
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    #[test]
+    fn state_files_are_moved_to_the_new_layout_without_overwriting() {
+        let (mut device, base_dir) = test_device("state-migration");
+        std::fs::write(device.get_paused_file_path(), "true").unwrap();
+        std::fs::write(device.get_activity_coverage_file_path(), "old").unwrap();
+
+        device.state_dir = true;
+        device.create_dirs().unwrap();
+        std::fs::write(device.get_activity_coverage_file_path(), "new").unwrap();
+
+        assert_eq!(device.migrate_state_files().unwrap(), 1);
+        assert_eq!(
+            std::fs::read_to_string(device.get_paused_file_path()).unwrap(),
+            "true"
+        );
+        assert_eq!(
+            std::fs::read_to_string(device.get_activity_coverage_file_path()).unwrap(),
+            "new"
+        );
+        // Left in place rather than lost
+        device.state_dir = false;
+        assert_eq!(
+            std::fs::read_to_string(device.get_activity_coverage_file_path()).unwrap(),
+            "old"
+        );
+        assert!(!Path::new(&device.get_paused_file_path()).exists());
+
+        // Back to the service dirs
+        assert_eq!(device.migrate_state_files().unwrap(), 1);
+        assert_eq!(
+            std::fs::read_to_string(device.get_paused_file_path()).unwrap(),
+            "true"
+        );
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+}
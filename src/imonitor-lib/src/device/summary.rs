@@ -0,0 +1,218 @@
+use super::Device;
+use super::activity_coverage;
+use super::disk_usage::DiskUsage;
+use super::errors::DeviceError;
+use crate::services::crashes::client::KnownCrashesFile;
+use crate::services::crashes::dead_letter::DeadLetterCrash;
+use crate::services::heartbeat::history::uptime_ratio;
+use crate::state_store::decode_state;
+use chrono::{DateTime, FixedOffset, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::{metadata, read_dir};
+use std::ops::Range;
+use std::path::Path;
+use std::time::SystemTime;
+use tokio::fs::{read, try_exists};
+
+/// Summary of the data collected for a device, built from the files on disk.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceSummary {
+    pub udid: String,
+    pub name: String,
+    pub crash_files: usize,
+    pub crash_bytes: u64,
+    /// Crash files failing to be written locally, see `crashes/dead_letter.json`
+    pub dead_letter: BTreeMap<String, DeadLetterCrash>,
+    pub syslog_bytes: u64,
+    pub os_trace_log_bytes: u64,
+    pub disk_usage: DiskUsage,
+    pub coverage_ratio: Option<f64>,
+    pub largest_gap_start: Option<DateTime<Utc>>,
+    pub largest_gap_secs: Option<u64>,
+    pub last_heartbeat: Option<DateTime<Utc>>,
+    /// Share of the window, or of the heartbeat history, the heartbeat was connected
+    pub uptime_ratio: Option<f64>,
+    /// Device timezone cached at startup, such as "Europe/Paris"
+    pub device_time_zone: Option<String>,
+    pub device_utc_offset_secs: Option<i32>,
+    /// Same as the UTC fields, in the device local time
+    pub largest_gap_start_device: Option<DateTime<FixedOffset>>,
+    pub last_heartbeat_device: Option<DateTime<FixedOffset>>,
+    /// Window the coverage fields are clamped to, if any.
+    pub window_start: Option<DateTime<Utc>>,
+    pub window_end: Option<DateTime<Utc>>,
+}
+
+impl Device {
+    /// Summarizes the collected data. Read-only, does not connect to the device.
+    /// With a `window`, coverage fields only account for that window; sizes and crash
+    /// counts are always totals.
+    pub async fn summarize(
+        &self,
+        window: Option<Range<SystemTime>>,
+    ) -> Result<DeviceSummary, DeviceError> {
+        let known_crashes: Option<KnownCrashesFile> =
+            read_state_file(&self.get_known_crashes_file_path()).await?;
+
+        let dead_letter: Option<BTreeMap<String, DeadLetterCrash>> =
+            read_state_file(&self.get_dead_letter_file_path()).await?;
+
+        let coverage =
+            activity_coverage::load_from_fs(&self.get_activity_coverage_file_path()).await?;
+        let (coverage_ratio, largest_gap) = match &window {
+            Some(window) => {
+                let window_coverage = coverage.coverage_in(window.clone());
+                (window_coverage.ratio, window_coverage.largest_gap())
+            }
+            None => (coverage.coverage_ratio(), coverage.largest_gap()),
+        };
+
+        let last_heartbeat: Option<DateTime<Utc>> =
+            read_state_file(&self.get_hb_last_established_file_path()).await?;
+
+        let hb_history = self.load_hb_history().await;
+        let uptime_window = match &window {
+            Some(window) => window.start.into()..window.end.into(),
+            // Only the time after the first transition counts
+            None => DateTime::<Utc>::MIN_UTC..self.clock.now_utc(),
+        };
+
+        let device_timezone = self.load_device_timezone().await;
+        let largest_gap_start: Option<DateTime<Utc>> =
+            largest_gap.as_ref().map(|gap| gap.start.into());
+        let to_device_local = |time: Option<DateTime<Utc>>| {
+            device_timezone
+                .as_ref()
+                .and_then(|device_timezone| device_timezone.to_device_local(time?))
+        };
+
+        Ok(DeviceSummary {
+            udid: self.info.udid.clone(),
+            name: self.info.name.clone(),
+            crash_files: known_crashes
+                .map(|c| c.into_map().len())
+                .unwrap_or_default(),
+            crash_bytes: dir_size(self.get_crash_files_dir())?,
+            dead_letter: dead_letter.unwrap_or_default(),
+            syslog_bytes: file_size(self.get_syslog_file_path())?,
+            os_trace_log_bytes: file_size(self.get_os_trace_log_file_path())?,
+            disk_usage: self.total_disk_usage()?,
+            coverage_ratio,
+            largest_gap_start,
+            largest_gap_secs: largest_gap.map(|gap| {
+                gap.end
+                    .duration_since(gap.start)
+                    .unwrap_or_default()
+                    .as_secs()
+            }),
+            last_heartbeat,
+            uptime_ratio: uptime_ratio(&hb_history, uptime_window),
+            device_time_zone: device_timezone
+                .as_ref()
+                .and_then(|device_timezone| device_timezone.time_zone.clone()),
+            device_utc_offset_secs: device_timezone
+                .as_ref()
+                .and_then(|device_timezone| device_timezone.utc_offset_secs),
+            largest_gap_start_device: to_device_local(largest_gap_start),
+            last_heartbeat_device: to_device_local(last_heartbeat),
+            window_start: window.as_ref().map(|window| window.start.into()),
+            window_end: window.map(|window| window.end.into()),
+        })
+    }
+}
+
+/// Reads a state file in whichever format it was written.
+async fn read_state_file<T: serde::de::DeserializeOwned>(
+    path: &str,
+) -> Result<Option<T>, DeviceError> {
+    if !try_exists(path)
+        .await
+        .map_err(|e| DeviceError::ReadFile(e, path.to_string()))?
+    {
+        return Ok(None);
+    }
+
+    let content = read(path)
+        .await
+        .map_err(|e| DeviceError::ReadFile(e, path.to_string()))?;
+
+    decode_state(&content)
+        .map(Some)
+        .map_err(|e| DeviceError::DecodeState(e, path.to_string()))
+}
+
+fn file_size(path: impl AsRef<Path>) -> Result<u64, DeviceError> {
+    match metadata(&path) {
+        Ok(meta) => Ok(meta.len()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(DeviceError::ReadFile(
+            e,
+            path.as_ref().to_string_lossy().to_string(),
+        )),
+    }
+}
+
+fn dir_size(path: impl AsRef<Path>) -> Result<u64, DeviceError> {
+    let path_string = path.as_ref().to_string_lossy().to_string();
+    let entries = match read_dir(&path) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(DeviceError::ReadFile(e, path_string)),
+    };
+
+    let mut size = 0;
+    for entry in entries {
+        let entry = entry.map_err(|e| DeviceError::ReadFile(e, path_string.clone()))?;
+        let file_type = entry
+            .file_type()
+            .map_err(|e| DeviceError::ReadFile(e, path_string.clone()))?;
+        if file_type.is_dir() {
+            size += dir_size(entry.path())?;
+        } else if file_type.is_file() {
+            size += file_size(entry.path())?;
+        }
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::activity_coverage::ActivityCoverage;
+    use crate::device::test_support::test_device;
+    use std::time::Duration;
+
+    fn t(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[tokio::test]
+    async fn coverage_fields_are_clamped_to_the_window() {
+        let (device, base_dir) = test_device("summary-window");
+        let mut coverage = ActivityCoverage::new();
+        coverage.add_range(t(0)..t(10));
+        coverage.add_range(t(20)..t(30));
+        coverage.add_range(t(100)..t(200));
+        coverage
+            .write_to_fs(
+                device.get_activity_coverage_file_path(),
+                device.state_format,
+            )
+            .await
+            .unwrap();
+
+        let summary = device.summarize(None).await.unwrap();
+        assert_eq!(summary.largest_gap_secs, Some(70));
+        assert_eq!(summary.window_start, None);
+
+        let summary = device.summarize(Some(t(5)..t(25))).await.unwrap();
+        assert_eq!(summary.coverage_ratio, Some(0.5));
+        assert_eq!(summary.largest_gap_start, Some(t(10).into()));
+        assert_eq!(summary.largest_gap_secs, Some(10));
+        assert_eq!(summary.window_start, Some(t(5).into()));
+        assert_eq!(summary.window_end, Some(t(25).into()));
+
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+}
@@ -0,0 +1,25 @@
+use super::Device;
+use idevice::pairing_file::PairingFile;
+use std::path::{Path, PathBuf};
+
+pub(crate) const TEST_UDID: &str = "00008030-TEST";
+
+/// Pairing file with self-signed certificates, never accepted by a real device.
+const PAIRING_FILE: &[u8] = include_bytes!("testdata/pairing.plist");
+
+/// Device whose dirs are created under a new temp dir, returned for the test to remove it.
+pub(crate) fn test_device(name: &str) -> (Device, PathBuf) {
+    let base_dir = std::env::temp_dir().join(format!("imonitor-{name}-{}", uuid::Uuid::new_v4()));
+    let device = test_device_in(&base_dir);
+    (device, base_dir)
+}
+
+/// Device over an existing base dir, e.g. to read what a previous run left there.
+pub(crate) fn test_device_in(base_dir: &Path) -> Device {
+    let pairing_file = PairingFile::from_bytes(PAIRING_FILE).unwrap();
+    let ip_addr = "127.0.0.1".parse().unwrap();
+    let base_dirs = [base_dir.to_string_lossy().to_string()];
+    let device = Device::new(TEST_UDID, &pairing_file, &ip_addr, "test", &base_dirs);
+    device.create_dirs().unwrap();
+    device
+}
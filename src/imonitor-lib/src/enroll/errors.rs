@@ -0,0 +1,38 @@
+use idevice::IdeviceError;
+
+#[derive(Debug)]
+pub enum EnrollError {
+    Usbmuxd(IdeviceError),
+    GetDevice(IdeviceError, String),
+    GetDevices(IdeviceError),
+    NoUsbDevice,
+    GetBuid(IdeviceError),
+    Connect(IdeviceError),
+    Pair(IdeviceError),
+    StartSession(IdeviceError),
+    EnableWifi(IdeviceError),
+    Timeout,
+}
+
+impl std::error::Error for EnrollError {}
+
+impl std::fmt::Display for EnrollError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            EnrollError::Usbmuxd(e) => write!(f, "Failed to connect to usbmuxd: {e}"),
+            EnrollError::GetDevice(e, udid) => {
+                write!(f, "Failed to get device {udid} from usbmuxd: {e}")
+            }
+            EnrollError::GetDevices(e) => write!(f, "Failed to get devices from usbmuxd: {e}"),
+            EnrollError::NoUsbDevice => write!(f, "No devices connected via USB"),
+            EnrollError::GetBuid(e) => write!(f, "Failed to get usbmuxd BUID: {e}"),
+            EnrollError::Connect(e) => write!(f, "Failed to connect to lockdown: {e}"),
+            EnrollError::Pair(e) => write!(f, "Failed to pair: {e}"),
+            EnrollError::StartSession(e) => write!(f, "Pairing file test failed: {e}"),
+            EnrollError::EnableWifi(e) => {
+                write!(f, "Failed to enable lockdownd wifi connection: {e}")
+            }
+            EnrollError::Timeout => write!(f, "Lockdown connection timeout"),
+        }
+    }
+}
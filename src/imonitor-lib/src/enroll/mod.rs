@@ -0,0 +1,117 @@
+pub mod errors;
+
+use errors::EnrollError;
+use idevice::{
+    IdeviceService,
+    lockdown::LockdownClient,
+    pairing_file::PairingFile,
+    provider::IdeviceProvider,
+    usbmuxd::{Connection, UsbmuxdAddr, UsbmuxdConnection, UsbmuxdDevice},
+};
+use tokio::time::{Duration, timeout};
+
+const WIRELESS_LOCKDOWN_DOMAIN: &str = "com.apple.mobile.wireless_lockdown";
+const CONNECT_TIMEOUT_SECS: u64 = 2;
+
+/// Pairs with the device reachable through `provider` and enables lockdownd wifi
+/// connections. The device must be reachable through usbmuxd to trust the host.
+pub async fn enroll_device(
+    provider: &dyn IdeviceProvider,
+    udid: &str,
+) -> Result<PairingFile, EnrollError> {
+    let buid = UsbmuxdConnection::default()
+        .await
+        .map_err(EnrollError::Usbmuxd)?
+        .get_buid()
+        .await
+        .map_err(EnrollError::GetBuid)?;
+
+    let mut lockdown_client = LockdownClient::connect(provider)
+        .await
+        .map_err(EnrollError::Connect)?;
+
+    let id = uuid::Uuid::new_v4().to_string().to_uppercase();
+
+    let mut pairing_file = lockdown_client
+        .pair(id, buid, None)
+        .await
+        .map_err(EnrollError::Pair)?;
+
+    // Test the pairing file
+    lockdown_client
+        .start_session(&pairing_file)
+        .await
+        .map_err(EnrollError::StartSession)?;
+
+    lockdown_client
+        .set_value(
+            "EnableWifiConnections",
+            true.into(),
+            Some(WIRELESS_LOCKDOWN_DOMAIN),
+        )
+        .await
+        .map_err(EnrollError::EnableWifi)?;
+
+    // Add the UDID
+    pairing_file.udid = Some(udid.to_string());
+
+    Ok(pairing_file)
+}
+
+/// Finds the device with the given UDID in usbmuxd, or the first one connected via USB.
+pub async fn find_usb_device(udid: Option<&str>) -> Result<UsbmuxdDevice, EnrollError> {
+    let mut usbmuxd = UsbmuxdConnection::default()
+        .await
+        .map_err(EnrollError::Usbmuxd)?;
+
+    match udid {
+        Some(udid) => usbmuxd
+            .get_device(udid)
+            .await
+            .map_err(|e| EnrollError::GetDevice(e, udid.to_string())),
+        None => usbmuxd
+            .get_devices()
+            .await
+            .map_err(EnrollError::GetDevices)?
+            .into_iter()
+            .find(|device| device.connection_type == Connection::Usb)
+            .ok_or(EnrollError::NoUsbDevice),
+    }
+}
+
+/// Lists the devices usbmuxd sees, connected via USB or over the network.
+pub async fn list_usbmuxd_devices() -> Result<Vec<UsbmuxdDevice>, EnrollError> {
+    UsbmuxdConnection::default()
+        .await
+        .map_err(EnrollError::Usbmuxd)?
+        .get_devices()
+        .await
+        .map_err(EnrollError::GetDevices)
+}
+
+/// Enrolls the device with the given UDID through usbmuxd.
+pub async fn enroll_usb_device(udid: &str, label: &str) -> Result<PairingFile, EnrollError> {
+    let device = find_usb_device(Some(udid)).await?;
+    let provider = device.to_provider(UsbmuxdAddr::default(), label);
+    enroll_device(&provider, udid).await
+}
+
+/// Checks that a lockdown session can be started with the pairing file.
+/// Connection failures are returned as errors, distinct from an invalid pairing file.
+pub async fn check_pairing(
+    provider: &dyn IdeviceProvider,
+    pairing_file: &PairingFile,
+) -> Result<(), EnrollError> {
+    let mut lockdown_client = timeout(
+        Duration::from_secs(CONNECT_TIMEOUT_SECS),
+        LockdownClient::connect(provider),
+    )
+    .await
+    .map_err(|_| EnrollError::Timeout)?
+    .map_err(EnrollError::Connect)?;
+
+    lockdown_client
+        .start_session(pairing_file)
+        .await
+        .map_err(EnrollError::StartSession)
+}
@@ -1,17 +1,50 @@
 #![warn(clippy::unwrap_used, clippy::expect_used)]
 
+/// Append-only log of monitoring events
+pub mod audit;
+
+/// Time source.
+pub mod clock;
+
 /// Configuration file parser.
 pub mod config;
 
+/// Prioritized limit of concurrent service connections
+pub mod connection;
+
 /// Device struct
 pub mod device;
 
+/// Device pairing and enrollment
+pub mod enroll;
+
+/// Heartbeat-driven file for external watchdogs
+pub mod liveness;
+
+/// Hook to observe monitoring events
+pub mod observer;
+
+/// Stores pulled crash files and log chunks are sent to
+pub mod object_store;
+
 /// Get idevice provider from Device
 pub mod provider;
 
+/// Device connections through a proxy
+pub mod proxy;
+
 /// Use idevice services
 pub mod services;
 
+/// Encoding of the on-disk state files
+pub mod state_store;
+
+/// Download bandwidth limiting.
+pub mod throttle;
+
+/// Helpers shared by the modules
+pub(crate) mod util;
+
 ///// File encryption from memory buffer to disk.
 //pub mod encrypt;
 
@@ -0,0 +1,43 @@
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::PathBuf;
+use tokio::fs::{rename, write};
+use tokio::sync::Mutex;
+
+/// File rewritten on every heartbeat of any device, see `liveness_file` in the config. Its
+/// mtime goes stale when no device has a working heartbeat, so that an external watchdog
+/// can restart the daemon. It holds the last heartbeat of each device, as JSON.
+#[derive(Debug)]
+pub struct LivenessFile {
+    path: PathBuf,
+    // Also serializes the writes, which go through the same temporary file
+    last_heartbeats: Mutex<BTreeMap<String, DateTime<Utc>>>,
+}
+
+impl LivenessFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            last_heartbeats: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Records the heartbeat of a device and rewrites the file.
+    pub async fn record_heartbeat(&self, udid: &str, at: DateTime<Utc>) -> io::Result<()> {
+        let mut last_heartbeats = self.last_heartbeats.lock().await;
+        last_heartbeats.insert(udid.to_string(), at);
+        let content = serde_json::to_string_pretty(&*last_heartbeats)?;
+
+        // Renamed once written, a watchdog never reads a partial file
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        write(&tmp_path, content).await?;
+        rename(&tmp_path, &self.path).await
+    }
+
+    /// Last heartbeat of each device seen since startup.
+    pub async fn last_heartbeats(&self) -> BTreeMap<String, DateTime<Utc>> {
+        self.last_heartbeats.lock().await.clone()
+    }
+}
@@ -0,0 +1,256 @@
+use crate::config::RemoteStoreConfig;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::fs::{create_dir_all, rename, write};
+
+#[cfg(feature = "s3")]
+mod s3;
+#[cfg(feature = "s3")]
+mod tls;
+
+#[cfg(feature = "s3")]
+pub use s3::{ACCESS_KEY_ENV, S3Backend, SECRET_KEY_ENV};
+
+/// Attributes stored along with an object, ignored by stores without them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ObjectAttributes {
+    pub content_type: Option<String>,
+    pub metadata: HashMap<String, String>,
+}
+
+/// Store pulled crash files and `imonitor-send` log chunks are sent to.
+pub trait Backend {
+    /// Stores `content` under `key`, a `/` separated relative path. An existing object is
+    /// replaced.
+    fn put(
+        &self,
+        key: &str,
+        content: &[u8],
+        attributes: &ObjectAttributes,
+    ) -> impl Future<Output = Result<(), BackendError>> + Send;
+
+    /// Stores `content` in parts of `part_size` bytes where the store supports it, with a
+    /// single [`Backend::put`] otherwise.
+    fn put_in_parts(
+        &self,
+        key: &str,
+        content: &[u8],
+        attributes: &ObjectAttributes,
+        part_size: usize,
+    ) -> impl Future<Output = Result<(), BackendError>> + Send {
+        let _ = part_size;
+        self.put(key, content, attributes)
+    }
+}
+
+/// Files under a local dir, the key being their path relative to it.
+#[derive(Debug, Clone)]
+pub struct LocalBackend {
+    root: PathBuf,
+}
+
+/// Backend the crash files of every device are sent to with `crashes.store = "remote"`.
+#[derive(Debug, Clone)]
+pub enum CrashBackend {
+    Local(LocalBackend),
+    #[cfg(feature = "s3")]
+    S3(S3Backend),
+}
+
+impl LocalBackend {
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+}
+
+impl Backend for LocalBackend {
+    async fn put(
+        &self,
+        key: &str,
+        content: &[u8],
+        _attributes: &ObjectAttributes,
+    ) -> Result<(), BackendError> {
+        let path = self.path(key);
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)
+                .await
+                .map_err(|e| BackendError::Io(e, parent.to_string_lossy().to_string()))?;
+        }
+
+        // Renamed once written, a reader never sees a partial file
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        write(&tmp_path, content)
+            .await
+            .map_err(|e| BackendError::Io(e, tmp_path.to_string_lossy().to_string()))?;
+        rename(&tmp_path, &path)
+            .await
+            .map_err(|e| BackendError::Io(e, path.to_string_lossy().to_string()))
+    }
+}
+
+impl CrashBackend {
+    /// Backend of a validated `[crashes.remote]` table. The S3 client is set up once and
+    /// shared by all devices.
+    pub async fn from_config(remote: &RemoteStoreConfig) -> Result<Self, BackendError> {
+        if let Some(dir) = &remote.dir {
+            return Ok(CrashBackend::Local(LocalBackend::new(dir)));
+        }
+        #[cfg(feature = "s3")]
+        if let Some(s3) = &remote.s3 {
+            return S3Backend::connect(s3).await.map(CrashBackend::S3);
+        }
+        Err(BackendError::Config(
+            "crashes.remote needs either a dir or a [crashes.remote.s3] table".to_string(),
+        ))
+    }
+}
+
+impl Backend for CrashBackend {
+    async fn put(
+        &self,
+        key: &str,
+        content: &[u8],
+        attributes: &ObjectAttributes,
+    ) -> Result<(), BackendError> {
+        match self {
+            CrashBackend::Local(backend) => backend.put(key, content, attributes).await,
+            #[cfg(feature = "s3")]
+            CrashBackend::S3(backend) => {
+                let key = format!("{}{key}", backend.prefix());
+                backend.put(&key, content, attributes).await
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BackendError {
+    Config(String),
+    Io(std::io::Error, String),
+    /// Rejected request, with the error code and the retry-after of the response if any
+    Upload {
+        key: String,
+        message: String,
+        code: Option<String>,
+        retry_after: Option<Duration>,
+    },
+    /// Any step of a multipart upload, with the error context already rendered
+    Multipart(String, String),
+}
+
+impl std::error::Error for BackendError {}
+
+impl std::fmt::Display for BackendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BackendError::Config(e) => write!(f, "Invalid store configuration: {e}"),
+            BackendError::Io(e, path) => write!(f, "I/O error on {path}: {e}"),
+            BackendError::Upload { key, message, .. } => {
+                write!(f, "Failed to upload {key}: {message}")
+            }
+            BackendError::Multipart(e, key) => {
+                write!(f, "Failed to upload {key} in parts: {e}")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!(
+            "imonitor-object-store-{name}-{}",
+            uuid::Uuid::new_v4()
+        ));
+        std::fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    #[tokio::test]
+    async fn local_backend_writes_nested_keys() {
+        let root = test_root("nested");
+        let backend = CrashBackend::Local(LocalBackend::new(&root));
+
+        backend
+            .put(
+                "udid/crashes/Retired/a.ips",
+                b"crash",
+                &ObjectAttributes::default(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            std::fs::read(root.join("udid/crashes/Retired/a.ips")).unwrap(),
+            b"crash"
+        );
+        assert!(!root.join("udid/crashes/Retired/a.ips.tmp").exists());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn local_backend_replaces_existing_key() {
+        let root = test_root("replace");
+        let backend = LocalBackend::new(&root);
+        let attributes = ObjectAttributes::default();
+
+        backend.put("a.ips", b"first", &attributes).await.unwrap();
+        backend.put("a.ips", b"second", &attributes).await.unwrap();
+
+        assert_eq!(std::fs::read(backend.path("a.ips")).unwrap(), b"second");
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn local_backend_stores_parts_as_one_file() {
+        let root = test_root("parts");
+        let backend = LocalBackend::new(&root);
+
+        backend
+            .put_in_parts("chunk.log", b"0123456789", &ObjectAttributes::default(), 4)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            std::fs::read(backend.path("chunk.log")).unwrap(),
+            b"0123456789"
+        );
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn crash_backend_from_a_dir() {
+        let root = test_root("config");
+        let remote = RemoteStoreConfig {
+            dir: Some(root.to_string_lossy().to_string()),
+            s3: None,
+        };
+
+        let backend = CrashBackend::from_config(&remote).await.unwrap();
+        assert!(matches!(backend, CrashBackend::Local(_)));
+        backend
+            .put("udid/crashes/a.ips", b"crash", &ObjectAttributes::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read(root.join("udid/crashes/a.ips")).unwrap(),
+            b"crash"
+        );
+
+        assert!(matches!(
+            CrashBackend::from_config(&RemoteStoreConfig::default()).await,
+            Err(BackendError::Config(_))
+        ));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
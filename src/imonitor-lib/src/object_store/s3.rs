@@ -0,0 +1,462 @@
+use super::{Backend, BackendError, ObjectAttributes, tls};
+use crate::config::S3Config;
+use aws_config::retry::RetryConfig;
+use aws_config::{BehaviorVersion, defaults};
+use aws_sdk_s3::Client;
+use aws_sdk_s3::config::{Credentials, Region};
+use aws_sdk_s3::error::{DisplayErrorContext, ProvideErrorMetadata, SdkError};
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+use aws_smithy_runtime_api::client::orchestrator::HttpResponse;
+use std::env;
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+/// Environment variables holding the object store credentials.
+pub const ACCESS_KEY_ENV: &str = "S3_ACCESS_KEY";
+pub const SECRET_KEY_ENV: &str = "S3_SECRET_KEY";
+
+// Attempts of each request by the SDK itself, before the caller retries
+const SDK_MAX_ATTEMPTS: u32 = 5;
+
+/// Objects of an S3 compatible bucket. Keys are full object keys: callers prepend
+/// [`S3Backend::prefix`] where needed.
+#[derive(Debug, Clone)]
+pub struct S3Backend {
+    client: Client,
+    bucket: String,
+    prefix: String,
+}
+
+impl S3Backend {
+    /// Client of the configured endpoint, with the credentials of [`ACCESS_KEY_ENV`] and
+    /// [`SECRET_KEY_ENV`]. The endpoint certificate is checked against the system ones and
+    /// `ca_bundle`, unless `insecure_skip_verify` is set.
+    pub async fn connect(s3: &S3Config) -> Result<Self, BackendError> {
+        let access_key = get_env(ACCESS_KEY_ENV)?;
+        let secret_key = get_env(SECRET_KEY_ENV)?;
+        Self::with_credentials(s3, access_key, secret_key).await
+    }
+
+    async fn with_credentials(
+        s3: &S3Config,
+        access_key: String,
+        secret_key: String,
+    ) -> Result<Self, BackendError> {
+        let mut loader = defaults(BehaviorVersion::latest())
+            .retry_config(RetryConfig::standard().with_max_attempts(SDK_MAX_ATTEMPTS))
+            .endpoint_url(&s3.endpoint)
+            .credentials_provider(Credentials::new(
+                access_key, secret_key, None, None, "static",
+            ));
+        if let Some(region) = &s3.region {
+            loader = loader.region(Region::new(region.clone()));
+        }
+        if let Some(http_client) = tls::build_http_client(s3)? {
+            loader = loader.http_client(http_client);
+        }
+
+        let config = aws_sdk_s3::config::Builder::from(&loader.load().await)
+            .force_path_style(s3.force_path_style)
+            .build();
+
+        Ok(Self {
+            client: Client::from_conf(config),
+            bucket: s3.bucket.clone(),
+            prefix: s3.prefix.clone(),
+        })
+    }
+
+    /// `prefix` of the config.
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// Aborts the incomplete multipart uploads under the prefix started more than
+    /// `stale_after` ago, such as the ones of a killed process. Returns how many were
+    /// aborted.
+    pub async fn abort_stale_uploads(&self, stale_after: Duration) -> Result<usize, BackendError> {
+        let cutoff = SystemTime::now() - stale_after;
+        let mut aborted = 0;
+        let mut key_marker = None;
+        let mut upload_id_marker = None;
+
+        loop {
+            let listed = self
+                .client
+                .list_multipart_uploads()
+                .bucket(&self.bucket)
+                .prefix(&self.prefix)
+                .set_key_marker(key_marker)
+                .set_upload_id_marker(upload_id_marker)
+                .send()
+                .await
+                .map_err(|e| multipart_error(e, &self.prefix))?;
+
+            for upload in listed.uploads() {
+                let (Some(key), Some(upload_id), Some(initiated)) =
+                    (upload.key(), upload.upload_id(), upload.initiated())
+                else {
+                    continue;
+                };
+                if !SystemTime::try_from(*initiated).is_ok_and(|initiated| initiated < cutoff) {
+                    continue;
+                }
+
+                match self
+                    .client
+                    .abort_multipart_upload()
+                    .bucket(&self.bucket)
+                    .key(key)
+                    .upload_id(upload_id)
+                    .send()
+                    .await
+                {
+                    Ok(_) => {
+                        info!("Aborted stale multipart upload {upload_id} of {key}");
+                        aborted += 1;
+                    }
+                    Err(e) => warn!(
+                        "Failed to abort stale multipart upload {upload_id} of {key}: {}",
+                        DisplayErrorContext(&e)
+                    ),
+                }
+            }
+
+            if !listed.is_truncated().unwrap_or(false) {
+                return Ok(aborted);
+            }
+            key_marker = listed.next_key_marker().map(str::to_string);
+            upload_id_marker = listed.next_upload_id_marker().map(str::to_string);
+        }
+    }
+
+    async fn upload_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+        content: &[u8],
+        part_size: usize,
+    ) -> Result<(), BackendError> {
+        let mut parts = Vec::new();
+        for (index, part) in content.chunks(part_size).enumerate() {
+            // Part numbers start at 1
+            let part_number = index as i32 + 1;
+            let uploaded = self
+                .client
+                .upload_part()
+                .bucket(&self.bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .part_number(part_number)
+                .body(ByteStream::from(part.to_vec()))
+                .send()
+                .await
+                .map_err(|e| multipart_error(e, key))?;
+
+            parts.push(
+                CompletedPart::builder()
+                    .set_e_tag(uploaded.e_tag().map(str::to_string))
+                    .part_number(part_number)
+                    .build(),
+            );
+        }
+
+        self.client
+            .complete_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
+            )
+            .send()
+            .await
+            .map_err(|e| multipart_error(e, key))?;
+
+        Ok(())
+    }
+}
+
+impl Backend for S3Backend {
+    async fn put(
+        &self,
+        key: &str,
+        content: &[u8],
+        attributes: &ObjectAttributes,
+    ) -> Result<(), BackendError> {
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .body(ByteStream::from(content.to_vec()))
+            .set_content_type(attributes.content_type.clone())
+            .set_metadata(Some(attributes.metadata.clone()).filter(|metadata| !metadata.is_empty()))
+            .send()
+            .await
+            .map_err(|e| upload_error(e, key))?;
+        Ok(())
+    }
+
+    /// Multipart upload, aborted on failure so that no incomplete upload is left behind.
+    async fn put_in_parts(
+        &self,
+        key: &str,
+        content: &[u8],
+        attributes: &ObjectAttributes,
+        part_size: usize,
+    ) -> Result<(), BackendError> {
+        let created = self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .set_content_type(attributes.content_type.clone())
+            .set_metadata(Some(attributes.metadata.clone()).filter(|metadata| !metadata.is_empty()))
+            .send()
+            .await
+            .map_err(|e| multipart_error(e, key))?;
+        let upload_id = created.upload_id().ok_or_else(|| {
+            BackendError::Multipart("no upload id returned".to_string(), key.to_string())
+        })?;
+
+        let res = self.upload_parts(key, upload_id, content, part_size).await;
+
+        if res.is_err() {
+            // Incomplete uploads are kept, and billed, until aborted
+            if let Err(e) = self
+                .client
+                .abort_multipart_upload()
+                .bucket(&self.bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .send()
+                .await
+            {
+                warn!(
+                    "Failed to abort multipart upload {upload_id} of {key}, left to the stale upload sweep: {}",
+                    DisplayErrorContext(&e)
+                );
+            }
+        }
+        res
+    }
+}
+
+fn get_env(name: &str) -> Result<String, BackendError> {
+    env::var(name).map_err(|e| BackendError::Config(format!("{name}: {e}")))
+}
+
+/// Keeps what the caller needs to pace its retries: the error code and the retry-after.
+fn upload_error<E>(error: SdkError<E, HttpResponse>, key: &str) -> BackendError
+where
+    E: ProvideErrorMetadata + std::error::Error + 'static,
+{
+    BackendError::Upload {
+        key: key.to_string(),
+        code: error.code().map(str::to_string),
+        retry_after: retry_after(&error),
+        message: DisplayErrorContext(&error).to_string(),
+    }
+}
+
+/// Retry-After header of the response, in seconds. HTTP dates are not supported.
+fn retry_after<E>(error: &SdkError<E, HttpResponse>) -> Option<Duration> {
+    error
+        .raw_response()?
+        .headers()
+        .get("retry-after")?
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+fn multipart_error<E>(error: SdkError<E, HttpResponse>, key: &str) -> BackendError
+where
+    E: std::error::Error + 'static,
+{
+    BackendError::Multipart(DisplayErrorContext(&error).to_string(), key.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    /// Request line and headers of each request received by a stub server.
+    type Requests = Arc<Mutex<Vec<String>>>;
+
+    fn response(status: &str, headers: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 {status}\r\n{headers}Content-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
+    /// Stub S3 server answering each request with `respond(method, target)`.
+    async fn stub_server(respond: fn(&str, &str) -> String) -> (String, Requests) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Requests::default();
+
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let recorded = recorded.clone();
+                tokio::spawn(async move {
+                    let mut reader = BufReader::new(stream);
+                    // Requests of a kept-alive connection
+                    loop {
+                        let mut head = String::new();
+                        let mut line = String::new();
+                        if reader.read_line(&mut line).await.unwrap_or(0) == 0 {
+                            return;
+                        }
+                        let request_line = line.trim().to_string();
+                        let (mut content_length, mut chunked) = (0, false);
+                        loop {
+                            line.clear();
+                            if reader.read_line(&mut line).await.unwrap_or(0) == 0 {
+                                return;
+                            }
+                            if line == "\r\n" {
+                                break;
+                            }
+                            let header = line.to_ascii_lowercase();
+                            if let Some(value) = header.strip_prefix("content-length:") {
+                                content_length = value.trim().parse().unwrap_or(0);
+                            }
+                            chunked |= header.starts_with("transfer-encoding:")
+                                && header.contains("chunked");
+                            head.push_str(&header);
+                        }
+                        if chunked {
+                            loop {
+                                line.clear();
+                                reader.read_line(&mut line).await.unwrap();
+                                let size = usize::from_str_radix(
+                                    line.trim().split(';').next().unwrap_or("0"),
+                                    16,
+                                )
+                                .unwrap();
+                                if size == 0 {
+                                    // Trailers, up to the empty line
+                                    while line != "\r\n" {
+                                        line.clear();
+                                        reader.read_line(&mut line).await.unwrap();
+                                    }
+                                    break;
+                                }
+                                let mut data = vec![0; size + 2];
+                                reader.read_exact(&mut data).await.unwrap();
+                            }
+                        } else {
+                            let mut body = vec![0; content_length];
+                            reader.read_exact(&mut body).await.unwrap();
+                        }
+
+                        let mut parts = request_line.split(' ');
+                        let method = parts.next().unwrap_or_default();
+                        let target = parts.next().unwrap_or_default();
+                        let reply = respond(method, target);
+                        recorded
+                            .lock()
+                            .unwrap()
+                            .push(format!("{request_line}\n{head}"));
+                        if reader.get_mut().write_all(reply.as_bytes()).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+
+        (endpoint, requests)
+    }
+
+    async fn stub_backend(endpoint: String) -> S3Backend {
+        let s3 = S3Config {
+            bucket: "bucket".to_string(),
+            prefix: "logs/".to_string(),
+            endpoint,
+            region: Some("us-east-1".to_string()),
+            force_path_style: true,
+            ca_bundle: None,
+            insecure_skip_verify: false,
+        };
+        S3Backend::with_credentials(&s3, "access".to_string(), "secret".to_string())
+            .await
+            .unwrap()
+    }
+
+    fn s3_error(code: &str) -> String {
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><Error><Code>{code}</Code><Message>{code}</Message></Error>"
+        )
+    }
+
+    #[tokio::test]
+    async fn failed_part_aborts_the_multipart_upload() {
+        let (endpoint, requests) = stub_server(|method, target| match method {
+            "POST" if target.contains("uploads") => response(
+                "200 OK",
+                "",
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?><InitiateMultipartUploadResult><Bucket>bucket</Bucket><Key>chunk.log</Key><UploadId>upload-1</UploadId></InitiateMultipartUploadResult>",
+            ),
+            "PUT" => response("403 Forbidden", "", &s3_error("AccessDenied")),
+            "DELETE" => response("204 No Content", "", ""),
+            _ => response("400 Bad Request", "", &s3_error("InvalidRequest")),
+        })
+        .await;
+        let backend = stub_backend(endpoint).await;
+
+        let error = backend
+            .put_in_parts("chunk.log", &[b'x'; 10], &ObjectAttributes::default(), 4)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(error, BackendError::Multipart(..)), "{error}");
+        let requests = requests.lock().unwrap().clone();
+        // Created, first part rejected, then aborted
+        assert_eq!(requests.len(), 3, "{requests:?}");
+        assert!(requests[1].starts_with("PUT /bucket/chunk.log?"));
+        assert!(requests[2].starts_with("DELETE /bucket/chunk.log?"));
+        assert!(requests[2].contains("uploadId=upload-1"));
+    }
+
+    #[tokio::test]
+    async fn rejected_put_keeps_the_error_code_and_retry_after() {
+        let (endpoint, _) = stub_server(|_, _| {
+            response(
+                "429 Too Many Requests",
+                "Retry-After: 7\r\n",
+                &s3_error("TooManyRequests"),
+            )
+        })
+        .await;
+        let backend = stub_backend(endpoint).await;
+
+        let error = backend
+            .put("chunk.log", b"chunk\n", &ObjectAttributes::default())
+            .await
+            .unwrap_err();
+
+        let BackendError::Upload {
+            key,
+            code,
+            retry_after,
+            ..
+        } = &error
+        else {
+            panic!("{error}");
+        };
+        assert_eq!(key, "chunk.log");
+        assert_eq!(code.as_deref(), Some("TooManyRequests"));
+        assert_eq!(*retry_after, Some(Duration::from_secs(7)));
+    }
+}
@@ -0,0 +1,175 @@
+use super::BackendError;
+use crate::config::S3Config;
+use aws_smithy_runtime::client::http::hyper_014::HyperClientBuilder;
+use aws_smithy_runtime_api::client::http::SharedHttpClient;
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConfig, RootCertStore, ServerName};
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tracing::{info, warn};
+
+/// HTTP client trusting the `s3.ca_bundle` certificates in addition to the system ones, or
+/// any certificate with `s3.insecure_skip_verify`. None keeps the SDK default client.
+pub(super) fn build_http_client(s3: &S3Config) -> Result<Option<SharedHttpClient>, BackendError> {
+    if s3.ca_bundle.is_none() && !s3.insecure_skip_verify {
+        return Ok(None);
+    }
+
+    let tls_config = if s3.insecure_skip_verify {
+        warn!(
+            "!!! TLS certificate verification of the S3 endpoint {} is DISABLED \
+             (s3.insecure_skip_verify). Uploads can be intercepted, never use this in production !!!",
+            s3.endpoint
+        );
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(NoCertificateVerification))
+            .with_no_client_auth()
+    } else {
+        let mut roots = RootCertStore::empty();
+        let native_certs = rustls_native_certs::load_native_certs()
+            .map_err(|e| BackendError::Io(e, "system certificates".to_string()))?;
+        // Like the SDK default client, unparsable system certificates are ignored
+        let native_certs = native_certs
+            .into_iter()
+            .map(|cert| cert.0)
+            .collect::<Vec<_>>();
+        roots.add_parsable_certificates(&native_certs);
+
+        if let Some(ca_bundle) = &s3.ca_bundle {
+            let added = load_ca_bundle(&mut roots, ca_bundle)?;
+            info!("Trusting {added} certificate(s) of {ca_bundle} for the S3 endpoint");
+        }
+
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth()
+    };
+
+    let connector = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_tls_config(tls_config)
+        .https_or_http()
+        .enable_http1()
+        .enable_http2()
+        .build();
+
+    Ok(Some(HyperClientBuilder::new().build(connector)))
+}
+
+/// Adds the certificates of a PEM file to the store. Returns how many were added.
+fn load_ca_bundle(roots: &mut RootCertStore, path: &str) -> Result<usize, BackendError> {
+    let file = File::open(path).map_err(|e| BackendError::Io(e, path.to_string()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .map_err(|e| BackendError::Io(e, path.to_string()))?;
+
+    let (added, _) = roots.add_parsable_certificates(&certs);
+    if added == 0 {
+        return Err(BackendError::Config(format!(
+            "s3.ca_bundle {path} contains no valid PEM certificate"
+        )));
+    }
+    Ok(added)
+}
+
+/// Accepts any server certificate, see `s3.insecure_skip_verify`.
+struct NoCertificateVerification;
+
+impl ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Self-signed certificate of a test MinIO
+    const CA_CERT: &str = r#"
+-----BEGIN CERTIFICATE-----
+MIIBgDCCASegAwIBAgIUYlk40+RGbIChwJNw5b/pgwvg1xswCgYIKoZIzj0EAwIw
+FTETMBEGA1UEAwwKbWluaW8udGVzdDAgFw0yNjEwMTYwMzE4MDFaGA8yMTI2MDky
+MjAzMTgwMVowFTETMBEGA1UEAwwKbWluaW8udGVzdDBZMBMGByqGSM49AgEGCCqG
+SM49AwEHA0IABC2SGxk3y+ZEj09HIoVCs2hbs/1bvBRTFf8yFIvegjN0vXtEG52L
+C9YeqmpZb6XOa22rkxa7WiO+2se4rX1kZdijUzBRMB0GA1UdDgQWBBRvACML47h3
+4yUX1hkbCkQEXYWW8DAfBgNVHSMEGDAWgBRvACML47h34yUX1hkbCkQEXYWW8DAP
+BgNVHRMBAf8EBTADAQH/MAoGCCqGSM49BAMCA0cAMEQCIBbNwVa68o+10yAY1Umb
+DSIiBuc1ta/IiqKvCyhVLARAAiBgcwyVu44l6kTGTkjHcp+RMq+5J3igQw5tymYe
+sfV+kg==
+-----END CERTIFICATE-----
+"#;
+
+    fn test_file(name: &str, content: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("imonitor-tls-{name}-{}.pem", uuid::Uuid::new_v4()));
+        std::fs::write(&path, content).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    fn s3_config(ca_bundle: Option<String>, insecure_skip_verify: bool) -> S3Config {
+        S3Config {
+            bucket: "bucket".to_string(),
+            prefix: String::new(),
+            endpoint: "https://minio.test:9000".to_string(),
+            region: None,
+            force_path_style: true,
+            ca_bundle,
+            insecure_skip_verify,
+        }
+    }
+
+    #[test]
+    fn ca_bundle_certificates_are_trusted() {
+        let path = test_file("bundle", CA_CERT);
+        let mut roots = RootCertStore::empty();
+
+        assert_eq!(load_ca_bundle(&mut roots, &path).unwrap(), 1);
+        assert_eq!(roots.len(), 1);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn ca_bundle_without_certificate_is_rejected() {
+        let path = test_file("empty", "not a certificate\n");
+
+        let error = load_ca_bundle(&mut RootCertStore::empty(), &path).unwrap_err();
+        assert!(matches!(error, BackendError::Config(_)), "{error}");
+        std::fs::remove_file(path).unwrap();
+
+        let error = load_ca_bundle(&mut RootCertStore::empty(), &path).unwrap_err();
+        assert!(matches!(error, BackendError::Io(..)), "{error}");
+    }
+
+    #[test]
+    fn default_client_is_kept_without_tls_settings() {
+        assert!(
+            build_http_client(&s3_config(None, false))
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn custom_client_is_built_with_a_ca_bundle_or_without_verification() {
+        let path = test_file("client", CA_CERT);
+        assert!(
+            build_http_client(&s3_config(Some(path.clone()), false))
+                .unwrap()
+                .is_some()
+        );
+        std::fs::remove_file(path).unwrap();
+
+        assert!(build_http_client(&s3_config(None, true)).unwrap().is_some());
+    }
+}
@@ -0,0 +1,72 @@
+use std::error::Error;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+
+/// Hook called by the services on key transitions. All methods default to no-op.
+///
+/// Methods are called from the service loops: implementations must not block.
+pub trait MonitorObserver: Send + Sync + std::fmt::Debug {
+    fn on_heartbeat(&self, _udid: &str, _connected: bool) {}
+
+    /// Marco/polo round trip, called on each heartbeat.
+    fn on_heartbeat_latency(&self, _udid: &str, _latency: Duration) {}
+
+    fn on_crash_pulled(&self, _udid: &str, _file: &str, _bytes: u64) {}
+
+    fn on_archive_created(&self, _udid: &str, _range: &Range<SystemTime>) {}
+
+    fn on_service_error(&self, _udid: &str, _service: &str, _error: &dyn Error) {}
+
+    fn on_pairing_invalid(&self, _udid: &str, _error: &dyn Error) {}
+}
+
+/// Observer doing nothing, used by default.
+#[derive(Debug, Default)]
+pub struct NoopObserver;
+
+impl MonitorObserver for NoopObserver {}
+
+/// Observer counting calls, useful in tests.
+#[derive(Debug, Default)]
+pub struct CountingObserver {
+    pub heartbeats_connected: AtomicUsize,
+    pub heartbeats_disconnected: AtomicUsize,
+    pub heartbeat_latencies: AtomicUsize,
+    pub crashes_pulled: AtomicUsize,
+    pub crash_bytes: AtomicU64,
+    pub archives_created: AtomicUsize,
+    pub service_errors: AtomicUsize,
+    pub pairings_invalid: AtomicUsize,
+}
+
+impl MonitorObserver for CountingObserver {
+    fn on_heartbeat(&self, _udid: &str, connected: bool) {
+        if connected {
+            self.heartbeats_connected.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.heartbeats_disconnected.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn on_heartbeat_latency(&self, _udid: &str, _latency: Duration) {
+        self.heartbeat_latencies.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_crash_pulled(&self, _udid: &str, _file: &str, bytes: u64) {
+        self.crashes_pulled.fetch_add(1, Ordering::Relaxed);
+        self.crash_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn on_archive_created(&self, _udid: &str, _range: &Range<SystemTime>) {
+        self.archives_created.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_service_error(&self, _udid: &str, _service: &str, _error: &dyn Error) {
+        self.service_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_pairing_invalid(&self, _udid: &str, _error: &dyn Error) {
+        self.pairings_invalid.fetch_add(1, Ordering::Relaxed);
+    }
+}
@@ -1,11 +1,85 @@
+use crate::connection::{ConnectionPermit, ConnectionPriority};
 use crate::device::Device;
+use crate::proxy::ProxyProvider;
 use idevice::provider::{IdeviceProvider, TcpProvider};
+use tokio::sync::SemaphorePermit;
+use tokio::time::{Duration, timeout};
+
+const LOCKDOWN_PORT: u16 = 62078;
 
 impl Device {
     pub fn get_provider(&self, label_suffix: &str) -> Box<dyn IdeviceProvider> {
         let mut provider: TcpProvider = self.into();
         provider.label.push('-');
         provider.label.push_str(label_suffix);
-        Box::new(provider)
+        match &self.proxy {
+            Some(proxy) => Box::new(ProxyProvider {
+                addr: provider.addr,
+                pairing_file: provider.pairing_file,
+                label: provider.label,
+                proxy: proxy.clone(),
+            }),
+            None => Box::new(provider),
+        }
+    }
+
+    /// Whether the lockdown port of the device accepts a connection within `probe_timeout`.
+    /// Goes through the proxy if one is set.
+    pub async fn probe_reachable(&self, probe_timeout: Duration) -> bool {
+        let provider = self.get_provider("probe");
+        matches!(
+            timeout(probe_timeout, provider.connect(LOCKDOWN_PORT)).await,
+            Ok(Ok(_))
+        )
+    }
+
+    /// Waits for a connection permit if a global connection limit is configured.
+    pub async fn acquire_connection_permit(
+        &self,
+        priority: ConnectionPriority,
+    ) -> Option<ConnectionPermit<'_>> {
+        match &self.connection_limiter {
+            Some(manager) => Some(manager.acquire(priority).await),
+            None => None,
+        }
+    }
+
+    /// Waits for a heartbeat connection permit if a heartbeat connection limit is configured.
+    pub async fn acquire_heartbeat_permit(&self) -> Option<SemaphorePermit<'_>> {
+        match &self.heartbeat_limiter {
+            Some(semaphore) => semaphore.acquire().await.ok(),
+            None => None,
+        }
+    }
+
+    /// Runs a service connection while holding a connection permit. The permit is released
+    /// as soon as the connection is established (or failed), not for the whole stream.
+    pub async fn limit_connect<F: Future>(
+        &self,
+        priority: ConnectionPriority,
+        connect: F,
+    ) -> F::Output {
+        let _permit = self.acquire_connection_permit(priority).await;
+        connect.await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::test_support::test_device;
+    use std::time::Instant;
+
+    #[tokio::test]
+    async fn unreachable_device_fails_the_probe_within_the_timeout() {
+        let (mut device, base_dir) = test_device("probe");
+        // TEST-NET-1, never routed
+        device.connection.ip_addr = "192.0.2.1".parse().unwrap();
+
+        let started = Instant::now();
+        assert!(!device.probe_reachable(Duration::from_millis(200)).await);
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        std::fs::remove_dir_all(base_dir).unwrap();
     }
 }
@@ -0,0 +1,352 @@
+use crate::config::{ProxyConfig, ProxyKind};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use idevice::pairing_file::PairingFile;
+use idevice::provider::IdeviceProvider;
+use idevice::{Idevice, IdeviceError};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+// Bounds the HTTP CONNECT response read byte by byte
+const MAX_HTTP_RESPONSE_LEN: usize = 8192;
+
+/// Same as `TcpProvider`, with every service connection tunneled through a proxy.
+#[derive(Debug)]
+pub struct ProxyProvider {
+    pub addr: IpAddr,
+    pub pairing_file: PairingFile,
+    pub label: String,
+    pub proxy: ProxyConfig,
+}
+
+impl IdeviceProvider for ProxyProvider {
+    fn connect(
+        &self,
+        port: u16,
+    ) -> Pin<Box<dyn Future<Output = Result<Idevice, IdeviceError>> + Send>> {
+        let target = SocketAddr::new(self.addr, port);
+        let proxy = self.proxy.clone();
+        let label = self.label.clone();
+        Box::pin(async move {
+            let stream = connect(&proxy, target).await?;
+            Ok(Idevice::new(Box::new(stream), label))
+        })
+    }
+
+    fn label(&self) -> &str {
+        &self.label
+    }
+
+    fn get_pairing_file(
+        &self,
+    ) -> Pin<Box<dyn Future<Output = Result<PairingFile, IdeviceError>> + Send>> {
+        let pairing_file = self.pairing_file.clone();
+        Box::pin(async move { Ok(pairing_file) })
+    }
+}
+
+/// Opens a TCP stream to `target` through the proxy. Once this returns, the stream carries
+/// the device traffic as is.
+pub async fn connect(proxy: &ProxyConfig, target: SocketAddr) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(&proxy.address).await?;
+    match proxy.kind {
+        ProxyKind::Socks5 => socks5_handshake(&mut stream, proxy, target).await?,
+        ProxyKind::Http => http_connect(&mut stream, proxy, target).await?,
+    }
+    Ok(stream)
+}
+
+// RFC 1928, with the RFC 1929 username/password authentication
+async fn socks5_handshake(
+    stream: &mut TcpStream,
+    proxy: &ProxyConfig,
+    target: SocketAddr,
+) -> io::Result<()> {
+    let credentials = proxy.username.as_deref().zip(proxy.password.as_deref());
+
+    match credentials {
+        Some(_) => stream.write_all(&[5, 2, 0x00, 0x02]).await?,
+        None => stream.write_all(&[5, 1, 0x00]).await?,
+    }
+
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).await?;
+    match (method, credentials) {
+        ([5, 0x00], _) => {}
+        ([5, 0x02], Some((username, password))) => {
+            let mut request = vec![1, username.len() as u8];
+            request.extend_from_slice(username.as_bytes());
+            request.push(password.len() as u8);
+            request.extend_from_slice(password.as_bytes());
+            stream.write_all(&request).await?;
+
+            let mut status = [0u8; 2];
+            stream.read_exact(&mut status).await?;
+            if status[1] != 0 {
+                return Err(io::Error::other("SOCKS5 proxy authentication failed"));
+            }
+        }
+        _ => {
+            return Err(io::Error::other(
+                "SOCKS5 proxy accepts none of the offered authentication methods",
+            ));
+        }
+    }
+
+    let mut request = vec![5, 1, 0];
+    match target.ip() {
+        IpAddr::V4(ip) => {
+            request.push(1);
+            request.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            request.push(4);
+            request.extend_from_slice(&ip.octets());
+        }
+    }
+    request.extend_from_slice(&target.port().to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(io::Error::other(format!(
+            "SOCKS5 proxy refused connection to {target} (reply {})",
+            reply[1]
+        )));
+    }
+
+    // Bound address, unused
+    let bound_addr_len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => stream.read_u8().await? as usize,
+        atyp => {
+            return Err(io::Error::other(format!(
+                "Unexpected SOCKS5 address type {atyp}"
+            )));
+        }
+    };
+    let mut bound_addr = vec![0u8; bound_addr_len + 2];
+    stream.read_exact(&mut bound_addr).await?;
+
+    Ok(())
+}
+
+async fn http_connect(
+    stream: &mut TcpStream,
+    proxy: &ProxyConfig,
+    target: SocketAddr,
+) -> io::Result<()> {
+    let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+    if let Some(username) = &proxy.username {
+        let password = proxy.password.as_deref().unwrap_or_default();
+        let credentials = STANDARD.encode(format!("{username}:{password}"));
+        request.push_str(&format!("Proxy-Authorization: Basic {credentials}\r\n"));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read byte by byte: anything after the headers belongs to the device
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_HTTP_RESPONSE_LEN {
+            return Err(io::Error::other("HTTP proxy response headers too long"));
+        }
+        response.push(stream.read_u8().await?);
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some("200") => Ok(()),
+        _ => Err(io::Error::other(format!(
+            "HTTP proxy refused connection to {target}: {status_line}"
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    const TARGET: &str = "10.0.0.2:62078";
+
+    /// Proxy accepting a single connection, handled by `serve`.
+    async fn stub_proxy<F, Fut>(
+        kind: ProxyKind,
+        credentials: Option<(&str, &str)>,
+        serve: F,
+    ) -> (ProxyConfig, JoinHandle<()>)
+    where
+        F: FnOnce(TcpStream) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = ProxyConfig {
+            kind,
+            address: listener.local_addr().unwrap().to_string(),
+            username: credentials.map(|(username, _)| username.to_string()),
+            password: credentials.map(|(_, password)| password.to_string()),
+        };
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            serve(stream).await;
+        });
+        (proxy, server)
+    }
+
+    async fn read_vec(stream: &mut TcpStream, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        stream.read_exact(&mut buf).await.unwrap();
+        buf
+    }
+
+    /// Reads the CONNECT request to 10.0.0.2:62078 and replies `reply`.
+    async fn serve_socks5_connect(stream: &mut TcpStream, reply: u8) {
+        assert_eq!(
+            read_vec(stream, 10).await,
+            [5, 1, 0, 1, 10, 0, 0, 2, 0xf2, 0x7e]
+        );
+        stream
+            .write_all(&[5, reply, 0, 1, 127, 0, 0, 1, 0x1f, 0x90])
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn socks5_without_authentication() {
+        let (proxy, server) = stub_proxy(ProxyKind::Socks5, None, |mut stream| async move {
+            assert_eq!(read_vec(&mut stream, 3).await, [5, 1, 0]);
+            stream.write_all(&[5, 0]).await.unwrap();
+            serve_socks5_connect(&mut stream, 0).await;
+            stream.write_all(b"device").await.unwrap();
+        })
+        .await;
+
+        let mut stream = connect(&proxy, TARGET.parse().unwrap()).await.unwrap();
+        // The stream carries the device traffic once connected
+        assert_eq!(read_vec(&mut stream, 6).await, b"device");
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn socks5_with_authentication() {
+        let (proxy, server) = stub_proxy(
+            ProxyKind::Socks5,
+            Some(("user", "secret")),
+            |mut stream| async move {
+                assert_eq!(read_vec(&mut stream, 4).await, [5, 2, 0, 2]);
+                stream.write_all(&[5, 2]).await.unwrap();
+                assert_eq!(read_vec(&mut stream, 13).await, b"\x01\x04user\x06secret");
+                stream.write_all(&[1, 0]).await.unwrap();
+                serve_socks5_connect(&mut stream, 0).await;
+            },
+        )
+        .await;
+
+        connect(&proxy, TARGET.parse().unwrap()).await.unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn socks5_errors() {
+        // Refused authentication
+        let (proxy, server) = stub_proxy(
+            ProxyKind::Socks5,
+            Some(("user", "wrong")),
+            |mut stream| async move {
+                read_vec(&mut stream, 4).await;
+                stream.write_all(&[5, 2]).await.unwrap();
+                read_vec(&mut stream, 12).await;
+                stream.write_all(&[1, 1]).await.unwrap();
+            },
+        )
+        .await;
+        let error = connect(&proxy, TARGET.parse().unwrap()).await.unwrap_err();
+        assert_eq!(error.to_string(), "SOCKS5 proxy authentication failed");
+        server.await.unwrap();
+
+        // Refused connection, e.g. by a ruleset
+        let (proxy, server) = stub_proxy(ProxyKind::Socks5, None, |mut stream| async move {
+            read_vec(&mut stream, 3).await;
+            stream.write_all(&[5, 0]).await.unwrap();
+            serve_socks5_connect(&mut stream, 2).await;
+        })
+        .await;
+        let error = connect(&proxy, TARGET.parse().unwrap()).await.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "SOCKS5 proxy refused connection to 10.0.0.2:62078 (reply 2)"
+        );
+        server.await.unwrap();
+
+        // No acceptable method
+        let (proxy, server) = stub_proxy(ProxyKind::Socks5, None, |mut stream| async move {
+            read_vec(&mut stream, 3).await;
+            stream.write_all(&[5, 0xff]).await.unwrap();
+        })
+        .await;
+        assert!(connect(&proxy, TARGET.parse().unwrap()).await.is_err());
+        server.await.unwrap();
+    }
+
+    /// Reads the CONNECT request headers, up to the blank line.
+    async fn read_http_request(stream: &mut TcpStream) -> String {
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            request.push(stream.read_u8().await.unwrap());
+        }
+        String::from_utf8(request).unwrap()
+    }
+
+    #[tokio::test]
+    async fn http_connect_with_authentication() {
+        let (proxy, server) = stub_proxy(
+            ProxyKind::Http,
+            Some(("user", "secret")),
+            |mut stream| async move {
+                let request = read_http_request(&mut stream).await;
+                assert_eq!(
+                    request,
+                    "CONNECT 10.0.0.2:62078 HTTP/1.1\r\nHost: 10.0.0.2:62078\r\n\
+                     Proxy-Authorization: Basic dXNlcjpzZWNyZXQ=\r\n\r\n"
+                );
+                // Device bytes right after the headers must not be consumed
+                stream
+                    .write_all(b"HTTP/1.1 200 Connection established\r\n\r\ndevice")
+                    .await
+                    .unwrap();
+            },
+        )
+        .await;
+
+        let mut stream = connect(&proxy, TARGET.parse().unwrap()).await.unwrap();
+        assert_eq!(read_vec(&mut stream, 6).await, b"device");
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn http_connect_refused() {
+        let (proxy, server) = stub_proxy(ProxyKind::Http, None, |mut stream| async move {
+            read_http_request(&mut stream).await;
+            stream
+                .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                .await
+                .unwrap();
+        })
+        .await;
+
+        let error = connect(&proxy, TARGET.parse().unwrap()).await.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "HTTP proxy refused connection to 10.0.0.2:62078: HTTP/1.1 407 Proxy Authentication Required"
+        );
+        server.await.unwrap();
+    }
+}
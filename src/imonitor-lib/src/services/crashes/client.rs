@@ -1,43 +1,90 @@
 use super::errors::CrashError;
+use super::sysdiagnose::is_sysdiagnose_path;
+use crate::config::{CrashPathMode, CrashesConfig, IdleConfig};
+use crate::connection::ConnectionPriority;
+use crate::device::CrashDirIdentity;
 use crate::device::Device;
+use crate::object_store::{Backend, ObjectAttributes};
+use crate::state_store::{StateStore, decode_state};
+use crate::util::fnv1a;
+use glob::Pattern;
 use idevice::{
-    IdeviceError, IdeviceService, afc::errors::AfcError,
+    IdeviceError, IdeviceService,
+    afc::{FileInfo, errors::AfcError},
     crashreportcopymobile::CrashReportCopyMobileClient,
 };
-use logger::{HasLogger, debug, error, info};
-use std::collections::HashSet;
+use logger::{HasLogger, debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::path::{Path, PathBuf};
-use tokio::fs::{File, create_dir_all, read_to_string, try_exists};
+use tokio::fs::{File, create_dir_all, read, try_exists};
 use tokio::io::{AsyncWriteExt, BufWriter};
 use tokio::sync::watch;
 use tokio::time::{Duration, sleep, timeout};
 
-const RETRY_CONNECT_WAIT_SECS: u64 = 15;
-const POLL_WAIT_SECS: u64 = 15;
 const KNOWN_CRASHES_FILE_NAME: &str = "known_crashes.json";
 const KNOWN_CRASH_DIRS_FILE_NAME: &str = "known_dirs.json";
+// Keeps flattened file names under common file system limits
+const MAX_FLATTENED_NAME_LEN: usize = 150;
+
+/// Size and modification time of a crash file on the device.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CrashFileMeta {
+    pub size: u64,
+    pub modified: i64,
+}
+
+/// Content of `known_crashes.json`. Older versions stored a plain list of paths.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum KnownCrashesFile {
+    Legacy(HashSet<String>),
+    Extended(HashMap<String, Option<CrashFileMeta>>),
+}
+
+impl KnownCrashesFile {
+    pub fn into_map(self) -> HashMap<String, Option<CrashFileMeta>> {
+        match self {
+            KnownCrashesFile::Legacy(files) => files.into_iter().map(|file| (file, None)).collect(),
+            KnownCrashesFile::Extended(files) => files,
+        }
+    }
+}
 
 impl Device {
     pub async fn get_crashes(
         &self,
         refresh_rate: Duration,
+        crashes_config: CrashesConfig,
+        idle_config: IdleConfig,
         hb_connected_rx: &mut watch::Receiver<bool>,
     ) -> Result<(), CrashError> {
         let mut _interval = refresh_rate.as_secs();
 
+        let exclude_patterns = crashes_config
+            .exclude_globs
+            .iter()
+            .map(|glob| Pattern::new(glob).map_err(|e| CrashError::Pattern(e, glob.clone())))
+            .collect::<Result<Vec<Pattern>, CrashError>>()?;
+
         // Get already known crashes
         self.get_known_crashes_from_fs().await?;
 
         loop {
+            self.wait_unpaused().await;
             let provider = self.get_provider("crashes");
 
             // Wait for heartbeat connected state
             if hb_connected_rx.wait_for(|val| *val).await.is_ok()
-                && let Ok(connection) = timeout(
-                    Duration::from_secs(2),
-                    CrashReportCopyMobileClient::connect(&*provider),
-                )
-                .await
+                && let Ok(connection) = self
+                    .limit_connect(
+                        ConnectionPriority::Normal,
+                        timeout(
+                            Duration::from_secs(2),
+                            CrashReportCopyMobileClient::connect(&*provider),
+                        ),
+                    )
+                    .await
             {
                 debug!(self, "Connecting to crash report service");
                 // Got response before timeout
@@ -45,7 +92,14 @@ impl Device {
                     Ok(mut client) => {
                         info!(self, "Crash service connected");
                         loop {
-                            if let Err(e) = self.write_crashes(&mut client).await {
+                            if self.is_paused() {
+                                info!(self, "Collection paused, disconnecting");
+                                break;
+                            }
+                            if let Err(e) = self
+                                .write_crashes(&mut client, &crashes_config, &exclude_patterns)
+                                .await
+                            {
                                 match e {
                                     CrashError::Connect(err) => {
                                         error!(
@@ -63,23 +117,31 @@ impl Device {
                                     }
                                     err => {
                                         error!(self, "Failed to write crashes: {err}");
+                                        self.observer.on_service_error(
+                                            &self.info.udid,
+                                            "crashes",
+                                            &err,
+                                        );
                                         break;
                                     }
                                 }
                             } else {
-                                sleep(Duration::from_secs(POLL_WAIT_SECS)).await;
+                                self.idle_aware_sleep(crashes_config.poll_interval, &idle_config)
+                                    .await;
                             }
                         }
                     }
                     Err(e) => {
                         error!(self, "Failed to connect to crashes service : {e}");
-                        sleep(Duration::from_secs(RETRY_CONNECT_WAIT_SECS)).await;
+                        self.observer
+                            .on_service_error(&self.info.udid, "crashes", &e);
+                        sleep(crashes_config.retry_wait).await;
                         continue;
                     }
                 }
             } else {
                 debug!(self, "Service connection timeout");
-                sleep(Duration::from_secs(RETRY_CONNECT_WAIT_SECS)).await;
+                sleep(crashes_config.retry_wait).await;
                 continue;
             }
         }
@@ -93,12 +155,13 @@ impl Device {
             .await
             .map_err(|e| CrashError::FileExists(e, crashes_file_path.clone()))?
         {
-            let content = read_to_string(crashes_file_path.clone())
+            let content = read(crashes_file_path.clone())
                 .await
                 .map_err(|e| CrashError::ReadFile(e, crashes_file_path.clone()))?;
 
-            let known_crashes: HashSet<String> =
-                serde_json::from_str(&content).map_err(CrashError::DeserializeKnownCrashes)?;
+            let known_crashes = decode_state::<KnownCrashesFile>(&content)
+                .map_err(CrashError::DecodeKnownCrashes)?
+                .into_map();
 
             {
                 let mut crash_files = self
@@ -107,7 +170,20 @@ impl Device {
                     .write()
                     .map_err(|_| CrashError::WriteLock)?;
 
-                *crash_files = known_crashes.clone();
+                *crash_files = known_crashes.keys().cloned().collect();
+            }
+
+            {
+                let mut crash_files_meta = self
+                    .crashes
+                    .crash_files_meta
+                    .write()
+                    .map_err(|_| CrashError::WriteLock)?;
+
+                *crash_files_meta = known_crashes
+                    .into_iter()
+                    .filter_map(|(file, meta)| meta.map(|meta| (file, meta)))
+                    .collect();
             }
         }
 
@@ -115,12 +191,12 @@ impl Device {
             .await
             .map_err(|e| CrashError::FileExists(e, crash_dirs_file_path.clone()))?
         {
-            let content = read_to_string(crash_dirs_file_path.clone())
+            let content = read(crash_dirs_file_path.clone())
                 .await
                 .map_err(|e| CrashError::ReadFile(e, crash_dirs_file_path.clone()))?;
 
             let known_crash_dirs: HashSet<String> =
-                serde_json::from_str(&content).map_err(CrashError::DeserializeKnownCrashes)?;
+                decode_state(&content).map_err(CrashError::DecodeKnownCrashes)?;
 
             {
                 let mut crash_dirs = self
@@ -133,19 +209,71 @@ impl Device {
             }
         }
 
-        Ok(())
+        self.load_permanently_failed().await?;
+        self.load_dead_letter().await
+    }
+
+    /// Lists a known crash dir, as full paths. A dir timing out is kept but skipped next
+    /// cycle, a dir failing to list is forgotten.
+    async fn list_crash_dir(
+        &self,
+        dir: &str,
+        list_timeout: Duration,
+        listing: impl Future<Output = Result<Vec<String>, IdeviceError>>,
+    ) -> Result<Vec<String>, CrashError> {
+        let listing = match timeout(list_timeout, listing).await {
+            Ok(listing) => listing,
+            Err(_) => {
+                // Slow but not gone: kept in known dirs. The pending AFC request leaves the
+                // connection unusable, hence the reconnection
+                warn!(self, "Listing dir {dir} timed out, skipping it next cycle");
+                self.crashes
+                    .slow_dirs
+                    .write()
+                    .map_err(|_| CrashError::WriteLock)?
+                    .insert(dir.to_string());
+                return Err(CrashError::Timeout);
+            }
+        };
+
+        let listing = listing.map_err(|e| {
+            // Remove dir from known dirs if listing fails
+            // Ensures deleted dirs do not fail in loop
+            if let Ok(mut crash_dirs) = self.crashes.crash_dirs.write() {
+                crash_dirs.remove(dir);
+            }
+            CrashError::ListFiles(e, dir.to_string())
+        })?;
+
+        Ok(listing
+            .iter()
+            .filter_map(|file| {
+                if file == "." || file == ".." || file.starts_with("IN_PROGRESS_sysdiagnose_") {
+                    None
+                } else {
+                    Some(Path::new(dir).join(file).to_string_lossy().to_string())
+                }
+            })
+            .collect())
     }
 
     pub async fn write_crashes(
         &self,
         client: &mut CrashReportCopyMobileClient,
+        crashes_config: &CrashesConfig,
+        exclude_patterns: &[Pattern],
     ) -> Result<(), CrashError> {
-        // List all files
-        // TODO : add timeout
+        // Queued by `imonitor force-pull`
+        if let Err(e) = self.apply_force_pull_requests().await {
+            warn!(self, "Failed to apply forced pulls: {e}");
+        }
+
+        // List all files. A pending AFC request leaves the connection unusable, a timeout
+        // reconnects
         let mut files = HashSet::<String>::from_iter(
-            client
-                .ls(None)
+            timeout(crashes_config.list_timeout, client.ls(None))
                 .await
+                .map_err(|_| CrashError::Timeout)?
                 .map_err(|e| CrashError::ListFiles(e, "".to_string()))?,
         );
 
@@ -162,47 +290,75 @@ impl Device {
                     .clone();
             }
 
+            // Dirs that timed out last cycle are skipped once, so they cannot stall the
+            // collection of the other dirs
+            let skipped_dirs = std::mem::take(
+                &mut *self
+                    .crashes
+                    .slow_dirs
+                    .write()
+                    .map_err(|_| CrashError::WriteLock)?,
+            );
+
             // List files in all dirs
             for dir in crash_dirs {
-                files.extend(HashSet::<String>::from_iter(
-                    //TODO : add timeout
-                    client
-                        .ls(Some(&dir))
-                        .await
-                        .map_err(|e| {
-                            // Remove dir from known dirs if listing fails
-                            // Ensures deleted dirs do not fail in loop
-                            let mut crash_dirs = self
-                                .crashes
-                                .crash_dirs
-                                .write()
-                                .map_err(|_| CrashError::WriteLock);
-
-                            match crash_dirs {
-                                Ok(ref mut set) => {
-                                    set.remove(&dir);
-                                    CrashError::ListFiles(e, dir.to_string())
-                                }
-                                Err(_) => CrashError::ListFiles(e, dir.to_string()),
-                            }
-                        })?
-                        .iter()
-                        .filter_map(|file| {
-                            if file == "."
-                                || file == ".."
-                                || file.starts_with("IN_PROGRESS_sysdiagnose_")
-                            {
-                                None
-                            } else {
-                                let dir_path = Path::new(&dir);
-                                Some(dir_path.join(file).to_string_lossy().to_string())
-                            }
-                        }),
-                ))
+                // Known dirs may predate the depth limit
+                if crash_dir_depth(&dir) > crashes_config.max_dir_depth {
+                    warn!(self, "Dir {dir} exceeds max depth, forgetting it");
+                    self.crashes
+                        .crash_dirs
+                        .write()
+                        .map_err(|_| CrashError::WriteLock)?
+                        .remove(&dir);
+                    continue;
+                }
+
+                // Known dirs may predate its exclusion
+                if is_sysdiagnose_path(&dir) {
+                    self.crashes
+                        .crash_dirs
+                        .write()
+                        .map_err(|_| CrashError::WriteLock)?
+                        .remove(&dir);
+                    continue;
+                }
+
+                if skipped_dirs.contains(&dir) {
+                    debug!(self, "Skipping slow dir {dir} for this cycle");
+                    continue;
+                }
+
+                files.extend(
+                    self.list_crash_dir(&dir, crashes_config.list_timeout, client.ls(Some(&dir)))
+                        .await?,
+                );
             }
         }
+        // Nor discovered as a dir
+        files.retain(|file| !is_sysdiagnose_path(file));
+
+        // Failures of files gone from the device are forgotten
+        let mut failures_changed = self.forget_permanent_failures(|file| files.contains(file))?;
+        let mut dead_letter_changed = self.forget_dead_letters(|file| files.contains(file))?;
+        let failed_skipped =
+            self.permanently_failed_skipped(crashes_config.permanent_failure_retry)?;
+        if !failed_skipped.is_empty() {
+            debug!(
+                self,
+                "Skipping {} permanently failed file(s) until their retry",
+                failed_skipped.len()
+            );
+        }
+        let dead_letter_skipped = self.dead_letter_skipped(crashes_config.dead_letter_retry)?;
+        if !dead_letter_skipped.is_empty() {
+            debug!(
+                self,
+                "Skipping {} dead-lettered file(s) until their retry",
+                dead_letter_skipped.len()
+            );
+        }
 
-        let files_to_get;
+        let mut files_to_get;
         {
             let crash_files = self
                 .crashes
@@ -216,37 +372,128 @@ impl Device {
                 .read()
                 .map_err(|_| CrashError::ReadLock)?;
 
+            let ignored_paths = self
+                .crashes
+                .ignored_paths
+                .read()
+                .map_err(|_| CrashError::ReadLock)?;
+
             files_to_get = files
                 .difference(&crash_files)
                 .cloned()
                 .collect::<HashSet<String>>()
                 .difference(&crash_dirs)
+                .filter(|file| !ignored_paths.contains(*file))
+                .filter(|file| !failed_skipped.contains(*file))
+                .filter(|file| !dead_letter_skipped.contains(*file))
+                .filter(|file| !is_excluded(file, exclude_patterns))
                 .cloned()
                 .collect::<HashSet<String>>();
 
             debug!(self, "Remaining files to get: {}", files_to_get.len());
         }
 
+        if crashes_config.track_changes {
+            let known_files = self
+                .crashes
+                .crash_files
+                .read()
+                .map_err(|_| CrashError::ReadLock)?
+                .intersection(&files)
+                .filter(|file| !is_excluded(file, exclude_patterns))
+                .cloned()
+                .collect::<Vec<String>>();
+
+            // One file info request per known file, hence opt-in
+            for file in known_files {
+                let meta = match get_crash_file_meta(client, &file).await {
+                    Ok(meta) => meta,
+                    Err(e) => {
+                        debug!(self, "Failed to get file info for {file}: {e}");
+                        continue;
+                    }
+                };
+
+                let mut crash_files_meta = self
+                    .crashes
+                    .crash_files_meta
+                    .write()
+                    .map_err(|_| CrashError::WriteLock)?;
+
+                match crash_files_meta.get(&file).map(|known| *known != meta) {
+                    Some(true) => {
+                        info!(self, "Crash file changed on device: {file}");
+                        files_to_get.insert(file);
+                    }
+                    Some(false) => {}
+                    // Known before changes were tracked, assume it did not change
+                    None => {
+                        crash_files_meta.insert(file, meta);
+                    }
+                }
+            }
+        }
+
         let mut files_give_up = HashSet::<String>::new();
 
         // Files to download
         for file in files_to_get {
             info!(self, "File : {file:?}");
-            let root_dir = PathBuf::from(self.get_crash_files_dir());
-            let dst_file_path = root_dir.join(&file);
+            // Object key when uploaded, it is what the crash index records
+            let dst_file_path = match &self.crash_backend {
+                Some(_) => PathBuf::from(crash_store_key(
+                    &self.info.udid,
+                    &file,
+                    crashes_config.path_mode,
+                )),
+                None => PathBuf::from(self.get_crash_files_dir())
+                    .join(local_crash_path(&file, crashes_config.path_mode)),
+            };
+
+            if let Some(max_file_bytes) = crashes_config.max_file_bytes
+                && let Ok(file_info) = client.afc_client.get_file_info(file.clone()).await
+                && file_info.st_ifmt == "S_IFREG"
+                && self.ignore_oversized_crash(&file, file_info.size as u64, max_file_bytes)?
+            {
+                files_give_up.insert(file.clone());
+                continue;
+            }
 
             // Try to pull file from device
-            // TODO : add timeout
-            let content = match client.pull(file.clone()).await {
-                Ok(content) => content,
+            let pulled = match timeout(crashes_config.pull_timeout, client.pull(file.clone())).await
+            {
+                Ok(pulled) => pulled,
+                Err(_) => {
+                    // Retried after the permanent failure backoff, so that a file too
+                    // large for the link does not stall every cycle
+                    warn!(
+                        self,
+                        "Pulling {file} timed out, skipping it until its retry"
+                    );
+                    self.record_permanent_failure(&file, "pull timed out".to_string())?;
+                    self.update_permanently_failed().await?;
+                    return Err(CrashError::Timeout);
+                }
+            };
+            let content = match pulled {
+                Ok(content) => {
+                    if let Some(limiter) = &self.download_limiter {
+                        limiter.consume(content.len() as u64).await;
+                    }
+                    content
+                }
                 Err(e) => {
                     // Check if path is a dir
                     match client.afc_client.get_file_info(file.clone()).await {
                         Ok(file_info) => {
                             if file_info.st_ifmt == "S_IFDIR" {
                                 debug!(self, "Directory found : {file}");
-                                // Update known dirs
-                                {
+                                if self.accept_crash_dir(
+                                    &file,
+                                    crash_dir_identity(&file_info),
+                                    crashes_config.max_dir_depth,
+                                )? {
+                                    // Update known dirs
                                     let mut crash_dirs_mut = self
                                         .crashes
                                         .crash_dirs
@@ -254,12 +501,28 @@ impl Device {
                                         .map_err(|_| CrashError::WriteLock)?;
 
                                     crash_dirs_mut.insert(file.clone());
+                                } else {
+                                    self.crashes
+                                        .ignored_paths
+                                        .write()
+                                        .map_err(|_| CrashError::WriteLock)?
+                                        .insert(file.clone());
                                 }
+                            } else if file_info.st_ifmt == "S_IFLNK" {
+                                // Links may point to a parent dir, never followed
+                                debug!(self, "Skipping symlink : {file}");
+                                self.crashes
+                                    .ignored_paths
+                                    .write()
+                                    .map_err(|_| CrashError::WriteLock)?
+                                    .insert(file.clone());
                             } else {
                                 match e {
                                     IdeviceError::Afc(AfcError::ObjectNotFound)
                                     | IdeviceError::Afc(AfcError::PermDenied) => {
                                         files_give_up.insert(file.clone());
+                                        self.record_permanent_failure(&file, e.to_string())?;
+                                        failures_changed = true;
                                     }
                                     _ => {}
                                 }
@@ -272,9 +535,56 @@ impl Device {
                 }
             };
 
-            // Write file to filesystem
-            match write_file(&content, &dst_file_path).await {
-                Ok(_) => {
+            // Send the file to the remote store, or write it to filesystem
+            let stored = if let Some(backend) = &self.crash_backend {
+                backend
+                    .put(
+                        &dst_file_path.to_string_lossy(),
+                        &content,
+                        &ObjectAttributes::default(),
+                    )
+                    .await
+                    .map(|_| None)
+                    .map_err(CrashError::Store)
+            } else if crashes_config.dedup {
+                self.write_dedup_crash_file(&content, &dst_file_path)
+                    .await
+                    .map(Some)
+            } else {
+                write_file(&content, &dst_file_path).await.map(|_| None)
+            };
+            match stored {
+                Ok(blob) => {
+                    if let Some(blob) = blob.as_ref().filter(|blob| blob.deduplicated) {
+                        debug!(self, "{file} is identical to blob {}", blob.content_hash);
+                    }
+                    self.observer
+                        .on_crash_pulled(&self.info.udid, &file, content.len() as u64);
+                    self.record_activity();
+
+                    // The known crashes, not the index, decide what is pulled again
+                    if let Err(e) = self
+                        .index_crash(&file, &dst_file_path, &content, blob.as_ref())
+                        .await
+                    {
+                        warn!(self, "Failed to index crash file {file}: {e}");
+                    }
+
+                    if crashes_config.track_changes {
+                        match get_crash_file_meta(client, &file).await {
+                            Ok(meta) => {
+                                let mut crash_files_meta = self
+                                    .crashes
+                                    .crash_files_meta
+                                    .write()
+                                    .map_err(|_| CrashError::WriteLock)?;
+
+                                crash_files_meta.insert(file.clone(), meta);
+                            }
+                            Err(e) => debug!(self, "Failed to get file info for {file}: {e}"),
+                        }
+                    }
+
                     let mut crash_files = self
                         .crashes
                         .crash_files
@@ -282,9 +592,30 @@ impl Device {
                         .map_err(|_| CrashError::WriteLock)?;
 
                     crash_files.insert(file.clone());
+                    drop(crash_files);
+
+                    if self.forget_permanent_failures(|failed| failed != file)? {
+                        info!(self, "{file} pulled after failing permanently");
+                        failures_changed = true;
+                    }
+                    if self.forget_dead_letters(|dead| dead != file)? {
+                        info!(self, "{file} written after being dead-lettered");
+                        dead_letter_changed = true;
+                    }
                 }
                 Err(e) => {
                     error!(self, "Failed to write file {file}: {e}");
+                    if self.record_write_failure(
+                        &file,
+                        e.to_string(),
+                        crashes_config.dead_letter_after,
+                    )? {
+                        warn!(
+                            self,
+                            "{file} dead-lettered, retried after crashes.dead_letter_retry"
+                        );
+                        dead_letter_changed = true;
+                    }
                     continue;
                 }
             }
@@ -297,23 +628,93 @@ impl Device {
             .await?;
         }
 
+        if failures_changed {
+            self.update_permanently_failed().await?;
+        }
+        // The disk failing the crash files may fail this one too, retried next cycle
+        if dead_letter_changed && let Err(e) = self.update_dead_letter().await {
+            warn!(self, "Failed to update dead-lettered crash files: {e}");
+        }
+
         Ok(())
     }
 
+    /// Checks a newly discovered dir against the depth limit and the identity of its
+    /// parent dirs. Rejected dirs are logged.
+    fn accept_crash_dir(
+        &self,
+        dir: &str,
+        identity: CrashDirIdentity,
+        max_depth: usize,
+    ) -> Result<bool, CrashError> {
+        if crash_dir_depth(dir) > max_depth {
+            warn!(
+                self,
+                "Dir {dir} exceeds max depth of {max_depth}, skipping it"
+            );
+            return Ok(false);
+        }
+
+        let mut crash_dir_ids = self
+            .crashes
+            .crash_dir_ids
+            .write()
+            .map_err(|_| CrashError::WriteLock)?;
+
+        let parent_loop = Path::new(dir)
+            .ancestors()
+            .skip(1)
+            .filter_map(|parent| crash_dir_ids.get(&*parent.to_string_lossy()))
+            .any(|parent_identity| *parent_identity == identity);
+
+        if parent_loop {
+            warn!(
+                self,
+                "Dir {dir} loops back to one of its parents, skipping it"
+            );
+            return Ok(false);
+        }
+
+        crash_dir_ids.insert(dir.to_string(), identity);
+        Ok(true)
+    }
+
     pub fn get_known_crashes_file_path(&self) -> String {
-        let crashes_dir = PathBuf::from(self.get_crashes_dir());
-        let known_crashes_file_path = crashes_dir.join(KNOWN_CRASHES_FILE_NAME);
-        known_crashes_file_path.to_string_lossy().to_string()
+        self.get_state_file_path(self.get_crashes_dir(), KNOWN_CRASHES_FILE_NAME)
     }
 
     pub fn get_known_crash_dirs_file_path(&self) -> String {
-        let crashes_dir = PathBuf::from(self.get_crashes_dir());
-        let known_crash_dirs_file_path = crashes_dir.join(KNOWN_CRASH_DIRS_FILE_NAME);
-        known_crash_dirs_file_path.to_string_lossy().to_string()
+        self.get_state_file_path(self.get_crashes_dir(), KNOWN_CRASH_DIRS_FILE_NAME)
+    }
+
+    /// Ignores a crash file larger than `max_file_bytes`, so that it is never pulled into
+    /// memory. Not checked again until restart. Returns whether it was ignored.
+    fn ignore_oversized_crash(
+        &self,
+        file: &str,
+        size: u64,
+        max_file_bytes: u64,
+    ) -> Result<bool, CrashError> {
+        if size <= max_file_bytes {
+            return Ok(false);
+        }
+
+        warn!(
+            self,
+            "Skipping {file}: {size} bytes, more than crashes.max_file_bytes ({max_file_bytes})"
+        );
+        self.crashes
+            .ignored_paths
+            .write()
+            .map_err(|_| CrashError::WriteLock)?
+            .insert(file.to_string());
+        Ok(true)
     }
 
+    /// Writes the known crashes and dirs. Both are sorted so that the files only change
+    /// when their content does.
     pub async fn update_known_crashes(&self, _files: &HashSet<String>) -> Result<(), CrashError> {
-        let crash_dirs: HashSet<String>;
+        let crash_dirs: BTreeSet<String>;
         {
             let crash_dirs_orig = self
                 .crashes
@@ -321,7 +722,7 @@ impl Device {
                 .read()
                 .map_err(|_| CrashError::ReadLock)?;
 
-            crash_dirs = crash_dirs_orig.clone();
+            crash_dirs = crash_dirs_orig.iter().cloned().collect();
         }
 
         let crash_files;
@@ -333,6 +734,23 @@ impl Device {
                 .map_err(|_| CrashError::ReadLock)?
                 .clone();
         }
+
+        let known_crashes: BTreeMap<String, Option<CrashFileMeta>>;
+        {
+            let crash_files_meta = self
+                .crashes
+                .crash_files_meta
+                .read()
+                .map_err(|_| CrashError::ReadLock)?;
+
+            known_crashes = crash_files
+                .into_iter()
+                .map(|file| {
+                    let meta = crash_files_meta.get(&file).cloned();
+                    (file, meta)
+                })
+                .collect();
+        }
         /*
          * TODO : fix the following code
          * We cannot clean the files as we are not sure we visited all the files we already knew
@@ -361,13 +779,16 @@ impl Device {
 
         //let crash_files_content = serde_json::to_string_pretty(&crash_files_cleaned)
         let crash_files_info = (
-            serde_json::to_string_pretty(&crash_files)
-                .map_err(CrashError::SerializeKnownCrashes)?,
+            self.state_format
+                .encode(&known_crashes)
+                .map_err(CrashError::EncodeKnownCrashes)?,
             known_crashes_file_path,
         );
 
         let crash_dirs_info = (
-            serde_json::to_string_pretty(&crash_dirs).map_err(CrashError::SerializeKnownCrashes)?,
+            self.state_format
+                .encode(&crash_dirs)
+                .map_err(CrashError::EncodeKnownCrashes)?,
             known_crash_dirs_file_path,
         );
 
@@ -379,7 +800,7 @@ impl Device {
             let mut writer = BufWriter::new(file_h);
 
             writer
-                .write_all(content.as_bytes())
+                .write_all(&content)
                 .await
                 .map_err(|e| CrashError::WriteToFile(e, output_file_path.clone()))?;
 
@@ -392,7 +813,81 @@ impl Device {
     }
 }
 
-async fn write_file(content: &[u8], dst_file_path: &PathBuf) -> Result<(), CrashError> {
+async fn get_crash_file_meta(
+    client: &mut CrashReportCopyMobileClient,
+    file: &str,
+) -> Result<CrashFileMeta, IdeviceError> {
+    let file_info = client.afc_client.get_file_info(file.to_string()).await?;
+    Ok(CrashFileMeta {
+        size: file_info.size as u64,
+        modified: file_info.modified.and_utc().timestamp(),
+    })
+}
+
+fn crash_dir_identity(file_info: &FileInfo) -> CrashDirIdentity {
+    CrashDirIdentity {
+        creation: file_info.creation.and_utc().timestamp(),
+        modified: file_info.modified.and_utc().timestamp(),
+        nlink: file_info.st_nlink.clone(),
+    }
+}
+
+fn crash_dir_depth(dir: &str) -> usize {
+    Path::new(dir).components().count()
+}
+
+/// Local path of a device crash file, relative to the crash files dir.
+pub fn local_crash_path(file: &str, path_mode: CrashPathMode) -> PathBuf {
+    match path_mode {
+        CrashPathMode::Preserve => PathBuf::from(file),
+        CrashPathMode::Flatten => PathBuf::from(flatten_crash_path(file)),
+    }
+}
+
+/// Object key of a device crash file with `crashes.store = "remote"`, laid out like the
+/// device dir: `<udid>/crashes/<local path>`.
+pub fn crash_store_key(udid: &str, file: &str, path_mode: CrashPathMode) -> String {
+    format!(
+        "{udid}/crashes/{}",
+        local_crash_path(file, path_mode).to_string_lossy()
+    )
+}
+
+/// Sanitizes the device path into a single file name. A hash of the original path is
+/// appended so that paths differing only by case or by sanitized characters do not collide.
+fn flatten_crash_path(file: &str) -> String {
+    let (stem, extension) = match file.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() && !extension.contains('/') => {
+            (stem, Some(extension))
+        }
+        _ => (file, None),
+    };
+
+    let sanitize = |s: &str| {
+        s.chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .take(MAX_FLATTENED_NAME_LEN)
+            .collect::<String>()
+    };
+
+    let hash = fnv1a(file.as_bytes());
+    match extension {
+        Some(extension) => format!("{}-{hash:016x}.{}", sanitize(stem), sanitize(extension)),
+        None => format!("{}-{hash:016x}", sanitize(stem)),
+    }
+}
+
+fn is_excluded(file: &str, exclude_patterns: &[Pattern]) -> bool {
+    exclude_patterns.iter().any(|pattern| pattern.matches(file))
+}
+
+pub(super) async fn write_file(content: &[u8], dst_file_path: &PathBuf) -> Result<(), CrashError> {
     let dst_file_path_string = dst_file_path.to_string_lossy().to_string();
 
     if let Some(dir) = dst_file_path.parent() {
@@ -417,3 +912,296 @@ async fn write_file(content: &[u8], dst_file_path: &PathBuf) -> Result<(), Crash
         .await
         .map_err(|e| CrashError::WriteToFile(e, dst_file_path_string.clone()))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::test_support::test_device;
+
+    #[test]
+    fn flattened_paths_keep_the_extension() {
+        let flattened = flatten_crash_path("Retired/App 1.ips");
+        assert!(flattened.starts_with("Retired_App_1-"), "{flattened}");
+        assert!(flattened.ends_with(".ips"), "{flattened}");
+        assert!(!flattened.contains('/'));
+
+        let flattened = flatten_crash_path("Retired/JetsamEvent");
+        assert!(flattened.starts_with("Retired_JetsamEvent-"), "{flattened}");
+        // A dot in a dir name is not an extension
+        let flattened = flatten_crash_path("Logs.old/report");
+        assert!(flattened.starts_with("Logs.old_report-"), "{flattened}");
+        assert!(!flattened.contains('/'));
+    }
+
+    #[test]
+    fn flattened_paths_do_not_collide() {
+        // Same once sanitized, or differing only by case
+        assert_ne!(flatten_crash_path("a/b.ips"), flatten_crash_path("a_b.ips"));
+        assert_ne!(flatten_crash_path("A.ips"), flatten_crash_path("a.ips"));
+        assert_eq!(flatten_crash_path("a/b.ips"), flatten_crash_path("a/b.ips"));
+    }
+
+    #[test]
+    fn flattened_names_are_bounded() {
+        let file = format!("{}.ips", "a".repeat(1000));
+        let flattened = flatten_crash_path(&file);
+        assert!(
+            flattened.len() <= MAX_FLATTENED_NAME_LEN + 32,
+            "{}",
+            flattened.len()
+        );
+    }
+
+    #[test]
+    fn store_key_follows_the_path_mode() {
+        assert_eq!(
+            crash_store_key("udid", "Retired/a.ips", CrashPathMode::Preserve),
+            "udid/crashes/Retired/a.ips"
+        );
+        let flattened = crash_store_key("udid", "Retired/a.ips", CrashPathMode::Flatten);
+        assert!(flattened.starts_with("udid/crashes/Retired_a-"));
+        assert!(flattened.ends_with(".ips"));
+    }
+
+    #[tokio::test]
+    async fn stored_crash_round_trips_through_a_local_backend() {
+        use crate::object_store::LocalBackend;
+
+        let root =
+            std::env::temp_dir().join(format!("imonitor-crash-store-key-{}", uuid::Uuid::new_v4()));
+        let backend = LocalBackend::new(&root);
+        let key = crash_store_key("udid", "Retired/a.ips", CrashPathMode::Preserve);
+
+        backend
+            .put(&key, b"crash", &ObjectAttributes::default())
+            .await
+            .unwrap();
+
+        // Same layout as the device dir, with the local path of the file
+        let path = root
+            .join("udid/crashes")
+            .join(local_crash_path("Retired/a.ips", CrashPathMode::Preserve));
+        assert_eq!(path, backend.path(&key));
+        assert_eq!(std::fs::read(path).unwrap(), b"crash");
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn known_crashes_file_reads_both_formats() {
+        let legacy = serde_json::from_str::<KnownCrashesFile>(r#"["a.ips", "b.ips"]"#).unwrap();
+        assert_eq!(
+            legacy.into_map(),
+            HashMap::from([("a.ips".to_string(), None), ("b.ips".to_string(), None)])
+        );
+
+        let extended = serde_json::from_str::<KnownCrashesFile>(
+            r#"{"a.ips": {"size": 10, "modified": 1700000000}, "b.ips": null}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            extended.into_map(),
+            HashMap::from([
+                (
+                    "a.ips".to_string(),
+                    Some(CrashFileMeta {
+                        size: 10,
+                        modified: 1_700_000_000
+                    })
+                ),
+                ("b.ips".to_string(), None),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn legacy_known_crashes_are_rewritten_extended() {
+        let (device, base_dir) = test_device("known-crashes");
+        std::fs::write(
+            device.get_known_crashes_file_path(),
+            r#"["Retired/a.ips", "b.ips"]"#,
+        )
+        .unwrap();
+
+        device.get_known_crashes_from_fs().await.unwrap();
+        assert_eq!(
+            *device.crashes.crash_files.read().unwrap(),
+            HashSet::from(["Retired/a.ips".to_string(), "b.ips".to_string()])
+        );
+        assert!(device.crashes.crash_files_meta.read().unwrap().is_empty());
+
+        // Pulled again once changed, its metadata is now tracked
+        let meta = CrashFileMeta {
+            size: 42,
+            modified: 1_700_000_000,
+        };
+        device
+            .crashes
+            .crash_files_meta
+            .write()
+            .unwrap()
+            .insert("b.ips".to_string(), meta.clone());
+        device
+            .update_known_crashes(&Default::default())
+            .await
+            .unwrap();
+
+        let content = std::fs::read(device.get_known_crashes_file_path()).unwrap();
+        assert!(matches!(
+            decode_state::<KnownCrashesFile>(&content).unwrap(),
+            KnownCrashesFile::Extended(_)
+        ));
+        device.crashes.crash_files_meta.write().unwrap().clear();
+        device.get_known_crashes_from_fs().await.unwrap();
+        assert_eq!(
+            *device.crashes.crash_files_meta.read().unwrap(),
+            HashMap::from([("b.ips".to_string(), meta)])
+        );
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn slow_crash_dirs_are_kept_and_gone_ones_forgotten() {
+        let (device, base_dir) = test_device("crash-list-timeout");
+        device.crashes.crash_dirs.write().unwrap().extend([
+            "Slow".to_string(),
+            "Gone".to_string(),
+            "Fine".to_string(),
+        ]);
+        let list_timeout = Duration::from_millis(50);
+
+        let hanging = std::future::pending();
+        let result = device.list_crash_dir("Slow", list_timeout, hanging).await;
+        assert!(matches!(result, Err(CrashError::Timeout)));
+
+        let gone = async { Err::<Vec<String>, _>(IdeviceError::Afc(AfcError::ObjectNotFound)) };
+        let result = device.list_crash_dir("Gone", list_timeout, gone).await;
+        assert!(matches!(result, Err(CrashError::ListFiles(..))));
+
+        let listing = async {
+            Ok::<_, IdeviceError>(vec![
+                ".".to_string(),
+                "..".to_string(),
+                "App.ips".to_string(),
+                "IN_PROGRESS_sysdiagnose_1".to_string(),
+            ])
+        };
+        let files = device
+            .list_crash_dir("Fine", list_timeout, listing)
+            .await
+            .unwrap();
+        assert_eq!(files, ["Fine/App.ips"]);
+
+        let crash_dirs = device.crashes.crash_dirs.read().unwrap().clone();
+        assert_eq!(
+            crash_dirs,
+            HashSet::from(["Slow".to_string(), "Fine".to_string()])
+        );
+        // Skipped on the next cycle only
+        let slow_dirs = device.crashes.slow_dirs.read().unwrap().clone();
+        assert_eq!(slow_dirs, HashSet::from(["Slow".to_string()]));
+
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    #[test]
+    fn self_referential_and_too_deep_dirs_are_rejected() {
+        let (device, base_dir) = test_device("crash-dir-loop");
+        // A link to its parent dir lists as the same dir over and over
+        let identity = CrashDirIdentity {
+            creation: 1_700_000_000,
+            modified: 1_700_000_100,
+            nlink: "3".to_string(),
+        };
+        assert!(
+            device
+                .accept_crash_dir("Loop", identity.clone(), 5)
+                .unwrap()
+        );
+        assert!(
+            !device
+                .accept_crash_dir("Loop/Loop", identity.clone(), 5)
+                .unwrap()
+        );
+
+        // A sibling with the same attributes is no loop
+        assert!(
+            device
+                .accept_crash_dir("Other", identity.clone(), 5)
+                .unwrap()
+        );
+
+        let child = CrashDirIdentity {
+            modified: 1_700_000_200,
+            ..identity
+        };
+        assert!(
+            device
+                .accept_crash_dir("Loop/Child", child.clone(), 5)
+                .unwrap()
+        );
+        assert!(
+            !device
+                .accept_crash_dir("Loop/Child/A/B/C/D", child, 5)
+                .unwrap()
+        );
+
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn known_crashes_files_are_byte_identical_for_the_same_sets() {
+        let names: Vec<String> = (0..50).map(|i| format!("Dir{i}/crash{i}.ips")).collect();
+        let mut contents = Vec::new();
+        for order in [names.clone(), names.iter().rev().cloned().collect()] {
+            // Each set gets its own hasher, hence its own iteration order
+            let (device, base_dir) = test_device("known-crashes-order");
+            for name in &order {
+                device
+                    .crashes
+                    .crash_files
+                    .write()
+                    .unwrap()
+                    .insert(name.clone());
+                device.crashes.crash_files_meta.write().unwrap().insert(
+                    name.clone(),
+                    CrashFileMeta {
+                        size: name.len() as u64,
+                        modified: 1_700_000_000,
+                    },
+                );
+                let dir = name.split('/').next().unwrap().to_string();
+                device.crashes.crash_dirs.write().unwrap().insert(dir);
+            }
+
+            device.update_known_crashes(&HashSet::new()).await.unwrap();
+            contents.push((
+                std::fs::read(device.get_known_crashes_file_path()).unwrap(),
+                std::fs::read(device.get_known_crash_dirs_file_path()).unwrap(),
+            ));
+            std::fs::remove_dir_all(base_dir).unwrap();
+        }
+
+        assert_eq!(contents[0], contents[1]);
+    }
+
+    #[test]
+    fn oversized_crash_files_are_ignored() {
+        let (device, base_dir) = test_device("crash-max-size");
+
+        assert!(
+            !device
+                .ignore_oversized_crash("small.ips", 1024, 1024)
+                .unwrap()
+        );
+        assert!(
+            device
+                .ignore_oversized_crash("core.dump", 4 * 1024 * 1024 * 1024, 1024)
+                .unwrap()
+        );
+
+        let ignored_paths = device.crashes.ignored_paths.read().unwrap().clone();
+        assert_eq!(ignored_paths, HashSet::from(["core.dump".to_string()]));
+
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+}
@@ -0,0 +1,239 @@
+use super::errors::CrashError;
+use crate::device::Device;
+use crate::state_store::{StateStore, decode_state};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::time::Duration;
+use tokio::fs::{read, rename, try_exists, write};
+
+const DEAD_LETTER_FILE_NAME: &str = "dead_letter.json";
+
+/// Crash file pulled from the device but failing to be written locally
+/// `crashes.dead_letter_after` times in a row, e.g. on a full or read-only disk. It is only
+/// retried once `crashes.dead_letter_retry` has elapsed, or once cleared by a forced pull.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeadLetterCrash {
+    pub error: String,
+    pub dead_lettered: DateTime<Utc>,
+    pub last_attempt: DateTime<Utc>,
+    pub attempts: u32,
+}
+
+impl Device {
+    pub fn get_dead_letter_file_path(&self) -> String {
+        self.get_state_file_path(self.get_crashes_dir(), DEAD_LETTER_FILE_NAME)
+    }
+
+    /// Files not to pull this cycle, their last attempt being more recent than `retry_after`.
+    pub fn dead_letter_skipped(
+        &self,
+        retry_after: Duration,
+    ) -> Result<HashSet<String>, CrashError> {
+        let now = self.clock.now_utc();
+        let retry_after = TimeDelta::from_std(retry_after).unwrap_or(TimeDelta::MAX);
+        let dead_letter = self
+            .crashes
+            .dead_letter
+            .read()
+            .map_err(|_| CrashError::ReadLock)?;
+
+        Ok(dead_letter
+            .iter()
+            .filter(|(_, dead)| now - dead.last_attempt < retry_after)
+            .map(|(file, _)| file.clone())
+            .collect())
+    }
+
+    /// Counts a failed local write of `file`. Returns whether the dead letters changed, that is
+    /// whether the file reached `dead_letter_after` failures or was already dead-lettered.
+    pub fn record_write_failure(
+        &self,
+        file: &str,
+        error: String,
+        dead_letter_after: u32,
+    ) -> Result<bool, CrashError> {
+        let now = self.clock.now_utc();
+        let mut dead_letter = self
+            .crashes
+            .dead_letter
+            .write()
+            .map_err(|_| CrashError::WriteLock)?;
+
+        // Retried after its backoff and failing again
+        if let Some(dead) = dead_letter.get_mut(file) {
+            dead.error = error;
+            dead.last_attempt = now;
+            dead.attempts = dead.attempts.saturating_add(1);
+            return Ok(true);
+        }
+
+        let mut write_failures = self
+            .crashes
+            .write_failures
+            .write()
+            .map_err(|_| CrashError::WriteLock)?;
+        let attempts = write_failures.entry(file.to_string()).or_default();
+        *attempts = attempts.saturating_add(1);
+        if *attempts < dead_letter_after {
+            return Ok(false);
+        }
+
+        dead_letter.insert(
+            file.to_string(),
+            DeadLetterCrash {
+                error,
+                dead_lettered: now,
+                last_attempt: now,
+                attempts: *attempts,
+            },
+        );
+        write_failures.remove(file);
+        Ok(true)
+    }
+
+    /// Forgets the write failures of files written since, or gone from the device. Returns
+    /// whether any dead letter was forgotten.
+    pub fn forget_dead_letters(&self, keep: impl Fn(&str) -> bool) -> Result<bool, CrashError> {
+        self.crashes
+            .write_failures
+            .write()
+            .map_err(|_| CrashError::WriteLock)?
+            .retain(|file, _| keep(file));
+
+        let mut dead_letter = self
+            .crashes
+            .dead_letter
+            .write()
+            .map_err(|_| CrashError::WriteLock)?;
+
+        let before = dead_letter.len();
+        dead_letter.retain(|file, _| keep(file));
+        Ok(dead_letter.len() != before)
+    }
+
+    pub async fn load_dead_letter(&self) -> Result<(), CrashError> {
+        let file_path = self.get_dead_letter_file_path();
+        if !try_exists(&file_path)
+            .await
+            .map_err(|e| CrashError::FileExists(e, file_path.clone()))?
+        {
+            return Ok(());
+        }
+
+        let content = read(&file_path)
+            .await
+            .map_err(|e| CrashError::ReadFile(e, file_path.clone()))?;
+        let dead_letter = decode_state::<BTreeMap<String, DeadLetterCrash>>(&content)
+            .map_err(CrashError::DecodeKnownCrashes)?;
+
+        *self
+            .crashes
+            .dead_letter
+            .write()
+            .map_err(|_| CrashError::WriteLock)? = dead_letter.into_iter().collect();
+        Ok(())
+    }
+
+    /// Sorted, so that the file only changes when its content does.
+    pub async fn update_dead_letter(&self) -> Result<(), CrashError> {
+        let dead_letter: BTreeMap<String, DeadLetterCrash> = self
+            .crashes
+            .dead_letter
+            .read()
+            .map_err(|_| CrashError::ReadLock)?
+            .iter()
+            .map(|(file, dead)| (file.clone(), dead.clone()))
+            .collect();
+        let content = self
+            .state_format
+            .encode(&dead_letter)
+            .map_err(CrashError::EncodeKnownCrashes)?;
+
+        let file_path = self.get_dead_letter_file_path();
+        let tmp_file_path = format!("{file_path}.tmp");
+        write(&tmp_file_path, content)
+            .await
+            .map_err(|e| CrashError::WriteToFile(e, tmp_file_path.clone()))?;
+        rename(&tmp_file_path, &file_path)
+            .await
+            .map_err(|e| CrashError::WriteToFile(e, file_path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::device::test_support::{test_device, test_device_in};
+    use std::sync::Arc;
+    use std::time::SystemTime;
+
+    #[tokio::test]
+    async fn files_failing_to_be_written_are_dead_lettered_until_the_retry() {
+        let (mut device, base_dir) = test_device("crash-dead-letter");
+        let clock = Arc::new(MockClock::new(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        ));
+        device.set_clock(clock.clone());
+        let retry_after = Duration::from_secs(3600);
+
+        assert!(
+            !device
+                .record_write_failure("JetsamEvent.ips", "No space left".to_string(), 3)
+                .unwrap()
+        );
+        assert!(
+            !device
+                .record_write_failure("JetsamEvent.ips", "No space left".to_string(), 3)
+                .unwrap()
+        );
+        assert!(device.dead_letter_skipped(retry_after).unwrap().is_empty());
+        assert!(
+            device
+                .record_write_failure("JetsamEvent.ips", "No space left".to_string(), 3)
+                .unwrap()
+        );
+        device.update_dead_letter().await.unwrap();
+
+        // Next cycle, also after a restart
+        clock.advance(Duration::from_secs(60));
+        let mut restarted = test_device_in(&base_dir);
+        restarted.set_clock(clock.clone());
+        restarted.load_dead_letter().await.unwrap();
+        assert_eq!(
+            restarted.dead_letter_skipped(retry_after).unwrap(),
+            HashSet::from(["JetsamEvent.ips".to_string()])
+        );
+
+        // Retried after the backoff, failing again keeps it dead-lettered
+        clock.advance(retry_after);
+        assert!(
+            restarted
+                .dead_letter_skipped(retry_after)
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            restarted
+                .record_write_failure("JetsamEvent.ips", "Read-only".to_string(), 3)
+                .unwrap()
+        );
+        {
+            let dead_letter = restarted.crashes.dead_letter.read().unwrap();
+            let dead = &dead_letter["JetsamEvent.ips"];
+            assert_eq!(dead.attempts, 4);
+            assert_eq!(dead.error, "Read-only");
+            assert!(dead.last_attempt > dead.dead_lettered);
+        }
+
+        // Written at last
+        assert!(
+            restarted
+                .forget_dead_letters(|file| file != "JetsamEvent.ips")
+                .unwrap()
+        );
+        assert!(!restarted.forget_dead_letters(|_| false).unwrap());
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+}
@@ -0,0 +1,120 @@
+use super::client::write_file;
+use super::errors::CrashError;
+use crate::device::Device;
+use sha2::{Digest, Sha256};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use tokio::fs::{create_dir_all, hard_link, remove_file, rename, try_exists};
+
+/// How a crash file was stored with `crashes.dedup`.
+#[derive(Debug, Clone)]
+pub struct StoredBlob {
+    /// SHA-256 of the content, also the blob file name
+    pub content_hash: String,
+    /// An identical file was already stored
+    pub deduplicated: bool,
+}
+
+/// Hex SHA-256 of a crash file content.
+pub fn content_hash(content: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(content))
+}
+
+impl Device {
+    /// Stores the content once in the blobs dir, under its hash, and hard links the crash file
+    /// to it. Identical crash files share a single blob.
+    pub async fn write_dedup_crash_file(
+        &self,
+        content: &[u8],
+        dst_file_path: &Path,
+    ) -> Result<StoredBlob, CrashError> {
+        let content_hash = content_hash(content);
+        let blob_path = PathBuf::from(self.get_crash_blobs_dir()).join(&content_hash);
+        let blob_path_string = blob_path.to_string_lossy().to_string();
+
+        let deduplicated = try_exists(&blob_path)
+            .await
+            .map_err(|e| CrashError::FileExists(e, blob_path_string.clone()))?;
+        if !deduplicated {
+            // Renamed once complete, a partial blob is never linked to
+            let part_path = blob_path.with_extension("part");
+            write_file(content, &part_path).await?;
+            rename(&part_path, &blob_path)
+                .await
+                .map_err(|e| CrashError::WriteToFile(e, blob_path_string.clone()))?;
+        }
+
+        let dst_file_path_string = dst_file_path.to_string_lossy().to_string();
+        if let Some(dir) = dst_file_path.parent() {
+            let dir_string = dir.to_string_lossy().to_string();
+            create_dir_all(dir)
+                .await
+                .map_err(|e| CrashError::CreateDir(e, dir_string))?;
+        }
+        // A changed crash file pulled again replaces the previous link
+        match remove_file(dst_file_path).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(CrashError::LinkFile(e, dst_file_path_string)),
+        }
+        hard_link(&blob_path, dst_file_path)
+            .await
+            .map_err(|e| CrashError::LinkFile(e, dst_file_path_string))?;
+
+        Ok(StoredBlob {
+            content_hash,
+            deduplicated,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::test_support::test_device;
+    use std::os::unix::fs::MetadataExt;
+
+    #[tokio::test]
+    async fn identical_crash_files_share_one_blob() {
+        let (device, base_dir) = test_device("crash-dedup");
+        let crash_files_dir = PathBuf::from(device.get_crash_files_dir());
+
+        let first = device
+            .write_dedup_crash_file(b"jetsam", &crash_files_dir.join("JetsamEvent-1.ips"))
+            .await
+            .unwrap();
+        let second = device
+            .write_dedup_crash_file(
+                b"jetsam",
+                &crash_files_dir.join("Retired/JetsamEvent-2.ips"),
+            )
+            .await
+            .unwrap();
+        let other = device
+            .write_dedup_crash_file(b"watchdog", &crash_files_dir.join("Watchdog.ips"))
+            .await
+            .unwrap();
+
+        assert!(!first.deduplicated);
+        assert!(second.deduplicated);
+        assert!(!other.deduplicated);
+        assert_eq!(first.content_hash, second.content_hash);
+        assert_ne!(first.content_hash, other.content_hash);
+
+        let blobs: Vec<_> = std::fs::read_dir(device.get_crash_blobs_dir())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        assert_eq!(blobs.len(), 2);
+        let first_meta = std::fs::metadata(crash_files_dir.join("JetsamEvent-1.ips")).unwrap();
+        let second_meta =
+            std::fs::metadata(crash_files_dir.join("Retired/JetsamEvent-2.ips")).unwrap();
+        assert_eq!(first_meta.ino(), second_meta.ino());
+        assert_eq!(
+            std::fs::read(crash_files_dir.join("Retired/JetsamEvent-2.ips")).unwrap(),
+            b"jetsam"
+        );
+
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+}
@@ -1,3 +1,5 @@
+use crate::object_store::BackendError;
+use crate::state_store::StateStoreError;
 use idevice::IdeviceError;
 
 #[derive(Debug)]
@@ -7,11 +9,17 @@ pub enum CrashError {
     ReadFile(std::io::Error, String),
     FileExists(std::io::Error, String),
     WriteToFile(std::io::Error, String),
+    LinkFile(std::io::Error, String),
     Connect(IdeviceError),
     ListFiles(IdeviceError, String),
     PullFile(IdeviceError, String),
     SerializeKnownCrashes(serde_json::Error),
     DeserializeKnownCrashes(serde_json::Error),
+    SerializeCrashIndex(serde_json::Error),
+    EncodeKnownCrashes(StateStoreError),
+    DecodeKnownCrashes(StateStoreError),
+    Pattern(glob::PatternError, String),
+    Store(BackendError),
     ReadLock,
     WriteLock,
     Timeout,
@@ -37,6 +45,9 @@ impl std::fmt::Display for CrashError {
             CrashError::WriteToFile(e, file_name) => {
                 write!(f, "Failed to write to file {file_name}: {e}")
             }
+            CrashError::LinkFile(e, file_name) => {
+                write!(f, "Failed to link file {file_name} to its blob: {e}")
+            }
             CrashError::Connect(e) => write!(f, "Failed to connect to crash service: {e}"),
             CrashError::ListFiles(e, path) => {
                 write!(f, "Failed to list files from path \"{path}\": {e}")
@@ -48,6 +59,19 @@ impl std::fmt::Display for CrashError {
             CrashError::DeserializeKnownCrashes(e) => {
                 write!(f, "Failed to deserialize known crashes: {e}")
             }
+            CrashError::EncodeKnownCrashes(e) => {
+                write!(f, "Failed to encode known crashes: {e}")
+            }
+            CrashError::DecodeKnownCrashes(e) => {
+                write!(f, "Failed to decode known crashes: {e}")
+            }
+            CrashError::SerializeCrashIndex(e) => {
+                write!(f, "Failed to serialize crash index entry: {e}")
+            }
+            CrashError::Pattern(e, pattern) => {
+                write!(f, "Invalid exclude pattern \"{pattern}\": {e}")
+            }
+            CrashError::Store(e) => write!(f, "Failed to store crash file: {e}"),
             CrashError::Timeout => write!(f, "Crash service waiting timeout"),
             CrashError::ReadLock => write!(f, "Failed acquiring crash files read lock"),
             CrashError::WriteLock => write!(f, "Failed acquiring crash files write lock"),
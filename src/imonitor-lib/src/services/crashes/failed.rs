@@ -0,0 +1,206 @@
+use super::errors::CrashError;
+use crate::device::Device;
+use crate::state_store::{StateStore, decode_state};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::time::Duration;
+use tokio::fs::{read, rename, try_exists, write};
+
+const PERMANENTLY_FAILED_FILE_NAME: &str = "permanently_failed.json";
+
+/// Crash file that failed with an error retrying soon cannot fix, such as a permission
+/// denied. It is only retried once `crashes.permanent_failure_retry` has elapsed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FailedCrash {
+    pub reason: String,
+    pub first_failed: DateTime<Utc>,
+    pub last_attempt: DateTime<Utc>,
+    pub attempts: u32,
+}
+
+impl Device {
+    pub fn get_permanently_failed_file_path(&self) -> String {
+        self.get_state_file_path(self.get_crashes_dir(), PERMANENTLY_FAILED_FILE_NAME)
+    }
+
+    /// Files not to pull this cycle, their last attempt being more recent than `retry_after`.
+    pub fn permanently_failed_skipped(
+        &self,
+        retry_after: Duration,
+    ) -> Result<HashSet<String>, CrashError> {
+        let now = self.clock.now_utc();
+        let retry_after = TimeDelta::from_std(retry_after).unwrap_or(TimeDelta::MAX);
+        let permanently_failed = self
+            .crashes
+            .permanently_failed
+            .read()
+            .map_err(|_| CrashError::ReadLock)?;
+
+        Ok(permanently_failed
+            .iter()
+            .filter(|(_, failed)| now - failed.last_attempt < retry_after)
+            .map(|(file, _)| file.clone())
+            .collect())
+    }
+
+    /// Records a failed attempt, keeping the first failure time of a file already failed.
+    pub fn record_permanent_failure(&self, file: &str, reason: String) -> Result<(), CrashError> {
+        let now = self.clock.now_utc();
+        let mut permanently_failed = self
+            .crashes
+            .permanently_failed
+            .write()
+            .map_err(|_| CrashError::WriteLock)?;
+
+        permanently_failed
+            .entry(file.to_string())
+            .and_modify(|failed| {
+                failed.reason = reason.clone();
+                failed.last_attempt = now;
+                failed.attempts = failed.attempts.saturating_add(1);
+            })
+            .or_insert(FailedCrash {
+                reason,
+                first_failed: now,
+                last_attempt: now,
+                attempts: 1,
+            });
+        Ok(())
+    }
+
+    /// Forgets the files pulled since, or gone from the device. Returns whether any was
+    /// forgotten.
+    pub fn forget_permanent_failures(
+        &self,
+        keep: impl Fn(&str) -> bool,
+    ) -> Result<bool, CrashError> {
+        let mut permanently_failed = self
+            .crashes
+            .permanently_failed
+            .write()
+            .map_err(|_| CrashError::WriteLock)?;
+
+        let before = permanently_failed.len();
+        permanently_failed.retain(|file, _| keep(file));
+        Ok(permanently_failed.len() != before)
+    }
+
+    pub async fn load_permanently_failed(&self) -> Result<(), CrashError> {
+        let file_path = self.get_permanently_failed_file_path();
+        if !try_exists(&file_path)
+            .await
+            .map_err(|e| CrashError::FileExists(e, file_path.clone()))?
+        {
+            return Ok(());
+        }
+
+        let content = read(&file_path)
+            .await
+            .map_err(|e| CrashError::ReadFile(e, file_path.clone()))?;
+        let permanently_failed = decode_state::<BTreeMap<String, FailedCrash>>(&content)
+            .map_err(CrashError::DecodeKnownCrashes)?;
+
+        *self
+            .crashes
+            .permanently_failed
+            .write()
+            .map_err(|_| CrashError::WriteLock)? = permanently_failed.into_iter().collect();
+        Ok(())
+    }
+
+    /// Sorted, so that the file only changes when its content does.
+    pub async fn update_permanently_failed(&self) -> Result<(), CrashError> {
+        let permanently_failed: BTreeMap<String, FailedCrash> = self
+            .crashes
+            .permanently_failed
+            .read()
+            .map_err(|_| CrashError::ReadLock)?
+            .iter()
+            .map(|(file, failed)| (file.clone(), failed.clone()))
+            .collect();
+        let content = self
+            .state_format
+            .encode(&permanently_failed)
+            .map_err(CrashError::EncodeKnownCrashes)?;
+
+        let file_path = self.get_permanently_failed_file_path();
+        let tmp_file_path = format!("{file_path}.tmp");
+        write(&tmp_file_path, content)
+            .await
+            .map_err(|e| CrashError::WriteToFile(e, tmp_file_path.clone()))?;
+        rename(&tmp_file_path, &file_path)
+            .await
+            .map_err(|e| CrashError::WriteToFile(e, file_path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::device::test_support::{test_device, test_device_in};
+    use std::sync::Arc;
+    use std::time::SystemTime;
+
+    #[tokio::test]
+    async fn permanently_failed_files_are_skipped_until_the_backoff() {
+        let (mut device, base_dir) = test_device("crash-failed");
+        let clock = Arc::new(MockClock::new(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        ));
+        device.set_clock(clock.clone());
+        let retry_after = Duration::from_secs(3600);
+
+        device
+            .record_permanent_failure("Private.ips", "PermDenied".to_string())
+            .unwrap();
+        device.update_permanently_failed().await.unwrap();
+
+        // Next cycle, also after a restart
+        clock.advance(Duration::from_secs(60));
+        let mut restarted = test_device_in(&base_dir);
+        restarted.set_clock(clock.clone());
+        restarted.load_permanently_failed().await.unwrap();
+        assert_eq!(
+            restarted.permanently_failed_skipped(retry_after).unwrap(),
+            HashSet::from(["Private.ips".to_string()])
+        );
+
+        // Retried after the backoff, failing again restarts it
+        clock.advance(retry_after);
+        assert!(
+            restarted
+                .permanently_failed_skipped(retry_after)
+                .unwrap()
+                .is_empty()
+        );
+        restarted
+            .record_permanent_failure("Private.ips", "PermDenied".to_string())
+            .unwrap();
+        assert_eq!(
+            restarted
+                .permanently_failed_skipped(retry_after)
+                .unwrap()
+                .len(),
+            1
+        );
+        let failed = restarted.crashes.permanently_failed.read().unwrap()["Private.ips"].clone();
+        assert_eq!(failed.attempts, 2);
+        assert_eq!(
+            failed.first_failed,
+            DateTime::<Utc>::from(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+        );
+
+        // Pulled since
+        assert!(restarted.forget_permanent_failures(|_| false).unwrap());
+        assert!(
+            restarted
+                .permanently_failed_skipped(retry_after)
+                .unwrap()
+                .is_empty()
+        );
+
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+}
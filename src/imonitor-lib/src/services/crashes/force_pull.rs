@@ -0,0 +1,197 @@
+use super::errors::CrashError;
+use crate::device::Device;
+use glob::Pattern;
+use logger::{HasLogger, info, warn};
+use std::collections::BTreeSet;
+use std::io::ErrorKind;
+use tokio::fs::{read_to_string, remove_file, rename, write};
+
+const FORCE_PULL_REQUESTS_FILE_NAME: &str = "force_pull_requests.json";
+
+impl Device {
+    /// Forgets the known crash files matching the glob, so that the next cycle pulls them
+    /// again. Unrelated known files are left as is. Returns how many were forgotten.
+    pub async fn force_pull(&self, glob: &str) -> Result<usize, CrashError> {
+        let pattern = Pattern::new(glob).map_err(|e| CrashError::Pattern(e, glob.to_string()))?;
+
+        let forgotten = {
+            let mut crash_files = self
+                .crashes
+                .crash_files
+                .write()
+                .map_err(|_| CrashError::WriteLock)?;
+            let forgotten = crash_files
+                .iter()
+                .filter(|file| pattern.matches(file))
+                .cloned()
+                .collect::<Vec<String>>();
+            for file in &forgotten {
+                crash_files.remove(file);
+            }
+            forgotten
+        };
+
+        {
+            let mut crash_files_meta = self
+                .crashes
+                .crash_files_meta
+                .write()
+                .map_err(|_| CrashError::WriteLock)?;
+            for file in &forgotten {
+                crash_files_meta.remove(file);
+            }
+        }
+
+        // Also retried right away, instead of after their backoff
+        if self.forget_permanent_failures(|file| !pattern.matches(file))? {
+            self.update_permanently_failed().await?;
+        }
+        if self.forget_dead_letters(|file| !pattern.matches(file))? {
+            self.update_dead_letter().await?;
+        }
+
+        // The argument is unused, the known files are written from memory
+        self.update_known_crashes(&Default::default()).await?;
+        info!(
+            self,
+            "Forced pull of {} known crash file(s) matching {glob}",
+            forgotten.len()
+        );
+        Ok(forgotten.len())
+    }
+
+    pub fn get_force_pull_requests_file_path(&self) -> String {
+        self.get_state_file_path(self.get_crashes_dir(), FORCE_PULL_REQUESTS_FILE_NAME)
+    }
+
+    /// Queues a forced pull for the monitoring process, which applies it at the start of its
+    /// next crash cycle, see [`Device::force_pull`]. The glob is checked first.
+    pub async fn request_force_pull(&self, glob: &str) -> Result<(), CrashError> {
+        Pattern::new(glob).map_err(|e| CrashError::Pattern(e, glob.to_string()))?;
+
+        let mut globs = self.load_force_pull_requests().await?;
+        globs.insert(glob.to_string());
+        self.write_force_pull_requests(&globs).await
+    }
+
+    /// Applies the queued forced pulls, each one being removed once applied. The failed ones
+    /// stay queued for the next crash cycle, the last failure is returned.
+    pub async fn apply_force_pull_requests(&self) -> Result<(), CrashError> {
+        let globs = self.load_force_pull_requests().await?;
+        if globs.is_empty() {
+            return Ok(());
+        }
+
+        let mut applied = BTreeSet::new();
+        let mut failure = None;
+        for glob in globs {
+            match self.force_pull(&glob).await {
+                Ok(_) => {
+                    applied.insert(glob);
+                }
+                // Never applies, e.g. a hand edited file
+                Err(e @ CrashError::Pattern(..)) => {
+                    warn!(self, "Dropped forced pull: {e}");
+                    applied.insert(glob);
+                }
+                Err(e) => failure = Some(e),
+            }
+        }
+
+        // Read again, a request may have been queued meanwhile
+        let mut globs = self.load_force_pull_requests().await?;
+        globs.retain(|glob| !applied.contains(glob));
+        self.write_force_pull_requests(&globs).await?;
+        failure.map_or(Ok(()), Err)
+    }
+
+    /// Replaces the queued forced pulls, the file being removed once none is left.
+    async fn write_force_pull_requests(&self, globs: &BTreeSet<String>) -> Result<(), CrashError> {
+        let file_path = self.get_force_pull_requests_file_path();
+        if globs.is_empty() {
+            return match remove_file(&file_path).await {
+                Err(e) if e.kind() != ErrorKind::NotFound => {
+                    Err(CrashError::WriteToFile(e, file_path))
+                }
+                _ => Ok(()),
+            };
+        }
+
+        let content =
+            serde_json::to_string_pretty(globs).map_err(CrashError::SerializeKnownCrashes)?;
+
+        // Renamed once written, the monitoring process never reads a partial file
+        let tmp_file_path = format!("{file_path}.tmp");
+        write(&tmp_file_path, content)
+            .await
+            .map_err(|e| CrashError::WriteToFile(e, tmp_file_path.clone()))?;
+        rename(&tmp_file_path, &file_path)
+            .await
+            .map_err(|e| CrashError::WriteToFile(e, file_path))
+    }
+
+    async fn load_force_pull_requests(&self) -> Result<BTreeSet<String>, CrashError> {
+        let file_path = self.get_force_pull_requests_file_path();
+        match read_to_string(&file_path).await {
+            Ok(content) => {
+                serde_json::from_str(&content).map_err(CrashError::DeserializeKnownCrashes)
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(BTreeSet::new()),
+            Err(e) => Err(CrashError::ReadFile(e, file_path)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::test_support::test_device;
+    use std::collections::HashSet;
+    use std::path::Path;
+
+    #[tokio::test]
+    async fn failed_forced_pulls_stay_queued() {
+        let (device, base_dir) = test_device("force-pull");
+        device
+            .crashes
+            .crash_files
+            .write()
+            .unwrap()
+            .extend(["Retired/a.ips".to_string(), "b.log".to_string()]);
+        device.request_force_pull("Retired/*").await.unwrap();
+        device.request_force_pull("*.ips").await.unwrap();
+
+        // A dir in the way of the known crashes file fails every forced pull
+        let known_crashes_file_path = device.get_known_crashes_file_path();
+        std::fs::create_dir(&known_crashes_file_path).unwrap();
+        assert!(device.apply_force_pull_requests().await.is_err());
+        assert_eq!(
+            device.load_force_pull_requests().await.unwrap(),
+            BTreeSet::from(["*.ips".to_string(), "Retired/*".to_string()])
+        );
+
+        std::fs::remove_dir(&known_crashes_file_path).unwrap();
+        device.apply_force_pull_requests().await.unwrap();
+        assert!(!Path::new(&device.get_force_pull_requests_file_path()).exists());
+        assert_eq!(
+            *device.crashes.crash_files.read().unwrap(),
+            HashSet::from(["b.log".to_string()])
+        );
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn invalid_forced_pulls_are_dropped() {
+        let (device, base_dir) = test_device("force-pull-invalid");
+        std::fs::write(
+            device.get_force_pull_requests_file_path(),
+            r#"["[", "*.ips"]"#,
+        )
+        .unwrap();
+
+        device.apply_force_pull_requests().await.unwrap();
+        assert!(device.load_force_pull_requests().await.unwrap().is_empty());
+        assert!(device.request_force_pull("[").await.is_err());
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+}
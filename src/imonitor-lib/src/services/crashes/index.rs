@@ -0,0 +1,252 @@
+use super::dedup::StoredBlob;
+use super::errors::CrashError;
+use super::ips::parse_ips;
+use crate::device::Device;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tokio::fs::{OpenOptions, read_to_string, try_exists};
+use tokio::io::AsyncWriteExt;
+
+// One JSON entry per line, appended as crashes are pulled
+const CRASH_INDEX_FILE_NAME: &str = "crash_index.ndjson";
+// Name of the index before it was named after its format
+const LEGACY_CRASH_INDEX_FILE_NAME: &str = "crash_index.json";
+
+/// A pulled crash file, as recorded in the crash index.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CrashIndexEntry {
+    pub device_path: String,
+    pub local_path: String,
+    pub size: u64,
+    pub pulled_at: DateTime<Utc>,
+    pub process_name: Option<String>,
+    pub bundle_id: Option<String>,
+    /// Such as "EXC_BAD_ACCESS (SIGSEGV)", read from `.ips` files
+    #[serde(default)]
+    pub exception_type: Option<String>,
+    #[serde(default)]
+    pub termination_reason: Option<String>,
+    /// SHA-256 of the content, set with `crashes.dedup`
+    #[serde(default)]
+    pub content_hash: Option<String>,
+    /// Linked to the blob of an identical crash file pulled before
+    #[serde(default)]
+    pub deduplicated: bool,
+}
+
+/// Criteria of `Device::query_crashes`. Unset fields match every entry.
+#[derive(Debug, Clone, Default)]
+pub struct CrashFilter {
+    pub process_name: Option<String>,
+    pub bundle_id: Option<String>,
+    /// Part of the exception type, such as "SIGSEGV"
+    pub exception_type: Option<String>,
+    /// Range of pull dates
+    pub window: Option<Range<SystemTime>>,
+}
+
+impl CrashFilter {
+    pub fn matches(&self, entry: &CrashIndexEntry) -> bool {
+        let matches_field = |expected: &Option<String>, actual: &Option<String>| {
+            expected
+                .as_ref()
+                .is_none_or(|expected| actual.as_ref() == Some(expected))
+        };
+
+        matches_field(&self.process_name, &entry.process_name)
+            && matches_field(&self.bundle_id, &entry.bundle_id)
+            && self.exception_type.as_ref().is_none_or(|expected| {
+                entry
+                    .exception_type
+                    .as_ref()
+                    .is_some_and(|actual| actual.contains(expected.as_str()))
+            })
+            && self
+                .window
+                .as_ref()
+                .is_none_or(|window| window.contains(&SystemTime::from(entry.pulled_at)))
+    }
+}
+
+impl Device {
+    pub fn get_crash_index_file_path(&self) -> String {
+        let crashes_dir = PathBuf::from(self.get_crashes_dir());
+        crashes_dir
+            .join(CRASH_INDEX_FILE_NAME)
+            .to_string_lossy()
+            .to_string()
+    }
+
+    /// Former path of the index, moved by [`Device::migrate_state_files`].
+    pub(crate) fn get_legacy_crash_index_file_path(&self) -> String {
+        let crashes_dir = PathBuf::from(self.get_crashes_dir());
+        crashes_dir
+            .join(LEGACY_CRASH_INDEX_FILE_NAME)
+            .to_string_lossy()
+            .to_string()
+    }
+
+    /// Records a pulled crash file in the index.
+    pub async fn index_crash(
+        &self,
+        device_path: &str,
+        local_path: &Path,
+        content: &[u8],
+        blob: Option<&StoredBlob>,
+    ) -> Result<(), CrashError> {
+        let ips = if device_path.ends_with(".ips") {
+            parse_ips(content)
+        } else {
+            Default::default()
+        };
+
+        let entry = CrashIndexEntry {
+            device_path: device_path.to_string(),
+            local_path: local_path.to_string_lossy().to_string(),
+            size: content.len() as u64,
+            pulled_at: self.clock.now_utc(),
+            process_name: ips.process_name,
+            bundle_id: ips.bundle_id,
+            exception_type: ips.exception_type,
+            termination_reason: ips.termination_reason,
+            content_hash: blob.map(|blob| blob.content_hash.clone()),
+            deduplicated: blob.is_some_and(|blob| blob.deduplicated),
+        };
+
+        let mut line = serde_json::to_string(&entry).map_err(CrashError::SerializeCrashIndex)?;
+        line.push('\n');
+
+        let index_file_path = self.get_crash_index_file_path();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&index_file_path)
+            .await
+            .map_err(|e| CrashError::CreateFile(e, index_file_path.clone()))?;
+
+        file.write_all(line.as_bytes())
+            .await
+            .map_err(|e| CrashError::WriteToFile(e, index_file_path.clone()))
+    }
+
+    /// Indexed crash files matching the filter, in pull order.
+    pub async fn query_crashes(
+        &self,
+        filter: &CrashFilter,
+    ) -> Result<Vec<CrashIndexEntry>, CrashError> {
+        let index_file_path = self.get_crash_index_file_path();
+        if !try_exists(&index_file_path)
+            .await
+            .map_err(|e| CrashError::FileExists(e, index_file_path.clone()))?
+        {
+            return Ok(Vec::new());
+        }
+
+        let content = read_to_string(&index_file_path)
+            .await
+            .map_err(|e| CrashError::ReadFile(e, index_file_path.clone()))?;
+
+        let mut entries = Vec::new();
+        for line in content.lines().filter(|line| !line.trim().is_empty()) {
+            // A crash during an append leaves a partial last line, skipped
+            if let Ok(entry) = serde_json::from_str::<CrashIndexEntry>(line)
+                && filter.matches(&entry)
+            {
+                entries.push(entry);
+            }
+        }
+
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, MockClock};
+    use crate::device::test_support::test_device;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    const IPS: &[u8] = br#"{"app_name":"Maps","bundleID":"com.apple.Maps","bug_type":"309"}
+{"procName":"Maps","exception":{"type":"EXC_BAD_ACCESS","signal":"SIGSEGV"}}"#;
+
+    #[tokio::test]
+    async fn indexed_crashes_are_queried_by_field_and_pull_date() {
+        let (mut device, base_dir) = test_device("crash-index");
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let clock = Arc::new(MockClock::new(start));
+        device.set_clock(clock.clone());
+
+        device
+            .index_crash("Maps.ips", Path::new("/tmp/Maps.ips"), IPS, None)
+            .await
+            .unwrap();
+        clock.advance(Duration::from_secs(3600));
+        device
+            .index_crash("JetsamEvent", Path::new("/tmp/JetsamEvent"), b"{}", None)
+            .await
+            .unwrap();
+
+        assert!(
+            device
+                .get_crash_index_file_path()
+                .ends_with("crash_index.ndjson")
+        );
+        let all = device.query_crashes(&CrashFilter::default()).await.unwrap();
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0].pulled_at, DateTime::<Utc>::from(start));
+        assert_eq!(all[1].size, 2);
+
+        let by_exception = CrashFilter {
+            exception_type: Some("SIGSEGV".to_string()),
+            ..Default::default()
+        };
+        let entries = device.query_crashes(&by_exception).await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].device_path, "Maps.ips");
+
+        let last_pull = CrashFilter {
+            window: Some(start + Duration::from_secs(1)..clock.now()),
+            ..Default::default()
+        };
+        assert!(device.query_crashes(&last_pull).await.unwrap().is_empty());
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn partial_last_line_is_skipped() {
+        let (device, base_dir) = test_device("crash-index-partial");
+        device
+            .index_crash("a.ips", Path::new("/tmp/a.ips"), b"", None)
+            .await
+            .unwrap();
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(device.get_crash_index_file_path())
+            .await
+            .unwrap();
+        file.write_all(b"{\"device_path\":\"b.").await.unwrap();
+
+        let entries = device.query_crashes(&CrashFilter::default()).await.unwrap();
+        assert_eq!(entries.len(), 1);
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    #[test]
+    fn legacy_index_is_renamed() {
+        let (device, base_dir) = test_device("crash-index-legacy");
+        std::fs::write(device.get_legacy_crash_index_file_path(), b"{}\n").unwrap();
+
+        assert_eq!(device.migrate_state_files().unwrap(), 1);
+        assert!(!Path::new(&device.get_legacy_crash_index_file_path()).exists());
+        assert_eq!(
+            std::fs::read(device.get_crash_index_file_path()).unwrap(),
+            b"{}\n"
+        );
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+}
@@ -0,0 +1,163 @@
+use serde::Deserialize;
+use serde_json::Value;
+
+/// What the crash index keeps of an `.ips` file. Fields missing from the file are None.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IpsSummary {
+    pub process_name: Option<String>,
+    pub bundle_id: Option<String>,
+    /// Such as "EXC_CRASH (SIGABRT)"
+    pub exception_type: Option<String>,
+    pub termination_reason: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct IpsHeader {
+    app_name: Option<String>,
+    name: Option<String>,
+    #[serde(rename = "bundleID")]
+    bundle_id: Option<String>,
+}
+
+/// Parses an `.ips` file, which starts with a single line JSON header. Since iOS 15 the rest
+/// is a JSON payload, before it was the plain text report. Never fails: a malformed header
+/// or payload only leaves the fields it would hold unset.
+pub fn parse_ips(content: &[u8]) -> IpsSummary {
+    let (header_line, payload) = match content.iter().position(|b| *b == b'\n') {
+        Some(end) => (&content[..end], &content[end + 1..]),
+        None => (content, &[][..]),
+    };
+    let header = serde_json::from_slice::<IpsHeader>(header_line).unwrap_or_default();
+
+    let mut summary = match serde_json::from_slice::<Value>(payload) {
+        Ok(payload) => parse_json_payload(&payload),
+        Err(_) => parse_text_payload(&String::from_utf8_lossy(payload)),
+    };
+
+    // The header wins, the payload fills in
+    summary.process_name = header.app_name.or(header.name).or(summary.process_name);
+    summary.bundle_id = header.bundle_id.or(summary.bundle_id);
+    summary
+}
+
+/// iOS 15 and later.
+fn parse_json_payload(payload: &Value) -> IpsSummary {
+    let string = |value: Option<&Value>| value.and_then(Value::as_str).map(str::to_string);
+
+    let exception = payload.get("exception");
+    let exception_type =
+        string(exception.and_then(|exception| exception.get("type"))).map(|kind| {
+            match string(exception.and_then(|exception| exception.get("signal"))) {
+                Some(signal) => format!("{kind} ({signal})"),
+                None => kind,
+            }
+        });
+
+    let termination = payload.get("termination");
+    let termination_reason = string(termination.and_then(|t| t.get("indicator"))).or_else(|| {
+        let namespace = string(termination.and_then(|t| t.get("namespace")))?;
+        let code = termination.and_then(|t| t.get("code"));
+        Some(match code {
+            Some(code) => format!("Namespace {namespace}, Code {code}"),
+            None => format!("Namespace {namespace}"),
+        })
+    });
+
+    IpsSummary {
+        process_name: string(payload.get("procName")),
+        bundle_id: string(
+            payload
+                .get("bundleInfo")
+                .and_then(|info| info.get("CFBundleIdentifier")),
+        ),
+        exception_type,
+        termination_reason,
+    }
+}
+
+/// Before iOS 15, such as "Exception Type:  EXC_CRASH (SIGABRT)".
+fn parse_text_payload(payload: &str) -> IpsSummary {
+    let mut summary = IpsSummary::default();
+    for line in payload.lines() {
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        let (field, value) = match key.trim() {
+            // "Process: Name [123]"
+            "Process" => (
+                &mut summary.process_name,
+                value.split(" [").next().unwrap_or(value),
+            ),
+            "Identifier" => (&mut summary.bundle_id, value),
+            "Exception Type" => (&mut summary.exception_type, value),
+            "Termination Reason" => (&mut summary.termination_reason, value),
+            _ => continue,
+        };
+        if field.is_none() && !value.is_empty() {
+            *field = Some(value.to_string());
+        }
+    }
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_payload() {
+        let content = br#"{"app_name":"Maps","bundleID":"com.apple.Maps"}
+{"procName":"MapsRenamed","exception":{"type":"EXC_CRASH","signal":"SIGABRT"},"termination":{"namespace":"SIGNAL","code":6}}"#;
+
+        assert_eq!(
+            parse_ips(content),
+            IpsSummary {
+                process_name: Some("Maps".to_string()),
+                bundle_id: Some("com.apple.Maps".to_string()),
+                exception_type: Some("EXC_CRASH (SIGABRT)".to_string()),
+                termination_reason: Some("Namespace SIGNAL, Code 6".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn termination_indicator_wins_over_namespace() {
+        let content = br#"{}
+{"procName":"App","bundleInfo":{"CFBundleIdentifier":"com.example.app"},"termination":{"indicator":"Abort trap: 6","namespace":"SIGNAL"}}"#;
+
+        let summary = parse_ips(content);
+        assert_eq!(summary.process_name.as_deref(), Some("App"));
+        assert_eq!(summary.bundle_id.as_deref(), Some("com.example.app"));
+        assert_eq!(summary.termination_reason.as_deref(), Some("Abort trap: 6"));
+        assert_eq!(summary.exception_type, None);
+    }
+
+    #[test]
+    fn text_payload() {
+        let content = b"{}\n\
+Process:             App [123]\n\
+Identifier:          com.example.app\n\
+Exception Type:      EXC_BAD_ACCESS (SIGSEGV)\n\
+Termination Reason:  Namespace SIGNAL, Code 0xb\n\
+Exception Type:      ignored\n";
+
+        let summary = parse_ips(content);
+        assert_eq!(summary.process_name.as_deref(), Some("App"));
+        assert_eq!(summary.bundle_id.as_deref(), Some("com.example.app"));
+        assert_eq!(
+            summary.exception_type.as_deref(),
+            Some("EXC_BAD_ACCESS (SIGSEGV)")
+        );
+        assert_eq!(
+            summary.termination_reason.as_deref(),
+            Some("Namespace SIGNAL, Code 0xb")
+        );
+    }
+
+    #[test]
+    fn malformed_content_leaves_fields_unset() {
+        assert_eq!(parse_ips(b""), IpsSummary::default());
+        assert_eq!(parse_ips(b"not json\n\xff\xfe"), IpsSummary::default());
+    }
+}
@@ -1,2 +1,9 @@
 pub mod client;
+pub mod dead_letter;
+pub mod dedup;
 pub mod errors;
+pub mod failed;
+pub mod force_pull;
+pub mod index;
+pub mod ips;
+pub mod sysdiagnose;
@@ -0,0 +1,215 @@
+use super::client::write_file;
+use super::errors::CrashError;
+use crate::connection::ConnectionPriority;
+use crate::device::Device;
+use idevice::{IdeviceService, crashreportcopymobile::CrashReportCopyMobileClient};
+use logger::{HasLogger, info, warn};
+use std::path::PathBuf;
+use tokio::fs::{rename, try_exists};
+use tokio::time::{Duration, Instant, interval, sleep, timeout};
+
+// Relative to the crash report root
+const SYSDIAGNOSE_DEVICE_DIR: &str = "DiagnosticLogs/sysdiagnose";
+const SYSDIAGNOSE_PREFIX: &str = "sysdiagnose_";
+const IN_PROGRESS_PREFIX: &str = "IN_PROGRESS_sysdiagnose_";
+const SYSDIAGNOSE_EXTENSION: &str = ".tar.gz";
+const IN_PROGRESS_POLL_SECS: u64 = 10;
+const PULL_PROGRESS_INTERVAL_SECS: u64 = 10;
+
+/// Sysdiagnoses found in the sysdiagnose dir of the device.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SysdiagnoseListing {
+    /// Most recent completed sysdiagnose
+    pub latest: Option<String>,
+    /// A sysdiagnose is being written
+    pub in_progress: bool,
+}
+
+/// Sysdiagnoses are large and pulled on demand only, see
+/// [`Device::pull_latest_sysdiagnose`], never by the routine crash pull.
+pub fn is_sysdiagnose_path(path: &str) -> bool {
+    path.strip_prefix(SYSDIAGNOSE_DEVICE_DIR)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Sorts out the file names of the sysdiagnose dir. Names start with the creation date,
+/// so the most recent sysdiagnose sorts last.
+pub fn list_sysdiagnoses<'a>(files: impl IntoIterator<Item = &'a str>) -> SysdiagnoseListing {
+    let mut listing = SysdiagnoseListing::default();
+    for file in files {
+        if file.starts_with(IN_PROGRESS_PREFIX) {
+            listing.in_progress = true;
+        } else if file.starts_with(SYSDIAGNOSE_PREFIX)
+            && file.ends_with(SYSDIAGNOSE_EXTENSION)
+            && listing.latest.as_deref().is_none_or(|latest| file > latest)
+        {
+            listing.latest = Some(file.to_string());
+        }
+    }
+    listing
+}
+
+impl Device {
+    /// Pulls the most recent completed sysdiagnose into the sysdiagnose dir, apart from the
+    /// crash files. A sysdiagnose being written is waited for up to `wait`, else skipped.
+    /// Returns the local path, None if the device has no completed sysdiagnose.
+    pub async fn pull_latest_sysdiagnose(
+        &self,
+        wait: Option<Duration>,
+    ) -> Result<Option<PathBuf>, CrashError> {
+        let provider = self.get_provider("sysdiagnose");
+        let mut client = self
+            .limit_connect(
+                ConnectionPriority::Normal,
+                timeout(
+                    Duration::from_secs(2),
+                    CrashReportCopyMobileClient::connect(&*provider),
+                ),
+            )
+            .await
+            .map_err(|_| CrashError::Timeout)?
+            .map_err(CrashError::Connect)?;
+
+        let deadline = wait.map(|wait| Instant::now() + wait);
+        let listing = loop {
+            let files = client
+                .ls(Some(SYSDIAGNOSE_DEVICE_DIR))
+                .await
+                .map_err(|e| CrashError::ListFiles(e, SYSDIAGNOSE_DEVICE_DIR.to_string()))?;
+            let listing = list_sysdiagnoses(files.iter().map(String::as_str));
+            if !listing.in_progress {
+                break listing;
+            }
+
+            match deadline {
+                Some(deadline) if Instant::now() < deadline => {
+                    info!(self, "A sysdiagnose is being written, waiting for it");
+                    sleep(Duration::from_secs(IN_PROGRESS_POLL_SECS)).await;
+                }
+                _ => {
+                    warn!(self, "A sysdiagnose is still being written, skipping it");
+                    break listing;
+                }
+            }
+        };
+
+        let Some(name) = listing.latest else {
+            info!(self, "No completed sysdiagnose on the device");
+            return Ok(None);
+        };
+
+        let device_path = format!("{SYSDIAGNOSE_DEVICE_DIR}/{name}");
+        let dst_file_path = PathBuf::from(self.get_sysdiagnose_dir()).join(&name);
+        let dst_file_path_string = dst_file_path.to_string_lossy().to_string();
+        if try_exists(&dst_file_path)
+            .await
+            .map_err(|e| CrashError::FileExists(e, dst_file_path_string.clone()))?
+        {
+            info!(self, "Sysdiagnose {name} already pulled");
+            return Ok(Some(dst_file_path));
+        }
+
+        // Only used for progress logs
+        let size = client
+            .afc_client
+            .get_file_info(device_path.clone())
+            .await
+            .map(|file_info| file_info.size.to_string())
+            .unwrap_or_else(|_| "unknown".to_string());
+        info!(self, "Pulling sysdiagnose {name} ({size} bytes)");
+
+        // Sysdiagnoses take minutes to pull, progress is logged meanwhile
+        let started = Instant::now();
+        let content = {
+            let mut pull = std::pin::pin!(client.pull(device_path.clone()));
+            let mut progress = interval(Duration::from_secs(PULL_PROGRESS_INTERVAL_SECS));
+            // The first tick completes immediately
+            progress.tick().await;
+            loop {
+                tokio::select!(
+                    content = &mut pull => {
+                        break content.map_err(|e| CrashError::PullFile(e, device_path.clone()))?;
+                    }
+                    _ = progress.tick() => {
+                        info!(
+                            self,
+                            "Still pulling sysdiagnose {name}, {}s elapsed",
+                            started.elapsed().as_secs()
+                        );
+                    }
+                )
+            }
+        };
+
+        if let Some(limiter) = &self.download_limiter {
+            limiter.consume(content.len() as u64).await;
+        }
+
+        // Renamed once complete, a partial file is never taken for a pulled sysdiagnose
+        let part_file_path = dst_file_path.with_file_name(format!("{name}.part"));
+        write_file(&content, &part_file_path).await?;
+        rename(&part_file_path, &dst_file_path)
+            .await
+            .map_err(|e| CrashError::WriteToFile(e, dst_file_path_string.clone()))?;
+
+        info!(
+            self,
+            "Sysdiagnose {name} pulled to {dst_file_path_string} ({} bytes in {}s)",
+            content.len(),
+            started.elapsed().as_secs()
+        );
+        Ok(Some(dst_file_path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latest_completed_sysdiagnose_is_listed() {
+        let listing = list_sysdiagnoses([
+            ".",
+            "..",
+            "sysdiagnose_2024.05.01_10-00-00+0200_iPhone-OS_iPhone_21E236.tar.gz",
+            "sysdiagnose_2024.06.01_10-00-00+0200_iPhone-OS_iPhone_21F90.tar.gz",
+            "sysdiagnose_2024.07.01_10-00-00+0200_iPhone-OS_iPhone_21F90.txt",
+            "other_2024.08.01.tar.gz",
+        ]);
+        assert_eq!(
+            listing,
+            SysdiagnoseListing {
+                latest: Some(
+                    "sysdiagnose_2024.06.01_10-00-00+0200_iPhone-OS_iPhone_21F90.tar.gz"
+                        .to_string()
+                ),
+                in_progress: false,
+            }
+        );
+    }
+
+    #[test]
+    fn sysdiagnose_being_written_is_reported() {
+        let listing =
+            list_sysdiagnoses(["IN_PROGRESS_sysdiagnose_2024.06.02_10-00-00+0200.tar.gz"]);
+        assert_eq!(
+            listing,
+            SysdiagnoseListing {
+                latest: None,
+                in_progress: true,
+            }
+        );
+        assert_eq!(list_sysdiagnoses([]), SysdiagnoseListing::default());
+    }
+
+    #[test]
+    fn sysdiagnose_dir_is_left_to_the_routine_pull() {
+        assert!(is_sysdiagnose_path("DiagnosticLogs/sysdiagnose"));
+        assert!(is_sysdiagnose_path(
+            "DiagnosticLogs/sysdiagnose/sysdiagnose_2024.06.01.tar.gz"
+        ));
+        assert!(!is_sysdiagnose_path("DiagnosticLogs"));
+        assert!(!is_sysdiagnose_path("DiagnosticLogs/sysdiagnose_old.log"));
+        assert!(!is_sysdiagnose_path("Retired/sysdiagnose.ips"));
+    }
+}
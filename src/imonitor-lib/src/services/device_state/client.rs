@@ -0,0 +1,177 @@
+use super::errors::DeviceStateError;
+use crate::connection::ConnectionPriority;
+use crate::device::Device;
+use chrono::{DateTime, Utc};
+use idevice::{IdeviceService, lockdown::LockdownClient};
+use logger::{HasLogger, debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use tokio::fs::File;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::watch;
+use tokio::time::{Duration, sleep, timeout};
+
+const RETRY_CONNECT_WAIT_SECS: u64 = 30;
+const CHECK_INTERVAL_SECS: u64 = 300;
+const DEVICE_STATE_FILE_NAME: &str = "device_state.json";
+
+const AMFI_DOMAIN: &str = "com.apple.security.mac.amfi";
+const BATTERY_DOMAIN: &str = "com.apple.mobile.battery";
+const DISK_USAGE_DOMAIN: &str = "com.apple.disk_usage";
+
+/// Device state values queried from lockdown.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceState {
+    pub developer_mode_enabled: Option<bool>,
+    pub password_protected: Option<bool>,
+    pub battery_level: Option<u64>,
+    pub checked_at: DateTime<Utc>,
+}
+
+impl Device {
+    pub async fn check_device_state(
+        &self,
+        hb_connected_rx: &mut watch::Receiver<bool>,
+    ) -> Result<(), DeviceStateError> {
+        let provider = self.get_provider("device_state");
+
+        loop {
+            // Wait for heartbeat connected state
+            if hb_connected_rx.wait_for(|val| *val).await.is_ok()
+                && let Ok(connection) = self
+                    .limit_connect(
+                        ConnectionPriority::Low,
+                        timeout(Duration::from_secs(2), LockdownClient::connect(&*provider)),
+                    )
+                    .await
+            {
+                debug!(self, "Connecting to lockdown for device state");
+                match connection {
+                    Ok(mut client) => match self.query_device_state(&mut client).await {
+                        Ok(state) => {
+                            if state.developer_mode_enabled == Some(false) {
+                                warn!(
+                                    self,
+                                    "Developer mode is disabled, os trace and crash services may fail"
+                                );
+                            }
+                            info!(self, "Device state: {state:?}");
+                            if let Err(e) = self.update_device_state(state).await {
+                                error!(self, "Failed to write device state: {e}");
+                            }
+                            sleep(Duration::from_secs(CHECK_INTERVAL_SECS)).await;
+                        }
+                        Err(e) => {
+                            error!(self, "Failed to query device state: {e}");
+                            sleep(Duration::from_secs(RETRY_CONNECT_WAIT_SECS)).await;
+                        }
+                    },
+                    Err(e) => {
+                        error!(self, "Failed to connect to lockdown: {e}");
+                        sleep(Duration::from_secs(RETRY_CONNECT_WAIT_SECS)).await;
+                    }
+                }
+            } else {
+                debug!(self, "Lockdown connection timeout");
+                sleep(Duration::from_secs(RETRY_CONNECT_WAIT_SECS)).await;
+            }
+        }
+    }
+
+    async fn query_device_state(
+        &self,
+        client: &mut LockdownClient,
+    ) -> Result<DeviceState, DeviceStateError> {
+        client
+            .start_session(&self.connection.pairing_file)
+            .await
+            .map_err(DeviceStateError::StartSession)?;
+
+        // Key does not exist before iOS 16
+        let developer_mode_enabled = client
+            .get_value(Some("DeveloperModeStatus"), Some(AMFI_DOMAIN))
+            .await
+            .ok()
+            .and_then(|value| value.as_boolean());
+
+        let password_protected = client
+            .get_value(Some("PasswordProtected"), None)
+            .await
+            .map_err(|e| DeviceStateError::GetValue(e, "PasswordProtected".to_string()))?
+            .as_boolean();
+
+        let battery_level = client
+            .get_value(Some("BatteryCurrentCapacity"), Some(BATTERY_DOMAIN))
+            .await
+            .map_err(|e| DeviceStateError::GetValue(e, "BatteryCurrentCapacity".to_string()))?
+            .as_unsigned_integer();
+
+        Ok(DeviceState {
+            developer_mode_enabled,
+            password_protected,
+            battery_level,
+            checked_at: self.clock.now_utc(),
+        })
+    }
+
+    /// Bytes available in the device data partition, None if the device does not tell.
+    pub async fn query_available_storage(&self) -> Result<Option<u64>, DeviceStateError> {
+        let provider = self.get_provider("disk_usage");
+        let mut client = self
+            .limit_connect(
+                ConnectionPriority::Low,
+                timeout(Duration::from_secs(2), LockdownClient::connect(&*provider)),
+            )
+            .await
+            .map_err(|_| DeviceStateError::Timeout)?
+            .map_err(DeviceStateError::Connect)?;
+
+        client
+            .start_session(&self.connection.pairing_file)
+            .await
+            .map_err(DeviceStateError::StartSession)?;
+
+        Ok(client
+            .get_value(Some("AmountDataAvailable"), Some(DISK_USAGE_DOMAIN))
+            .await
+            .map_err(|e| DeviceStateError::GetValue(e, "AmountDataAvailable".to_string()))?
+            .as_unsigned_integer())
+    }
+
+    pub fn get_device_state_file_path(&self) -> String {
+        let info_dir = PathBuf::from(self.get_info_dir());
+        let file_path = info_dir.join(DEVICE_STATE_FILE_NAME);
+        file_path.to_string_lossy().to_string()
+    }
+
+    pub async fn update_device_state(&self, state: DeviceState) -> Result<(), DeviceStateError> {
+        let content =
+            serde_json::to_string_pretty(&state).map_err(DeviceStateError::SerializeState)?;
+
+        {
+            let mut device_state = self
+                .device_state
+                .write()
+                .map_err(|_| DeviceStateError::WriteLock)?;
+            *device_state = Some(state);
+        }
+
+        let state_file_path = self.get_device_state_file_path();
+
+        let dst_file = File::create(state_file_path.clone())
+            .await
+            .map_err(|e| DeviceStateError::CreateFile(e, state_file_path.clone()))?;
+
+        let mut writer = BufWriter::new(dst_file);
+
+        writer
+            .write_all(content.as_bytes())
+            .await
+            .map_err(|e| DeviceStateError::WriteToFile(e, state_file_path.clone()))?;
+
+        writer
+            .flush()
+            .await
+            .map_err(|e| DeviceStateError::WriteToFile(e, state_file_path.clone()))
+    }
+}
@@ -0,0 +1,48 @@
+use idevice::IdeviceError;
+
+#[derive(Debug)]
+pub enum DeviceStateError {
+    Connect(IdeviceError),
+    StartSession(IdeviceError),
+    GetValue(IdeviceError, String),
+    CreateFile(std::io::Error, String),
+    WriteToFile(std::io::Error, String),
+    SerializeState(serde_json::Error),
+    SerializeProductInfo(serde_json::Error),
+    SerializeTimezone(serde_json::Error),
+    WriteLock,
+    Timeout,
+}
+
+impl std::error::Error for DeviceStateError {}
+
+impl std::fmt::Display for DeviceStateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DeviceStateError::Connect(e) => write!(f, "Failed to connect to lockdown: {e}"),
+            DeviceStateError::StartSession(e) => {
+                write!(f, "Failed to start lockdown session: {e}")
+            }
+            DeviceStateError::GetValue(e, key) => {
+                write!(f, "Failed to get lockdown value {key}: {e}")
+            }
+            DeviceStateError::CreateFile(e, file_name) => {
+                write!(f, "Failed to create file {file_name}: {e}")
+            }
+            DeviceStateError::WriteToFile(e, file_name) => {
+                write!(f, "Failed to write to file {file_name}: {e}")
+            }
+            DeviceStateError::SerializeState(e) => {
+                write!(f, "Failed to serialize device state: {e}")
+            }
+            DeviceStateError::SerializeProductInfo(e) => {
+                write!(f, "Failed to serialize product info: {e}")
+            }
+            DeviceStateError::SerializeTimezone(e) => {
+                write!(f, "Failed to serialize timezone: {e}")
+            }
+            DeviceStateError::WriteLock => write!(f, "Failed acquiring device state write lock"),
+            DeviceStateError::Timeout => write!(f, "Lockdown connection timeout"),
+        }
+    }
+}
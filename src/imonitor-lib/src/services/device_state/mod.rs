@@ -0,0 +1,4 @@
+pub mod client;
+pub mod errors;
+pub mod product;
+pub mod timezone;
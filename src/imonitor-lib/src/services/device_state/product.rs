@@ -0,0 +1,126 @@
+use super::errors::DeviceStateError;
+use crate::connection::ConnectionPriority;
+use crate::device::Device;
+use chrono::{DateTime, Utc};
+use idevice::{IdeviceService, lockdown::LockdownClient};
+use logger::{HasLogger, warn};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use tokio::fs::{File, read_to_string};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::time::{Duration, timeout};
+
+const PRODUCT_INFO_FILE_NAME: &str = "product_info.json";
+
+/// Model and iOS version of a device, queried from lockdown at startup.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProductInfo {
+    /// Such as "iPhone15,2"
+    pub product_type: Option<String>,
+    /// Such as "17.4.1"
+    pub product_version: Option<String>,
+    pub queried_at: DateTime<Utc>,
+}
+
+impl Device {
+    /// Queries the model and iOS version and caches them in the info dir. The cached values
+    /// are used if the device cannot be queried.
+    pub async fn product_info(&self) -> Result<ProductInfo, DeviceStateError> {
+        match self.query_product_info().await {
+            Ok(product_info) => {
+                if let Err(e) = self.update_product_info(&product_info).await {
+                    warn!(self, "Failed to cache product info: {e}");
+                }
+                Ok(product_info)
+            }
+            Err(e) => match self.load_product_info().await {
+                Some(product_info) => {
+                    warn!(
+                        self,
+                        "Failed to query product info, using the one cached at {}: {e}",
+                        product_info.queried_at
+                    );
+                    Ok(product_info)
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    async fn query_product_info(&self) -> Result<ProductInfo, DeviceStateError> {
+        let provider = self.get_provider("product_info");
+        let mut client = self
+            .limit_connect(
+                ConnectionPriority::Low,
+                timeout(Duration::from_secs(2), LockdownClient::connect(&*provider)),
+            )
+            .await
+            .map_err(|_| DeviceStateError::Timeout)?
+            .map_err(DeviceStateError::Connect)?;
+
+        client
+            .start_session(&self.connection.pairing_file)
+            .await
+            .map_err(DeviceStateError::StartSession)?;
+
+        let product_type = client
+            .get_value(Some("ProductType"), None)
+            .await
+            .map_err(|e| DeviceStateError::GetValue(e, "ProductType".to_string()))?
+            .as_string()
+            .map(str::to_string);
+
+        let product_version = client
+            .get_value(Some("ProductVersion"), None)
+            .await
+            .map_err(|e| DeviceStateError::GetValue(e, "ProductVersion".to_string()))?
+            .as_string()
+            .map(str::to_string);
+
+        Ok(ProductInfo {
+            product_type,
+            product_version,
+            queried_at: self.clock.now_utc(),
+        })
+    }
+
+    pub fn get_product_info_file_path(&self) -> String {
+        let info_dir = PathBuf::from(self.get_info_dir());
+        let file_path = info_dir.join(PRODUCT_INFO_FILE_NAME);
+        file_path.to_string_lossy().to_string()
+    }
+
+    /// Returns the cached product info, None if missing or unreadable.
+    pub async fn load_product_info(&self) -> Option<ProductInfo> {
+        let content = read_to_string(self.get_product_info_file_path())
+            .await
+            .ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    async fn update_product_info(
+        &self,
+        product_info: &ProductInfo,
+    ) -> Result<(), DeviceStateError> {
+        let content = serde_json::to_string_pretty(product_info)
+            .map_err(DeviceStateError::SerializeProductInfo)?;
+
+        let product_info_file_path = self.get_product_info_file_path();
+
+        let dst_file = File::create(product_info_file_path.clone())
+            .await
+            .map_err(|e| DeviceStateError::CreateFile(e, product_info_file_path.clone()))?;
+
+        let mut writer = BufWriter::new(dst_file);
+
+        writer
+            .write_all(content.as_bytes())
+            .await
+            .map_err(|e| DeviceStateError::WriteToFile(e, product_info_file_path.clone()))?;
+
+        writer
+            .flush()
+            .await
+            .map_err(|e| DeviceStateError::WriteToFile(e, product_info_file_path.clone()))
+    }
+}
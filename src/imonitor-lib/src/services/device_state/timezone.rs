@@ -0,0 +1,210 @@
+use super::errors::DeviceStateError;
+use crate::connection::ConnectionPriority;
+use crate::device::Device;
+use chrono::{DateTime, FixedOffset, Utc};
+use idevice::{IdeviceService, lockdown::LockdownClient};
+use logger::{HasLogger, warn};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::{Duration as StdDuration, SystemTime};
+use tokio::fs::{File, read_to_string};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::time::{Duration, timeout};
+
+const TIMEZONE_FILE_NAME: &str = "timezone.json";
+
+/// Timezone of a device, queried from lockdown at startup.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceTimezone {
+    /// Such as "Europe/Paris"
+    pub time_zone: Option<String>,
+    /// Offset of the device local time from UTC when queried, DST included
+    pub utc_offset_secs: Option<i32>,
+    pub queried_at: DateTime<Utc>,
+}
+
+impl DeviceTimezone {
+    pub fn fixed_offset(&self) -> Option<FixedOffset> {
+        FixedOffset::east_opt(self.utc_offset_secs?)
+    }
+
+    /// The UTC time in the device local frame.
+    pub fn to_device_local(&self, time: DateTime<Utc>) -> Option<DateTime<FixedOffset>> {
+        Some(time.with_timezone(&self.fixed_offset()?))
+    }
+}
+
+/// UTC time of a timestamp in the device local frame, such as an os trace `WallTime`, in
+/// seconds since the epoch.
+pub fn local_to_utc(local_secs: u64, utc_offset_secs: i32) -> SystemTime {
+    let local = SystemTime::UNIX_EPOCH + StdDuration::from_secs(local_secs);
+    let offset = StdDuration::from_secs(utc_offset_secs.unsigned_abs() as u64);
+    if utc_offset_secs >= 0 {
+        local - offset
+    } else {
+        local + offset
+    }
+}
+
+impl Device {
+    /// Queries the timezone and caches it in the info dir. The cached value is used if the
+    /// device cannot be queried.
+    pub async fn device_timezone(&self) -> Result<DeviceTimezone, DeviceStateError> {
+        match self.query_device_timezone().await {
+            Ok(device_timezone) => {
+                if let Err(e) = self.update_device_timezone(&device_timezone).await {
+                    warn!(self, "Failed to cache timezone: {e}");
+                }
+                Ok(device_timezone)
+            }
+            Err(e) => match self.load_device_timezone().await {
+                Some(device_timezone) => {
+                    warn!(
+                        self,
+                        "Failed to query timezone, using the one cached at {}: {e}",
+                        device_timezone.queried_at
+                    );
+                    Ok(device_timezone)
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    async fn query_device_timezone(&self) -> Result<DeviceTimezone, DeviceStateError> {
+        let provider = self.get_provider("timezone");
+        let mut client = self
+            .limit_connect(
+                ConnectionPriority::Low,
+                timeout(Duration::from_secs(2), LockdownClient::connect(&*provider)),
+            )
+            .await
+            .map_err(|_| DeviceStateError::Timeout)?
+            .map_err(DeviceStateError::Connect)?;
+
+        client
+            .start_session(&self.connection.pairing_file)
+            .await
+            .map_err(DeviceStateError::StartSession)?;
+
+        let time_zone = client
+            .get_value(Some("TimeZone"), None)
+            .await
+            .map_err(|e| DeviceStateError::GetValue(e, "TimeZone".to_string()))?
+            .as_string()
+            .map(str::to_string);
+
+        let offset = client
+            .get_value(Some("TimeZoneOffsetFromUTC"), None)
+            .await
+            .map_err(|e| DeviceStateError::GetValue(e, "TimeZoneOffsetFromUTC".to_string()))?;
+        // A real number of seconds, an integer on some versions
+        let utc_offset_secs = offset
+            .as_real()
+            .map(|secs| secs.round() as i32)
+            .or_else(|| offset.as_signed_integer().map(|secs| secs as i32));
+
+        Ok(DeviceTimezone {
+            time_zone,
+            utc_offset_secs,
+            queried_at: self.clock.now_utc(),
+        })
+    }
+
+    pub fn get_timezone_file_path(&self) -> String {
+        let info_dir = PathBuf::from(self.get_info_dir());
+        let file_path = info_dir.join(TIMEZONE_FILE_NAME);
+        file_path.to_string_lossy().to_string()
+    }
+
+    /// Returns the cached timezone, None if missing or unreadable.
+    pub async fn load_device_timezone(&self) -> Option<DeviceTimezone> {
+        let content = read_to_string(self.get_timezone_file_path()).await.ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    async fn update_device_timezone(
+        &self,
+        device_timezone: &DeviceTimezone,
+    ) -> Result<(), DeviceStateError> {
+        let content = serde_json::to_string_pretty(device_timezone)
+            .map_err(DeviceStateError::SerializeTimezone)?;
+
+        let timezone_file_path = self.get_timezone_file_path();
+
+        let dst_file = File::create(timezone_file_path.clone())
+            .await
+            .map_err(|e| DeviceStateError::CreateFile(e, timezone_file_path.clone()))?;
+
+        let mut writer = BufWriter::new(dst_file);
+
+        writer
+            .write_all(content.as_bytes())
+            .await
+            .map_err(|e| DeviceStateError::WriteToFile(e, timezone_file_path.clone()))?;
+
+        writer
+            .flush()
+            .await
+            .map_err(|e| DeviceStateError::WriteToFile(e, timezone_file_path.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::test_support::test_device;
+
+    #[test]
+    fn wall_time_is_converted_with_a_fixed_offset() {
+        // 2023-11-14 23:13:20 in Paris (UTC+1) is 22:13:20 UTC
+        let wall_time = 1_700_003_600;
+        assert_eq!(
+            local_to_utc(wall_time, 3600),
+            SystemTime::UNIX_EPOCH + StdDuration::from_secs(1_700_000_000)
+        );
+        // West of UTC, such as New York (UTC-5)
+        assert_eq!(
+            local_to_utc(1_699_982_000, -5 * 3600),
+            SystemTime::UNIX_EPOCH + StdDuration::from_secs(1_700_000_000)
+        );
+
+        let device_timezone = DeviceTimezone {
+            time_zone: Some("Europe/Paris".to_string()),
+            utc_offset_secs: Some(3600),
+            queried_at: DateTime::<Utc>::from_timestamp(0, 0).unwrap(),
+        };
+        let utc = DateTime::<Utc>::from_timestamp(1_700_000_000, 0).unwrap();
+        let local = device_timezone.to_device_local(utc).unwrap();
+        assert_eq!(local.to_rfc3339(), "2023-11-14T23:13:20+01:00");
+        assert_eq!(local, utc);
+
+        let unknown = DeviceTimezone {
+            utc_offset_secs: None,
+            ..device_timezone
+        };
+        assert_eq!(unknown.to_device_local(utc), None);
+    }
+
+    #[tokio::test]
+    async fn cached_timezone_is_read_back() {
+        let (device, base_dir) = test_device("timezone");
+        assert!(device.load_device_timezone().await.is_none());
+
+        let device_timezone = DeviceTimezone {
+            time_zone: Some("America/New_York".to_string()),
+            utc_offset_secs: Some(-5 * 3600),
+            queried_at: DateTime::<Utc>::from_timestamp(1_700_000_000, 0).unwrap(),
+        };
+        device
+            .update_device_timezone(&device_timezone)
+            .await
+            .unwrap();
+        let cached = device.load_device_timezone().await.unwrap();
+        assert_eq!(cached.time_zone, device_timezone.time_zone);
+        assert_eq!(cached.utc_offset_secs, Some(-5 * 3600));
+        assert_eq!(cached.queried_at, device_timezone.queried_at);
+
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+}
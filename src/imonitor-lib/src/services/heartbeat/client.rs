@@ -1,20 +1,27 @@
 use super::errors::HeartbeatError;
-use crate::config::Config;
+use crate::config::{Config, HeartbeatConfig, HeartbeatTimeoutMode};
+use crate::connection::ConnectionPriority;
 use crate::device::Device;
-use chrono::Utc;
-use idevice::{IdeviceService, heartbeat::HeartbeatClient};
-use logger::{HasLogger, error, info};
-use std::path::PathBuf;
+use crate::state_store::{StateStore, decode_state};
+use chrono::{DateTime, Utc};
+use idevice::{IdeviceError, IdeviceService, heartbeat::HeartbeatClient};
+use logger::{HasLogger, debug, error, info, warn};
 use std::sync::{Arc, RwLock};
-use tokio::fs::File;
+use tokio::fs::{File, read};
 use tokio::io::{AsyncWriteExt, BufWriter};
 use tokio::sync::watch;
-use tokio::time::{Duration, sleep};
+use tokio::time::{Duration, Instant, sleep, timeout};
 
 const RETRY_CONNECT_WAIT_SECS: u64 = 30;
+const MAX_RETRY_CONNECT_WAIT_SECS: u64 = 1800;
+// Consecutive connection failures after which the device is considered offline
+pub(crate) const CIRCUIT_OPEN_FAILURES: u32 = 10;
 const HB_LAST_ESTABLISHED_FILE_NAME: &str = "heartbeat_last_established.json";
+const HB_FAILURES_FILE_NAME: &str = "heartbeat_failures.json";
 const HEARTBEAT_TIMEOUT_SEC: u64 = 7u64;
 const HEARTBEAT_NO_RESPONSE_CONSIDER_ALIVE_SEC: u64 = 420u64;
+// Spreads the first connections of a fleet restart
+const MAX_START_JITTER_MS: u64 = 5000;
 
 impl Device {
     pub async fn maintain_heartbeat(
@@ -23,65 +30,142 @@ impl Device {
         connected_sender: &watch::Sender<bool>,
     ) -> Result<(), HeartbeatError> {
         let mut interval;
+        let heartbeat_config;
+        let read_timeout;
         {
-            interval = config
-                .read()
-                .map_err(|_| HeartbeatError::ConfigReadLock)?
-                .settings
-                .clone()
-                .refresh_rate
-                .as_secs();
+            let config = config.read().map_err(|_| HeartbeatError::ConfigReadLock)?;
+            interval = config.settings.refresh_rate.as_secs();
+            heartbeat_config = config.heartbeat.clone();
+            read_timeout = config.settings.read_timeout;
         }
         let mut reconnect;
 
+        // Restored from fs so that a restart keeps backing off from a known dead device
+        let mut consecutive_failures = self.load_hb_failures().await;
+        self.load_hb_last_established().await;
+
         let provider = self.get_provider("heartbeat");
+
+        sleep(Duration::from_millis(rand::random_range(
+            0..=MAX_START_JITTER_MS,
+        )))
+        .await;
+
         loop {
             info!(self, "Connecting to heartbeat");
+            let hb_permit = self.acquire_heartbeat_permit().await;
+            let permit = self
+                .acquire_connection_permit(ConnectionPriority::High)
+                .await;
             tokio::select!(
                 // Force tokio not to select randomly the select! branches.
                 // It processes it in the appearing order
                 biased;
-                heartbeat_res = HeartbeatClient::connect(&*provider) => {
+                heartbeat_res = async {
+                    // Only hold the connection permits while connecting. They are also
+                    // released if the timeout branch wins
+                    let res = HeartbeatClient::connect(&*provider).await;
+                    drop(permit);
+                    drop(hb_permit);
+                    res
+                } => {
 
                 let mut heartbeat_client = match heartbeat_res {
                     Ok(client) => {
                         info!(self, "Heartbeat connection established");
                         reconnect = false;
+                        if consecutive_failures > 0 {
+                            consecutive_failures = 0;
+                            let _ = self.update_hb_failures(consecutive_failures).await;
+                        }
                         // Ignore error if not updated
                         let _ = self.update_hb_last_established().await;
-                        connected_sender
-                            .send(true)
+                        self.record_hb_history(true, &heartbeat_config).await;
+                        self.observer.on_heartbeat(&self.info.udid, true);
+                        self.touch_liveness().await;
+                        if !*connected_sender.borrow() {
+                            self.record_activity();
+                        }
+                        self.set_connected(connected_sender, true)
                             .map_err(HeartbeatError::SendConnectedState)?;
                         client
                     }
                     Err(e) => {
-                        error!(self, "Unable to connect to heartbeat: {e}");
-                        connected_sender
-                            .send(false)
+                        consecutive_failures = consecutive_failures.saturating_add(1);
+                        let retry_wait = retry_connect_wait(consecutive_failures);
+                        if consecutive_failures < CIRCUIT_OPEN_FAILURES {
+                            error!(self, "Unable to connect to heartbeat: {e}");
+                        } else if consecutive_failures == CIRCUIT_OPEN_FAILURES {
+                            warn!(
+                                self,
+                                "Unable to connect to heartbeat {consecutive_failures} times in a row, device seems offline. Retrying every {}s: {e}",
+                                retry_wait.as_secs()
+                            );
+                        } else {
+                            debug!(self, "Unable to connect to heartbeat: {e}");
+                        }
+                        // Ignore error if not updated
+                        let _ = self.update_hb_failures(consecutive_failures).await;
+                        self.record_hb_history(false, &heartbeat_config).await;
+                        self.observer.on_service_error(&self.info.udid, "heartbeat", &e);
+                        self.observer.on_heartbeat(&self.info.udid, false);
+                        if *connected_sender.borrow() {
+                            self.record_activity();
+                        }
+                        self.set_connected(connected_sender, false)
                             .map_err(HeartbeatError::SendConnectedState)?;
-                        sleep(Duration::from_secs(RETRY_CONNECT_WAIT_SECS)).await;
+                        sleep(retry_wait).await;
                         continue;
                     }
                 };
 
+                // The device sends the next marco its announced interval after a polo
+                let mut polo_sent_at = None;
+                let mut announced_interval = 0;
                 while !reconnect {
-                    match heartbeat_client.get_marco(interval).await {
+                    // Beyond the interval, the connection is considered half-open
+                    let marco = timeout(
+                        Duration::from_secs(interval) + read_timeout,
+                        heartbeat_client.get_marco(interval),
+                    )
+                    .await
+                    .unwrap_or(Err(IdeviceError::HeartbeatTimeout));
+                    match marco {
                         Ok(new_interval) => {
                             info!(self, "Heartbeat ok. Interval: {new_interval}");
+                            self.touch_liveness().await;
+                            if let Some(polo_sent_at) = polo_sent_at.take() {
+                                self.record_hb_latency(
+                                    Instant::now()
+                                        .duration_since(polo_sent_at)
+                                        .saturating_sub(Duration::from_secs(announced_interval)),
+                                );
+                            }
+                            announced_interval = new_interval;
+                            let new_interval =
+                                self.clamp_hb_interval(new_interval, &heartbeat_config);
                             // Wait for message interval + 5 (in case of network failure)
                             interval = new_interval + 5;
                         }
                         Err(e) => {
                             info!(self, "Error getting marco: {e}");
                             reconnect = true;
-                            connected_sender
-                                .send(false)
+                            self.record_hb_history(false, &heartbeat_config).await;
+                            self.observer.on_heartbeat(&self.info.udid, false);
+                            if *connected_sender.borrow() {
+                                self.record_activity();
+                            }
+                            self.set_connected(connected_sender, false)
                                 .map_err(HeartbeatError::SendConnectedState)?;
                         }
                     };
 
-                    if !reconnect && let Err(e) = heartbeat_client.send_polo().await {
-                        info!(self, "Error sending polo: {e}");
+                    if !reconnect {
+                        match timeout(read_timeout, heartbeat_client.send_polo()).await {
+                            Ok(Ok(())) => polo_sent_at = Some(Instant::now()),
+                            Ok(Err(e)) => info!(self, "Error sending polo: {e}"),
+                            Err(_) => info!(self, "Timeout sending polo"),
+                        }
                     }
                 }
                 sleep(Duration::from_secs(RETRY_CONNECT_WAIT_SECS)).await;
@@ -94,9 +178,15 @@ impl Device {
                         // ok.
                         // TODO: dynamically hook lockdownd to reproduce the bug
                         sleep(Duration::from_secs(HEARTBEAT_TIMEOUT_SEC)).await;
+                        if !self.hb_alive_on_timeout(&heartbeat_config) {
+                            // A device gone for long would keep the services connecting
+                            // in vain during the whole consider alive window
+                            info!(self, "Timeout while connecting to heartbeat, retrying");
+                            sleep(Duration::from_secs(RETRY_CONNECT_WAIT_SECS)).await;
+                            return Ok(());
+                        }
                         info!(self, "Timeout while connecting to heartbeat, trying to use services either way");
-                        connected_sender
-                            .send(true)
+                        self.set_connected(connected_sender, true)
                             .map_err(HeartbeatError::SendConnectedState)?;
                         // If we don't receive an answer after a while, we consider the connection
                         // alive
@@ -114,14 +204,134 @@ impl Device {
         }
     }
 
+    /// Interval the next marco is waited for, from the one announced by the device. Raised
+    /// to `min_interval`, but never below the announced one: the device only sends the next
+    /// marco once it elapsed, a shorter wait would reconnect in a loop. An interval beyond
+    /// `max_interval` is only reported.
+    fn clamp_hb_interval(&self, interval: u64, heartbeat_config: &HeartbeatConfig) -> u64 {
+        let min = heartbeat_config.min_interval.as_secs();
+        let max = heartbeat_config.max_interval.as_secs().max(min);
+        if interval < min {
+            warn!(
+                self,
+                "Device announced a heartbeat interval of {interval}s, using {min}s"
+            );
+            return min;
+        }
+        if interval > max {
+            warn!(
+                self,
+                "Device announced a heartbeat interval of {interval}s, beyond max_interval of {max}s"
+            );
+        }
+        interval
+    }
+
+    fn record_hb_latency(&self, latency: Duration) {
+        match self.heartbeat.latency.write() {
+            Ok(mut window) => window.record(latency),
+            Err(_) => error!(self, "Failed to record heartbeat latency, skipping"),
+        }
+        self.observer.on_heartbeat_latency(&self.info.udid, latency);
+    }
+
+    /// Best effort, a failed write never stops the heartbeat.
+    async fn record_hb_history(&self, connected: bool, heartbeat_config: &HeartbeatConfig) {
+        if let Err(e) = self.record_hb_transition(connected, heartbeat_config).await {
+            warn!(self, "Failed to update the heartbeat history: {e}");
+        }
+    }
+
+    /// Best effort, a failed write never stops the heartbeat.
+    async fn touch_liveness(&self) {
+        if let Some(liveness) = &self.liveness
+            && let Err(e) = liveness
+                .record_heartbeat(&self.info.udid, self.clock.now_utc())
+                .await
+        {
+            warn!(self, "Failed to update the liveness file: {e}");
+        }
+    }
+
+    pub fn get_hb_failures_file_path(&self) -> String {
+        self.get_state_file_path(self.get_heartbeat_dir(), HB_FAILURES_FILE_NAME)
+    }
+
+    /// Returns the persisted number of consecutive connection failures, 0 if unknown.
+    pub async fn load_hb_failures(&self) -> u32 {
+        match read(self.get_hb_failures_file_path()).await {
+            Ok(content) => decode_state(&content).unwrap_or_default(),
+            Err(_) => 0,
+        }
+    }
+
+    pub async fn update_hb_failures(&self, failures: u32) -> Result<(), HeartbeatError> {
+        let failures_file_path_string = self.get_hb_failures_file_path();
+
+        let content = self
+            .state_format
+            .encode(&failures)
+            .map_err(HeartbeatError::SerializeFailures)?;
+
+        let dst_file = File::create(failures_file_path_string.clone())
+            .await
+            .map_err(|e| HeartbeatError::CreateFile(e, failures_file_path_string.clone()))?;
+
+        let mut writer = BufWriter::new(dst_file);
+
+        writer
+            .write_all(&content)
+            .await
+            .map_err(|e| HeartbeatError::WriteToFile(e, failures_file_path_string.clone()))?;
+
+        writer
+            .flush()
+            .await
+            .map_err(|e| HeartbeatError::WriteToFile(e, failures_file_path_string.clone()))
+    }
+
     pub fn get_hb_last_established_file_path(&self) -> String {
-        let crashes_dir = PathBuf::from(self.get_heartbeat_dir());
-        let file_path = crashes_dir.join(HB_LAST_ESTABLISHED_FILE_NAME);
-        file_path.to_string_lossy().to_string()
+        self.get_state_file_path(self.get_heartbeat_dir(), HB_LAST_ESTABLISHED_FILE_NAME)
+    }
+
+    /// Restores the last established date persisted by a previous run, if more recent.
+    pub async fn load_hb_last_established(&self) {
+        let Ok(content) = read(self.get_hb_last_established_file_path()).await else {
+            return;
+        };
+        let Ok(date) = decode_state::<DateTime<Utc>>(&content) else {
+            return;
+        };
+        if let Ok(mut last_established) = self.heartbeat.last_established.write()
+            && date > *last_established
+        {
+            *last_established = date;
+        }
+    }
+
+    /// Whether the device is considered alive when the heartbeat connection times out.
+    pub fn hb_alive_on_timeout(&self, heartbeat_config: &HeartbeatConfig) -> bool {
+        match heartbeat_config.timeout_alive {
+            HeartbeatTimeoutMode::Off => false,
+            HeartbeatTimeoutMode::On => true,
+            HeartbeatTimeoutMode::RecentlySeen => {
+                self.hb_established_within(heartbeat_config.recently_seen)
+            }
+        }
+    }
+
+    /// Whether the heartbeat was established less than `duration` ago.
+    pub fn hb_established_within(&self, duration: Duration) -> bool {
+        let Ok(last_established) = self.heartbeat.last_established.read() else {
+            return false;
+        };
+        (self.clock.now_utc() - *last_established)
+            .to_std()
+            .is_ok_and(|elapsed| elapsed <= duration)
     }
 
     pub async fn update_hb_last_established(&self) -> Result<(), HeartbeatError> {
-        let now = Utc::now();
+        let now = self.clock.now_utc();
 
         match self.heartbeat.last_established.clone().write() {
             Ok(mut le) => *le = now,
@@ -135,7 +345,10 @@ impl Device {
 
         let heartbeat_file_path_string = self.get_hb_last_established_file_path();
 
-        let content = serde_json::to_string_pretty(&now).map_err(HeartbeatError::SerializeDate)?;
+        let content = self
+            .state_format
+            .encode(&now)
+            .map_err(HeartbeatError::SerializeDate)?;
 
         let dst_file = File::create(heartbeat_file_path_string.clone())
             .await
@@ -144,7 +357,7 @@ impl Device {
         let mut writer = BufWriter::new(dst_file);
 
         writer
-            .write_all(content.as_bytes())
+            .write_all(&content)
             .await
             .map_err(|e| HeartbeatError::WriteToFile(e, heartbeat_file_path_string.clone()))?;
 
@@ -154,3 +367,136 @@ impl Device {
             .map_err(|e| HeartbeatError::WriteToFile(e, heartbeat_file_path_string.clone()))
     }
 }
+
+/// Exponential backoff, capped. Always the maximum once the circuit is open.
+fn retry_connect_wait(consecutive_failures: u32) -> Duration {
+    if consecutive_failures >= CIRCUIT_OPEN_FAILURES {
+        return Duration::from_secs(MAX_RETRY_CONNECT_WAIT_SECS);
+    }
+    let factor = 1u64 << consecutive_failures.saturating_sub(1).min(16);
+    Duration::from_secs(
+        RETRY_CONNECT_WAIT_SECS
+            .saturating_mul(factor)
+            .min(MAX_RETRY_CONNECT_WAIT_SECS),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, MockClock};
+    use crate::device::test_support::test_device;
+    use crate::liveness::LivenessFile;
+    use std::collections::BTreeMap;
+    use std::time::SystemTime;
+
+    #[test]
+    fn announced_interval_is_raised_to_the_minimum_only() {
+        let (device, base_dir) = test_device("hb-interval");
+        let heartbeat_config = HeartbeatConfig {
+            min_interval: Duration::from_secs(5),
+            max_interval: Duration::from_secs(300),
+            ..Default::default()
+        };
+
+        assert_eq!(device.clamp_hb_interval(0, &heartbeat_config), 5);
+        assert_eq!(device.clamp_hb_interval(10, &heartbeat_config), 10);
+        // Waiting less would time out before the next marco
+        assert_eq!(device.clamp_hb_interval(3600, &heartbeat_config), 3600);
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn last_established_follows_the_device_clock() {
+        let (mut device, base_dir) = test_device("hb-clock");
+        let clock = Arc::new(MockClock::new(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        ));
+        device.set_clock(clock.clone());
+
+        device.update_hb_last_established().await.unwrap();
+        assert!(device.hb_established_within(Duration::from_secs(60)));
+
+        clock.advance(Duration::from_secs(61));
+        assert!(!device.hb_established_within(Duration::from_secs(60)));
+        assert!(device.hb_established_within(Duration::from_secs(61)));
+
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn timeout_keepalive_modes() {
+        let (mut device, base_dir) = test_device("hb-timeout-alive");
+        let clock = Arc::new(MockClock::new(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        ));
+        device.set_clock(clock.clone());
+        let config_for = |timeout_alive| HeartbeatConfig {
+            timeout_alive,
+            recently_seen: Duration::from_secs(3600),
+            ..Default::default()
+        };
+        let off = config_for(HeartbeatTimeoutMode::Off);
+        let on = config_for(HeartbeatTimeoutMode::On);
+        let recently_seen = config_for(HeartbeatTimeoutMode::RecentlySeen);
+
+        // Never established
+        assert!(!device.hb_alive_on_timeout(&off));
+        assert!(device.hb_alive_on_timeout(&on));
+        assert!(!device.hb_alive_on_timeout(&recently_seen));
+
+        device.update_hb_last_established().await.unwrap();
+        clock.advance(Duration::from_secs(60));
+        assert!(!device.hb_alive_on_timeout(&off));
+        assert!(device.hb_alive_on_timeout(&on));
+        assert!(device.hb_alive_on_timeout(&recently_seen));
+
+        // Gone for hours
+        clock.advance(Duration::from_secs(4 * 3600));
+        assert!(!device.hb_alive_on_timeout(&off));
+        assert!(device.hb_alive_on_timeout(&on));
+        assert!(!device.hb_alive_on_timeout(&recently_seen));
+
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn liveness_file_advances_on_each_heartbeat() {
+        let (mut device, base_dir) = test_device("hb-liveness");
+        let clock = Arc::new(MockClock::new(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        ));
+        device.set_clock(clock.clone());
+        let liveness_path = base_dir.join("liveness.json");
+        let liveness = Arc::new(LivenessFile::new(&liveness_path));
+        device.liveness = Some(liveness.clone());
+
+        device.touch_liveness().await;
+        let first_mtime = std::fs::metadata(&liveness_path)
+            .unwrap()
+            .modified()
+            .unwrap();
+
+        sleep(Duration::from_millis(20)).await;
+        clock.advance(Duration::from_secs(30));
+        device.touch_liveness().await;
+        let second_mtime = std::fs::metadata(&liveness_path)
+            .unwrap()
+            .modified()
+            .unwrap();
+        assert!(second_mtime > first_mtime);
+
+        let content = std::fs::read(&liveness_path).unwrap();
+        let last_heartbeats: BTreeMap<String, DateTime<Utc>> =
+            serde_json::from_slice(&content).unwrap();
+        assert_eq!(last_heartbeats[&device.info.udid], clock.now_utc());
+
+        // Best effort
+        device.liveness = Some(Arc::new(LivenessFile::new(
+            base_dir.join("missing/liveness"),
+        )));
+        device.touch_liveness().await;
+
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+}
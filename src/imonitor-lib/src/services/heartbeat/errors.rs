@@ -1,3 +1,4 @@
+use crate::state_store::StateStoreError;
 use idevice::IdeviceError;
 
 #[derive(Debug)]
@@ -8,7 +9,9 @@ pub enum HeartbeatError {
     UnexpectedError(IdeviceError),
     WriteToFile(std::io::Error, String),
     CreateFile(std::io::Error, String),
-    SerializeDate(serde_json::Error),
+    SerializeDate(StateStoreError),
+    SerializeFailures(StateStoreError),
+    SerializeHistory(serde_json::Error),
     SendConnectedState(tokio::sync::watch::error::SendError<bool>),
     ConfigReadLock,
 }
@@ -35,6 +38,12 @@ impl std::fmt::Display for HeartbeatError {
             HeartbeatError::SerializeDate(e) => {
                 write!(f, "Failed to serialize date: {e}")
             }
+            HeartbeatError::SerializeHistory(e) => {
+                write!(f, "Failed to serialize heartbeat transition: {e}")
+            }
+            HeartbeatError::SerializeFailures(e) => {
+                write!(f, "Failed to serialize connection failures count: {e}")
+            }
         }
     }
 }
@@ -0,0 +1,193 @@
+use super::errors::HeartbeatError;
+use crate::config::HeartbeatConfig;
+use crate::device::Device;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+use tokio::fs::{OpenOptions, read_to_string, rename, write};
+use tokio::io::AsyncWriteExt;
+
+const HB_HISTORY_FILE_NAME: &str = "history.ndjson";
+
+/// A line of `heartbeat/history.ndjson`, written when the heartbeat connects or drops.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HbTransition {
+    pub ts: DateTime<Utc>,
+    pub connected: bool,
+}
+
+impl Device {
+    pub fn get_hb_history_file_path(&self) -> String {
+        self.get_state_file_path(self.get_heartbeat_dir(), HB_HISTORY_FILE_NAME)
+    }
+
+    /// Returns the recorded transitions, oldest first. Unreadable lines are skipped.
+    pub async fn load_hb_history(&self) -> Vec<HbTransition> {
+        match read_to_string(self.get_hb_history_file_path()).await {
+            Ok(content) => parse_history(&content),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Appends a transition, unless the last one recorded is already in that state. The
+    /// transitions older than the retention are dropped, except the last of them which
+    /// gives the state at the start of the retention window.
+    pub async fn record_hb_transition(
+        &self,
+        connected: bool,
+        heartbeat_config: &HeartbeatConfig,
+    ) -> Result<(), HeartbeatError> {
+        if !heartbeat_config.history {
+            return Ok(());
+        }
+
+        let file_path = self.get_hb_history_file_path();
+        let history = self.load_hb_history().await;
+        if history
+            .last()
+            .is_some_and(|last| last.connected == connected)
+        {
+            return Ok(());
+        }
+
+        let now = self.clock.now_utc();
+        let transition = HbTransition { ts: now, connected };
+        let kept = match chrono::Duration::from_std(heartbeat_config.history_retention) {
+            Ok(retention) => trim_history(&history, now - retention),
+            Err(_) => &history[..],
+        };
+
+        if kept.len() == history.len() {
+            let line = to_line(&transition)?;
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&file_path)
+                .await
+                .map_err(|e| HeartbeatError::CreateFile(e, file_path.clone()))?;
+            return file
+                .write_all(line.as_bytes())
+                .await
+                .map_err(|e| HeartbeatError::WriteToFile(e, file_path));
+        }
+
+        // Rewritten once trimmed, renamed so that readers never see a partial file
+        let mut content = String::new();
+        for transition in kept.iter().chain([&transition]) {
+            content.push_str(&to_line(transition)?);
+        }
+        let tmp_file_path = format!("{file_path}.tmp");
+        write(&tmp_file_path, content)
+            .await
+            .map_err(|e| HeartbeatError::CreateFile(e, tmp_file_path.clone()))?;
+        rename(&tmp_file_path, &file_path)
+            .await
+            .map_err(|e| HeartbeatError::WriteToFile(e, file_path))
+    }
+}
+
+fn to_line(transition: &HbTransition) -> Result<String, HeartbeatError> {
+    let mut line = serde_json::to_string(transition).map_err(HeartbeatError::SerializeHistory)?;
+    line.push('\n');
+    Ok(line)
+}
+
+fn parse_history(content: &str) -> Vec<HbTransition> {
+    content
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect()
+}
+
+/// Transitions from the last one before `cutoff` on.
+fn trim_history(history: &[HbTransition], cutoff: DateTime<Utc>) -> &[HbTransition] {
+    let first_recent = history.partition_point(|transition| transition.ts < cutoff);
+    &history[first_recent.saturating_sub(1)..]
+}
+
+/// Share of the window the heartbeat was connected, only accounting for the time after the
+/// first transition. None if no time of the window is known.
+pub fn uptime_ratio(history: &[HbTransition], window: Range<DateTime<Utc>>) -> Option<f64> {
+    let mut known = chrono::Duration::zero();
+    let mut connected = chrono::Duration::zero();
+
+    for (i, transition) in history.iter().enumerate() {
+        let end = history.get(i + 1).map_or(window.end, |next| next.ts);
+        let start = transition.ts.max(window.start);
+        let end = end.min(window.end);
+        if end <= start {
+            continue;
+        }
+        known += end - start;
+        if transition.connected {
+            connected += end - start;
+        }
+    }
+
+    (known.num_milliseconds() > 0)
+        .then(|| connected.num_milliseconds() as f64 / known.num_milliseconds() as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(hour: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 1, 1, hour, 0, 0).unwrap()
+    }
+
+    fn transition(hour: u32, connected: bool) -> HbTransition {
+        HbTransition {
+            ts: at(hour),
+            connected,
+        }
+    }
+
+    #[test]
+    fn uptime_ratio_only_counts_known_time() {
+        let history = [
+            transition(2, true),
+            transition(5, false),
+            transition(6, true),
+        ];
+
+        // Nothing is known before 2h: 3h connected, 1h disconnected, 2h connected
+        assert_eq!(uptime_ratio(&history, at(0)..at(8)), Some(5.0 / 6.0));
+        assert_eq!(uptime_ratio(&history, at(5)..at(6)), Some(0.0));
+        assert_eq!(uptime_ratio(&history, at(0)..at(1)), None);
+        assert_eq!(uptime_ratio(&[], at(0)..at(8)), None);
+    }
+
+    #[test]
+    fn uptime_ratio_clips_transitions_to_the_window() {
+        let history = [transition(0, false), transition(4, true)];
+        assert_eq!(uptime_ratio(&history, at(2)..at(6)), Some(0.5));
+    }
+
+    #[test]
+    fn trim_keeps_the_last_transition_before_the_cutoff() {
+        let history = [
+            transition(1, true),
+            transition(2, false),
+            transition(5, true),
+        ];
+
+        assert_eq!(trim_history(&history, at(3)), &history[1..]);
+        assert_eq!(trim_history(&history, at(0)), &history[..]);
+        assert_eq!(trim_history(&history, at(6)), &history[2..]);
+    }
+
+    #[test]
+    fn unreadable_history_lines_are_skipped() {
+        let content = format!(
+            "{}not json\n{}",
+            to_line(&transition(1, true)).unwrap(),
+            to_line(&transition(2, false)).unwrap()
+        );
+        assert_eq!(
+            parse_history(&content),
+            [transition(1, true), transition(2, false)]
+        );
+    }
+}
@@ -0,0 +1,90 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Number of marco/polo round trips the rolling latency is computed over.
+pub const HB_LATENCY_WINDOW: usize = 20;
+
+/// Latency of the last marco/polo round trips of a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct LatencyStats {
+    pub min_ms: u64,
+    pub avg_ms: u64,
+    pub max_ms: u64,
+    pub samples: usize,
+}
+
+/// Last round trip latencies, the oldest dropped beyond the window.
+#[derive(Debug, Clone)]
+pub struct LatencyWindow {
+    samples: VecDeque<Duration>,
+    capacity: usize,
+}
+
+impl LatencyWindow {
+    pub fn new(capacity: usize) -> LatencyWindow {
+        LatencyWindow {
+            samples: VecDeque::with_capacity(capacity),
+            capacity: capacity.max(1),
+        }
+    }
+
+    pub fn record(&mut self, latency: Duration) {
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(latency);
+    }
+
+    /// None until a round trip is recorded.
+    pub fn stats(&self) -> Option<LatencyStats> {
+        let min = self.samples.iter().min()?;
+        let max = self.samples.iter().max()?;
+        let total = self.samples.iter().sum::<Duration>();
+        Some(LatencyStats {
+            min_ms: min.as_millis() as u64,
+            avg_ms: (total / self.samples.len() as u32).as_millis() as u64,
+            max_ms: max.as_millis() as u64,
+            samples: self.samples.len(),
+        })
+    }
+}
+
+impl Default for LatencyWindow {
+    fn default() -> Self {
+        Self::new(HB_LATENCY_WINDOW)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_cover_the_last_round_trips_only() {
+        let mut window = LatencyWindow::new(3);
+        assert_eq!(window.stats(), None);
+
+        for ms in [500, 10, 20, 30] {
+            window.record(Duration::from_millis(ms));
+        }
+        assert_eq!(
+            window.stats(),
+            Some(LatencyStats {
+                min_ms: 10,
+                avg_ms: 20,
+                max_ms: 30,
+                samples: 3,
+            })
+        );
+    }
+
+    #[test]
+    fn zero_capacity_keeps_the_last_round_trip() {
+        let mut window = LatencyWindow::new(0);
+        window.record(Duration::from_millis(10));
+        window.record(Duration::from_millis(40));
+        assert_eq!(window.stats().map(|stats| stats.max_ms), Some(40));
+        assert_eq!(window.stats().map(|stats| stats.samples), Some(1));
+    }
+}
@@ -1,2 +1,6 @@
 mod client;
 pub mod errors;
+pub mod history;
+pub mod latency;
+
+pub(crate) use client::CIRCUIT_OPEN_FAILURES;
@@ -0,0 +1,224 @@
+use super::errors::InstalledAppsError;
+use crate::config::InstalledAppsConfig;
+use crate::connection::ConnectionPriority;
+use crate::device::Device;
+use chrono::{DateTime, Utc};
+use idevice::{IdeviceService, installation_proxy::InstallationProxyClient};
+use logger::{HasLogger, debug, error, info};
+use plist::Value;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use tokio::fs::{read_to_string, rename, write};
+use tokio::sync::watch;
+use tokio::time::{Duration, sleep, timeout};
+
+const RETRY_CONNECT_WAIT_SECS: u64 = 30;
+const INSTALLED_APPS_FILE_NAME: &str = "installed_apps.json";
+
+/// An app installed on the device.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InstalledApp {
+    pub bundle_id: String,
+    pub name: Option<String>,
+    /// `CFBundleShortVersionString`, such as "2.4.1"
+    pub version: Option<String>,
+    /// `CFBundleVersion`
+    pub build: Option<String>,
+    /// "User" or "System"
+    pub app_type: Option<String>,
+}
+
+/// Content of `info/installed_apps.json`, only rewritten when the list changes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstalledApps {
+    /// When the list was last found different
+    pub changed_at: DateTime<Utc>,
+    /// Sorted by bundle id
+    pub apps: Vec<InstalledApp>,
+}
+
+impl Device {
+    pub async fn snapshot_installed_apps(
+        &self,
+        installed_apps_config: InstalledAppsConfig,
+        hb_connected_rx: &mut watch::Receiver<bool>,
+    ) -> Result<(), InstalledAppsError> {
+        let mut known = self.load_installed_apps().await.map(|known| known.apps);
+
+        loop {
+            self.wait_unpaused().await;
+            // Wait for heartbeat connected state
+            if hb_connected_rx.wait_for(|val| *val).await.is_err() {
+                sleep(Duration::from_secs(RETRY_CONNECT_WAIT_SECS)).await;
+                continue;
+            }
+
+            let apps = match self.query_installed_apps().await {
+                Ok(apps) => apps,
+                Err(e) => {
+                    error!(self, "Failed to query installed apps: {e}");
+                    sleep(Duration::from_secs(RETRY_CONNECT_WAIT_SECS)).await;
+                    continue;
+                }
+            };
+
+            if known.as_ref() == Some(&apps) {
+                debug!(self, "Installed apps unchanged");
+            } else {
+                info!(self, "Installed apps changed, {} app(s)", apps.len());
+                let installed_apps = InstalledApps {
+                    changed_at: self.clock.now_utc(),
+                    apps,
+                };
+                match self.update_installed_apps(&installed_apps).await {
+                    Ok(()) => known = Some(installed_apps.apps),
+                    Err(e) => error!(self, "Failed to write installed apps: {e}"),
+                }
+            }
+
+            sleep(installed_apps_config.interval).await;
+        }
+    }
+
+    async fn query_installed_apps(&self) -> Result<Vec<InstalledApp>, InstalledAppsError> {
+        let provider = self.get_provider("installed_apps");
+        let mut client = self
+            .limit_connect(
+                ConnectionPriority::Low,
+                timeout(
+                    Duration::from_secs(2),
+                    InstallationProxyClient::connect(&*provider),
+                ),
+            )
+            .await
+            .map_err(|_| InstalledAppsError::Timeout)?
+            .map_err(InstalledAppsError::Connect)?;
+
+        let apps = client
+            .get_apps(None, None)
+            .await
+            .map_err(InstalledAppsError::ListApps)?;
+
+        let mut apps = apps
+            .into_iter()
+            .map(|(bundle_id, info)| installed_app(bundle_id, &info))
+            .collect::<Vec<InstalledApp>>();
+        apps.sort_by(|a, b| a.bundle_id.cmp(&b.bundle_id));
+        Ok(apps)
+    }
+
+    pub fn get_installed_apps_file_path(&self) -> String {
+        let info_dir = PathBuf::from(self.get_info_dir());
+        let file_path = info_dir.join(INSTALLED_APPS_FILE_NAME);
+        file_path.to_string_lossy().to_string()
+    }
+
+    /// Returns the last snapshot, None if missing or unreadable.
+    pub async fn load_installed_apps(&self) -> Option<InstalledApps> {
+        let content = read_to_string(self.get_installed_apps_file_path())
+            .await
+            .ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    async fn update_installed_apps(
+        &self,
+        installed_apps: &InstalledApps,
+    ) -> Result<(), InstalledAppsError> {
+        let content =
+            serde_json::to_string_pretty(installed_apps).map_err(InstalledAppsError::Serialize)?;
+
+        // Renamed once written, readers never see a partial file
+        let file_path = self.get_installed_apps_file_path();
+        let tmp_file_path = format!("{file_path}.tmp");
+        write(&tmp_file_path, content)
+            .await
+            .map_err(|e| InstalledAppsError::CreateFile(e, tmp_file_path.clone()))?;
+        rename(&tmp_file_path, &file_path)
+            .await
+            .map_err(|e| InstalledAppsError::WriteToFile(e, file_path))
+    }
+}
+
+fn installed_app(bundle_id: String, info: &Value) -> InstalledApp {
+    let string = |key: &str| {
+        info.as_dictionary()
+            .and_then(|dict| dict.get(key))
+            .and_then(Value::as_string)
+            .map(str::to_string)
+    };
+
+    InstalledApp {
+        name: string("CFBundleDisplayName").or_else(|| string("CFBundleName")),
+        version: string("CFBundleShortVersionString"),
+        build: string("CFBundleVersion"),
+        app_type: string("ApplicationType"),
+        bundle_id,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::test_support::test_device;
+    use plist::Dictionary;
+
+    #[test]
+    fn app_fields_are_read_from_the_info_dictionary() {
+        let mut info = Dictionary::new();
+        info.insert("CFBundleName".to_string(), Value::from("Maps"));
+        info.insert(
+            "CFBundleShortVersionString".to_string(),
+            Value::from("2.4.1"),
+        );
+        info.insert("CFBundleVersion".to_string(), Value::from("241"));
+        info.insert("ApplicationType".to_string(), Value::from("System"));
+        let app = installed_app(
+            "com.apple.Maps".to_string(),
+            &Value::Dictionary(info.clone()),
+        );
+        assert_eq!(
+            app,
+            InstalledApp {
+                bundle_id: "com.apple.Maps".to_string(),
+                name: Some("Maps".to_string()),
+                version: Some("2.4.1".to_string()),
+                build: Some("241".to_string()),
+                app_type: Some("System".to_string()),
+            }
+        );
+
+        // The display name wins over the bundle name
+        info.insert("CFBundleDisplayName".to_string(), Value::from("Plans"));
+        let app = installed_app("com.apple.Maps".to_string(), &Value::Dictionary(info));
+        assert_eq!(app.name.as_deref(), Some("Plans"));
+
+        let app = installed_app("com.example.app".to_string(), &Value::Boolean(true));
+        assert_eq!(app.name, None);
+        assert_eq!(app.version, None);
+    }
+
+    #[tokio::test]
+    async fn snapshot_is_read_back() {
+        let (device, base_dir) = test_device("installed-apps");
+        assert!(device.load_installed_apps().await.is_none());
+
+        let installed_apps = InstalledApps {
+            changed_at: DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+            apps: vec![InstalledApp {
+                bundle_id: "com.example.app".to_string(),
+                name: Some("Example".to_string()),
+                version: None,
+                build: Some("1".to_string()),
+                app_type: Some("User".to_string()),
+            }],
+        };
+        device.update_installed_apps(&installed_apps).await.unwrap();
+
+        let loaded = device.load_installed_apps().await.unwrap();
+        assert_eq!(loaded.changed_at, installed_apps.changed_at);
+        assert_eq!(loaded.apps, installed_apps.apps);
+        assert!(!PathBuf::from(format!("{}.tmp", device.get_installed_apps_file_path())).exists());
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+}
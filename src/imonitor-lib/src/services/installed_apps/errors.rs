@@ -0,0 +1,34 @@
+use idevice::IdeviceError;
+
+#[derive(Debug)]
+pub enum InstalledAppsError {
+    Connect(IdeviceError),
+    ListApps(IdeviceError),
+    CreateFile(std::io::Error, String),
+    WriteToFile(std::io::Error, String),
+    Serialize(serde_json::Error),
+    Timeout,
+}
+
+impl std::error::Error for InstalledAppsError {}
+
+impl std::fmt::Display for InstalledAppsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            InstalledAppsError::Connect(e) => {
+                write!(f, "Failed to connect to installation proxy: {e}")
+            }
+            InstalledAppsError::ListApps(e) => write!(f, "Failed to list installed apps: {e}"),
+            InstalledAppsError::CreateFile(e, file_name) => {
+                write!(f, "Failed to create file {file_name}: {e}")
+            }
+            InstalledAppsError::WriteToFile(e, file_name) => {
+                write!(f, "Failed to write to file {file_name}: {e}")
+            }
+            InstalledAppsError::Serialize(e) => {
+                write!(f, "Failed to serialize installed apps: {e}")
+            }
+            InstalledAppsError::Timeout => write!(f, "Installation proxy connection timeout"),
+        }
+    }
+}
@@ -1,4 +1,6 @@
 pub mod crashes;
+pub mod device_state;
 pub mod heartbeat;
+pub mod installed_apps;
 pub mod os_trace;
 pub mod syslog;
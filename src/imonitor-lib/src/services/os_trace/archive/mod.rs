@@ -1,4 +1,6 @@
 pub mod errors;
+#[cfg(test)]
+pub(crate) mod test_support;
 
 use errors::ArchiveError;
 use plist::Value;
@@ -31,6 +33,11 @@ fn read_info_plist_from_tar(path: impl AsRef<Path>) -> Result<Value, ArchiveErro
     Err(ArchiveError::NoPlist)
 }
 
+/// Checks that an archive is complete enough to hold a readable Info.plist.
+pub fn verify_archive(path: impl AsRef<Path>) -> Result<(), ArchiveError> {
+    read_info_plist_from_tar(path).map(|_| ())
+}
+
 pub fn extract_time_coverage_from_tar(
     path: impl AsRef<Path>,
 ) -> Result<Range<SystemTime>, ArchiveError> {
@@ -0,0 +1,36 @@
+use plist::{Dictionary, Value};
+use std::path::Path;
+
+/// Writes an archive whose Info.plist covers `start..end`, in seconds since the epoch.
+pub(crate) fn write_test_archive(path: impl AsRef<Path>, start: u64, end: u64) {
+    let time_ref = |wall_time: u64| {
+        let mut time_ref = Dictionary::new();
+        time_ref.insert("WallTime".to_string(), Value::Integer(wall_time.into()));
+        Value::Dictionary(time_ref)
+    };
+    let mut info = Dictionary::new();
+    for key in [
+        "LiveMetadata",
+        "HighVolumeMetadata",
+        "SignPostMetadata",
+        "SpecialMetadata",
+    ] {
+        let mut metadata = Dictionary::new();
+        metadata.insert("OldestTimeRef".to_string(), time_ref(start));
+        info.insert(key.to_string(), Value::Dictionary(metadata));
+    }
+    info.insert("EndTimeRef".to_string(), time_ref(end));
+    let mut plist = Vec::new();
+    Value::Dictionary(info).to_writer_xml(&mut plist).unwrap();
+
+    let mut builder = tar::Builder::new(std::fs::File::create(path).unwrap());
+    let mut header = tar::Header::new_gnu();
+    // Named like the device does, which `Header::set_path` would normalize
+    let name = b"./Info.plist";
+    header.as_old_mut().name[..name.len()].copy_from_slice(name);
+    header.set_size(plist.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append(&header, plist.as_slice()).unwrap();
+    builder.finish().unwrap();
+}
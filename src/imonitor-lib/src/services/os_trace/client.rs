@@ -1,55 +1,107 @@
-//use super::archive::extract_time_coverage_from_tar;
+use super::archive::errors::ArchiveError;
+use super::archive::{extract_time_coverage_from_tar, verify_archive};
 use super::errors::OsTraceError;
+use crate::config::{IdleConfig, OsTraceConfig};
+use crate::connection::ConnectionPriority;
 use crate::device::Device;
 use crate::device::activity_coverage::ActivityCoverage;
+use crate::services::device_state::errors::DeviceStateError;
+use crate::throttle::ThrottledWriter;
 use chrono::{DateTime, Utc};
 use idevice::{
-    IdeviceService,
-    services::os_trace_relay::OsTraceLog,
-    services::os_trace_relay::{OsTraceRelayClient, OsTraceRelayReceiver},
+    IdeviceError, IdeviceService, services::os_trace_relay::OsTraceLog,
+    services::os_trace_relay::OsTraceRelayClient,
 };
 use logger::HasLogger;
-use logger::{debug, error, info};
-use std::path::PathBuf;
+use logger::{TruncateLock, debug, error, info, warn};
+use serde::Serialize;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::SystemTime;
 use tokio::fs::File;
 use tokio::io::{AsyncWriteExt, BufWriter};
 use tokio::sync::watch;
-use tokio::time::{Duration, sleep, timeout};
+use tokio::time::{Duration, Instant, Interval, MissedTickBehavior, interval_at, sleep, timeout};
 
 const RETRY_CONNECT_WAIT_SECS: u64 = 5;
+const MAX_START_TRACE_RETRY_WAIT_SECS: u64 = 600;
+// Failed starts in a row before warning that the device keeps rejecting tracing
+const START_TRACE_WARN_FAILURES: u32 = 5;
+// Guards against a zero flush interval, which tokio rejects
+const MIN_FLUSH_INTERVAL_MS: u64 = 100;
 const OS_TRACE_LOG_FILE_NAME: &str = "os_trace_log.json";
 const ARCHIVE_EXTENSION: &str = "tar";
 
+/// Result of [`Device::reconcile_coverage`].
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReconcileSummary {
+    /// Archives whose time range was added to the coverage
+    pub merged: Vec<String>,
+    /// Archives that could not be read, with the reason
+    pub failed: Vec<(String, String)>,
+}
+
+/// Callback receiving every os trace log before it is written to file.
+///
+/// Called from the streaming loop: it must not block, hand the log over to another task
+/// for any slow processing.
+#[derive(Clone)]
+pub struct OsTraceSink(pub Arc<dyn Fn(&OsTraceLog) + Send + Sync>);
+
+impl std::fmt::Debug for OsTraceSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("OsTraceSink")
+    }
+}
+
 impl Device {
     pub async fn stream_os_trace_logs(
         &self,
         refresh_rate: Duration,
+        idle_config: IdleConfig,
+        flush_interval: Duration,
+        read_timeout: Duration,
         hb_connected_rx: &mut watch::Receiver<bool>,
     ) -> Result<(), OsTraceError> {
         let provider = self.get_provider("os_trace_log");
 
         let mut _interval = refresh_rate.as_secs();
 
-        let log_base_path = PathBuf::from(self.get_os_trace_log_dir());
-        let log_file_path = log_base_path.join(OS_TRACE_LOG_FILE_NAME);
+        let log_file_path = self.get_os_trace_log_file_path();
         let mut f = BufWriter::new(
             File::options()
                 .append(true)
                 .create(true)
-                .open(log_file_path)
+                .open(&log_file_path)
                 .await
                 .map_err(OsTraceError::OpenFile)?,
         );
+        // Taken for each write, imonitor-send truncates the log under it
+        let truncate_lock =
+            TruncateLock::open(Path::new(&log_file_path)).map_err(OsTraceError::OpenFile)?;
+
+        let mut paused_rx = self.paused.subscribe();
+
+        let flush_interval = flush_interval.max(Duration::from_millis(MIN_FLUSH_INTERVAL_MS));
+        let mut flush_tick = interval_at(Instant::now() + flush_interval, flush_interval);
+        flush_tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        let mut start_trace_failures = 0u32;
 
         loop {
+            self.wait_unpaused().await;
             // Wait for heartbeat connected state
             if hb_connected_rx.wait_for(|val| *val).await.is_ok()
-                && let Ok(connection) = timeout(
-                    Duration::from_secs(2),
-                    OsTraceRelayClient::connect(&*provider),
-                )
-                .await
+                && let Ok(connection) = self
+                    .limit_connect(
+                        ConnectionPriority::Low,
+                        timeout(
+                            Duration::from_secs(2),
+                            OsTraceRelayClient::connect(&*provider),
+                        ),
+                    )
+                    .await
             {
                 debug!(self, "Connecting os trace log");
                 // Got response before timeout
@@ -58,83 +110,185 @@ impl Device {
                         info!(self, "Os trace (log) connected");
                         match os_trace_client.start_trace(None).await {
                             Ok(mut client) => {
-                                let interval_start = std::time::SystemTime::now();
+                                start_trace_failures = 0;
+                                let interval_start = self.clock.now();
                                 let mut interval_end;
                                 loop {
-                                    interval_end = std::time::SystemTime::now();
-                                    match write_log(&mut client, &mut f, hb_connected_rx).await {
-                                        Err(e) => match e {
-                                            OsTraceError::Connect(err) => {
-                                                error!(
-                                                    self,
-                                                    "Service needs reconnecting, retrying: {err}"
-                                                );
-                                                sleep(Duration::from_secs(RETRY_CONNECT_WAIT_SECS))
-                                                    .await;
-                                                break;
-                                            }
-                                            OsTraceError::Timeout => {
-                                                error!(
-                                                    self,
-                                                    "Service needs reconnecting (timeout), retrying"
-                                                );
-                                                sleep(Duration::from_secs(RETRY_CONNECT_WAIT_SECS))
-                                                    .await;
-                                                break;
-                                            }
-                                            err => {
-                                                error!(self, "Failed to write logs: {err}");
-                                                return Err(err);
-                                            }
-                                        },
+                                    interval_end = self.clock.now();
+                                    match write_log(
+                                        client.next(),
+                                        &mut f,
+                                        hb_connected_rx,
+                                        &mut paused_rx,
+                                        &mut flush_tick,
+                                        &truncate_lock,
+                                        read_timeout,
+                                        self.os_trace_sink.as_ref(),
+                                    )
+                                    .await
+                                    {
+                                        Err(e) => {
+                                            self.prepare_reconnect(e, &mut f, &truncate_lock)
+                                                .await?;
+                                            sleep(Duration::from_secs(RETRY_CONNECT_WAIT_SECS))
+                                                .await;
+                                            break;
+                                        }
                                         Ok(new_heartbeat) => {
-                                            if new_heartbeat {
+                                            if new_heartbeat && self.is_paused() {
+                                                info!(self, "Collection paused, disconnecting");
+                                                break;
+                                            } else if new_heartbeat {
                                                 info!(self, "New heartbeat, reconnecting");
                                                 sleep(Duration::from_secs(RETRY_CONNECT_WAIT_SECS))
                                                     .await;
                                                 break;
                                             } else {
+                                                self.record_activity();
                                                 continue;
                                             }
                                         }
                                     }
                                 }
-                                // Update activity coverage to calculate gaps
-                                let coverage: ActivityCoverage;
-                                {
-                                    let mut activity_coverage = self
-                                        .activity_coverage
-                                        .write()
-                                        .map_err(|_| OsTraceError::WriteLock)?;
-                                    activity_coverage.add_range(interval_start..interval_end);
-                                    coverage = activity_coverage.clone();
-                                    info!(self, "{activity_coverage:?}");
-                                }
-                                coverage
-                                    .write_to_fs(&self.get_activity_coverage_file_path())
-                                    .await?;
+                                self.cover_streamed_interval(
+                                    &mut f,
+                                    &truncate_lock,
+                                    interval_start..interval_end,
+                                )
+                                .await?;
                             }
                             Err(e) => {
-                                error!(self, "Failed to init log tracing: {e}");
+                                start_trace_failures = start_trace_failures.saturating_add(1);
+                                let retry_wait = start_trace_retry_wait(start_trace_failures);
+                                if start_trace_failures < START_TRACE_WARN_FAILURES {
+                                    error!(self, "Failed to init log tracing: {e}");
+                                } else if start_trace_failures == START_TRACE_WARN_FAILURES {
+                                    warn!(
+                                        self,
+                                        "Failed to init log tracing {start_trace_failures} times in a row, the device keeps rejecting it. Check that it is unlocked and trusts this host. Retrying every {}s at most: {e}",
+                                        MAX_START_TRACE_RETRY_WAIT_SECS
+                                    );
+                                } else {
+                                    debug!(self, "Failed to init log tracing: {e}");
+                                }
+                                self.observer
+                                    .on_service_error(&self.info.udid, "os_trace_log", &e);
+                                self.idle_aware_sleep(retry_wait, &idle_config).await;
                             }
                         }
                     }
                     Err(e) => {
                         error!(self, "Failed to connect to os trace: {e}");
-                        sleep(Duration::from_secs(RETRY_CONNECT_WAIT_SECS)).await;
+                        self.idle_aware_sleep(
+                            Duration::from_secs(RETRY_CONNECT_WAIT_SECS),
+                            &idle_config,
+                        )
+                        .await;
                         continue;
                     }
                 }
             } else {
                 debug!(self, "Os trace connection timeout");
-                sleep(Duration::from_secs(RETRY_CONNECT_WAIT_SECS)).await;
+                self.idle_aware_sleep(Duration::from_secs(RETRY_CONNECT_WAIT_SECS), &idle_config)
+                    .await;
+            }
+        }
+    }
+
+    /// Adds a streamed interval to the activity coverage, to calculate gaps, and wakes the
+    /// archive task. A host clock stepped backward, e.g. by NTP, would give an inverted
+    /// range: the interval is left to the archives.
+    async fn cover_streamed_interval<T>(
+        &self,
+        writer: &mut T,
+        truncate_lock: &TruncateLock,
+        interval: Range<SystemTime>,
+    ) -> Result<(), OsTraceError>
+    where
+        T: tokio::io::AsyncWrite + std::marker::Unpin,
+    {
+        if interval.end < interval.start {
+            warn!(
+                self,
+                "Host clock went backward during the os trace interval, coverage not updated"
+            );
+            return Ok(());
+        }
+        // The interval is only covered once its logs are on disk
+        flush_log(writer, truncate_lock).await?;
+        let coverage: ActivityCoverage;
+        {
+            let mut activity_coverage = self
+                .activity_coverage
+                .write()
+                .map_err(|_| OsTraceError::WriteLock)?;
+            activity_coverage.add_range(interval);
+            coverage = activity_coverage.clone();
+            info!(self, "{activity_coverage:?}");
+        }
+        self.coverage_changed.send_replace(());
+        coverage
+            .write_to_fs(&self.get_activity_coverage_file_path(), self.state_format)
+            .await?;
+        Ok(())
+    }
+
+    /// Flushes the buffered logs when streaming failed in a way a new connection can
+    /// recover from, returns the error otherwise.
+    async fn prepare_reconnect<T>(
+        &self,
+        e: OsTraceError,
+        writer: &mut T,
+        truncate_lock: &TruncateLock,
+    ) -> Result<(), OsTraceError>
+    where
+        T: tokio::io::AsyncWrite + std::marker::Unpin,
+    {
+        match e {
+            OsTraceError::Connect(err) => {
+                error!(self, "Service needs reconnecting, retrying: {err}");
+            }
+            OsTraceError::Timeout => {
+                error!(self, "Service needs reconnecting (timeout), retrying");
+            }
+            err => {
+                error!(self, "Failed to write logs: {err}");
+                self.observer
+                    .on_service_error(&self.info.udid, "os_trace_log", &err);
+                return Err(err);
             }
         }
+        flush_log(writer, truncate_lock).await
+    }
+
+    /// Gaps of the activity coverage. Gaps only appear when a range is added, so while there
+    /// is none, waits for the coverage to change instead of computing them again.
+    async fn wait_for_gaps(
+        &self,
+        coverage_changed_rx: &mut watch::Receiver<()>,
+    ) -> Result<Vec<Range<SystemTime>>, OsTraceError> {
+        loop {
+            self.wait_unpaused().await;
+            // Changes made from now on wake up the wait below
+            coverage_changed_rx.borrow_and_update();
+            let gaps = self
+                .activity_coverage
+                .read()
+                .map_err(|_| OsTraceError::ReadLock)?
+                .missing_ranges();
+            if !gaps.is_empty() {
+                return Ok(gaps);
+            }
+
+            debug!(self, "No gap in activity coverage, waiting for a change");
+            let _ = coverage_changed_rx.changed().await;
+        }
     }
 
     pub async fn create_os_trace_archive(
         &self,
         refresh_rate: Duration,
+        os_trace_config: OsTraceConfig,
         hb_connected_rx: &mut watch::Receiver<bool>,
     ) -> Result<(), OsTraceError> {
         let provider = self.get_provider("os_trace_archive");
@@ -143,24 +297,29 @@ impl Device {
 
         let archive_base_path = PathBuf::from(self.get_os_trace_archive_dir());
 
+        let mut coverage_changed_rx = self.coverage_changed.subscribe();
+
         loop {
-            let gaps;
+            let gaps = self.wait_for_gaps(&mut coverage_changed_rx).await?;
+
+            if *hb_connected_rx.borrow()
+                && self.archive_storage_low(self.query_available_storage().await, &os_trace_config)
             {
-                let activity_coverage = self
-                    .activity_coverage
-                    .read()
-                    .map_err(|_| OsTraceError::ReadLock)?;
-                gaps = activity_coverage.missing_ranges();
+                sleep(os_trace_config.archive_low_storage_wait).await;
+                continue;
             }
             //
             // Wait for heartbeat connected state
-            if !gaps.is_empty()
-                && hb_connected_rx.wait_for(|val| *val).await.is_ok()
-                && let Ok(connection) = timeout(
-                    Duration::from_secs(2),
-                    OsTraceRelayClient::connect(&*provider),
-                )
-                .await
+            if hb_connected_rx.wait_for(|val| *val).await.is_ok()
+                && let Ok(connection) = self
+                    .limit_connect(
+                        ConnectionPriority::Low,
+                        timeout(
+                            Duration::from_secs(2),
+                            OsTraceRelayClient::connect(&*provider),
+                        ),
+                    )
+                    .await
             {
                 // Got response before timeout
                 match connection {
@@ -169,27 +328,26 @@ impl Device {
 
                         info!(self, "Gaps: {gaps:?}");
 
-                        // TODO: calculate gaps only when we know there is a new one (get the
-                        // information from a channel, as with heartbeat)
+                        // A failed archive may leave the connection unusable, the remaining
+                        // gaps are retried on a new one
+                        let mut failed = false;
                         for gap in gaps {
+                            if self.is_paused() {
+                                info!(self, "Collection paused, stopping archives");
+                                break;
+                            }
                             // Create archive
-                            let archive_file_path =
-                                archive_base_path.join(self.get_archive_name(&gap.start.into()));
-                            let mut f = BufWriter::new(
-                                File::options()
-                                    .create(true)
-                                    .truncate(true)
-                                    .write(true)
-                                    .open(&archive_file_path)
-                                    .await
-                                    .map_err(OsTraceError::OpenFile)?,
+                            let (archive_file, archive_file_path) = self
+                                .create_archive_file(&archive_base_path, &gap.start.into())
+                                .await?;
+                            debug!(self, "Archive file: {}", archive_file_path.display());
+                            let mut f = ThrottledWriter::new(
+                                BufWriter::new(archive_file),
+                                self.download_limiter.clone(),
                             );
 
-                            let archive_start = gap
-                                .start
-                                .duration_since(SystemTime::UNIX_EPOCH)
-                                .map_err(OsTraceError::OppositeTime)?
-                                .as_secs();
+                            let request = ArchiveRequest::new(gap.start, &os_trace_config);
+                            let archive_start = request.start;
 
                             info!(self, "Creating archive beginning at {archive_start}");
                             // Check if archive was finished
@@ -200,36 +358,51 @@ impl Device {
                                 .map_err(OsTraceError::CreateArchive)?;
                             */
                             if let Err(e) = os_trace_client
-                                .create_archive(&mut f, Some(5000u64), Some(1), Some(archive_start))
+                                .create_archive(
+                                    &mut f,
+                                    Some(request.size_limit),
+                                    Some(request.age_limit),
+                                    Some(request.start),
+                                )
                                 .await
                             {
                                 info!(self, "Failed to create archive: {e}");
-                                sleep(Duration::from_secs(60)).await;
-                                continue;
+                                self.observer.on_service_error(
+                                    &self.info.udid,
+                                    "os_trace_archive",
+                                    &e,
+                                );
+                                drop(f);
+                                self.remove_partial_archive(&archive_file_path).await;
+                                failed = true;
+                                break;
                             } else {
-                                let coverage: ActivityCoverage;
+                                if let Err(e) = f.flush().await {
+                                    info!(self, "Failed to write archive: {e}");
+                                    drop(f);
+                                    self.remove_partial_archive(&archive_file_path).await;
+                                    failed = true;
+                                    break;
+                                }
+                                if !self
+                                    .cover_archived_gap(&archive_file_path, &gap, &os_trace_config)
+                                    .await?
                                 {
-                                    let mut activity_coverage = self
-                                        .activity_coverage
-                                        .write()
-                                        .map_err(|_| OsTraceError::WriteLock)?;
-
-                                    /*
-                                    let tar_coverage =
-                                        extract_time_coverage_from_tar(&archive_file_path)?;
-                                    activity_coverage.add_range(gap.start..tar_coverage.end);
-                                    */
-                                    activity_coverage.add_range(gap);
-                                    coverage = activity_coverage.clone();
+                                    failed = true;
+                                    break;
                                 }
-                                coverage
-                                    .write_to_fs(&self.get_activity_coverage_file_path())
-                                    .await?;
-                                info!(self, "Archive created");
                             }
                         }
-                        // TODO: get that sleep time from config
-                        sleep(Duration::from_secs(60)).await;
+                        // Otherwise the gaps are covered, the next ones are waited for at
+                        // the start of the loop
+                        if failed {
+                            info!(
+                                self,
+                                "Retrying the remaining gaps in {}s",
+                                os_trace_config.archive_retry_wait.as_secs()
+                            );
+                            sleep(os_trace_config.archive_retry_wait).await;
+                        }
                     }
                     Err(e) => {
                         error!(self, "Failed to connect to os trace: {e}");
@@ -243,50 +416,315 @@ impl Device {
         }
     }
 
-    pub fn get_archive_name(&self, date: &DateTime<Utc>) -> String {
+    /// Adds the gap of a written archive to the activity coverage, once the archive is
+    /// verified with `verify_archives`. An unreadable archive is deleted and its gap kept:
+    /// false is returned.
+    async fn cover_archived_gap(
+        &self,
+        archive_file_path: &Path,
+        gap: &Range<SystemTime>,
+        os_trace_config: &OsTraceConfig,
+    ) -> Result<bool, OsTraceError> {
+        if os_trace_config.verify_archives
+            && let Err(e) = self.verify_archive_file(archive_file_path).await
+        {
+            warn!(
+                self,
+                "Deleted unreadable archive {}, its gap is kept: {e}",
+                archive_file_path.display()
+            );
+            self.observer
+                .on_service_error(&self.info.udid, "os_trace_archive", &e);
+            return Ok(false);
+        }
+        let coverage: ActivityCoverage;
+        {
+            let mut activity_coverage = self
+                .activity_coverage
+                .write()
+                .map_err(|_| OsTraceError::WriteLock)?;
+
+            /*
+            let tar_coverage = extract_time_coverage_from_tar(&archive_file_path)?;
+            activity_coverage.add_range(gap.start..tar_coverage.end);
+            */
+            self.observer.on_archive_created(&self.info.udid, gap);
+            activity_coverage.add_range(gap.clone());
+            coverage = activity_coverage.clone();
+        }
+        self.coverage_changed.send_replace(());
+        coverage
+            .write_to_fs(&self.get_activity_coverage_file_path(), self.state_format)
+            .await?;
+        info!(self, "Archive created");
+
+        if os_trace_config.archive_metadata
+            && let Err(e) = self.write_archive_meta(archive_file_path, gap).await
+        {
+            warn!(
+                self,
+                "Failed to write metadata of archive {}: {e}",
+                archive_file_path.display()
+            );
+        }
+        Ok(true)
+    }
+
+    /// Deletes the archive if it has no readable Info.plist, such as one truncated by the
+    /// device dropping mid-transfer.
+    async fn verify_archive_file(&self, archive_file_path: &Path) -> Result<(), OsTraceError> {
+        let path = archive_file_path.to_path_buf();
+        let verified = tokio::task::spawn_blocking(move || verify_archive(path))
+            .await
+            .unwrap_or_else(|e| Err(ArchiveError::IO(std::io::Error::other(e))));
+
+        if let Err(e) = verified {
+            if let Err(remove_err) = tokio::fs::remove_file(archive_file_path).await {
+                warn!(
+                    self,
+                    "Failed to delete unreadable archive {}: {remove_err}",
+                    archive_file_path.display()
+                );
+            }
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// Deletes the archive a failed transfer left partial, which would otherwise be taken
+    /// for a complete one, e.g. by [`Device::reconcile_coverage`].
+    async fn remove_partial_archive(&self, archive_file_path: &Path) {
+        if let Err(e) = tokio::fs::remove_file(archive_file_path).await
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            warn!(
+                self,
+                "Failed to delete partial archive {}: {e}",
+                archive_file_path.display()
+            );
+        }
+    }
+
+    /// Whether the device has less free storage, as `available` from
+    /// [`Device::query_available_storage`], than required to build an archive. False if the
+    /// free storage cannot be queried, so that archives are still attempted.
+    fn archive_storage_low(
+        &self,
+        available: Result<Option<u64>, DeviceStateError>,
+        os_trace_config: &OsTraceConfig,
+    ) -> bool {
+        let min_free_bytes = os_trace_config
+            .archive_min_device_free_mb
+            .saturating_mul(1024 * 1024);
+
+        match available {
+            Ok(Some(available)) if available < min_free_bytes => {
+                warn!(
+                    self,
+                    "Device has {} MB free, less than the {} MB needed to build an os trace archive. Retrying in {}s",
+                    available / (1024 * 1024),
+                    os_trace_config.archive_min_device_free_mb,
+                    os_trace_config.archive_low_storage_wait.as_secs()
+                );
+                true
+            }
+            Ok(_) => false,
+            Err(e) => {
+                debug!(self, "Failed to query device free storage: {e}");
+                false
+            }
+        }
+    }
+
+    /// Adds the time range of every archive in the archive dir to the activity coverage and
+    /// writes it to disk. Recovers the coverage if `activity_coverage.json` was lost.
+    /// Unreadable archives are skipped and reported.
+    pub async fn reconcile_coverage(&self) -> Result<ReconcileSummary, OsTraceError> {
+        let archive_dir = self.get_os_trace_archive_dir();
+        let mut archive_paths = Vec::new();
+        let mut entries = tokio::fs::read_dir(&archive_dir)
+            .await
+            .map_err(OsTraceError::ReadDir)?;
+        while let Some(entry) = entries.next_entry().await.map_err(OsTraceError::ReadDir)? {
+            let path = entry.path();
+            if path.extension().and_then(|ext| ext.to_str()) == Some(ARCHIVE_EXTENSION) {
+                archive_paths.push(path);
+            }
+        }
+        archive_paths.sort();
+
+        // Archives are read off the runtime, and before locking the coverage which the
+        // streaming task also updates
+        let ranges = tokio::task::spawn_blocking(move || {
+            archive_paths
+                .into_iter()
+                .map(|path| {
+                    let range = extract_time_coverage_from_tar(&path);
+                    (path.to_string_lossy().to_string(), range)
+                })
+                .collect::<Vec<_>>()
+        })
+        .await
+        .map_err(|e| OsTraceError::from(ArchiveError::IO(std::io::Error::other(e))))?;
+
+        let mut summary = ReconcileSummary::default();
+        let coverage: ActivityCoverage;
+        {
+            let mut activity_coverage = self
+                .activity_coverage
+                .write()
+                .map_err(|_| OsTraceError::WriteLock)?;
+
+            for (name, range) in ranges {
+                match range {
+                    Ok(range) => {
+                        activity_coverage.add_range(range);
+                        summary.merged.push(name);
+                    }
+                    Err(e) => summary.failed.push((name, e.to_string())),
+                }
+            }
+            coverage = activity_coverage.clone();
+        }
+        self.coverage_changed.send_replace(());
+
+        coverage
+            .write_to_fs(&self.get_activity_coverage_file_path(), self.state_format)
+            .await?;
+
+        Ok(summary)
+    }
+
+    pub fn get_os_trace_log_file_path(&self) -> String {
+        let log_base_path = PathBuf::from(self.get_os_trace_log_dir());
+        let log_file_path = log_base_path.join(OS_TRACE_LOG_FILE_NAME);
+        log_file_path.to_string_lossy().to_string()
+    }
+
+    /// Archive file name for a gap start. `sequence` tells apart archives starting in the
+    /// same second, the first one has no suffix.
+    pub fn get_archive_name(&self, date: &DateTime<Utc>, sequence: u32) -> String {
         //let now_utc: DateTime<Utc> = Utc::now();
         let udid = self.info.udid.clone();
-        format!(
-            "{}_{}.{ARCHIVE_EXTENSION}",
-            &udid,
-            date.timestamp() //date.to_rfc3339_opts(SecondsFormat::Secs, true)
-        )
+        match sequence {
+            0 => format!(
+                "{}_{}.{ARCHIVE_EXTENSION}",
+                &udid,
+                date.timestamp() //date.to_rfc3339_opts(SecondsFormat::Secs, true)
+            ),
+            _ => format!(
+                "{}_{}_{sequence}.{ARCHIVE_EXTENSION}",
+                &udid,
+                date.timestamp()
+            ),
+        }
+    }
+
+    /// Opens the file of a new archive. A non-empty archive is never overwritten: the
+    /// next free sequence suffix is used instead. Empty files left by failed attempts are
+    /// reused.
+    async fn create_archive_file(
+        &self,
+        archive_base_path: &Path,
+        date: &DateTime<Utc>,
+    ) -> Result<(File, PathBuf), OsTraceError> {
+        let mut sequence = 0;
+        loop {
+            let archive_file_path = archive_base_path.join(self.get_archive_name(date, sequence));
+            match tokio::fs::metadata(&archive_file_path).await {
+                Ok(metadata) if metadata.len() > 0 => {
+                    sequence += 1;
+                    continue;
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(OsTraceError::OpenFile(e)),
+            }
+
+            let archive_file = File::options()
+                .create(true)
+                .truncate(true)
+                .write(true)
+                .open(&archive_file_path)
+                .await
+                .map_err(OsTraceError::OpenFile)?;
+            return Ok((archive_file, archive_file_path));
+        }
     }
 }
 
+/// Waits for the next log, `next_log`, and writes it. Returns whether streaming must
+/// restart, on a new heartbeat or a pause.
 async fn write_log<T>(
-    client: &mut OsTraceRelayReceiver,
+    next_log: impl Future<Output = Result<OsTraceLog, IdeviceError>>,
     writer: &mut T,
     hb_connected_rx: &mut watch::Receiver<bool>,
+    paused_rx: &mut watch::Receiver<bool>,
+    flush_tick: &mut Interval,
+    truncate_lock: &TruncateLock,
+    read_timeout: Duration,
+    sink: Option<&OsTraceSink>,
 ) -> Result<bool, OsTraceError>
 where
     T: tokio::io::AsyncWrite + std::marker::Unpin,
 {
     let mut hb_rx = hb_connected_rx.clone();
-    let res = tokio::select!(
-        ok = async {
-            // Heartbeat lost
-            hb_rx.changed().await
-                .map_err(OsTraceError::HeartbeatWatch)?;
-            // Heartbeat retrieved, no need to continue streaming
-            hb_rx.wait_for(|val| *val).await
-                .map_err(OsTraceError::HeartbeatWatch)?;
-            Ok::<_,OsTraceError>(())
-        } => {
-            ok?;
-            None
-        },
-        log = client.next() => {
-           // Log received
-           Some(log)
-        }
-    );
+    let mut new_heartbeat = std::pin::pin!(async {
+        // Heartbeat lost
+        hb_rx
+            .changed()
+            .await
+            .map_err(OsTraceError::HeartbeatWatch)?;
+        // Heartbeat retrieved, no need to continue streaming
+        hb_rx
+            .wait_for(|val| *val)
+            .await
+            .map_err(OsTraceError::HeartbeatWatch)?;
+        Ok::<_, OsTraceError>(())
+    });
+    let mut paused = std::pin::pin!(paused_rx.wait_for(|paused| *paused));
+    // Kept across flushes: dropping a pending read could lose part of a log
+    let mut next_log = std::pin::pin!(next_log);
+    // Not reset by flushes, only by a log
+    let mut read_deadline = std::pin::pin!(sleep(read_timeout));
+
+    let res = loop {
+        tokio::select!(
+            ok = &mut new_heartbeat => {
+                ok?;
+                break None;
+            },
+            _ = &mut paused => {
+                // Collection paused, stop streaming
+                break None;
+            },
+            _ = flush_tick.tick() => {
+                flush_log(writer, truncate_lock).await?;
+            },
+            _ = &mut read_deadline => {
+                // Half-open connection, reconnecting
+                return Err(OsTraceError::Timeout);
+            },
+            log = &mut next_log => {
+               // Log received
+               break Some(log);
+            }
+        );
+    };
 
     if let Some(log) = res {
+        let log = log.map_err(OsTraceError::Connect)?;
+        if let Some(sink) = sink {
+            (sink.0)(&log);
+        }
         let mut log_json =
-            serde_json::to_string::<OsTraceLog>(&(log.map_err(OsTraceError::Connect)?))
-                .map_err(OsTraceError::SerializeLog)?;
+            serde_json::to_string::<OsTraceLog>(&log).map_err(OsTraceError::SerializeLog)?;
         log_json.push('\n');
+        // The writer may flush while writing
+        let _guard = truncate_lock
+            .shared_async()
+            .await
+            .map_err(OsTraceError::WriteToFile)?;
         writer
             .write_all(log_json.as_bytes())
             .await
@@ -295,6 +733,416 @@ where
         Ok(false)
     } else {
         // New heartbeat, init new os trace connection
+        flush_log(writer, truncate_lock).await?;
         Ok(true)
     }
 }
+
+/// Writes the buffered logs to the file, under the truncate lock like any write.
+async fn flush_log<T>(writer: &mut T, truncate_lock: &TruncateLock) -> Result<(), OsTraceError>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let _guard = truncate_lock
+        .shared_async()
+        .await
+        .map_err(OsTraceError::WriteToFile)?;
+    writer.flush().await.map_err(OsTraceError::WriteToFile)
+}
+
+/// Parameters of the archive request covering a gap.
+#[derive(Debug, PartialEq)]
+struct ArchiveRequest {
+    /// `SizeLimit`
+    size_limit: u64,
+    /// `AgeLimit`
+    age_limit: u64,
+    /// `StartTime`, in seconds since the epoch
+    start: u64,
+}
+
+impl ArchiveRequest {
+    fn new(gap_start: SystemTime, os_trace_config: &OsTraceConfig) -> ArchiveRequest {
+        // Saturating, a gap cannot start before the epoch
+        let start = gap_start
+            .checked_sub(os_trace_config.archive_start_margin)
+            .unwrap_or(SystemTime::UNIX_EPOCH)
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        ArchiveRequest {
+            size_limit: os_trace_config.archive_size_limit,
+            age_limit: os_trace_config.archive_age_limit,
+            start,
+        }
+    }
+}
+
+/// Exponential backoff after failed trace starts, capped.
+fn start_trace_retry_wait(consecutive_failures: u32) -> Duration {
+    let factor = 1u64 << consecutive_failures.saturating_sub(1).min(16);
+    Duration::from_secs(
+        RETRY_CONNECT_WAIT_SECS
+            .saturating_mul(factor)
+            .min(MAX_START_TRACE_RETRY_WAIT_SECS),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::test_support::test_device;
+    use crate::observer::CountingObserver;
+    use crate::services::os_trace::archive::test_support::write_test_archive;
+    use std::sync::atomic::Ordering;
+
+    fn t(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[tokio::test]
+    async fn archives_of_the_same_second_get_a_sequence_suffix() {
+        let (device, base_dir) = test_device("archive-file");
+        let archive_dir = PathBuf::from(device.get_os_trace_archive_dir());
+        let date = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+
+        let (mut first, first_path) = device
+            .create_archive_file(&archive_dir, &date)
+            .await
+            .unwrap();
+        first.write_all(b"archive").await.unwrap();
+        first.flush().await.unwrap();
+        let (_, second_path) = device
+            .create_archive_file(&archive_dir, &date)
+            .await
+            .unwrap();
+        // Left empty by a failed attempt, reused
+        let (_, third_path) = device
+            .create_archive_file(&archive_dir, &date)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            first_path,
+            archive_dir.join(format!("{}_1700000000.tar", device.info.udid))
+        );
+        assert_eq!(
+            second_path,
+            archive_dir.join(format!("{}_1700000000_1.tar", device.info.udid))
+        );
+        assert_eq!(third_path, second_path);
+        assert_eq!(std::fs::read(&first_path).unwrap(), b"archive");
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn partial_archive_is_removed() {
+        let (device, base_dir) = test_device("partial-archive");
+        let archive_dir = PathBuf::from(device.get_os_trace_archive_dir());
+        let date = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let (mut archive_file, archive_file_path) = device
+            .create_archive_file(&archive_dir, &date)
+            .await
+            .unwrap();
+        archive_file.write_all(b"partial").await.unwrap();
+        drop(archive_file);
+
+        device.remove_partial_archive(&archive_file_path).await;
+        assert!(!archive_file_path.exists());
+        // Already gone, nothing to report
+        device.remove_partial_archive(&archive_file_path).await;
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reconcile_merges_the_readable_archives() {
+        let (device, base_dir) = test_device("reconcile");
+        let archive_dir = PathBuf::from(device.get_os_trace_archive_dir());
+        write_test_archive(archive_dir.join("a_100.tar"), 100, 200);
+        write_test_archive(archive_dir.join("a_300.tar"), 300, 400);
+        std::fs::write(archive_dir.join("a_500.tar"), b"not an archive").unwrap();
+        std::fs::write(archive_dir.join("a_100.tar.meta.json"), b"{}").unwrap();
+        device
+            .activity_coverage
+            .write()
+            .unwrap()
+            .add_range(t(150)..t(250));
+        let mut coverage_changed_rx = device.coverage_changed.subscribe();
+
+        let summary = device.reconcile_coverage().await.unwrap();
+
+        let name = |file: &str| archive_dir.join(file).to_string_lossy().to_string();
+        assert_eq!(summary.merged, [name("a_100.tar"), name("a_300.tar")]);
+        assert_eq!(summary.failed.len(), 1);
+        assert_eq!(summary.failed[0].0, name("a_500.tar"));
+        assert_eq!(
+            device.activity_coverage.read().unwrap().missing_ranges(),
+            [t(250)..t(300)]
+        );
+        assert!(coverage_changed_rx.has_changed().unwrap());
+
+        let written = crate::device::activity_coverage::load_from_fs(
+            &device.get_activity_coverage_file_path(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(written.missing_ranges(), [t(250)..t(300)]);
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn truncated_archive_keeps_its_gap() {
+        let (device, base_dir) = test_device("verify-archive");
+        let archive_dir = PathBuf::from(device.get_os_trace_archive_dir());
+        let os_trace_config = OsTraceConfig {
+            verify_archives: true,
+            archive_metadata: false,
+            ..Default::default()
+        };
+        device
+            .activity_coverage
+            .write()
+            .unwrap()
+            .add_range(t(100)..t(200));
+        device
+            .activity_coverage
+            .write()
+            .unwrap()
+            .add_range(t(300)..t(400));
+
+        let valid = archive_dir.join("a_200.tar");
+        write_test_archive(&valid, 200, 300);
+        // Cut mid-transfer, before the end of the Info.plist entry
+        let truncated = archive_dir.join("a_200_1.tar");
+        std::fs::write(&truncated, &std::fs::read(&valid).unwrap()[..600]).unwrap();
+
+        let covered = device
+            .cover_archived_gap(&truncated, &(t(200)..t(300)), &os_trace_config)
+            .await
+            .unwrap();
+        assert!(!covered);
+        assert!(!truncated.exists());
+        assert_eq!(
+            device.activity_coverage.read().unwrap().missing_ranges(),
+            [t(200)..t(300)]
+        );
+
+        let covered = device
+            .cover_archived_gap(&valid, &(t(200)..t(300)), &os_trace_config)
+            .await
+            .unwrap();
+        assert!(covered);
+        assert!(valid.exists());
+        assert!(
+            device
+                .activity_coverage
+                .read()
+                .unwrap()
+                .missing_ranges()
+                .is_empty()
+        );
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    #[test]
+    fn archives_are_skipped_only_when_the_device_storage_is_known_low() {
+        let (device, base_dir) = test_device("archive-storage");
+        let os_trace_config = OsTraceConfig {
+            archive_min_device_free_mb: 1024,
+            ..Default::default()
+        };
+        let mb = 1024 * 1024;
+
+        assert!(device.archive_storage_low(Ok(Some(100 * mb)), &os_trace_config));
+        assert!(!device.archive_storage_low(Ok(Some(1024 * mb)), &os_trace_config));
+        // Unknown storage does not prevent archives
+        assert!(!device.archive_storage_low(Ok(None), &os_trace_config));
+        assert!(!device.archive_storage_low(Err(DeviceStateError::Timeout), &os_trace_config));
+
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    /// Log file of the device and its truncate lock, as opened by the streaming task.
+    async fn open_log(device: &Device) -> (BufWriter<File>, TruncateLock) {
+        let log_file_path = device.get_os_trace_log_file_path();
+        let writer = BufWriter::new(File::create(&log_file_path).await.unwrap());
+        let truncate_lock = TruncateLock::open(Path::new(&log_file_path)).unwrap();
+        (writer, truncate_lock)
+    }
+
+    #[tokio::test]
+    async fn archive_task_is_woken_by_a_streamed_range() {
+        let (device, base_dir) = test_device("archive-wake");
+        let (mut writer, truncate_lock) = open_log(&device).await;
+        device
+            .activity_coverage
+            .write()
+            .unwrap()
+            .add_range(t(0)..t(10));
+        let mut coverage_changed_rx = device.coverage_changed.subscribe();
+
+        // No gap yet, the archive task waits
+        assert!(
+            timeout(
+                Duration::from_millis(100),
+                device.wait_for_gaps(&mut coverage_changed_rx)
+            )
+            .await
+            .is_err()
+        );
+
+        let (gaps, covered) = tokio::join!(
+            timeout(
+                Duration::from_secs(1),
+                device.wait_for_gaps(&mut coverage_changed_rx)
+            ),
+            async {
+                sleep(Duration::from_millis(50)).await;
+                device
+                    .cover_streamed_interval(&mut writer, &truncate_lock, t(20)..t(30))
+                    .await
+            }
+        );
+        covered.unwrap();
+        assert_eq!(gaps.unwrap().unwrap(), [t(10)..t(20)]);
+
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    #[test]
+    fn archive_request_follows_the_config() {
+        let os_trace_config = OsTraceConfig {
+            archive_size_limit: 200,
+            archive_age_limit: 3,
+            archive_start_margin: Duration::from_secs(60),
+            ..Default::default()
+        };
+        assert_eq!(
+            ArchiveRequest::new(t(1000), &os_trace_config),
+            ArchiveRequest {
+                size_limit: 200,
+                age_limit: 3,
+                start: 940,
+            }
+        );
+        // The margin stops at the epoch
+        assert_eq!(ArchiveRequest::new(t(30), &os_trace_config).start, 0);
+
+        let request = ArchiveRequest::new(t(1000), &OsTraceConfig::default());
+        assert_eq!((request.size_limit, request.age_limit), (5000, 1));
+        assert_eq!(request.start, 1000);
+    }
+
+    #[tokio::test]
+    async fn buffered_logs_are_flushed_before_reconnecting() {
+        let (mut device, base_dir) = test_device("os-trace-reconnect");
+        let observer = Arc::new(CountingObserver::default());
+        device.observer = observer.clone();
+        let (mut writer, truncate_lock) = open_log(&device).await;
+        let log_file_path = device.get_os_trace_log_file_path();
+
+        writer.write_all(b"{}\n").await.unwrap();
+        device
+            .prepare_reconnect(OsTraceError::Timeout, &mut writer, &truncate_lock)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&log_file_path).unwrap(), b"{}\n");
+
+        // Not recoverable by reconnecting
+        let result = device
+            .prepare_reconnect(OsTraceError::WriteLock, &mut writer, &truncate_lock)
+            .await;
+        assert!(matches!(result, Err(OsTraceError::WriteLock)), "{result:?}");
+        assert_eq!(observer.service_errors.load(Ordering::Relaxed), 1);
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn streamed_interval_is_covered_once_its_logs_are_flushed() {
+        let (device, base_dir) = test_device("os-trace-cover-flush");
+        let (mut writer, truncate_lock) = open_log(&device).await;
+
+        writer.write_all(b"{}\n").await.unwrap();
+        device
+            .cover_streamed_interval(&mut writer, &truncate_lock, t(0)..t(10))
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read(device.get_os_trace_log_file_path()).unwrap(),
+            b"{}\n"
+        );
+        assert_eq!(
+            device.activity_coverage.read().unwrap().span(),
+            Some(t(0)..t(10))
+        );
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn backward_clock_step_leaves_the_coverage_unchanged() {
+        let (device, base_dir) = test_device("os-trace-clock-step");
+        let (mut writer, truncate_lock) = open_log(&device).await;
+        device
+            .activity_coverage
+            .write()
+            .unwrap()
+            .add_range(t(0)..t(10));
+        let coverage_changed_rx = device.coverage_changed.subscribe();
+
+        device
+            .cover_streamed_interval(&mut writer, &truncate_lock, t(30)..t(20))
+            .await
+            .unwrap();
+
+        let coverage = device.activity_coverage.read().unwrap().clone();
+        assert_eq!(coverage.span(), Some(t(0)..t(10)));
+        assert!(coverage.missing_ranges().is_empty());
+        assert!(!coverage_changed_rx.has_changed().unwrap());
+        assert!(!Path::new(&device.get_activity_coverage_file_path()).exists());
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn silent_stream_times_out() {
+        let (device, base_dir) = test_device("os-trace-read-timeout");
+        let (mut writer, truncate_lock) = open_log(&device).await;
+        let (_hb_tx, mut hb_rx) = watch::channel(true);
+        let (_paused_tx, mut paused_rx) = watch::channel(false);
+        let mut flush_tick = interval_at(
+            Instant::now() + Duration::from_millis(20),
+            Duration::from_millis(20),
+        );
+
+        // Flushes do not reset the read deadline
+        let started = Instant::now();
+        let result = write_log(
+            std::future::pending(),
+            &mut writer,
+            &mut hb_rx,
+            &mut paused_rx,
+            &mut flush_tick,
+            &truncate_lock,
+            Duration::from_millis(200),
+            None,
+        )
+        .await;
+        assert!(matches!(result, Err(OsTraceError::Timeout)));
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    #[test]
+    fn start_trace_retry_wait_backs_off_up_to_the_cap() {
+        let secs = |failures| start_trace_retry_wait(failures).as_secs();
+        assert_eq!(secs(1), RETRY_CONNECT_WAIT_SECS);
+        assert_eq!(secs(2), RETRY_CONNECT_WAIT_SECS * 2);
+        assert_eq!(secs(3), RETRY_CONNECT_WAIT_SECS * 4);
+
+        // A device always rejecting the trace is never retried in a tight loop
+        let waits: Vec<u64> = (1..=100).map(secs).collect();
+        assert!(waits.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(waits[99], MAX_START_TRACE_RETRY_WAIT_SECS);
+        assert_eq!(secs(u32::MAX), MAX_START_TRACE_RETRY_WAIT_SECS);
+    }
+}
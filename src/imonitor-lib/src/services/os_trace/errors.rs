@@ -5,12 +5,12 @@ use idevice::IdeviceError;
 #[derive(Debug)]
 pub enum OsTraceError {
     OpenFile(std::io::Error),
+    ReadDir(std::io::Error),
     WriteToFile(std::io::Error),
     Connect(IdeviceError),
     CreateArchive(IdeviceError),
     HeartbeatWatch(tokio::sync::watch::error::RecvError),
     SerializeLog(serde_json::Error),
-    OppositeTime(std::time::SystemTimeError),
     ActivityCoverage(ActivityCoverageError),
     Archive(ArchiveError),
     Timeout,
@@ -25,6 +25,7 @@ impl std::fmt::Display for OsTraceError {
         match self {
             OsTraceError::WriteToFile(e) => write!(f, "Failed to write to os trace log file: {e}"),
             OsTraceError::OpenFile(e) => write!(f, "Failed to open/create os trace log file: {e}"),
+            OsTraceError::ReadDir(e) => write!(f, "Failed to list os trace archives: {e}"),
             OsTraceError::Connect(e) => {
                 write!(f, "Failed to connect to os trace log service: {e}")
             }
@@ -34,9 +35,6 @@ impl std::fmt::Display for OsTraceError {
             OsTraceError::HeartbeatWatch(e) => write!(f, "Heartbeat watch receiver failed: {e}"),
             OsTraceError::SerializeLog(e) => write!(f, "Failed to serialize log: {e}"),
             OsTraceError::ActivityCoverage(e) => write!(f, "ActivityCoverageError: {e}"),
-            OsTraceError::OppositeTime(e) => {
-                write!(f, "Opposite time, difference is: {:?}", e.duration())
-            }
             OsTraceError::Timeout => write!(f, "OsTrace waiting timeout"),
             OsTraceError::ReadLock => write!(f, "Failed acquiring os trace read lock"),
             OsTraceError::WriteLock => write!(f, "Failed acquiring os trace write lock"),
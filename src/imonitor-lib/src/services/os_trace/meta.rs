@@ -0,0 +1,142 @@
+use super::archive::extract_time_coverage_from_tar;
+use super::errors::OsTraceError;
+use crate::device::Device;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+const META_EXTENSION: &str = "meta.json";
+
+/// Sidecar of an os trace archive, so that tooling learns what it covers without opening it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArchiveMeta {
+    pub udid: String,
+    /// Coverage gap the archive was requested for
+    pub requested_start: DateTime<Utc>,
+    pub requested_end: DateTime<Utc>,
+    /// Time range read from the archive Info.plist, none if it could not be read
+    pub coverage_start: Option<DateTime<Utc>>,
+    pub coverage_end: Option<DateTime<Utc>>,
+    pub created: DateTime<Utc>,
+    pub bytes: u64,
+}
+
+/// `{archive}.meta.json`, next to the archive.
+pub fn archive_meta_path(archive_file_path: &Path) -> PathBuf {
+    let mut meta_path = archive_file_path.as_os_str().to_owned();
+    meta_path.push(format!(".{META_EXTENSION}"));
+    PathBuf::from(meta_path)
+}
+
+impl Device {
+    /// Writes the sidecar of an archive just created for `gap`.
+    pub(super) async fn write_archive_meta(
+        &self,
+        archive_file_path: &Path,
+        gap: &Range<SystemTime>,
+    ) -> Result<(), OsTraceError> {
+        let bytes = tokio::fs::metadata(archive_file_path)
+            .await
+            .map_err(OsTraceError::OpenFile)?
+            .len();
+
+        let path = archive_file_path.to_path_buf();
+        let coverage = tokio::task::spawn_blocking(move || extract_time_coverage_from_tar(path))
+            .await
+            .ok()
+            .and_then(Result::ok);
+
+        let meta = ArchiveMeta {
+            udid: self.info.udid.clone(),
+            requested_start: gap.start.into(),
+            requested_end: gap.end.into(),
+            coverage_start: coverage.as_ref().map(|coverage| coverage.start.into()),
+            coverage_end: coverage.map(|coverage| coverage.end.into()),
+            created: self.clock.now_utc(),
+            bytes,
+        };
+        let content = serde_json::to_string_pretty(&meta).map_err(OsTraceError::SerializeLog)?;
+
+        // Renamed once written, tooling never reads a partial sidecar
+        let meta_path = archive_meta_path(archive_file_path);
+        let mut tmp_meta_path = meta_path.clone().into_os_string();
+        tmp_meta_path.push(".tmp");
+        tokio::fs::write(&tmp_meta_path, content)
+            .await
+            .map_err(OsTraceError::WriteToFile)?;
+        tokio::fs::rename(&tmp_meta_path, &meta_path)
+            .await
+            .map_err(OsTraceError::WriteToFile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::device::test_support::test_device;
+    use crate::services::os_trace::archive::test_support::write_test_archive;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn t(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn read_meta(archive_file_path: &Path) -> ArchiveMeta {
+        let content = std::fs::read(archive_meta_path(archive_file_path)).unwrap();
+        serde_json::from_slice(&content).unwrap()
+    }
+
+    #[tokio::test]
+    async fn sidecar_matches_the_archive_plist() {
+        let (mut device, base_dir) = test_device("archive-meta");
+        device.set_clock(Arc::new(MockClock::new(t(1_000))));
+        let archive_dir = PathBuf::from(device.get_os_trace_archive_dir());
+        let archive_file_path = archive_dir.join("a_150.tar");
+        write_test_archive(&archive_file_path, 200, 300);
+
+        device
+            .write_archive_meta(&archive_file_path, &(t(150)..t(350)))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            archive_meta_path(&archive_file_path),
+            archive_dir.join("a_150.tar.meta.json")
+        );
+        assert_eq!(
+            read_meta(&archive_file_path),
+            ArchiveMeta {
+                udid: device.info.udid.clone(),
+                requested_start: t(150).into(),
+                requested_end: t(350).into(),
+                coverage_start: Some(t(200).into()),
+                coverage_end: Some(t(300).into()),
+                created: t(1_000).into(),
+                bytes: std::fs::metadata(&archive_file_path).unwrap().len(),
+            }
+        );
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn sidecar_of_an_unreadable_archive_has_no_coverage() {
+        let (device, base_dir) = test_device("archive-meta-unreadable");
+        let archive_file_path = PathBuf::from(device.get_os_trace_archive_dir()).join("a_150.tar");
+        std::fs::write(&archive_file_path, b"not an archive").unwrap();
+
+        device
+            .write_archive_meta(&archive_file_path, &(t(150)..t(350)))
+            .await
+            .unwrap();
+
+        let meta = read_meta(&archive_file_path);
+        assert_eq!(meta.coverage_start, None);
+        assert_eq!(meta.coverage_end, None);
+        assert_eq!(meta.bytes, 14);
+        std::fs::remove_dir_all(base_dir).unwrap();
+    }
+}
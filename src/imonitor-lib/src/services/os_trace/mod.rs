@@ -1,3 +1,5 @@
 pub mod archive;
 pub mod client;
 pub mod errors;
+pub mod meta;
+pub mod reader;
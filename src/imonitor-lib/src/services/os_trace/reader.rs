@@ -0,0 +1,91 @@
+use super::errors::OsTraceError;
+use crate::device::Device;
+use idevice::services::os_trace_relay::OsTraceLog;
+use serde::de::DeserializeOwned;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::ops::Range;
+use std::time::SystemTime;
+
+impl Device {
+    /// Os trace logs of the log file whose timestamp falls in the window, in file order.
+    ///
+    /// Logs are appended as received, so reading stops at the first log past the end of the
+    /// window. Lines that cannot be parsed, such as a partially written last line, are skipped.
+    pub fn read_os_trace_range(
+        &self,
+        window: Range<SystemTime>,
+    ) -> Result<impl Iterator<Item = OsTraceLog>, OsTraceError> {
+        let file = File::open(self.get_os_trace_log_file_path()).map_err(OsTraceError::OpenFile)?;
+        Ok(logs_in_window(BufReader::new(file), window))
+    }
+}
+
+/// Parses the NDJSON lines of `reader`, see [`Device::read_os_trace_range`].
+pub fn logs_in_window(
+    reader: impl BufRead,
+    window: Range<SystemTime>,
+) -> impl Iterator<Item = OsTraceLog> {
+    entries_in_window(reader, window, |log: &OsTraceLog| {
+        log.timestamp.and_utc().into()
+    })
+}
+
+/// Parses the NDJSON lines of `reader` in the window, `date` giving the time of an entry.
+fn entries_in_window<T: DeserializeOwned>(
+    reader: impl BufRead,
+    window: Range<SystemTime>,
+    date: impl Fn(&T) -> SystemTime,
+) -> impl Iterator<Item = T> {
+    let Range { start, end } = window;
+    reader
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|line| serde_json::from_str::<T>(&line).ok())
+        .map(move |entry| (date(&entry), entry))
+        .skip_while(move |(date, _)| *date < start)
+        .take_while(move |(date, _)| *date < end)
+        .map(|(_, entry)| entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::time::Duration;
+
+    #[derive(Debug, Deserialize)]
+    struct Entry {
+        secs: u64,
+        message: String,
+    }
+
+    fn t(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    const FIXTURE: &str = r#"{"secs":5,"message":"before"}
+{"secs":9,"message":"before"}
+{"secs":10,"message":"first"}
+not json
+{"secs":15,"message":"second"}
+{"secs":19,"message":"third"}
+{"secs":20,"message":"after"}
+{"secs":12,"message":"past the end"}
+{"secs":30,"mess"#;
+
+    fn messages(window: Range<SystemTime>) -> Vec<String> {
+        entries_in_window(FIXTURE.as_bytes(), window, |entry: &Entry| t(entry.secs))
+            .map(|entry| entry.message)
+            .collect()
+    }
+
+    #[test]
+    fn only_entries_in_the_window_are_read() {
+        // Reading stops at the first entry past the window, later ones are never parsed
+        assert_eq!(messages(t(10)..t(20)), ["first", "second", "third"]);
+        assert_eq!(messages(t(11)..t(19)), ["second"]);
+        assert!(messages(t(0)..t(5)).is_empty());
+        assert!(messages(t(40)..t(50)).is_empty());
+    }
+}
@@ -1,46 +1,67 @@
+use super::entry::{SyslogEntry, sanitize_line};
 use super::errors::SyslogError;
+use crate::config::{SyslogConfig, SyslogFormat};
+use crate::connection::ConnectionPriority;
 use crate::device::Device;
-use idevice::{IdeviceService, syslog_relay::SyslogRelayClient};
+use idevice::{IdeviceError, IdeviceService, syslog_relay::SyslogRelayClient};
 use logger::HasLogger;
-use logger::{debug, error, info};
-use std::path::PathBuf;
+use logger::{TruncateLock, debug, error, info};
+use std::path::{Path, PathBuf};
 use tokio::fs::File;
 use tokio::io::{AsyncWriteExt, BufWriter};
 use tokio::sync::watch;
-use tokio::time::{Duration, sleep, timeout};
+use tokio::time::{Duration, Instant, Interval, MissedTickBehavior, interval_at, sleep, timeout};
 
 const RETRY_CONNECT_WAIT_SECS: u64 = 5;
+// Guards against a zero flush interval, which tokio rejects
+const MIN_FLUSH_INTERVAL_MS: u64 = 100;
 const SYSLOG_FILE_NAME: &str = "syslog.log";
 
 impl Device {
     pub async fn stream_syslog(
         &self,
         refresh_rate: Duration,
+        syslog_config: SyslogConfig,
+        flush_interval: Duration,
+        read_timeout: Duration,
         hb_connected_rx: &mut watch::Receiver<bool>,
     ) -> Result<(), SyslogError> {
         let provider = self.get_provider("syslog");
 
         let mut _interval = refresh_rate.as_secs();
 
-        let syslog_base_path = PathBuf::from(self.get_syslog_dir());
-        let syslog_file_path = syslog_base_path.join(SYSLOG_FILE_NAME);
+        let syslog_file_path = self.get_syslog_file_path();
         let mut f = BufWriter::new(
             File::options()
                 .append(true)
                 .create(true)
-                .open(syslog_file_path)
+                .open(&syslog_file_path)
                 .await
                 .map_err(SyslogError::OpenFile)?,
         );
+        // Taken for each write, imonitor-send truncates the log under it
+        let truncate_lock =
+            TruncateLock::open(Path::new(&syslog_file_path)).map_err(SyslogError::OpenFile)?;
+
+        let mut paused_rx = self.paused.subscribe();
+
+        let flush_interval = flush_interval.max(Duration::from_millis(MIN_FLUSH_INTERVAL_MS));
+        let mut flush_tick = interval_at(Instant::now() + flush_interval, flush_interval);
+        flush_tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
         loop {
+            self.wait_unpaused().await;
             // Wait for heartbeat connected state
             if hb_connected_rx.wait_for(|val| *val).await.is_ok()
-                && let Ok(connection) = timeout(
-                    Duration::from_secs(2),
-                    SyslogRelayClient::connect(&*provider),
-                )
-                .await
+                && let Ok(connection) = self
+                    .limit_connect(
+                        ConnectionPriority::Low,
+                        timeout(
+                            Duration::from_secs(2),
+                            SyslogRelayClient::connect(&*provider),
+                        ),
+                    )
+                    .await
             {
                 debug!(self, "Connecting syslog");
                 // Got response before timeout
@@ -48,7 +69,18 @@ impl Device {
                     Ok(mut client) => {
                         info!(self, "Syslog connected");
                         loop {
-                            match write_log(&mut client, &mut f, hb_connected_rx).await {
+                            match write_log(
+                                client.next(),
+                                &mut f,
+                                hb_connected_rx,
+                                &mut paused_rx,
+                                &mut flush_tick,
+                                &truncate_lock,
+                                read_timeout,
+                                &syslog_config,
+                            )
+                            .await
+                            {
                                 Err(e) => match e {
                                     SyslogError::Connect(err) => {
                                         error!(self, "Service needs reconnecting, retrying: {err}");
@@ -65,11 +97,19 @@ impl Device {
                                     }
                                     err => {
                                         error!(self, "Failed to write logs: {err}");
+                                        self.observer.on_service_error(
+                                            &self.info.udid,
+                                            "syslog",
+                                            &err,
+                                        );
                                         return Err(err);
                                     }
                                 },
                                 Ok(new_heartbeat) => {
-                                    if new_heartbeat {
+                                    if new_heartbeat && self.is_paused() {
+                                        info!(self, "Collection paused, disconnecting");
+                                        break;
+                                    } else if new_heartbeat {
                                         info!(self, "New heartbeat, reconnecting");
                                         sleep(Duration::from_secs(RETRY_CONNECT_WAIT_SECS)).await;
                                         break;
@@ -92,39 +132,89 @@ impl Device {
             }
         }
     }
+
+    pub fn get_syslog_file_path(&self) -> String {
+        let syslog_base_path = PathBuf::from(self.get_syslog_dir());
+        let syslog_file_path = syslog_base_path.join(SYSLOG_FILE_NAME);
+        syslog_file_path.to_string_lossy().to_string()
+    }
 }
 
+/// Waits for the next log, `next_log`, and writes it. Returns whether streaming must
+/// restart, on a new heartbeat or a pause.
 async fn write_log<T>(
-    client: &mut SyslogRelayClient,
+    next_log: impl Future<Output = Result<String, IdeviceError>>,
     writer: &mut T,
     hb_connected_rx: &mut watch::Receiver<bool>,
+    paused_rx: &mut watch::Receiver<bool>,
+    flush_tick: &mut Interval,
+    truncate_lock: &TruncateLock,
+    read_timeout: Duration,
+    syslog_config: &SyslogConfig,
 ) -> Result<bool, SyslogError>
 where
     T: tokio::io::AsyncWrite + std::marker::Unpin,
 {
     let mut hb_rx = hb_connected_rx.clone();
-    let res = tokio::select!(
-        ok = async {
-            // Heartbeat lost
-            hb_rx.changed().await
-                .map_err(SyslogError::HeartbeatWatch)?;
-            // Heartbeat retrieved, no need to continue streaming
-            hb_rx.wait_for(|val| *val).await
-                .map_err(SyslogError::HeartbeatWatch)?;
-            Ok::<_,SyslogError>(())
-        } => {
-            ok?;
-            None
-        },
-        log = client.next() => {
-           // Log received
-           Some(log)
-        }
-    );
+    let mut new_heartbeat = std::pin::pin!(async {
+        // Heartbeat lost
+        hb_rx.changed().await.map_err(SyslogError::HeartbeatWatch)?;
+        // Heartbeat retrieved, no need to continue streaming
+        hb_rx
+            .wait_for(|val| *val)
+            .await
+            .map_err(SyslogError::HeartbeatWatch)?;
+        Ok::<_, SyslogError>(())
+    });
+    let mut paused = std::pin::pin!(paused_rx.wait_for(|paused| *paused));
+    // Kept across flushes: dropping a pending read could lose part of a log
+    let mut next_log = std::pin::pin!(next_log);
+    // Not reset by flushes, only by a log
+    let mut read_deadline = std::pin::pin!(sleep(read_timeout));
+
+    let res = loop {
+        tokio::select!(
+            ok = &mut new_heartbeat => {
+                ok?;
+                break None;
+            },
+            _ = &mut paused => {
+                // Collection paused, stop streaming
+                break None;
+            },
+            _ = flush_tick.tick() => {
+                let _guard = truncate_lock
+                    .shared_async()
+                    .await
+                    .map_err(SyslogError::WriteToFile)?;
+                writer.flush().await.map_err(SyslogError::WriteToFile)?;
+            },
+            _ = &mut read_deadline => {
+                // Half-open connection, reconnecting
+                return Err(SyslogError::Timeout);
+            },
+            log = &mut next_log => {
+               // Log received
+               break Some(log);
+            }
+        );
+    };
 
     if let Some(log) = res {
         let mut log = log.map_err(SyslogError::Connect)?;
+        if syslog_config.sanitize {
+            log = sanitize_line(&log);
+        }
+        if syslog_config.format == SyslogFormat::Json {
+            log = serde_json::to_string(&SyslogEntry::parse(&log))
+                .map_err(SyslogError::SerializeLog)?;
+        }
         log.push('\n');
+        // The writer may flush while writing
+        let _guard = truncate_lock
+            .shared_async()
+            .await
+            .map_err(SyslogError::WriteToFile)?;
         writer
             .write_all(log.as_bytes())
             .await
@@ -133,6 +223,95 @@ where
         Ok(false)
     } else {
         // New heartbeat, init new syslog connection
+        let _guard = truncate_lock
+            .shared_async()
+            .await
+            .map_err(SyslogError::WriteToFile)?;
+        writer.flush().await.map_err(SyslogError::WriteToFile)?;
         Ok(true)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn buffered_logs_are_flushed_without_new_logs() {
+        let dir = std::env::temp_dir().join(format!("imonitor-flush-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let log_path = dir.join(SYSLOG_FILE_NAME);
+        let truncate_lock = TruncateLock::open(&log_path).unwrap();
+        let mut writer = BufWriter::new(File::create(&log_path).await.unwrap());
+        writer.write_all(b"buffered\n").await.unwrap();
+        assert!(std::fs::read(&log_path).unwrap().is_empty());
+
+        let (_hb_tx, mut hb_rx) = watch::channel(true);
+        let (_paused_tx, mut paused_rx) = watch::channel(false);
+        let flush_interval = Duration::from_millis(50);
+        let mut flush_tick = interval_at(Instant::now() + flush_interval, flush_interval);
+
+        // The device stays silent until the read timeout
+        let result = write_log(
+            std::future::pending(),
+            &mut writer,
+            &mut hb_rx,
+            &mut paused_rx,
+            &mut flush_tick,
+            &truncate_lock,
+            Duration::from_millis(300),
+            &SyslogConfig::default(),
+        )
+        .await;
+        assert!(matches!(result, Err(SyslogError::Timeout)));
+        assert_eq!(std::fs::read(&log_path).unwrap(), b"buffered\n");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stream_stopping_mid_way_times_out() {
+        let dir = std::env::temp_dir().join(format!("imonitor-timeout-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let log_path = dir.join(SYSLOG_FILE_NAME);
+        let truncate_lock = TruncateLock::open(&log_path).unwrap();
+        let mut writer = BufWriter::new(File::create(&log_path).await.unwrap());
+        let (_hb_tx, mut hb_rx) = watch::channel(true);
+        let (_paused_tx, mut paused_rx) = watch::channel(false);
+        let flush_interval = Duration::from_secs(60);
+        let mut flush_tick = interval_at(Instant::now() + flush_interval, flush_interval);
+        let read_timeout = Duration::from_millis(200);
+        let syslog_config = SyslogConfig::default();
+
+        let result = write_log(
+            async {
+                Ok::<_, IdeviceError>("Oct 16 12:34:56 iPhone kernel <Notice>: up".to_string())
+            },
+            &mut writer,
+            &mut hb_rx,
+            &mut paused_rx,
+            &mut flush_tick,
+            &truncate_lock,
+            read_timeout,
+            &syslog_config,
+        )
+        .await;
+        assert!(matches!(result, Ok(false)));
+
+        // Then the device stops producing data
+        let result = write_log(
+            std::future::pending(),
+            &mut writer,
+            &mut hb_rx,
+            &mut paused_rx,
+            &mut flush_tick,
+            &truncate_lock,
+            read_timeout,
+            &syslog_config,
+        )
+        .await;
+        assert!(matches!(result, Err(SyslogError::Timeout)));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,165 @@
+use serde::Serialize;
+
+/// Syslog relay line split into fields, e.g.
+/// `Oct 16 12:34:56 iPhone SpringBoard(FrontBoard)[58] <Notice>: message`.
+/// Unparsed lines only have `message` set, with the full line.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct SyslogEntry {
+    pub timestamp: Option<String>,
+    pub device: Option<String>,
+    pub process: Option<String>,
+    pub sender: Option<String>,
+    pub pid: Option<u32>,
+    pub priority: Option<String>,
+    pub message: String,
+}
+
+// "Mmm dd HH:MM:SS"
+const TIMESTAMP_LEN: usize = 15;
+
+/// Escapes the control characters of a line as `\xNN` or `\u{NNNN}`, tabs excepted, and drops
+/// its line ending. Replacement characters of lossily decoded bytes are kept.
+pub fn sanitize_line(line: &str) -> String {
+    let line = line.trim_end_matches(['\r', '\n']);
+    let mut sanitized = String::with_capacity(line.len());
+    for c in line.chars() {
+        match c {
+            '\t' => sanitized.push(c),
+            c if c.is_ascii_control() => sanitized.push_str(&format!("\\x{:02x}", c as u32)),
+            c if c.is_control() => sanitized.push_str(&format!("\\u{{{:04x}}}", c as u32)),
+            c => sanitized.push(c),
+        }
+    }
+    sanitized
+}
+
+impl SyslogEntry {
+    pub fn parse(line: &str) -> SyslogEntry {
+        let line = line.trim_end_matches(['\r', '\n']);
+        Self::parse_fields(line).unwrap_or_else(|| SyslogEntry {
+            message: line.to_string(),
+            ..Default::default()
+        })
+    }
+
+    fn parse_fields(line: &str) -> Option<SyslogEntry> {
+        let timestamp = line.get(..TIMESTAMP_LEN)?;
+        let rest = line.get(TIMESTAMP_LEN..)?.strip_prefix(' ')?;
+
+        let (device, rest) = rest.split_once(' ')?;
+
+        // Header ends with the first "<Priority>: ", the message may contain anything
+        let priority_start = rest.find(" <")?;
+        let (process_part, rest) = rest.split_at(priority_start);
+        let (priority, message) = rest[2..].split_once(">: ").or_else(|| {
+            // Empty message
+            rest[2..].strip_suffix(">:").map(|priority| (priority, ""))
+        })?;
+
+        // process(sender)[pid], sender and pid are optional
+        let (process_part, pid) = match process_part.strip_suffix(']') {
+            Some(without_bracket) => {
+                let (process_part, pid) = without_bracket.rsplit_once('[')?;
+                (process_part, Some(pid.parse().ok()?))
+            }
+            None => (process_part, None),
+        };
+        let (process, sender) = match process_part.strip_suffix(')') {
+            Some(without_paren) => {
+                let (process, sender) = without_paren.split_once('(')?;
+                (process, Some(sender.to_string()))
+            }
+            None => (process_part, None),
+        };
+
+        Some(SyslogEntry {
+            timestamp: Some(timestamp.to_string()),
+            device: Some(device.to_string()),
+            process: Some(process.to_string()),
+            sender,
+            pid,
+            priority: Some(priority.to_string()),
+            message: message.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_header_is_split_into_fields() {
+        let entry = SyslogEntry::parse(
+            "Oct 16 12:34:56 iPhone SpringBoard(FrontBoard)[58] <Notice>: Scene: [a:b] ok\n",
+        );
+        assert_eq!(
+            entry,
+            SyslogEntry {
+                timestamp: Some("Oct 16 12:34:56".to_string()),
+                device: Some("iPhone".to_string()),
+                process: Some("SpringBoard".to_string()),
+                sender: Some("FrontBoard".to_string()),
+                pid: Some(58),
+                priority: Some("Notice".to_string()),
+                message: "Scene: [a:b] ok".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn message_may_contain_a_header_like_part() {
+        let entry =
+            SyslogEntry::parse("Oct  6 01:02:03 iPad kernel <Error>: x <Notice>: y(z)[1]: w");
+        assert_eq!(entry.timestamp.as_deref(), Some("Oct  6 01:02:03"));
+        assert_eq!(entry.process.as_deref(), Some("kernel"));
+        assert_eq!(entry.sender, None);
+        assert_eq!(entry.pid, None);
+        assert_eq!(entry.priority.as_deref(), Some("Error"));
+        assert_eq!(entry.message, "x <Notice>: y(z)[1]: w");
+    }
+
+    #[test]
+    fn sender_with_dots_and_empty_message() {
+        let entry = SyslogEntry::parse(
+            "Oct 16 12:34:56 iPhone locationd(com.apple.CoreLocation)[77] <Debug>:",
+        );
+        assert_eq!(entry.process.as_deref(), Some("locationd"));
+        assert_eq!(entry.sender.as_deref(), Some("com.apple.CoreLocation"));
+        assert_eq!(entry.pid, Some(77));
+        assert_eq!(entry.message, "");
+    }
+
+    #[test]
+    fn unparsed_lines_keep_the_full_line() {
+        for line in [
+            "",
+            "=== Device connected ===",
+            "Oct 16 12:34:56 iPhone app[notapid] <Notice>: message",
+            "Oct 16 12:34:56 iPhone app[1] no priority: message",
+        ] {
+            assert_eq!(
+                SyslogEntry::parse(line),
+                SyslogEntry {
+                    message: line.to_string(),
+                    ..Default::default()
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn control_characters_are_escaped() {
+        assert_eq!(sanitize_line("a\0b\x1b[0m\tc\r\n"), "a\\x00b\\x1b[0m\tc");
+        // Embedded line breaks would split the line
+        assert_eq!(sanitize_line("a\nb\rc\n"), "a\\x0ab\\x0dc");
+        assert_eq!(sanitize_line("a\u{85}b"), "a\\u{0085}b");
+    }
+
+    #[test]
+    fn invalid_bytes_stay_replacement_characters() {
+        let line = String::from_utf8_lossy(b"caf\xc3 \xff\xfe ok\0");
+        assert_eq!(sanitize_line(&line), "caf\u{fffd} \u{fffd}\u{fffd} ok\\x00");
+        assert_eq!(sanitize_line("déjà vu ✓"), "déjà vu ✓");
+    }
+}
@@ -5,6 +5,7 @@ pub enum SyslogError {
     OpenFile(std::io::Error),
     WriteToFile(std::io::Error),
     Connect(IdeviceError),
+    SerializeLog(serde_json::Error),
     HeartbeatWatch(tokio::sync::watch::error::RecvError),
     Timeout,
 }
@@ -17,6 +18,7 @@ impl std::fmt::Display for SyslogError {
             SyslogError::WriteToFile(e) => write!(f, "Failed to write to syslog file : {e}"),
             SyslogError::OpenFile(e) => write!(f, "Failed to open/create syslog file : {e}"),
             SyslogError::Connect(e) => write!(f, "Failed to connect to syslog service : {e}"),
+            SyslogError::SerializeLog(e) => write!(f, "Failed to serialize syslog entry: {e}"),
             SyslogError::HeartbeatWatch(e) => write!(f, "Heartbeat watch receiver failed: {e}"),
             SyslogError::Timeout => write!(f, "Syslog waiting timeout"),
         }
@@ -0,0 +1,144 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// Encoding of the state files, such as `activity_coverage.json` or `known_crashes.json`.
+pub trait StateStore {
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>, StateStoreError>;
+    fn decode<T: DeserializeOwned>(&self, content: &[u8]) -> Result<T, StateStoreError>;
+}
+
+/// Pretty-printed JSON, readable when debugging.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonStore;
+
+/// CBOR, smaller and faster for large sets.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CborStore;
+
+impl StateStore for JsonStore {
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>, StateStoreError> {
+        serde_json::to_vec_pretty(value).map_err(StateStoreError::Json)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, content: &[u8]) -> Result<T, StateStoreError> {
+        serde_json::from_slice(content).map_err(StateStoreError::Json)
+    }
+}
+
+impl StateStore for CborStore {
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>, StateStoreError> {
+        let mut content = Vec::new();
+        ciborium::into_writer(value, &mut content)
+            .map_err(|e| StateStoreError::Cbor(e.to_string()))?;
+        Ok(content)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, content: &[u8]) -> Result<T, StateStoreError> {
+        ciborium::from_reader(content).map_err(|e| StateStoreError::Cbor(e.to_string()))
+    }
+}
+
+/// Format the state files are written in, see `state_format` in the config. Files are
+/// read whatever their format, so that switching keeps the existing state.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StateFormat {
+    #[default]
+    Json,
+    Cbor,
+}
+
+impl StateFormat {
+    /// Format of an existing file. The state files are JSON objects, arrays, strings or
+    /// unsigned numbers, which no CBOR encoding of them starts with.
+    pub fn detect(content: &[u8]) -> StateFormat {
+        match content.iter().find(|byte| !byte.is_ascii_whitespace()) {
+            Some(b'{' | b'[' | b'"' | b'0'..=b'9') | None => StateFormat::Json,
+            Some(_) => StateFormat::Cbor,
+        }
+    }
+}
+
+impl StateStore for StateFormat {
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>, StateStoreError> {
+        match self {
+            StateFormat::Json => JsonStore.encode(value),
+            StateFormat::Cbor => CborStore.encode(value),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, content: &[u8]) -> Result<T, StateStoreError> {
+        match self {
+            StateFormat::Json => JsonStore.decode(content),
+            StateFormat::Cbor => CborStore.decode(content),
+        }
+    }
+}
+
+/// Decodes a state file in the format it was written in.
+pub fn decode_state<T: DeserializeOwned>(content: &[u8]) -> Result<T, StateStoreError> {
+    StateFormat::detect(content).decode(content)
+}
+
+#[derive(Debug)]
+pub enum StateStoreError {
+    Json(serde_json::Error),
+    Cbor(String),
+}
+
+impl std::error::Error for StateStoreError {}
+
+impl std::fmt::Display for StateStoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            StateStoreError::Json(e) => write!(f, "Invalid JSON state: {e}"),
+            StateStoreError::Cbor(e) => write!(f, "Invalid CBOR state: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn sample() -> BTreeMap<String, u32> {
+        BTreeMap::from([("a.ips".to_string(), 1), ("b.ips".to_string(), 2)])
+    }
+
+    #[test]
+    fn round_trips_in_both_formats() {
+        for format in [StateFormat::Json, StateFormat::Cbor] {
+            let content = format.encode(&sample()).unwrap();
+            assert_eq!(StateFormat::detect(&content), format);
+            assert_eq!(
+                decode_state::<BTreeMap<String, u32>>(&content).unwrap(),
+                sample()
+            );
+        }
+    }
+
+    #[test]
+    fn detects_json_values() {
+        for content in [&b"{}"[..], b"  [1]", b"\"2024-01-01T00:00:00Z\"", b"3", b""] {
+            assert_eq!(StateFormat::detect(content), StateFormat::Json);
+        }
+    }
+
+    #[test]
+    fn numbers_and_strings_round_trip_in_cbor() {
+        let content = StateFormat::Cbor.encode(&3u32).unwrap();
+        assert_eq!(decode_state::<u32>(&content).unwrap(), 3);
+
+        let content = StateFormat::Cbor.encode("2024-01-01T00:00:00Z").unwrap();
+        assert_eq!(
+            decode_state::<String>(&content).unwrap(),
+            "2024-01-01T00:00:00Z"
+        );
+    }
+
+    #[test]
+    fn invalid_content_is_an_error() {
+        assert!(decode_state::<BTreeMap<String, u32>>(b"{not json").is_err());
+    }
+}
@@ -0,0 +1,154 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, ready};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWrite;
+use tokio::time::{Sleep, sleep};
+
+/// Maximum burst allowed after an idle period, in seconds of budget.
+const BURST_SECS: u64 = 1;
+
+/// Limits downloaded bytes per second, shared by every download consulting it.
+///
+/// Bytes are accounted after they are received: callers sleep afterwards to stay under
+/// budget. This trades download latency for link friendliness.
+#[derive(Debug)]
+pub struct BandwidthLimiter {
+    bytes_per_sec: u64,
+    // Instant at which all the bytes accounted so far fit in the budget
+    next_free: Mutex<Instant>,
+}
+
+impl BandwidthLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec: bytes_per_sec.max(1),
+            next_free: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Accounts for `bytes` and returns how long the caller has to wait.
+    pub fn reserve(&self, bytes: u64) -> Duration {
+        let now = Instant::now();
+        let cost = Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64);
+        let burst_start = now
+            .checked_sub(Duration::from_secs(BURST_SECS))
+            .unwrap_or(now);
+
+        let mut next_free = match self.next_free.lock() {
+            Ok(next_free) => next_free,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        *next_free = (*next_free).max(burst_start) + cost;
+        next_free.saturating_duration_since(now)
+    }
+
+    /// Accounts for `bytes` and sleeps as long as needed.
+    pub async fn consume(&self, bytes: u64) {
+        let wait = self.reserve(bytes);
+        if !wait.is_zero() {
+            sleep(wait).await;
+        }
+    }
+}
+
+/// Writer that waits on a [`BandwidthLimiter`] after each write. No-op without limiter.
+pub struct ThrottledWriter<W> {
+    inner: W,
+    limiter: Option<Arc<BandwidthLimiter>>,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<W> ThrottledWriter<W> {
+    pub fn new(inner: W, limiter: Option<Arc<BandwidthLimiter>>) -> Self {
+        Self {
+            inner,
+            limiter,
+            delay: None,
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for ThrottledWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if let Some(delay) = this.delay.as_mut() {
+            ready!(delay.as_mut().poll(cx));
+            this.delay = None;
+        }
+
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+
+        if let Some(limiter) = &this.limiter {
+            let wait = limiter.reserve(written as u64);
+            if !wait.is_zero() {
+                this.delay = Some(Box::pin(sleep(wait)));
+            }
+        }
+
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    fn assert_between(wait: Duration, min_ms: u64, max_ms: u64) {
+        assert!(
+            wait >= Duration::from_millis(min_ms) && wait <= Duration::from_millis(max_ms),
+            "{wait:?}"
+        );
+    }
+
+    #[test]
+    fn waits_in_proportion_to_the_bytes() {
+        let limiter = BandwidthLimiter::new(1000);
+
+        assert_between(limiter.reserve(500), 400, 500);
+        // Accounted after the first reservation
+        assert_between(limiter.reserve(500), 900, 1000);
+    }
+
+    #[test]
+    fn idle_budget_allows_a_burst() {
+        let limiter = BandwidthLimiter::new(1000);
+        *limiter.next_free.lock().unwrap() = Instant::now() - Duration::from_secs(10);
+
+        // Up to a second of budget is saved, not the whole idle period
+        assert_eq!(limiter.reserve(1000), Duration::ZERO);
+        assert_between(limiter.reserve(1000), 900, 1000);
+    }
+
+    #[test]
+    fn zero_limit_is_one_byte_per_second() {
+        let limiter = BandwidthLimiter::new(0);
+        assert_between(limiter.reserve(1), 900, 1000);
+    }
+
+    #[tokio::test]
+    async fn throttled_writer_writes_everything() {
+        let limiter = Arc::new(BandwidthLimiter::new(u64::MAX));
+        for limiter in [None, Some(limiter)] {
+            let mut writer = ThrottledWriter::new(Vec::new(), limiter);
+            writer.write_all(b"crash report").await.unwrap();
+            writer.flush().await.unwrap();
+            assert_eq!(writer.inner, b"crash report");
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use imonitor_lib::object_store::BackendError;
+
+#[derive(Debug)]
+pub enum SendError {
+    Config(String),
+    Io(std::io::Error, String),
+    Backend(BackendError),
+    Serialize(serde_json::Error, String),
+    Exhausted {
+        attempts: u32,
+        source: Box<SendError>,
+    },
+}
+
+impl SendError {
+    /// Errors that retrying on the next check cannot fix.
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            SendError::Config(_) | SendError::Backend(BackendError::Config(_))
+        )
+    }
+}
+
+impl std::error::Error for SendError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SendError::Exhausted { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for SendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SendError::Config(e) => write!(f, "Invalid configuration: {e}"),
+            SendError::Io(e, path) => write!(f, "I/O error on {path}: {e}"),
+            SendError::Backend(e) => write!(f, "{e}"),
+            SendError::Serialize(e, path) => {
+                write!(f, "Failed to (de)serialize {path}: {e}")
+            }
+            SendError::Exhausted { attempts, source } => {
+                write!(
+                    f,
+                    "All {attempts} upload attempts failed, last error: {source}"
+                )
+            }
+        }
+    }
+}
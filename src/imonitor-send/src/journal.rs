@@ -0,0 +1,153 @@
+use super::ChunkMetadata;
+use crate::errors::SendError;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// Chunk written to the journal but not confirmed uploaded yet.
+/// `<sequence>.log` holds the data and `<sequence>.json` its metadata.
+pub struct PendingChunk {
+    pub metadata: ChunkMetadata,
+    data_path: PathBuf,
+    metadata_path: PathBuf,
+}
+
+impl PendingChunk {
+    pub fn read_data(&self) -> Result<Vec<u8>, SendError> {
+        fs::read(&self.data_path).map_err(|e| io_error(e, &self.data_path))
+    }
+
+    /// Removes the chunk from the journal, once uploaded.
+    pub fn remove(&self) -> Result<(), SendError> {
+        fs::remove_file(&self.metadata_path).map_err(|e| io_error(e, &self.metadata_path))?;
+        fs::remove_file(&self.data_path).map_err(|e| io_error(e, &self.data_path))
+    }
+}
+
+/// Writes a chunk to the journal. The metadata file is written last: a chunk without
+/// metadata was not fully journaled and is ignored.
+pub fn write(dir: &Path, metadata: &ChunkMetadata, data: &[u8]) -> Result<(), SendError> {
+    fs::create_dir_all(dir).map_err(|e| io_error(e, dir))?;
+
+    let data_path = dir.join(format!("{}.log", metadata.sequence));
+    fs::write(&data_path, data).map_err(|e| io_error(e, &data_path))?;
+
+    let metadata_path = dir.join(format!("{}.json", metadata.sequence));
+    let tmp_path = metadata_path.with_extension("json.tmp");
+    let content = serde_json::to_string_pretty(metadata)
+        .map_err(|e| SendError::Serialize(e, metadata_path.display().to_string()))?;
+    fs::write(&tmp_path, content).map_err(|e| io_error(e, &tmp_path))?;
+    fs::rename(&tmp_path, &metadata_path).map_err(|e| io_error(e, &metadata_path))?;
+
+    Ok(())
+}
+
+/// Lists journaled chunks, oldest first.
+pub fn list(dir: &Path) -> Result<Vec<PendingChunk>, SendError> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(io_error(e, dir)),
+    };
+
+    let mut chunks = Vec::new();
+    for entry in entries {
+        let metadata_path = entry.map_err(|e| io_error(e, dir))?.path();
+        if metadata_path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+            continue;
+        }
+
+        let metadata: ChunkMetadata = match fs::read_to_string(&metadata_path)
+            .map_err(|e| io_error(e, &metadata_path))
+            .and_then(|content| {
+                serde_json::from_str(&content)
+                    .map_err(|e| SendError::Serialize(e, metadata_path.display().to_string()))
+            }) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                warn!("Ignoring pending chunk {}: {}", metadata_path.display(), e);
+                continue;
+            }
+        };
+
+        let data_path = metadata_path.with_extension("log");
+        if !data_path.exists() {
+            warn!(
+                "Ignoring pending chunk {}: data is missing",
+                metadata_path.display()
+            );
+            continue;
+        }
+
+        chunks.push(PendingChunk {
+            metadata,
+            data_path,
+            metadata_path,
+        });
+    }
+
+    chunks.sort_by_key(|chunk| chunk.metadata.sequence);
+    Ok(chunks)
+}
+
+fn io_error(e: std::io::Error, path: &Path) -> SendError {
+    SendError::Io(e, path.display().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(sequence: u64) -> ChunkMetadata {
+        ChunkMetadata {
+            key: format!("{sequence}.log"),
+            udid: None,
+            sequence,
+            line_count: 1,
+            byte_start: 0,
+            byte_end: 6,
+        }
+    }
+
+    fn test_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("imonitor-send-journal-{}", uuid::Uuid::new_v4()))
+    }
+
+    #[test]
+    fn written_chunk_is_listed_and_removed() {
+        let dir = test_dir();
+        write(&dir, &metadata(3), b"chunk\n").unwrap();
+
+        let chunks = list(&dir).unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].metadata.sequence, 3);
+        assert_eq!(chunks[0].read_data().unwrap(), b"chunk\n");
+
+        chunks[0].remove().unwrap();
+        assert!(list(&dir).unwrap().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn incomplete_chunks_are_ignored() {
+        let dir = test_dir();
+        write(&dir, &metadata(1), b"chunk\n").unwrap();
+        // Crash before the metadata was written, and metadata whose data was lost
+        fs::write(dir.join("2.log"), b"chunk\n").unwrap();
+        write(&dir, &metadata(3), b"chunk\n").unwrap();
+        fs::remove_file(dir.join("3.log")).unwrap();
+
+        let sequences = list(&dir)
+            .unwrap()
+            .iter()
+            .map(|chunk| chunk.metadata.sequence)
+            .collect::<Vec<_>>();
+        assert_eq!(sequences, vec![1]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn missing_journal_is_empty() {
+        assert!(list(&test_dir()).unwrap().is_empty());
+    }
+}
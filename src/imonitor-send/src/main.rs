@@ -1,138 +1,595 @@
-use aws_config::retry::RetryConfig;
-use aws_config::{BehaviorVersion, defaults};
-use aws_sdk_s3::Client;
-use aws_smithy_types::byte_stream::ByteStream;
 use chrono::Utc;
-use serde::Deserialize;
-use std::{env, error::Error, fs, io::SeekFrom};
+use errors::SendError;
+use imonitor_lib::config::S3Config;
+use imonitor_lib::object_store::{Backend, BackendError, ObjectAttributes, S3Backend};
+use logger::{Rotation, StderrTee, TruncateLock};
+use multipart::MultipartConfig;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use stability::{StabilityConfig, StabilityTracker};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::{fs, io::SeekFrom, path::PathBuf};
 use tokio::io::AsyncReadExt;
 use tokio::{
     fs::OpenOptions,
     io::{AsyncBufReadExt, AsyncSeekExt, AsyncWriteExt, BufReader},
-    time::{Duration, sleep},
+    sync::Semaphore,
+    task::JoinSet,
+    time::{Duration, Instant, sleep},
 };
-use tracing::{error, info, warn};
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+mod errors;
+mod journal;
+mod multipart;
+mod stability;
+
+// Uncompressed log lines
+const CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+const UPLOAD_ATTEMPTS: u32 = 5;
+const DEFAULT_UPLOAD_CONCURRENCY: usize = 4;
+const DEFAULT_MAX_RETRY_DELAY_SECS: u64 = 60;
+const DEFAULT_MAX_CHUNKS_PER_CYCLE: usize = 10;
+const STALE_MULTIPART_SWEEP_INTERVAL_SECS: u64 = 3600;
+const BACKOFF_BASE_MS: u64 = 500;
+// Backoff base once the server said it is throttling without telling for how long
+const THROTTLED_BACKOFF_BASE_MS: u64 = 2000;
+const THROTTLING_ERROR_CODES: [&str; 5] = [
+    "SlowDown",
+    "Throttling",
+    "ThrottlingException",
+    "RequestLimitExceeded",
+    "TooManyRequests",
+];
+// Logs written by imonitor, relative to a device dir
+const DEVICE_LOG_FILES: [&str; 2] = ["os_trace/log/os_trace_log.json", "syslog/syslog.log"];
 
 #[derive(Deserialize)]
 struct Config {
-    log_file_path: String,
+    #[serde(default)]
+    log_file_path: Option<String>,
+    /// UDID of the device the log belongs to, added to object metadata.
+    #[serde(default)]
+    udid: Option<String>,
+    /// Directory of chunks waiting for upload. Defaults to `<log_file_path>.pending`.
+    #[serde(default)]
+    pending_dir: Option<String>,
+    /// imonitor base dir. The logs of every device dir found there are uploaded too.
+    #[serde(default)]
+    devices_dir: Option<String>,
+    /// Maximum number of devices whose chunks are uploaded at the same time.
+    #[serde(default = "default_upload_concurrency")]
+    upload_concurrency: usize,
+    /// Upper bound of the delay between two upload attempts, including server-provided ones.
+    #[serde(default = "default_max_retry_delay_seconds")]
+    max_retry_delay_seconds: u64,
     chunk_size_mb: usize,
     check_interval_seconds: u64,
+    /// Chunks cut from a same log in one check, so that a backlog is drained. A log is
+    /// chunked until it is back under `chunk_size_mb`.
+    #[serde(default = "default_max_chunks_per_cycle")]
+    max_chunks_per_cycle: usize,
+    /// Time after which no new chunk of a log is cut in the current check. Defaults to
+    /// `check_interval_seconds`.
+    #[serde(default)]
+    cycle_budget_seconds: Option<u64>,
     s3: S3Config,
+    /// Large chunks uploaded in parts. Every chunk is uploaded with a single request if unset.
+    #[serde(default)]
+    multipart: Option<MultipartConfig>,
+    /// Logs only chunked once unchanged for a while. Every log is chunked if unset.
+    #[serde(default)]
+    stability: Option<StabilityConfig>,
+    /// Copy of the stderr log to a rolling file.
+    #[serde(default)]
+    log: Option<LogConfig>,
 }
 
 #[derive(Deserialize)]
-struct S3Config {
-    bucket: String,
-    prefix: String,
-    endpoint: String,
+struct LogConfig {
+    path: String,
+    /// "never", "hourly" or "daily"
+    #[serde(default = "default_log_rotation")]
+    rotation: String,
+    #[serde(default)]
+    max_files: Option<usize>,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    tracing_subscriber::fmt::init();
+fn default_log_rotation() -> String {
+    "daily".to_string()
+}
 
+fn default_upload_concurrency() -> usize {
+    DEFAULT_UPLOAD_CONCURRENCY
+}
+
+fn default_max_chunks_per_cycle() -> usize {
+    DEFAULT_MAX_CHUNKS_PER_CYCLE
+}
+
+fn default_max_retry_delay_seconds() -> u64 {
+    DEFAULT_MAX_RETRY_DELAY_SECS
+}
+
+/// Log file uploaded in chunks. Each one has its own upload state and journal.
+struct LogSource {
+    path: String,
+    udid: Option<String>,
+    key_prefix: String,
+    pending_dir: PathBuf,
+}
+
+/// Upload progress, persisted next to the log file so that sequence numbers and offsets
+/// keep increasing across restarts.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct UploadState {
+    next_sequence: u64,
+    uploaded_bytes: u64,
+}
+
+/// Attributes of an uploaded chunk, stored as object metadata.
+#[derive(Debug, Serialize, Deserialize)]
+struct ChunkMetadata {
+    key: String,
+    udid: Option<String>,
+    sequence: u64,
+    line_count: usize,
+    // Byte range in the log as if it had never been truncated
+    byte_start: u64,
+    byte_end: u64,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), SendError> {
     // Load config file
-    let config: Config = load_config("config.toml")?;
+    let config = Arc::new(load_config("config.toml")?);
 
-    // Load secrets from env
-    let access_key = env::var("S3_ACCESS_KEY")?;
-    let secret_key = env::var("S3_SECRET_KEY")?;
+    init_tracing(config.log.as_ref())?;
 
-    let client = build_s3_client(&config, &access_key, &secret_key).await?;
+    // Credentials are read from env
+    let backend = S3Backend::connect(&config.s3)
+        .await
+        .map_err(SendError::Backend)?;
 
+    // Set on every object uploaded by this process, to group them
+    let run_id = Uuid::new_v4().to_string();
+    info!("Run ID: {run_id}");
+
+    // Kept across checks
+    let stability = Arc::new(Mutex::new(StabilityTracker::default()));
+
+    let mut last_multipart_sweep: Option<Instant> = None;
     loop {
-        if let Err(e) = process_log_file(&client, &config).await {
-            error!("Error processing log file: {:?}", e);
+        if let Some(multipart) = &config.multipart
+            && last_multipart_sweep.is_none_or(|last| {
+                last.elapsed() >= Duration::from_secs(STALE_MULTIPART_SWEEP_INTERVAL_SECS)
+            })
+        {
+            last_multipart_sweep = Some(Instant::now());
+            match backend
+                .abort_stale_uploads(Duration::from_secs(multipart.stale_after_hours * 3600))
+                .await
+            {
+                Ok(0) => {}
+                Ok(aborted) => info!("Aborted {aborted} stale multipart upload(s)"),
+                Err(e) => warn!("Failed to sweep stale multipart uploads: {e}"),
+            }
+        }
+
+        if let Err(e) = process_all_logs(&backend, &config, &run_id, &stability).await {
+            error!("Error processing logs: {e}");
+            if e.is_fatal() {
+                return Err(e);
+            }
         }
         sleep(Duration::from_secs(config.check_interval_seconds)).await;
     }
 }
 
-fn load_config(path: &str) -> Result<Config, Box<dyn Error>> {
-    let content = fs::read_to_string(path)?;
-    let config: Config = toml::from_str(&content)?;
+fn load_config(path: &str) -> Result<Config, SendError> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| SendError::Config(format!("failed to read {path}: {e}")))?;
+    let config: Config = toml::from_str(&content)
+        .map_err(|e| SendError::Config(format!("failed to parse {path}: {e}")))?;
+
+    if config.log_file_path.is_none() && config.devices_dir.is_none() {
+        return Err(SendError::Config(
+            "either log_file_path or devices_dir must be set".to_string(),
+        ));
+    }
+    if config.max_chunks_per_cycle == 0 {
+        return Err(SendError::Config(
+            "max_chunks_per_cycle must not be zero".to_string(),
+        ));
+    }
+    if config.upload_concurrency == 0 {
+        return Err(SendError::Config(
+            "upload_concurrency must not be zero".to_string(),
+        ));
+    }
+    if config
+        .stability
+        .as_ref()
+        .is_some_and(|stability| stability.stable_checks == 0)
+    {
+        return Err(SendError::Config(
+            "stability.stable_checks must not be zero".to_string(),
+        ));
+    }
+    if let Some(multipart) = &config.multipart {
+        if multipart.threshold_mb == 0 {
+            return Err(SendError::Config(
+                "multipart.threshold_mb must not be zero".to_string(),
+            ));
+        }
+        if multipart.part_size_mb < multipart::MIN_PART_SIZE_MB {
+            return Err(SendError::Config(format!(
+                "multipart.part_size_mb must be at least {}",
+                multipart::MIN_PART_SIZE_MB
+            )));
+        }
+    }
+
     Ok(config)
 }
 
-async fn build_s3_client(
-    config: &Config,
-    access_key: &str,
-    secret_key: &str,
-) -> Result<Client, Box<dyn Error>> {
-    let retry_config = RetryConfig::standard().with_max_attempts(5);
-
-    let aws_config = defaults(BehaviorVersion::latest())
-        .retry_config(retry_config)
-        .endpoint_url(&config.s3.endpoint)
-        .credentials_provider(aws_sdk_s3::config::Credentials::new(
-            access_key, secret_key, None, None, "static",
-        ))
-        .load()
-        .await;
+/// Logs to stderr, and to a rolling file if configured.
+fn init_tracing(log: Option<&LogConfig>) -> Result<(), SendError> {
+    let Some(log) = log else {
+        tracing_subscriber::fmt::init();
+        return Ok(());
+    };
+
+    let rotation = match log.rotation.as_str() {
+        "never" => Rotation::Never,
+        "hourly" => Rotation::Hourly,
+        "daily" => Rotation::Daily,
+        other => {
+            return Err(SendError::Config(format!(
+                "Invalid log rotation \"{other}\""
+            )));
+        }
+    };
+    let appender = logger::rolling_file(Path::new(&log.path), rotation, log.max_files)
+        .map_err(|e| SendError::Io(e, log.path.clone()))?;
 
-    Ok(Client::new(&aws_config))
+    tracing_subscriber::fmt()
+        .with_ansi(false)
+        .with_writer(Mutex::new(StderrTee::new(appender)))
+        .init();
+    Ok(())
 }
 
-async fn process_all_logs(client: &Client, config: &Config) -> Result<(), Box<dyn Error>> {
-    // Find all logs in dirs
-    // Process logs
+/// Uploads the chunks of all logs. Each device has its own queue: its logs are processed one
+/// after the other so that its chunks are uploaded in order, while up to
+/// `upload_concurrency` devices upload at the same time.
+async fn process_all_logs<B: Backend + Clone + Send + Sync + 'static>(
+    backend: &B,
+    config: &Arc<Config>,
+    run_id: &str,
+    stability: &Arc<Mutex<StabilityTracker>>,
+) -> Result<(), SendError> {
+    let mut queues = BTreeMap::<String, Vec<LogSource>>::new();
+    for source in get_log_sources(config)? {
+        let queue = source.udid.clone().unwrap_or_else(|| source.path.clone());
+        queues.entry(queue).or_default().push(source);
+    }
+
+    let limiter = Arc::new(Semaphore::new(config.upload_concurrency));
+    let mut uploads = JoinSet::new();
+
+    for (_, sources) in queues {
+        let backend = backend.clone();
+        let config = config.clone();
+        let limiter = limiter.clone();
+        let run_id = run_id.to_string();
+        let stability = stability.clone();
+
+        uploads.spawn(async move {
+            // Never closed
+            let Ok(_permit) = limiter.acquire_owned().await else {
+                return;
+            };
+
+            for source in &sources {
+                // Chunks left over by a previous run or a failed upload
+                if let Err(e) = process_pending_chunks(&backend, &config, source, &run_id).await {
+                    error!("Error processing pending chunks of {}: {e}", source.path);
+                }
+                if let Err(e) =
+                    process_log_file(&backend, &config, source, &run_id, &stability).await
+                {
+                    error!("Error processing log file {}: {e}", source.path);
+                }
+            }
+        });
+    }
+
+    while let Some(res) = uploads.join_next().await {
+        if let Err(e) = res {
+            error!("Upload task failed: {e}");
+        }
+    }
+
+    Ok(())
 }
 
-async fn process_log_file(client: &Client, config: &Config) -> Result<(), Box<dyn Error>> {
-    let metadata = tokio::fs::metadata(&config.log_file_path).await?;
-    let file_size_mb = metadata.len() / (1024 * 1024);
+/// Lists the configured log file and the existing logs of every device dir.
+fn get_log_sources(config: &Config) -> Result<Vec<LogSource>, SendError> {
+    let mut sources = Vec::new();
+
+    if let Some(path) = &config.log_file_path {
+        sources.push(LogSource {
+            path: path.clone(),
+            udid: config.udid.clone(),
+            key_prefix: config.s3.prefix.clone(),
+            pending_dir: match &config.pending_dir {
+                Some(dir) => PathBuf::from(dir),
+                None => PathBuf::from(format!("{path}.pending")),
+            },
+        });
+    }
+
+    let Some(devices_dir) = &config.devices_dir else {
+        return Ok(sources);
+    };
+
+    let entries = fs::read_dir(devices_dir).map_err(|e| SendError::Io(e, devices_dir.clone()))?;
+
+    for entry in entries {
+        let device_dir = entry
+            .map_err(|e| SendError::Io(e, devices_dir.clone()))?
+            .path();
+        if !device_dir.is_dir() {
+            continue;
+        }
+        let udid = device_dir
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
 
-    if file_size_mb <= config.chunk_size_mb.try_into()? {
+        for log_file in DEVICE_LOG_FILES {
+            let path = device_dir.join(log_file).to_string_lossy().to_string();
+            let pending_dir = PathBuf::from(format!("{path}.pending"));
+            if !fs::exists(&path).unwrap_or(false) && !pending_dir.exists() {
+                continue;
+            }
+
+            // Keys of a device log keep increasing, whatever the other devices upload
+            let log_name = log_file.split('/').next().unwrap_or_default();
+            sources.push(LogSource {
+                key_prefix: format!("{}{udid}/{log_name}/", config.s3.prefix),
+                udid: Some(udid.clone()),
+                path,
+                pending_dir,
+            });
+        }
+    }
+
+    Ok(sources)
+}
+
+/// Cuts and uploads chunks of the log until it is back under the chunk size, or the chunk
+/// count or time budget of the cycle is exhausted.
+async fn process_log_file<B: Backend + Sync>(
+    backend: &B,
+    config: &Config,
+    source: &LogSource,
+    run_id: &str,
+    stability: &Mutex<StabilityTracker>,
+) -> Result<(), SendError> {
+    if let Some(stability_config) = &config.stability
+        && let Ok(metadata) = fs::metadata(&source.path)
+        && let Ok(mut stability) = stability.lock()
+        && !stability.check(&source.path, &metadata, stability_config)
+    {
+        debug!("{} not stable yet, not chunked this check", source.path);
         return Ok(());
     }
 
+    let budget = Duration::from_secs(
+        config
+            .cycle_budget_seconds
+            .unwrap_or(config.check_interval_seconds),
+    );
+    let started = Instant::now();
+
+    for chunk in 1..=config.max_chunks_per_cycle {
+        if !process_log_chunk(backend, config, source, run_id).await? {
+            return Ok(());
+        }
+        if chunk == 1
+            && let Ok(mut stability) = stability.lock()
+        {
+            stability.forget(&source.path);
+        }
+        if started.elapsed() >= budget {
+            info!(
+                "Cycle budget exhausted after {chunk} chunk(s) of {}, resuming next check",
+                source.path
+            );
+            return Ok(());
+        }
+    }
+
+    info!(
+        "{} chunk(s) of {} uploaded this cycle, resuming next check",
+        config.max_chunks_per_cycle, source.path
+    );
+    Ok(())
+}
+
+/// Cuts one chunk from the beginning of the log and uploads it. Returns false if the log is
+/// under the chunk size, so that nothing was cut.
+async fn process_log_chunk<B: Backend + Sync>(
+    backend: &B,
+    config: &Config,
+    source: &LogSource,
+    run_id: &str,
+) -> Result<bool, SendError> {
+    let log_io_error = |e| SendError::Io(e, source.path.clone());
+
+    // Only the pending chunks remain
+    if !fs::exists(&source.path).map_err(log_io_error)? {
+        return Ok(false);
+    }
+
+    let metadata = tokio::fs::metadata(&source.path)
+        .await
+        .map_err(log_io_error)?;
+    let file_size_mb = metadata.len() / (1024 * 1024);
+
+    if file_size_mb <= config.chunk_size_mb as u64 {
+        return Ok(false);
+    }
+
     let chunk_size_bytes = config.chunk_size_mb * 1024 * 1024;
 
     let mut file = OpenOptions::new()
         .read(true)
         .write(true)
-        .open(&config.log_file_path)
-        .await?;
+        .open(&source.path)
+        .await
+        .map_err(log_io_error)?;
 
     let mut reader = BufReader::new(&mut file);
-    reader.seek(SeekFrom::Start(0)).await?;
+    reader
+        .seek(SeekFrom::Start(0))
+        .await
+        .map_err(log_io_error)?;
 
     let mut buffer = Vec::with_capacity(chunk_size_bytes);
     let mut bytes_read = 0;
+    let mut line_count = 0;
 
-    let mut lines = reader.lines();
-    while let Some(line) = lines.next_line().await? {
-        let len = line.len() + 1;
-        if bytes_read + len > chunk_size_bytes {
+    // Lines are kept byte for byte (CRLF included) so that the chunk length is exactly the
+    // offset the log is truncated at
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let len = reader
+            .read_until(b'\n', &mut line)
+            .await
+            .map_err(log_io_error)?;
+        // End of file, or last line still being written
+        if len == 0 || line.last() != Some(&b'\n') {
+            break;
+        }
+        // A line longer than a chunk is uploaded alone, the log would never shrink otherwise
+        if bytes_read + len > chunk_size_bytes && line_count > 0 {
             break;
         }
         bytes_read += len;
-        buffer.extend_from_slice(line.as_bytes());
-        buffer.push(b'\n');
+        buffer.extend_from_slice(&line);
+        line_count += 1;
     }
 
     if buffer.is_empty() {
-        return Ok(());
+        return Ok(false);
     }
 
+    let state_file_path = get_state_file_path(&source.path);
+    let mut state = load_state(&state_file_path)?;
+
+    // A crash between journaling and saving the state must not reuse a journaled sequence
+    let pending_dir = &source.pending_dir;
+    if let Some(last) = journal::list(pending_dir)?.last() {
+        state.next_sequence = state.next_sequence.max(last.metadata.sequence + 1);
+    }
+
+    // The sequence keeps keys unique, a journaled chunk is always uploaded to the same key
     let s3_key = format!(
-        "{}{}.log",
-        config.s3.prefix,
-        Utc::now().format("%Y%m%d-%H%M%S")
+        "{}{}-{}.log",
+        source.key_prefix,
+        Utc::now().format("%Y%m%d-%H%M%S"),
+        state.next_sequence
     );
 
-    upload_to_s3_with_retries(client, &config.s3.bucket, &s3_key, buffer).await?;
+    let metadata = ChunkMetadata {
+        key: s3_key,
+        udid: source.udid.clone(),
+        sequence: state.next_sequence,
+        line_count,
+        byte_start: state.uploaded_bytes,
+        byte_end: state.uploaded_bytes + bytes_read as u64,
+    };
+
+    // Journal the chunk before removing it from the log, so that it survives a crash
+    journal::write(pending_dir, &metadata, &buffer)?;
 
-    truncate_file_preserving_tail(&mut file, bytes_read).await?;
+    // Writers wait for the truncate to complete, see the logger crate for the protocol
+    let truncate_lock = TruncateLock::open(Path::new(&source.path)).map_err(log_io_error)?;
+    let guard = truncate_lock.exclusive().map_err(log_io_error)?;
+    truncate_file_preserving_tail(&mut file, bytes_read)
+        .await
+        .map_err(log_io_error)?;
+    drop(guard);
 
+    state.next_sequence += 1;
+    state.uploaded_bytes = metadata.byte_end;
+    save_state(&state_file_path, &state)?;
+
+    // Left in the journal on failure, retried on next check
+    process_pending_chunks(backend, config, source, run_id).await?;
+    Ok(true)
+}
+
+/// Uploads journaled chunks and removes them from the journal once uploaded.
+///
+/// Uploads are at-least-once: a chunk is uploaded again to the same key if the process stops
+/// before removing it, which S3 handles as an overwrite. A crash between journaling and
+/// truncating the log uploads the same lines again under a new key; the `byte-range`
+/// metadata allows spotting such duplicates.
+async fn process_pending_chunks<B: Backend + Sync>(
+    backend: &B,
+    config: &Config,
+    source: &LogSource,
+    run_id: &str,
+) -> Result<(), SendError> {
+    for chunk in journal::list(&source.pending_dir)? {
+        let data = chunk.read_data()?;
+        upload_to_s3_with_retries(
+            backend,
+            &chunk.metadata,
+            &data,
+            run_id,
+            config.multipart.as_ref(),
+            Duration::from_secs(config.max_retry_delay_seconds),
+        )
+        .await?;
+        chunk.remove()?;
+    }
     Ok(())
 }
 
+fn get_state_file_path(log_file_path: &str) -> String {
+    format!("{log_file_path}.upload_state.json")
+}
+
+fn load_state(path: &str) -> Result<UploadState, SendError> {
+    match fs::read_to_string(path) {
+        Ok(content) => {
+            serde_json::from_str(&content).map_err(|e| SendError::Serialize(e, path.to_string()))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(UploadState::default()),
+        Err(e) => Err(SendError::Io(e, path.to_string())),
+    }
+}
+
+fn save_state(path: &str, state: &UploadState) -> Result<(), SendError> {
+    let content = serde_json::to_string_pretty(state)
+        .map_err(|e| SendError::Serialize(e, path.to_string()))?;
+    fs::write(path, content).map_err(|e| SendError::Io(e, path.to_string()))
+}
+
 async fn truncate_file_preserving_tail(
     file: &mut tokio::fs::File,
     offset: usize,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<(), std::io::Error> {
     let mut remaining = Vec::new();
     file.seek(SeekFrom::Start(offset as u64)).await?;
     file.read_to_end(&mut remaining).await?;
@@ -146,40 +603,754 @@ async fn truncate_file_preserving_tail(
     Ok(())
 }
 
-async fn upload_to_s3_with_retries(
-    client: &Client,
-    bucket: &str,
-    key: &str,
-    data: Vec<u8>,
-) -> Result<(), Box<dyn Error>> {
-    for attempt in 0..5 {
-        match upload_to_s3(client, bucket, key, data.clone()).await {
+async fn upload_to_s3_with_retries<B: Backend + Sync>(
+    backend: &B,
+    metadata: &ChunkMetadata,
+    data: &[u8],
+    run_id: &str,
+    multipart: Option<&MultipartConfig>,
+    max_delay: Duration,
+) -> Result<(), SendError> {
+    let mut last_error = None;
+    for attempt in 0..UPLOAD_ATTEMPTS {
+        match upload_to_s3(backend, metadata, data, run_id, multipart).await {
             Ok(_) => return Ok(()),
             Err(e) => {
-                warn!("S3 upload failed (attempt {}/5): {:?}", attempt + 1, e);
-                let delay = 500 * (2_u64.pow(attempt as u32));
-                sleep(Duration::from_millis(delay)).await;
+                warn!(
+                    "S3 upload failed (attempt {}/{UPLOAD_ATTEMPTS}): {e}",
+                    attempt + 1
+                );
+                // No wait once the last attempt failed
+                if attempt + 1 < UPLOAD_ATTEMPTS {
+                    sleep(retry_delay(&e, attempt, max_delay)).await;
+                }
+                last_error = Some(e);
             }
         }
     }
-    Err("All upload attempts failed".into())
+
+    match last_error {
+        Some(e) => Err(SendError::Exhausted {
+            attempts: UPLOAD_ATTEMPTS,
+            source: Box::new(e),
+        }),
+        None => Ok(()),
+    }
 }
 
-async fn upload_to_s3(
-    client: &Client,
-    bucket: &str,
-    key: &str,
-    data: Vec<u8>,
-) -> Result<(), Box<dyn Error>> {
-    let stream = ByteStream::from(data);
-    client
-        .put_object()
-        .bucket(bucket)
-        .key(key)
-        .body(stream)
-        .send()
-        .await?;
+/// Delay before the next upload attempt: the server retry-after when there is one,
+/// jittered exponential backoff otherwise. Both are capped to `max_delay`.
+fn retry_delay(error: &SendError, attempt: u32, max_delay: Duration) -> Duration {
+    let SendError::Backend(BackendError::Upload {
+        code, retry_after, ..
+    }) = error
+    else {
+        return jittered_backoff(BACKOFF_BASE_MS, attempt, max_delay);
+    };
+
+    if let Some(retry_after) = retry_after {
+        info!("S3 asked to retry after {}s", retry_after.as_secs());
+        return (*retry_after).min(max_delay);
+    }
+
+    let throttled = code
+        .as_deref()
+        .is_some_and(|code| THROTTLING_ERROR_CODES.contains(&code));
+    let base_ms = if throttled {
+        THROTTLED_BACKOFF_BASE_MS
+    } else {
+        BACKOFF_BASE_MS
+    };
+    jittered_backoff(base_ms, attempt, max_delay)
+}
+
+/// Random delay between half and all of the exponential backoff, so that concurrent
+/// uploads do not retry in lockstep.
+fn jittered_backoff(base_ms: u64, attempt: u32, max_delay: Duration) -> Duration {
+    let backoff_ms = base_ms
+        .saturating_mul(2_u64.saturating_pow(attempt))
+        .min(max_delay.as_millis() as u64);
+    let half = backoff_ms / 2;
+    Duration::from_millis(rand::rng().random_range(half..=backoff_ms))
+}
+
+async fn upload_to_s3<B: Backend + Sync>(
+    backend: &B,
+    metadata: &ChunkMetadata,
+    data: &[u8],
+    run_id: &str,
+    multipart: Option<&MultipartConfig>,
+) -> Result<(), SendError> {
+    let attributes = ObjectAttributes {
+        content_type: Some(CONTENT_TYPE.to_string()),
+        metadata: object_metadata(metadata, run_id),
+    };
+
+    if let Some(multipart) = multipart
+        && data.len() >= multipart.threshold_mb * multipart::BYTES_PER_MB
+    {
+        match backend
+            .put_in_parts(
+                &metadata.key,
+                data,
+                &attributes,
+                multipart.part_size_mb * multipart::BYTES_PER_MB,
+            )
+            .await
+        {
+            Ok(()) => {
+                info!("Uploaded to S3 in parts: {}", metadata.key);
+                return Ok(());
+            }
+            // Same key and metadata, so the object does not tell which path uploaded it
+            Err(e) if data.len() <= multipart.fallback_max_mb * multipart::BYTES_PER_MB => {
+                warn!("{e}, uploading it with a single request");
+            }
+            Err(e) => return Err(SendError::Backend(e)),
+        }
+    }
+
+    backend
+        .put(&metadata.key, data, &attributes)
+        .await
+        .map_err(SendError::Backend)?;
 
-    info!("Uploaded to S3: {}", key);
+    info!("Uploaded to S3: {}", metadata.key);
     Ok(())
 }
+
+/// Object metadata of a chunk, whichever way it is uploaded.
+fn object_metadata(metadata: &ChunkMetadata, run_id: &str) -> HashMap<String, String> {
+    let mut object_metadata = HashMap::from([
+        ("sequence".to_string(), metadata.sequence.to_string()),
+        ("line-count".to_string(), metadata.line_count.to_string()),
+        // Uploading process, chunks journaled by a previous run included
+        ("run-id".to_string(), run_id.to_string()),
+        (
+            "byte-range".to_string(),
+            format!("{}-{}", metadata.byte_start, metadata.byte_end),
+        ),
+    ]);
+    if let Some(udid) = &metadata.udid {
+        object_metadata.insert("udid".to_string(), udid.clone());
+    }
+    object_metadata
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    /// Request received by [`MockBackend`].
+    #[derive(Debug, Clone)]
+    struct Put {
+        key: String,
+        data: Vec<u8>,
+        attributes: ObjectAttributes,
+        in_parts: bool,
+    }
+
+    /// Backend recording its requests instead of sending them. The queued failures are
+    /// returned first, one per request.
+    #[derive(Clone, Default)]
+    struct MockBackend {
+        puts: Arc<Mutex<Vec<Put>>>,
+        failures: Arc<Mutex<Vec<BackendError>>>,
+    }
+
+    impl MockBackend {
+        fn puts(&self) -> Vec<Put> {
+            self.puts.lock().unwrap().clone()
+        }
+
+        fn fail_next(&self, error: BackendError) {
+            self.failures.lock().unwrap().push(error);
+        }
+
+        fn record(
+            &self,
+            key: &str,
+            content: &[u8],
+            attributes: &ObjectAttributes,
+            in_parts: bool,
+        ) -> Result<(), BackendError> {
+            let mut failures = self.failures.lock().unwrap();
+            if !failures.is_empty() {
+                return Err(failures.remove(0));
+            }
+            self.puts.lock().unwrap().push(Put {
+                key: key.to_string(),
+                data: content.to_vec(),
+                attributes: attributes.clone(),
+                in_parts,
+            });
+            Ok(())
+        }
+    }
+
+    impl Backend for MockBackend {
+        async fn put(
+            &self,
+            key: &str,
+            content: &[u8],
+            attributes: &ObjectAttributes,
+        ) -> Result<(), BackendError> {
+            self.record(key, content, attributes, false)
+        }
+
+        async fn put_in_parts(
+            &self,
+            key: &str,
+            content: &[u8],
+            attributes: &ObjectAttributes,
+            _part_size: usize,
+        ) -> Result<(), BackendError> {
+            self.record(key, content, attributes, true)
+        }
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("imonitor-send-{name}-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Config with a 1 MB chunk size and no wait between upload attempts, `extra` being
+    /// top-level keys or tables.
+    fn test_config(extra: &str) -> Config {
+        toml::from_str(&format!(
+            "chunk_size_mb = 1\ncheck_interval_seconds = 60\nmax_retry_delay_seconds = 0\n{extra}\n\n[s3]\nbucket = \"bucket\"\nprefix = \"logs/\"\nendpoint = \"https://s3.example.com\"\n"
+        ))
+        .unwrap()
+    }
+
+    fn log_source(dir: &Path) -> LogSource {
+        let path = dir.join("syslog.log").to_string_lossy().to_string();
+        LogSource {
+            pending_dir: PathBuf::from(format!("{path}.pending")),
+            path,
+            udid: Some("udid".to_string()),
+            key_prefix: "logs/udid/syslog/".to_string(),
+        }
+    }
+
+    fn chunk_metadata(sequence: u64) -> ChunkMetadata {
+        ChunkMetadata {
+            key: format!("logs/udid/syslog/20250101-000000-{sequence}.log"),
+            udid: Some("udid".to_string()),
+            sequence,
+            line_count: 2,
+            byte_start: 10,
+            byte_end: 22,
+        }
+    }
+
+    #[tokio::test]
+    async fn upload_sets_the_content_type_and_metadata() {
+        let backend = MockBackend::default();
+
+        upload_to_s3(&backend, &chunk_metadata(7), b"line\nline 2\n", "run", None)
+            .await
+            .unwrap();
+
+        let puts = backend.puts();
+        assert_eq!(puts.len(), 1);
+        assert_eq!(puts[0].key, "logs/udid/syslog/20250101-000000-7.log");
+        assert_eq!(puts[0].data, b"line\nline 2\n");
+        assert!(!puts[0].in_parts);
+        let attributes = &puts[0].attributes;
+        assert_eq!(attributes.content_type.as_deref(), Some(CONTENT_TYPE));
+        assert_eq!(attributes.metadata["udid"], "udid");
+        assert_eq!(attributes.metadata["sequence"], "7");
+        assert_eq!(attributes.metadata["line-count"], "2");
+        assert_eq!(attributes.metadata["byte-range"], "10-22");
+    }
+
+    #[tokio::test]
+    async fn metadata_has_no_udid_for_a_plain_log_file() {
+        let backend = MockBackend::default();
+        let metadata = ChunkMetadata {
+            udid: None,
+            ..chunk_metadata(0)
+        };
+
+        upload_to_s3(&backend, &metadata, b"line\n", "run", None)
+            .await
+            .unwrap();
+
+        assert!(!backend.puts()[0].attributes.metadata.contains_key("udid"));
+    }
+
+    #[tokio::test]
+    async fn journaled_chunks_are_replayed_in_order_and_removed() {
+        let dir = test_dir("replay");
+        let config = test_config("");
+        let source = log_source(&dir);
+        for sequence in [2, 0, 1] {
+            journal::write(
+                &source.pending_dir,
+                &chunk_metadata(sequence),
+                format!("chunk {sequence}\n").as_bytes(),
+            )
+            .unwrap();
+        }
+        let backend = MockBackend::default();
+
+        process_pending_chunks(&backend, &config, &source, "run")
+            .await
+            .unwrap();
+
+        let data = backend
+            .puts()
+            .into_iter()
+            .map(|put| put.data)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            data,
+            vec![
+                b"chunk 0\n".to_vec(),
+                b"chunk 1\n".to_vec(),
+                b"chunk 2\n".to_vec()
+            ]
+        );
+        assert!(journal::list(&source.pending_dir).unwrap().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_chunk_stays_journaled_for_the_next_check() {
+        let dir = test_dir("replay-failure");
+        let config = test_config("");
+        let source = log_source(&dir);
+        journal::write(&source.pending_dir, &chunk_metadata(0), b"chunk\n").unwrap();
+        let backend = MockBackend::default();
+        for _ in 0..UPLOAD_ATTEMPTS {
+            backend.fail_next(BackendError::Config("unreachable".to_string()));
+        }
+
+        let error = process_pending_chunks(&backend, &config, &source, "run")
+            .await
+            .unwrap_err();
+        assert!(matches!(error, SendError::Exhausted { .. }));
+        assert_eq!(journal::list(&source.pending_dir).unwrap().len(), 1);
+
+        // Replayed to the same key by the next check
+        process_pending_chunks(&backend, &config, &source, "run")
+            .await
+            .unwrap();
+        assert_eq!(backend.puts()[0].key, chunk_metadata(0).key);
+        assert!(journal::list(&source.pending_dir).unwrap().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Lines of `len` bytes, `terminator` included, filling `total` bytes.
+    fn log_lines(len: usize, terminator: &str, total: usize) -> Vec<u8> {
+        let line = format!("{}{terminator}", "x".repeat(len - terminator.len()));
+        line.repeat(total / len).into_bytes()
+    }
+
+    /// Cuts one chunk of a log holding `content`. Returns the uploaded chunk and what is left
+    /// of the log.
+    async fn chunk_log(name: &str, content: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let dir = test_dir(name);
+        let source = log_source(&dir);
+        fs::write(&source.path, content).unwrap();
+        let backend = MockBackend::default();
+
+        assert!(
+            process_log_chunk(&backend, &test_config(""), &source, "run")
+                .await
+                .unwrap()
+        );
+
+        let puts = backend.puts();
+        assert_eq!(puts.len(), 1);
+        let remaining = fs::read(&source.path).unwrap();
+        // The state follows the uploaded bytes
+        let state = load_state(&get_state_file_path(&source.path)).unwrap();
+        assert_eq!(state.uploaded_bytes, puts[0].data.len() as u64);
+        assert_eq!(state.next_sequence, 1);
+        fs::remove_dir_all(dir).unwrap();
+        (puts[0].data.clone(), remaining)
+    }
+
+    #[tokio::test]
+    async fn chunk_is_cut_exactly_at_a_lf_line_boundary() {
+        let content = log_lines(1000, "\n", 3 * 1024 * 1024);
+
+        let (uploaded, remaining) = chunk_log("lf", &content).await;
+
+        assert!(uploaded.len() <= 1024 * 1024);
+        assert_eq!(uploaded.len() % 1000, 0);
+        assert_eq!([uploaded, remaining].concat(), content);
+    }
+
+    #[tokio::test]
+    async fn chunk_keeps_crlf_line_endings_byte_for_byte() {
+        let content = log_lines(1000, "\r\n", 3 * 1024 * 1024);
+
+        let (uploaded, remaining) = chunk_log("crlf", &content).await;
+
+        assert!(uploaded.ends_with(b"\r\n"));
+        assert_eq!(uploaded.len() % 1000, 0);
+        assert!(remaining.starts_with(b"x"));
+        assert_eq!([uploaded, remaining].concat(), content);
+    }
+
+    #[tokio::test]
+    async fn partial_last_line_is_left_in_the_log() {
+        // A few complete lines, then a line still being written
+        let mut content = log_lines(100, "\n", 300);
+        content.extend("y".repeat(3 * 1024 * 1024).as_bytes());
+
+        let (uploaded, remaining) = chunk_log("partial", &content).await;
+
+        assert_eq!(uploaded, log_lines(100, "\n", 300));
+        assert_eq!(remaining, "y".repeat(3 * 1024 * 1024).as_bytes());
+    }
+
+    /// Backend taking a while per request, recording the most requests in flight at once in
+    /// total and for a same device.
+    #[derive(Clone, Default)]
+    struct SlowBackend {
+        in_flight: Arc<Mutex<HashMap<String, usize>>>,
+        max_in_flight: Arc<Mutex<(usize, usize)>>,
+        uploaded: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Backend for SlowBackend {
+        async fn put(
+            &self,
+            key: &str,
+            _content: &[u8],
+            _attributes: &ObjectAttributes,
+        ) -> Result<(), BackendError> {
+            // logs/<udid>/syslog/<name>
+            let device = key.split('/').nth(1).unwrap_or_default().to_string();
+            {
+                let mut in_flight = self.in_flight.lock().unwrap();
+                *in_flight.entry(device.clone()).or_default() += 1;
+                let mut max = self.max_in_flight.lock().unwrap();
+                max.0 = max.0.max(in_flight.values().sum());
+                max.1 = max.1.max(in_flight[&device]);
+            }
+            sleep(Duration::from_millis(50)).await;
+            *self.in_flight.lock().unwrap().get_mut(&device).unwrap() -= 1;
+            self.uploaded.lock().unwrap().push(key.to_string());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn devices_upload_concurrently_and_each_one_in_order() {
+        let devices_dir = test_dir("devices");
+        for udid in ["device-a", "device-b"] {
+            let pending_dir = devices_dir.join(udid).join("syslog/syslog.log.pending");
+            for sequence in 0..3 {
+                let metadata = ChunkMetadata {
+                    key: format!("logs/{udid}/syslog/{sequence}.log"),
+                    udid: Some(udid.to_string()),
+                    ..chunk_metadata(sequence)
+                };
+                journal::write(&pending_dir, &metadata, b"chunk\n").unwrap();
+            }
+        }
+        let config = Arc::new(test_config(&format!(
+            "devices_dir = \"{}\"\nupload_concurrency = 2",
+            devices_dir.display()
+        )));
+        let backend = SlowBackend::default();
+        let stability = Arc::new(Mutex::new(StabilityTracker::default()));
+
+        process_all_logs(&backend, &config, "run", &stability)
+            .await
+            .unwrap();
+
+        let (max_total, max_per_device) = *backend.max_in_flight.lock().unwrap();
+        assert_eq!(max_total, 2);
+        assert_eq!(max_per_device, 1);
+        let uploaded = backend.uploaded.lock().unwrap().clone();
+        assert_eq!(uploaded.len(), 6);
+        for udid in ["device-a", "device-b"] {
+            let keys = uploaded
+                .iter()
+                .filter(|key| key.contains(udid))
+                .cloned()
+                .collect::<Vec<_>>();
+            assert_eq!(
+                keys,
+                (0..3)
+                    .map(|sequence| format!("logs/{udid}/syslog/{sequence}.log"))
+                    .collect::<Vec<_>>()
+            );
+        }
+        fs::remove_dir_all(devices_dir).unwrap();
+    }
+
+    fn upload_error(code: Option<&str>, retry_after: Option<Duration>) -> SendError {
+        SendError::Backend(BackendError::Upload {
+            key: "key".to_string(),
+            message: "rejected".to_string(),
+            code: code.map(str::to_string),
+            retry_after,
+        })
+    }
+
+    #[test]
+    fn server_retry_after_is_honored_and_capped() {
+        let max_delay = Duration::from_secs(60);
+
+        let error = upload_error(Some("SlowDown"), Some(Duration::from_secs(7)));
+        assert_eq!(retry_delay(&error, 0, max_delay), Duration::from_secs(7));
+
+        let error = upload_error(None, Some(Duration::from_secs(600)));
+        assert_eq!(retry_delay(&error, 3, max_delay), max_delay);
+    }
+
+    #[test]
+    fn throttling_without_retry_after_backs_off_longer() {
+        let max_delay = Duration::from_secs(60);
+        for _ in 0..100 {
+            let throttled = retry_delay(&upload_error(Some("SlowDown"), None), 0, max_delay);
+            assert!(
+                (Duration::from_millis(THROTTLED_BACKOFF_BASE_MS / 2)
+                    ..=Duration::from_millis(THROTTLED_BACKOFF_BASE_MS))
+                    .contains(&throttled),
+                "{throttled:?}"
+            );
+
+            let other = retry_delay(&upload_error(Some("InternalError"), None), 0, max_delay);
+            assert!(other <= Duration::from_millis(BACKOFF_BASE_MS), "{other:?}");
+
+            let io = SendError::Io(std::io::Error::other("reset"), "log".to_string());
+            assert!(retry_delay(&io, 0, max_delay) <= Duration::from_millis(BACKOFF_BASE_MS));
+        }
+    }
+
+    #[test]
+    fn backoff_is_jittered_within_half_and_capped() {
+        let max_delay = Duration::from_secs(5);
+        for attempt in 0..8 {
+            let backoff_ms = (BACKOFF_BASE_MS << attempt).min(5000);
+            for _ in 0..50 {
+                let delay = jittered_backoff(BACKOFF_BASE_MS, attempt, max_delay);
+                assert!(
+                    (Duration::from_millis(backoff_ms / 2)..=Duration::from_millis(backoff_ms))
+                        .contains(&delay),
+                    "attempt {attempt}: {delay:?}"
+                );
+            }
+        }
+        // Large attempts saturate instead of overflowing
+        assert!(jittered_backoff(BACKOFF_BASE_MS, 200, max_delay) <= max_delay);
+    }
+
+    #[tokio::test]
+    async fn no_wait_after_the_last_failed_attempt() {
+        let backend = MockBackend::default();
+        for _ in 0..UPLOAD_ATTEMPTS {
+            backend.fail_next(BackendError::Upload {
+                key: "key".to_string(),
+                message: "rejected".to_string(),
+                code: None,
+                retry_after: Some(Duration::from_millis(200)),
+            });
+        }
+
+        let started = Instant::now();
+        let error = upload_to_s3_with_retries(
+            &backend,
+            &chunk_metadata(0),
+            b"chunk\n",
+            "run",
+            None,
+            Duration::from_secs(1),
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(
+            error,
+            SendError::Exhausted {
+                attempts: UPLOAD_ATTEMPTS,
+                ..
+            }
+        ));
+        // One wait between each two attempts
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(200 * u64::from(UPLOAD_ATTEMPTS - 1)));
+        assert!(
+            elapsed < Duration::from_millis(200 * u64::from(UPLOAD_ATTEMPTS)),
+            "{elapsed:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn backlog_is_drained_over_several_chunks_in_one_cycle() {
+        let dir = test_dir("backlog");
+        let source = log_source(&dir);
+        let content = log_lines(1000, "\n", 5 * 1024 * 1024);
+        fs::write(&source.path, &content).unwrap();
+        let config = test_config("max_chunks_per_cycle = 10");
+        let backend = MockBackend::default();
+        let stability = Mutex::new(StabilityTracker::default());
+
+        process_log_file(&backend, &config, &source, "run", &stability)
+            .await
+            .unwrap();
+
+        // Chunked until back under the chunk size, in sequence
+        let puts = backend.puts();
+        assert!(puts.len() >= 3, "{}", puts.len());
+        let remaining = fs::read(&source.path).unwrap();
+        assert!(remaining.len() < 2 * 1024 * 1024);
+        let uploaded = puts.iter().flat_map(|put| put.data.clone());
+        assert_eq!(uploaded.chain(remaining).collect::<Vec<_>>(), content);
+        let sequences = puts
+            .iter()
+            .map(|put| put.attributes.metadata["sequence"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sequences,
+            (0..puts.len()).map(|n| n.to_string()).collect::<Vec<_>>()
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn chunks_per_cycle_are_bounded() {
+        let dir = test_dir("bounded");
+        let source = log_source(&dir);
+        fs::write(&source.path, log_lines(1000, "\n", 5 * 1024 * 1024)).unwrap();
+        let config = test_config("max_chunks_per_cycle = 2");
+        let backend = MockBackend::default();
+        let stability = Mutex::new(StabilityTracker::default());
+
+        process_log_file(&backend, &config, &source, "run", &stability)
+            .await
+            .unwrap();
+
+        assert_eq!(backend.puts().len(), 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn line_longer_than_a_chunk_is_uploaded_alone() {
+        let long_line = format!("{}\n", "z".repeat(3 * 1024 * 1024));
+        let content = [long_line.as_bytes(), b"next\n"].concat();
+
+        let (uploaded, remaining) = chunk_log("long-line", &content).await;
+
+        assert_eq!(uploaded, long_line.as_bytes());
+        assert_eq!(remaining, b"next\n");
+    }
+
+    #[tokio::test]
+    async fn run_id_is_set_on_every_upload() {
+        let dir = test_dir("run-id");
+        let config = test_config("[multipart]\nthreshold_mb = 1");
+        let source = log_source(&dir);
+        journal::write(&source.pending_dir, &chunk_metadata(0), b"small\n").unwrap();
+        journal::write(
+            &source.pending_dir,
+            &chunk_metadata(1),
+            &vec![b'x'; 1024 * 1024],
+        )
+        .unwrap();
+        let backend = MockBackend::default();
+
+        process_pending_chunks(&backend, &config, &source, "run-1")
+            .await
+            .unwrap();
+
+        let puts = backend.puts();
+        assert_eq!(
+            puts.iter().map(|put| put.in_parts).collect::<Vec<_>>(),
+            vec![false, true]
+        );
+        for put in puts {
+            assert_eq!(put.attributes.metadata["run-id"], "run-1");
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn multipart_config(fallback_max_mb: usize) -> MultipartConfig {
+        MultipartConfig {
+            threshold_mb: 1,
+            part_size_mb: multipart::MIN_PART_SIZE_MB,
+            fallback_max_mb,
+            stale_after_hours: 24,
+        }
+    }
+
+    #[tokio::test]
+    async fn small_chunk_is_uploaded_with_a_single_request() {
+        let backend = MockBackend::default();
+
+        upload_to_s3(
+            &backend,
+            &chunk_metadata(0),
+            b"chunk\n",
+            "run",
+            Some(&multipart_config(2)),
+        )
+        .await
+        .unwrap();
+
+        assert!(!backend.puts()[0].in_parts);
+    }
+
+    #[tokio::test]
+    async fn failed_multipart_upload_falls_back_to_a_single_request() {
+        let backend = MockBackend::default();
+        backend.fail_next(BackendError::Multipart(
+            "part rejected".to_string(),
+            "key".to_string(),
+        ));
+        let data = vec![b'x'; 1024 * 1024];
+
+        upload_to_s3(
+            &backend,
+            &chunk_metadata(0),
+            &data,
+            "run",
+            Some(&multipart_config(2)),
+        )
+        .await
+        .unwrap();
+
+        let puts = backend.puts();
+        assert_eq!(puts.len(), 1);
+        assert!(!puts[0].in_parts);
+        assert_eq!(puts[0].data, data);
+        // Same key and metadata whichever way it was uploaded
+        assert_eq!(puts[0].key, chunk_metadata(0).key);
+        assert_eq!(puts[0].attributes.metadata["sequence"], "0");
+    }
+
+    #[tokio::test]
+    async fn chunk_above_the_fallback_size_is_not_sent_in_one_request() {
+        let backend = MockBackend::default();
+        backend.fail_next(BackendError::Multipart(
+            "part rejected".to_string(),
+            "key".to_string(),
+        ));
+
+        let error = upload_to_s3(
+            &backend,
+            &chunk_metadata(0),
+            &vec![b'x'; 2 * 1024 * 1024],
+            "run",
+            Some(&multipart_config(1)),
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(
+            error,
+            SendError::Backend(BackendError::Multipart(..))
+        ));
+        assert!(backend.puts().is_empty());
+    }
+}
@@ -0,0 +1,36 @@
+use serde::Deserialize;
+
+pub const BYTES_PER_MB: usize = 1024 * 1024;
+// S3 rejects smaller parts, the last one excepted
+pub const MIN_PART_SIZE_MB: usize = 5;
+const DEFAULT_PART_SIZE_MB: usize = 8;
+const DEFAULT_FALLBACK_MAX_MB: usize = 100;
+const DEFAULT_STALE_AFTER_HOURS: u64 = 24;
+
+/// Chunks uploaded in parts, see `[multipart]` in the example config.
+#[derive(Deserialize)]
+pub struct MultipartConfig {
+    /// Chunks at least this large are uploaded in parts.
+    pub threshold_mb: usize,
+    #[serde(default = "default_part_size_mb")]
+    pub part_size_mb: usize,
+    /// Chunks up to this size are uploaded with a single request when the multipart upload
+    /// fails.
+    #[serde(default = "default_fallback_max_mb")]
+    pub fallback_max_mb: usize,
+    /// Incomplete multipart uploads older than this are aborted by the sweep.
+    #[serde(default = "default_stale_after_hours")]
+    pub stale_after_hours: u64,
+}
+
+fn default_part_size_mb() -> usize {
+    DEFAULT_PART_SIZE_MB
+}
+
+fn default_fallback_max_mb() -> usize {
+    DEFAULT_FALLBACK_MAX_MB
+}
+
+fn default_stale_after_hours() -> u64 {
+    DEFAULT_STALE_AFTER_HOURS
+}
@@ -0,0 +1,159 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::time::{Duration, Instant, SystemTime};
+
+const DEFAULT_STABLE_AFTER_SECS: u64 = 30;
+const DEFAULT_STABLE_CHECKS: u32 = 2;
+
+/// Logs only chunked once unchanged for a while, see `[stability]` in the example config.
+#[derive(Deserialize)]
+pub struct StabilityConfig {
+    /// Minimum time the size and mtime of a log must stay the same.
+    #[serde(default = "default_stable_after_seconds")]
+    pub stable_after_seconds: u64,
+    /// Consecutive checks that must find the log unchanged.
+    #[serde(default = "default_stable_checks")]
+    pub stable_checks: u32,
+}
+
+fn default_stable_after_seconds() -> u64 {
+    DEFAULT_STABLE_AFTER_SECS
+}
+
+fn default_stable_checks() -> u32 {
+    DEFAULT_STABLE_CHECKS
+}
+
+/// Last size and mtime seen of a log.
+struct Observation {
+    len: u64,
+    modified: Option<SystemTime>,
+    unchanged_checks: u32,
+    unchanged_since: Instant,
+}
+
+/// Size and mtime of each log across checks.
+#[derive(Default)]
+pub struct StabilityTracker {
+    observations: HashMap<String, Observation>,
+}
+
+impl StabilityTracker {
+    /// Records a check of the log. Returns whether it was unchanged for both
+    /// `stable_checks` checks and `stable_after_seconds`.
+    pub fn check(&mut self, path: &str, metadata: &Metadata, config: &StabilityConfig) -> bool {
+        let len = metadata.len();
+        let modified = metadata.modified().ok();
+        let now = Instant::now();
+
+        match self.observations.get_mut(path) {
+            Some(observation) if observation.len == len && observation.modified == modified => {
+                observation.unchanged_checks = observation.unchanged_checks.saturating_add(1);
+                observation.unchanged_checks >= config.stable_checks
+                    && now.duration_since(observation.unchanged_since)
+                        >= Duration::from_secs(config.stable_after_seconds)
+            }
+            _ => {
+                self.observations.insert(
+                    path.to_string(),
+                    Observation {
+                        len,
+                        modified,
+                        unchanged_checks: 0,
+                        unchanged_since: now,
+                    },
+                );
+                false
+            }
+        }
+    }
+
+    /// Forgets the log once chunked: the truncate changed it, the next checks start over.
+    pub fn forget(&mut self, path: &str) {
+        self.observations.remove(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    fn test_log() -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("imonitor-send-stability-{}", uuid::Uuid::new_v4()));
+        fs::write(&path, b"line\n").unwrap();
+        path
+    }
+
+    fn check(tracker: &mut StabilityTracker, path: &PathBuf, config: &StabilityConfig) -> bool {
+        let metadata = fs::metadata(path).unwrap();
+        tracker.check(&path.to_string_lossy(), &metadata, config)
+    }
+
+    #[test]
+    fn slow_writes_only_become_eligible_after_the_window() {
+        let path = test_log();
+        let config = StabilityConfig {
+            stable_after_seconds: 1,
+            stable_checks: 2,
+        };
+        let mut tracker = StabilityTracker::default();
+
+        // A few bytes appended between each check
+        for _ in 0..3 {
+            assert!(!check(&mut tracker, &path, &config));
+            fs::OpenOptions::new()
+                .append(true)
+                .open(&path)
+                .unwrap()
+                .write_all(b"x")
+                .unwrap();
+        }
+
+        // Unchanged, but not for long enough
+        assert!(!check(&mut tracker, &path, &config));
+        assert!(!check(&mut tracker, &path, &config));
+        assert!(!check(&mut tracker, &path, &config));
+        std::thread::sleep(Duration::from_millis(1100));
+        assert!(check(&mut tracker, &path, &config));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn stable_checks_are_counted_before_eligibility() {
+        let path = test_log();
+        let config = StabilityConfig {
+            stable_after_seconds: 0,
+            stable_checks: 3,
+        };
+        let mut tracker = StabilityTracker::default();
+
+        // First sighting, then two unchanged checks
+        assert!(!check(&mut tracker, &path, &config));
+        assert!(!check(&mut tracker, &path, &config));
+        assert!(!check(&mut tracker, &path, &config));
+        assert!(check(&mut tracker, &path, &config));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn forgotten_log_starts_over() {
+        let path = test_log();
+        let config = StabilityConfig {
+            stable_after_seconds: 0,
+            stable_checks: 1,
+        };
+        let mut tracker = StabilityTracker::default();
+        assert!(!check(&mut tracker, &path, &config));
+        assert!(check(&mut tracker, &path, &config));
+
+        tracker.forget(&path.to_string_lossy());
+
+        assert!(!check(&mut tracker, &path, &config));
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,843 @@
+use crate::monitored_devices::{DeviceConfig, MonitoredDevices};
+use crate::report::{DeviceListing, SelfTestReport};
+use chrono::DateTime;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use idevice::usbmuxd::Connection;
+use imonitor_lib::config::{Config, VOLUME_NEAR_FULL_FREE_RATIO, VolumeUsage};
+use imonitor_lib::device::Device;
+use imonitor_lib::device::archive::archive_device_dir;
+use imonitor_lib::enroll::{check_pairing, list_usbmuxd_devices};
+use imonitor_lib::services::crashes::index::CrashFilter;
+use logger::Logger;
+use std::error::Error;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+pub fn command() -> Command {
+    Command::new("imonitor")
+        .about("Monitor devices through remote lockdownd services")
+        .arg(
+            Arg::new("verbose")
+                .short('v')
+                .long("verbose")
+                .help("Increase logging verbosity (-v warn, -vv info, -vvv debug, -vvvv trace)")
+                .action(ArgAction::Count)
+                .global(true),
+        )
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .value_name("PATH")
+                .help(
+                    "Config file, instead of the CONFIG environment variable or config.toml. \
+                     Repeat it to layer overrides, later files winning",
+                )
+                .action(ArgAction::Append)
+                .global(true),
+        )
+        .arg(
+            Arg::new("devices")
+                .long("devices")
+                .value_name("PATH")
+                .help("Monitored devices file (default: devices.toml)")
+                .global(true),
+        )
+        .arg(
+            Arg::new("strict")
+                .long("strict")
+                .help("Exit if any device fails to be set up instead of skipping it")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("report_json")
+                .long("report-json")
+                .help("Print a JSON report of the devices setup once all devices are processed")
+                .action(ArgAction::SetTrue),
+        )
+        .subcommand(
+            Command::new("devices")
+                .about("Manage the monitored devices file")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("add")
+                        .about("Add a device to the monitored devices")
+                        .arg(Arg::new("udid").value_name("UDID").required(true))
+                        .arg(
+                            Arg::new("pairing_file")
+                                .value_name("PAIRING_FILE")
+                                .help("Path to the pairing file generated by imonitor-enroll")
+                                .required(true),
+                        )
+                        .arg(Arg::new("ip").value_name("IP").required(true))
+                        .arg(
+                            Arg::new("label")
+                                .long("label")
+                                .value_name("LABEL")
+                                .help("Connection label (random UUID if not set)"),
+                        )
+                        .arg(
+                            Arg::new("name")
+                                .long("name")
+                                .value_name("NAME")
+                                .help("Readable name shown with the UDID (the UDID if not set)"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("remove")
+                        .about("Remove a device from the monitored devices")
+                        .arg(Arg::new("udid").value_name("UDID").required(true)),
+                ),
+        )
+        .subcommand(
+            Command::new("summary")
+                .about("Print a JSON summary of the data collected for a device")
+                .arg(Arg::new("udid").value_name("UDID").required(true))
+                .arg(
+                    Arg::new("since")
+                        .long("since")
+                        .value_name("RFC3339")
+                        .help("Only account for coverage from this date"),
+                )
+                .arg(
+                    Arg::new("until")
+                        .long("until")
+                        .value_name("RFC3339")
+                        .requires("since")
+                        .help("Only account for coverage until this date (default: now)"),
+                ),
+        )
+        .subcommand(
+            Command::new("crashes")
+                .about("Print the pulled crash files of a device as JSON, oldest first")
+                .arg(Arg::new("udid").value_name("UDID").required(true))
+                .arg(
+                    Arg::new("process")
+                        .long("process")
+                        .value_name("NAME")
+                        .help("Only crashes of this process"),
+                )
+                .arg(
+                    Arg::new("bundle-id")
+                        .long("bundle-id")
+                        .value_name("BUNDLE_ID")
+                        .help("Only crashes of this bundle id"),
+                )
+                .arg(
+                    Arg::new("exception")
+                        .long("exception")
+                        .value_name("TEXT")
+                        .help("Only crashes whose exception type contains this, such as SIGSEGV"),
+                )
+                .arg(
+                    Arg::new("since")
+                        .long("since")
+                        .value_name("RFC3339")
+                        .help("Only crashes pulled from this date"),
+                )
+                .arg(
+                    Arg::new("until")
+                        .long("until")
+                        .value_name("RFC3339")
+                        .requires("since")
+                        .help("Only crashes pulled until this date (default: now)"),
+                ),
+        )
+        .subcommand(
+            Command::new("archive")
+                .about(
+                    "Pack the data of a device removed from the monitored devices into a single \
+                     archive, with a manifest",
+                )
+                .arg(Arg::new("udid").value_name("UDID").required(true))
+                .arg(
+                    Arg::new("dest")
+                        .long("dest")
+                        .value_name("DIR")
+                        .default_value(".")
+                        .help("Directory the archive and its manifest are written to"),
+                )
+                .arg(
+                    Arg::new("gzip")
+                        .long("gzip")
+                        .action(ArgAction::SetTrue)
+                        .help("Compress the archive"),
+                ),
+        )
+        .subcommand(
+            Command::new("sysdiagnose")
+                .about(
+                    "Pull the most recent completed sysdiagnose of a device into its \
+                     crashes/sysdiagnose dir",
+                )
+                .arg(Arg::new("udid").value_name("UDID").required(true))
+                .arg(
+                    Arg::new("wait")
+                        .long("wait")
+                        .value_name("DURATION")
+                        .value_parser(humantime::parse_duration)
+                        .help("Wait up to this long for a sysdiagnose being written (default: skip it)"),
+                ),
+        )
+        .subcommand(
+            Command::new("force-pull")
+                .about(
+                    "Pull again the known crash files of a device matching a glob, on the next \
+                     crash cycle of the daemon",
+                )
+                .arg(Arg::new("udid").value_name("UDID").required(true))
+                .arg(
+                    Arg::new("glob")
+                        .value_name("GLOB")
+                        .required(true)
+                        .help("Device crash path pattern, e.g. \"Retired/MyApp-*\""),
+                ),
+        )
+        .subcommand(Command::new("rebalance").about(
+            "Print the devices and free space of every base dir volume, and warn about the \
+             near full ones",
+        ))
+        .subcommand(
+            Command::new("reconcile")
+                .about(
+                    "Add the time range of every os trace archive of a device to its activity \
+                     coverage. Run it while the daemon is stopped",
+                )
+                .arg(Arg::new("udid").value_name("UDID").required(true)),
+        )
+        .subcommand(
+            Command::new("selftest").about(
+                "Check the config, the devices and a lockdown session per device, then exit",
+            ),
+        )
+        .subcommand(
+            Command::new("list-devices")
+                .about(
+                    "Compare the monitored devices with the devices usbmuxd sees: configured \
+                     and present, configured but missing, present but not configured",
+                )
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .action(ArgAction::SetTrue)
+                        .help("Print the listing as JSON"),
+                ),
+        )
+        .subcommand(
+            Command::new("pause")
+                .about("Pause collection for a device, the heartbeat keeps running")
+                .arg(Arg::new("udid").value_name("UDID").required(true)),
+        )
+        .subcommand(
+            Command::new("resume")
+                .about("Resume collection for a paused device")
+                .arg(Arg::new("udid").value_name("UDID").required(true)),
+        )
+}
+
+/// Builds the device with the given UDID from the monitored devices file.
+pub fn find_device(
+    udid: &str,
+    config: &Config,
+    devices_file_path: &Path,
+) -> Result<Device, Box<dyn Error>> {
+    let monitored_devices = MonitoredDevices::parse(devices_file_path)?;
+    let device_config = monitored_devices
+        .devices
+        .into_iter()
+        .find(|d| d.udid == udid)
+        .ok_or(format!("Device {udid} is not monitored"))?;
+
+    let base_dirs = device_config.base_dirs(config);
+    let mut device = device_config.try_into_device(&base_dirs)?;
+    device.state_format = config.settings.state_format;
+    device.state_dir = config.settings.state_dir;
+    Ok(device)
+}
+
+/// Builds a time window from RFC 3339 bounds. `until` defaults to now and requires `since`.
+pub fn parse_window(
+    since: Option<&str>,
+    until: Option<&str>,
+) -> Result<Option<Range<SystemTime>>, String> {
+    let parse = |date: &str| {
+        DateTime::parse_from_rfc3339(date)
+            .map(SystemTime::from)
+            .map_err(|e| format!("Invalid date {date}: {e}"))
+    };
+
+    let Some(since) = since else {
+        return match until {
+            Some(_) => Err("until requires since".to_string()),
+            None => Ok(None),
+        };
+    };
+
+    let start = parse(since)?;
+    let end = match until {
+        Some(until) => parse(until)?,
+        None => SystemTime::now(),
+    };
+
+    if end < start {
+        return Err(format!("until must not be before since ({since})"));
+    }
+
+    Ok(Some(start..end))
+}
+
+/// Handles the `summary` subcommand. Returns false on failure.
+pub async fn summary(matches: &ArgMatches, config: &Config, devices_file_path: &Path) -> bool {
+    let udid = matches
+        .get_one::<String>("udid")
+        .cloned()
+        .unwrap_or_default();
+
+    let device = match find_device(&udid, config, devices_file_path) {
+        Ok(device) => device,
+        Err(e) => {
+            println!("Failed to load device {udid}: {e}");
+            return false;
+        }
+    };
+
+    let window = match parse_window(
+        matches.get_one::<String>("since").map(String::as_str),
+        matches.get_one::<String>("until").map(String::as_str),
+    ) {
+        Ok(window) => window,
+        Err(e) => {
+            println!("{e}");
+            return false;
+        }
+    };
+
+    let summary = match device.summarize(window).await {
+        Ok(summary) => summary,
+        Err(e) => {
+            println!("Failed to summarize device {udid}: {e}");
+            return false;
+        }
+    };
+
+    match serde_json::to_string_pretty(&summary) {
+        Ok(json) => {
+            println!("{json}");
+            true
+        }
+        Err(e) => {
+            println!("Failed to serialize summary: {e}");
+            false
+        }
+    }
+}
+
+/// Handles the `selftest` subcommand. Prints a JSON report, returns false if any check failed.
+pub async fn selftest(config_paths: &[PathBuf], devices_file_path: &Path) -> bool {
+    let mut report = SelfTestReport::default();
+
+    let config_paths = config_paths
+        .iter()
+        .map(PathBuf::as_path)
+        .collect::<Vec<&Path>>();
+    let config = match Config::parse_layered(&config_paths) {
+        Ok(config) => {
+            report.add("config parse", Vec::new());
+            Some(config)
+        }
+        Err(e) => {
+            report.add("config parse", vec![e.to_string()]);
+            None
+        }
+    };
+
+    if let Some(config) = &config {
+        report.add("config values", config.validate());
+    }
+
+    let monitored_devices = match MonitoredDevices::parse(devices_file_path) {
+        Ok(devices) => {
+            report.add("devices parse", Vec::new());
+            Some(devices)
+        }
+        Err(e) => {
+            report.add(
+                "devices parse",
+                vec![format!("{}: {e}", devices_file_path.display())],
+            );
+            None
+        }
+    };
+
+    if let Some(monitored_devices) = &monitored_devices {
+        let mut problems = monitored_devices.validate();
+        problems.extend(monitored_devices.missing_pairing_files());
+        report.add("devices values", problems);
+    }
+
+    // Device checks need both files
+    if let (Some(config), Some(monitored_devices)) = (config, monitored_devices) {
+        report.add(
+            "device count",
+            monitored_devices
+                .check_count(config.settings.max_devices)
+                .err()
+                .into_iter()
+                .collect(),
+        );
+        for device_config in monitored_devices.devices {
+            let udid = device_config.udid.clone();
+
+            let base_dirs = device_config.base_dirs(config);
+            let mut device = match device_config.try_into_device(&base_dirs) {
+                Ok(device) => device,
+                Err(e) => {
+                    report.add(format!("device {udid} pairing file"), vec![e.to_string()]);
+                    continue;
+                }
+            };
+            report.add(format!("device {udid} pairing file"), Vec::new());
+            device.proxy = config.settings.proxy.clone();
+
+            let session = check_pairing(
+                &*device.get_provider("selftest"),
+                &device.connection.pairing_file,
+            )
+            .await;
+            report.add(
+                format!("device {udid} lockdown session"),
+                session.err().map(|e| e.to_string()).into_iter().collect(),
+            );
+        }
+    }
+
+    report.print();
+    report.success
+}
+
+/// Handles the `crashes` subcommand. Returns false on failure.
+pub async fn crashes(matches: &ArgMatches, config: &Config, devices_file_path: &Path) -> bool {
+    let udid = matches
+        .get_one::<String>("udid")
+        .cloned()
+        .unwrap_or_default();
+
+    let device = match find_device(&udid, config, devices_file_path) {
+        Ok(device) => device,
+        Err(e) => {
+            println!("Failed to load device {udid}: {e}");
+            return false;
+        }
+    };
+
+    let window = match parse_window(
+        matches.get_one::<String>("since").map(String::as_str),
+        matches.get_one::<String>("until").map(String::as_str),
+    ) {
+        Ok(window) => window,
+        Err(e) => {
+            println!("{e}");
+            return false;
+        }
+    };
+
+    let filter = CrashFilter {
+        process_name: matches.get_one::<String>("process").cloned(),
+        bundle_id: matches.get_one::<String>("bundle-id").cloned(),
+        exception_type: matches.get_one::<String>("exception").cloned(),
+        window,
+    };
+
+    let crashes = match device.query_crashes(&filter).await {
+        Ok(crashes) => crashes,
+        Err(e) => {
+            println!("Failed to query crashes of device {udid}: {e}");
+            return false;
+        }
+    };
+
+    match serde_json::to_string_pretty(&crashes) {
+        Ok(json) => {
+            println!("{json}");
+            true
+        }
+        Err(e) => {
+            println!("Failed to serialize crashes: {e}");
+            false
+        }
+    }
+}
+
+/// Handles the `archive` subcommand. Returns false on failure.
+pub async fn archive(matches: &ArgMatches, config: &Config, devices_file_path: &Path) -> bool {
+    let udid = matches
+        .get_one::<String>("udid")
+        .cloned()
+        .unwrap_or_default();
+    let dest = matches
+        .get_one::<String>("dest")
+        .cloned()
+        .unwrap_or_default();
+
+    // Files of a monitored device keep changing
+    match MonitoredDevices::parse(devices_file_path) {
+        Ok(monitored_devices) if monitored_devices.devices.iter().any(|d| d.udid == udid) => {
+            println!(
+                "Device {udid} is still monitored, remove it first with `imonitor devices remove {udid}`"
+            );
+            return false;
+        }
+        Ok(_) => {}
+        Err(e) => {
+            println!("Failed to parse monitored devices: {e}");
+            return false;
+        }
+    }
+
+    let base_dir = config.get_base_dir(&udid);
+    if !Path::new(&base_dir).join(&udid).is_dir() {
+        println!("No data found for device {udid}");
+        return false;
+    }
+
+    match archive_device_dir(&base_dir, &udid, Path::new(&dest), matches.get_flag("gzip")).await {
+        Ok(archive_path) => {
+            println!(
+                "Device {udid} data archived to {}, its data dir can now be deleted",
+                archive_path.display()
+            );
+            true
+        }
+        Err(e) => {
+            println!("Failed to archive device {udid}: {e}");
+            false
+        }
+    }
+}
+
+/// Handles the `sysdiagnose` subcommand. Returns false on failure.
+pub async fn sysdiagnose(matches: &ArgMatches, config: &Config, devices_file_path: &Path) -> bool {
+    let udid = matches
+        .get_one::<String>("udid")
+        .cloned()
+        .unwrap_or_default();
+
+    let mut device = match find_device(&udid, config, devices_file_path) {
+        Ok(device) => device,
+        Err(e) => {
+            println!("Failed to load device {udid}: {e}");
+            return false;
+        }
+    };
+    device.proxy = config.settings.proxy.clone();
+    // Pull progress is logged
+    device.logger = Some(Arc::new(Logger::stderr()));
+
+    match device
+        .pull_latest_sysdiagnose(matches.get_one::<Duration>("wait").copied())
+        .await
+    {
+        Ok(Some(path)) => {
+            println!("{}", path.display());
+            true
+        }
+        Ok(None) => {
+            println!("No completed sysdiagnose found on device {udid}");
+            false
+        }
+        Err(e) => {
+            println!("Failed to pull sysdiagnose of device {udid}: {e}");
+            false
+        }
+    }
+}
+
+/// Handles the `force-pull` subcommand. Returns false on failure.
+pub async fn force_pull(matches: &ArgMatches, config: &Config, devices_file_path: &Path) -> bool {
+    let udid = matches
+        .get_one::<String>("udid")
+        .cloned()
+        .unwrap_or_default();
+    let glob = matches
+        .get_one::<String>("glob")
+        .cloned()
+        .unwrap_or_default();
+
+    let device = match find_device(&udid, config, devices_file_path) {
+        Ok(device) => device,
+        Err(e) => {
+            println!("Failed to load device {udid}: {e}");
+            return false;
+        }
+    };
+
+    // Applied by the daemon, which holds the known crash files in memory
+    match device.request_force_pull(&glob).await {
+        Ok(_) => {
+            println!("Forced pull of {glob} queued for device {udid}");
+            true
+        }
+        Err(e) => {
+            println!("Failed to queue forced pull for device {udid}: {e}");
+            false
+        }
+    }
+}
+
+/// Handles the `rebalance` subcommand. Returns false if a volume is near full or on failure.
+pub fn rebalance(config: &Config, devices_file_path: &Path) -> bool {
+    let monitored_devices = match MonitoredDevices::parse(devices_file_path) {
+        Ok(monitored_devices) => monitored_devices,
+        Err(e) => {
+            println!("Failed to parse monitored devices: {e}");
+            return false;
+        }
+    };
+
+    // Overridden base dirs are reported too
+    let mut base_dirs = config.get_base_dirs();
+    let device_base_dirs = monitored_devices
+        .devices
+        .iter()
+        .map(|device_config| (device_config.udid.clone(), device_config.base_dir(config)))
+        .collect::<Vec<_>>();
+    for (_, base_dir) in &device_base_dirs {
+        if !base_dirs.contains(base_dir) {
+            base_dirs.push(base_dir.clone());
+        }
+    }
+
+    let mut ok = true;
+    for base_dir in base_dirs {
+        let udids = device_base_dirs
+            .iter()
+            .filter(|(_, device_base_dir)| *device_base_dir == base_dir)
+            .map(|(udid, _)| udid.as_str())
+            .collect::<Vec<_>>();
+
+        let usage = match VolumeUsage::new(&base_dir) {
+            Ok(usage) => usage,
+            Err(e) => {
+                println!("Failed to get free space of {base_dir}: {e}");
+                ok = false;
+                continue;
+            }
+        };
+
+        println!(
+            "{base_dir}: {} of {} bytes available, {} device(s): {}",
+            usage.available_bytes,
+            usage.total_bytes,
+            udids.len(),
+            udids.join(", ")
+        );
+        if usage.is_near_full(VOLUME_NEAR_FULL_FREE_RATIO) {
+            println!(
+                "Warning: {base_dir} is near full, move devices to another base dir with base_dir_override"
+            );
+            ok = false;
+        }
+    }
+
+    ok
+}
+
+/// Handles the `list-devices` subcommand. Returns false on failure, a drift between the
+/// monitored devices and usbmuxd is not one.
+pub async fn list_devices(matches: &ArgMatches, devices_file_path: &Path) -> bool {
+    let monitored_devices = match MonitoredDevices::parse(devices_file_path) {
+        Ok(monitored_devices) => monitored_devices,
+        Err(e) => {
+            println!("Failed to parse monitored devices: {e}");
+            return false;
+        }
+    };
+
+    let usbmuxd_devices = match list_usbmuxd_devices().await {
+        Ok(devices) => devices,
+        Err(e) => {
+            println!("Failed to list usbmuxd devices: {e}");
+            return false;
+        }
+    };
+
+    let configured = monitored_devices
+        .devices
+        .into_iter()
+        .map(|device_config| (device_config.udid, device_config.ip))
+        .collect::<Vec<_>>();
+    let present = usbmuxd_devices
+        .into_iter()
+        .map(|device| {
+            let connection = if device.connection_type == Connection::Usb {
+                "usb"
+            } else {
+                "network"
+            };
+            (device.udid, connection.to_string())
+        })
+        .collect::<Vec<_>>();
+
+    DeviceListing::new(&configured, &present).print(matches.get_flag("json"));
+    true
+}
+
+/// Handles the `reconcile` subcommand. Returns false on failure.
+pub async fn reconcile(matches: &ArgMatches, config: &Config, devices_file_path: &Path) -> bool {
+    let udid = matches
+        .get_one::<String>("udid")
+        .cloned()
+        .unwrap_or_default();
+
+    let mut device = match find_device(&udid, config, devices_file_path) {
+        Ok(device) => device,
+        Err(e) => {
+            println!("Failed to load device {udid}: {e}");
+            return false;
+        }
+    };
+
+    // Archives are merged into the existing coverage
+    if let Err(e) = device.load_activity_coverage().await {
+        println!("Failed to load activity coverage of device {udid}: {e}");
+        return false;
+    }
+
+    let summary = match device.reconcile_coverage().await {
+        Ok(summary) => summary,
+        Err(e) => {
+            println!("Failed to reconcile coverage of device {udid}: {e}");
+            return false;
+        }
+    };
+
+    match serde_json::to_string_pretty(&summary) {
+        Ok(json) => {
+            println!("{json}");
+            true
+        }
+        Err(e) => {
+            println!("Failed to serialize reconcile summary: {e}");
+            false
+        }
+    }
+}
+
+/// Handles the `pause` and `resume` subcommands. Returns false on failure.
+/// A running monitor picks up the change within a few seconds.
+pub async fn set_paused(
+    matches: &ArgMatches,
+    config: &Config,
+    devices_file_path: &Path,
+    paused: bool,
+) -> bool {
+    let udid = matches
+        .get_one::<String>("udid")
+        .cloned()
+        .unwrap_or_default();
+
+    let device = match find_device(&udid, config, devices_file_path) {
+        Ok(device) => device,
+        Err(e) => {
+            println!("Failed to load device {udid}: {e}");
+            return false;
+        }
+    };
+
+    let res = if paused {
+        device.pause().await
+    } else {
+        device.resume().await
+    };
+
+    match res {
+        Ok(_) => {
+            println!(
+                "Device {udid} {}",
+                if paused { "paused" } else { "resumed" }
+            );
+            true
+        }
+        Err(e) => {
+            println!("Failed to update paused state of device {udid}: {e}");
+            false
+        }
+    }
+}
+
+/// Handles the `devices` subcommand. Returns false on failure.
+pub fn devices(matches: &ArgMatches, devices_file_path: &Path) -> bool {
+    let mut monitored_devices = if devices_file_path.exists() {
+        match MonitoredDevices::parse(devices_file_path) {
+            Ok(devices) => devices,
+            Err(e) => {
+                println!("Failed to parse monitored devices list: {e}");
+                return false;
+            }
+        }
+    } else {
+        MonitoredDevices::default()
+    };
+
+    match matches.subcommand() {
+        Some(("add", sub_matches)) => {
+            let udid = sub_matches
+                .get_one::<String>("udid")
+                .cloned()
+                .unwrap_or_default();
+            let ip = match sub_matches
+                .get_one::<String>("ip")
+                .map(|ip| ip.parse::<std::net::IpAddr>())
+            {
+                Some(Ok(ip)) => ip,
+                _ => {
+                    println!("Invalid IP address");
+                    return false;
+                }
+            };
+
+            let device_config = DeviceConfig {
+                udid: udid.clone(),
+                pairing_file_path: sub_matches
+                    .get_one::<String>("pairing_file")
+                    .cloned()
+                    .unwrap_or_default(),
+                ip,
+                connection_label: sub_matches
+                    .get_one::<String>("label")
+                    .cloned()
+                    .unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+                name: sub_matches.get_one::<String>("name").cloned(),
+                overrides: None,
+                base_dir_override: None,
+            };
+
+            if let Err(e) = monitored_devices.add(device_config) {
+                println!("Failed to add device: {e}");
+                return false;
+            }
+            println!("Device {udid} added");
+        }
+        Some(("remove", sub_matches)) => {
+            let udid = sub_matches
+                .get_one::<String>("udid")
+                .cloned()
+                .unwrap_or_default();
+
+            if !monitored_devices.remove(&udid) {
+                println!("Device {udid} is not monitored");
+                return false;
+            }
+            println!("Device {udid} removed");
+        }
+        _ => return false,
+    }
+
+    if let Err(e) = monitored_devices.write_to_file(&devices_file_path.to_path_buf()) {
+        println!("Failed to write to monitored devices: {e}");
+        return false;
+    }
+
+    true
+}
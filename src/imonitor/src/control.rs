@@ -0,0 +1,201 @@
+use crate::cli;
+use chrono::{DateTime, Utc};
+use imonitor_lib::device::Device;
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+/// Read-only control socket answering one JSON line per command line:
+/// `status <udid>`, `coverage <udid> [since=<rfc3339>] [until=<rfc3339>]` and
+/// `crashes <udid>`.
+///
+/// Example: `echo "status <udid>" | socat - UNIX-CONNECT:/run/imonitor.sock`
+pub struct ControlSocket {
+    path: PathBuf,
+    listener: UnixListener,
+}
+
+impl ControlSocket {
+    /// Binds the socket, replacing a stale socket file left by a previous run.
+    pub fn bind(path: &Path) -> io::Result<ControlSocket> {
+        match std::fs::remove_file(path) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        Ok(ControlSocket {
+            path: path.to_path_buf(),
+            listener: UnixListener::bind(path)?,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Serves clients until the task is dropped. Devices share their state with the
+    /// monitoring tasks, so replies reflect the live state.
+    pub async fn serve(self, devices: Arc<HashMap<String, Device>>) {
+        loop {
+            match self.listener.accept().await {
+                Ok((stream, _)) => {
+                    let devices = devices.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_client(stream, &devices).await {
+                            println!("Control socket client error: {e}");
+                        }
+                    });
+                }
+                Err(e) => println!("Control socket accept error: {e}"),
+            }
+        }
+    }
+}
+
+async fn handle_client(stream: UnixStream, devices: &HashMap<String, Device>) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let mut reply = handle_command(&line, devices).to_string();
+        reply.push('\n');
+        writer.write_all(reply.as_bytes()).await?;
+    }
+
+    Ok(())
+}
+
+fn handle_command(line: &str, devices: &HashMap<String, Device>) -> Value {
+    let mut words = line.split_whitespace();
+    let (Some(command), Some(udid)) = (words.next(), words.next()) else {
+        return error("Expected: <status|coverage|crashes> <udid> [since=...] [until=...]");
+    };
+
+    let Some(device) = devices.get(udid) else {
+        return error(&format!("Device {udid} is not monitored"));
+    };
+
+    let mut since = None;
+    let mut until = None;
+    for param in words {
+        match param.split_once('=') {
+            Some(("since", value)) if command == "coverage" => since = Some(value),
+            Some(("until", value)) if command == "coverage" => until = Some(value),
+            _ => return error(&format!("Unexpected parameter {param}")),
+        }
+    }
+
+    match command {
+        "status" => status(device),
+        "coverage" => match cli::parse_window(since, until) {
+            Ok(window) => coverage(device, window),
+            Err(e) => error(&e),
+        },
+        "crashes" => crashes(device),
+        _ => error(&format!("Unknown command {command}")),
+    }
+}
+
+fn error(message: &str) -> Value {
+    json!({ "error": message })
+}
+
+fn status(device: &Device) -> Value {
+    let last_heartbeat = device
+        .heartbeat
+        .last_established
+        .read()
+        .map(|date| json!(*date))
+        .unwrap_or(Value::Null);
+    let heartbeat_latency = device
+        .heartbeat
+        .latency
+        .read()
+        .map(|window| json!(window.stats()))
+        .unwrap_or(Value::Null);
+    let device_state = device
+        .device_state
+        .read()
+        .map(|state| json!(*state))
+        .unwrap_or(Value::Null);
+
+    let disk_usage = device
+        .total_disk_usage()
+        .map(|usage| json!(usage))
+        .unwrap_or(Value::Null);
+
+    json!({
+        "udid": device.info.udid,
+        "name": device.info.name,
+        "paused": device.is_paused(),
+        "pairing_valid": device.is_pairing_valid(),
+        "last_heartbeat": last_heartbeat,
+        "heartbeat_latency": heartbeat_latency,
+        "device_state": device_state,
+        "disk_usage": disk_usage,
+    })
+}
+
+fn coverage(device: &Device, window: Option<Range<SystemTime>>) -> Value {
+    let coverage = match device.activity_coverage.read() {
+        Ok(coverage) => coverage,
+        Err(_) => return error("Failed acquiring activity coverage read lock"),
+    };
+
+    match window {
+        Some(window) => {
+            let window_coverage = coverage.coverage_in(window.clone());
+            let gaps = window_coverage
+                .gaps
+                .iter()
+                .map(|gap| json!([to_date(gap.start), to_date(gap.end)]))
+                .collect::<Vec<Value>>();
+
+            json!({
+                "udid": device.info.udid,
+                "since": to_date(window.start),
+                "until": to_date(window.end),
+                "coverage_ratio": window_coverage.ratio,
+                "covered_secs": window_coverage.covered.as_secs(),
+                "gaps": gaps,
+            })
+        }
+        None => json!({
+            "udid": device.info.udid,
+            "coverage_ratio": coverage.coverage_ratio(),
+            "covered_secs": coverage.covered_duration().as_secs(),
+            "gaps": coverage.missing_ranges().len(),
+            "coverage": *coverage,
+        }),
+    }
+}
+
+fn to_date(t: SystemTime) -> DateTime<Utc> {
+    t.into()
+}
+
+fn crashes(device: &Device) -> Value {
+    let (Ok(crash_files), Ok(crash_dirs)) = (
+        device.crashes.crash_files.read(),
+        device.crashes.crash_dirs.read(),
+    ) else {
+        return error("Failed acquiring crash files read lock");
+    };
+
+    let mut files = crash_files.iter().collect::<Vec<&String>>();
+    files.sort();
+
+    json!({
+        "udid": device.info.udid,
+        "known_files": crash_files.len(),
+        "known_dirs": crash_dirs.len(),
+        "files": files,
+    })
+}
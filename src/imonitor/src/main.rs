@@ -1,20 +1,46 @@
+use idevice::pairing_file::PairingFile;
 use imonitor_lib::CONFIG_ENV;
-use imonitor_lib::config::Config;
-use imonitor_lib::device::Device;
+use imonitor_lib::audit::AuditLog;
+use imonitor_lib::config::{
+    Config, CrashStore, DeviceFilterConfig, LogFileConfig, ProxyConfig,
+    VOLUME_NEAR_FULL_FREE_RATIO, VolumeUsage,
+};
+use imonitor_lib::connection::ConnectionManager;
+use imonitor_lib::device::{Device, MonitorOutcome};
+use imonitor_lib::enroll::errors::EnrollError;
+use imonitor_lib::enroll::{check_pairing, enroll_usb_device};
+use imonitor_lib::liveness::LivenessFile;
+use imonitor_lib::object_store::CrashBackend;
+use imonitor_lib::observer::MonitorObserver;
+use imonitor_lib::throttle::BandwidthLimiter;
+use log::LevelFilter;
+use std::collections::HashMap;
 use std::env;
+use std::error::Error;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
 
+pub mod cli;
+pub mod control;
 pub mod monitored_devices;
-use monitored_devices::MonitoredDevices;
+pub mod report;
+use control::ControlSocket;
+use monitored_devices::{DeviceConfig, MonitoredDevices};
+use report::{DeviceReport, StartupReport};
 
 const MONITORED_DEVICES_FILE_PATH: &str = "devices.toml";
 const CONFIG_FILE_NAME: &str = "config.toml";
+// Probe interval of devices unreachable at startup
+const UNREACHABLE_PROBE_INTERVAL_SECS: u64 = 60;
 
-/// Setup config
-fn setup(config_folder: &Path) -> Arc<RwLock<Config>> {
-    // Parse configuration.
-    let config_path = match env::var(CONFIG_ENV).ok() {
+/// Config file path, from the environment or in the given folder. `--config` takes
+/// precedence over both.
+fn config_path(config_folder: &Path) -> PathBuf {
+    match env::var(CONFIG_ENV).ok() {
         Some(path) => {
             unsafe {
                 env::remove_var(CONFIG_ENV);
@@ -22,18 +48,37 @@ fn setup(config_folder: &Path) -> Arc<RwLock<Config>> {
             PathBuf::from(path)
         }
         None => config_folder.join(CONFIG_FILE_NAME),
-    };
+    }
+}
 
-    if !config_path.exists() {
-        println!(
-            "{} is not found, please provide a configuration file.",
-            config_path.display()
-        );
-        std::process::exit(1);
+/// Setup config, layered in order when several files are given
+fn setup(config_paths: &[PathBuf]) -> Arc<RwLock<Config>> {
+    for config_path in config_paths {
+        if !config_path.exists() {
+            println!(
+                "{} is not found, please provide a configuration file.",
+                config_path.display()
+            );
+            std::process::exit(1);
+        }
     }
 
     // Parse config
-    let config = Config::parse(&config_path).expect("failed to parse config");
+    let config_paths = config_paths
+        .iter()
+        .map(PathBuf::as_path)
+        .collect::<Vec<&Path>>();
+    let config = Config::parse_layered(&config_paths).expect("failed to parse config");
+
+    // Values serde cannot check, such as a zero limit, are as fatal as a parse error
+    let problems = config.validate();
+    if !problems.is_empty() {
+        println!("Invalid configuration:");
+        for problem in problems {
+            println!("- {problem}");
+        }
+        std::process::exit(1);
+    }
 
     // Main configuration
     Arc::new(RwLock::new(config))
@@ -41,95 +86,681 @@ fn setup(config_folder: &Path) -> Arc<RwLock<Config>> {
 
 #[tokio::main]
 async fn main() {
-    env_logger::init();
-    let config = setup(&PathBuf::new());
+    let matches = cli::command().get_matches();
 
-    let monitored_devices = MonitoredDevices::parse(&PathBuf::from(MONITORED_DEVICES_FILE_PATH))
-        .expect("Failed to parse monitored devices list");
+    let verbosity = matches.get_count("verbose");
+    let config_paths = match matches.get_many::<String>("config") {
+        Some(paths) => paths.map(PathBuf::from).collect(),
+        None => vec![config_path(&PathBuf::new())],
+    };
+    let devices_file_path = matches
+        .get_one::<String>("devices")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(MONITORED_DEVICES_FILE_PATH));
+    // The monitor initializes it once its config, which may set a log file, is parsed
+    if matches.subcommand().is_some() {
+        init_logger(verbosity, None);
+    }
+
+    match matches.subcommand() {
+        Some(("devices", sub_matches)) => {
+            if !cli::devices(sub_matches, &devices_file_path) {
+                std::process::exit(1);
+            }
+        }
+        Some(("summary", sub_matches)) => {
+            let config = setup(&config_paths);
+            let config = config
+                .read()
+                .expect("Failed to get config read lock")
+                .clone();
+            if !cli::summary(sub_matches, &config, &devices_file_path).await {
+                std::process::exit(1);
+            }
+        }
+        Some(("crashes", sub_matches)) => {
+            let config = setup(&config_paths);
+            let config = config
+                .read()
+                .expect("Failed to get config read lock")
+                .clone();
+            if !cli::crashes(sub_matches, &config, &devices_file_path).await {
+                std::process::exit(1);
+            }
+        }
+        Some(("archive", sub_matches)) => {
+            let config = setup(&config_paths);
+            let config = config
+                .read()
+                .expect("Failed to get config read lock")
+                .clone();
+            if !cli::archive(sub_matches, &config, &devices_file_path).await {
+                std::process::exit(1);
+            }
+        }
+        Some(("sysdiagnose", sub_matches)) => {
+            let config = setup(&config_paths);
+            let config = config
+                .read()
+                .expect("Failed to get config read lock")
+                .clone();
+            if !cli::sysdiagnose(sub_matches, &config, &devices_file_path).await {
+                std::process::exit(1);
+            }
+        }
+        Some(("force-pull", sub_matches)) => {
+            let config = setup(&config_paths);
+            let config = config
+                .read()
+                .expect("Failed to get config read lock")
+                .clone();
+            if !cli::force_pull(sub_matches, &config, &devices_file_path).await {
+                std::process::exit(1);
+            }
+        }
+        Some(("rebalance", _)) => {
+            let config = setup(&config_paths);
+            let config = config
+                .read()
+                .expect("Failed to get config read lock")
+                .clone();
+            if !cli::rebalance(&config, &devices_file_path) {
+                std::process::exit(1);
+            }
+        }
+        Some(("reconcile", sub_matches)) => {
+            let config = setup(&config_paths);
+            let config = config
+                .read()
+                .expect("Failed to get config read lock")
+                .clone();
+            if !cli::reconcile(sub_matches, &config, &devices_file_path).await {
+                std::process::exit(1);
+            }
+        }
+        Some(("list-devices", sub_matches)) => {
+            if !cli::list_devices(sub_matches, &devices_file_path).await {
+                std::process::exit(1);
+            }
+        }
+        Some(("selftest", _)) => {
+            if !cli::selftest(&config_paths, &devices_file_path).await {
+                std::process::exit(1);
+            }
+        }
+        Some((subcommand @ ("pause" | "resume"), sub_matches)) => {
+            let config = setup(&config_paths);
+            let config = config
+                .read()
+                .expect("Failed to get config read lock")
+                .clone();
+            if !cli::set_paused(
+                sub_matches,
+                &config,
+                &devices_file_path,
+                subcommand == "pause",
+            )
+            .await
+            {
+                std::process::exit(1);
+            }
+        }
+        _ => {
+            monitor(
+                &config_paths,
+                &devices_file_path,
+                matches.get_flag("strict"),
+                matches.get_flag("report_json"),
+                verbosity,
+            )
+            .await
+        }
+    }
+}
+
+/// Initializes the daemon logger. Each `-v` raises the level from `error`, an explicit
+/// `RUST_LOG` still takes precedence.
+fn init_logger(verbosity: u8, log_file: Option<&LogFileConfig>) {
+    let level = match verbosity {
+        0 => LevelFilter::Error,
+        1 => LevelFilter::Warn,
+        2 => LevelFilter::Info,
+        3 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(level).parse_default_env();
+
+    if let Some(log_file) = log_file {
+        match logger::rolling_file(
+            Path::new(&log_file.path),
+            log_file.rotation.into(),
+            log_file.max_files,
+        ) {
+            Ok(appender) => {
+                builder.target(env_logger::Target::Pipe(Box::new(logger::StderrTee::new(
+                    appender,
+                ))));
+            }
+            Err(e) => println!(
+                "Failed to open log file {}, logging to stderr only: {e}",
+                log_file.path
+            ),
+        }
+    }
+
+    builder.init();
+}
+
+/// Monitor all devices listed in the monitored devices file.
+/// Devices failing to be set up are skipped, unless `strict` is set.
+async fn monitor(
+    config_paths: &[PathBuf],
+    devices_file_path: &Path,
+    strict: bool,
+    report_json: bool,
+    verbosity: u8,
+) {
+    let config = setup(config_paths);
+
+    init_logger(
+        verbosity,
+        config
+            .read()
+            .expect("Failed to get config read lock for log file")
+            .log
+            .as_ref(),
+    );
+
+    let monitored_devices =
+        MonitoredDevices::parse(devices_file_path).expect("Failed to parse monitored devices list");
+
+    let problems = monitored_devices.validate();
+    if !problems.is_empty() {
+        for problem in &problems {
+            log::error!("Invalid monitored devices list: {problem}");
+        }
+        std::process::exit(1);
+    }
+
+    let max_devices = config
+        .read()
+        .expect("Failed to get config read lock for max devices")
+        .settings
+        .max_devices;
+    if let Err(e) = monitored_devices.check_count(max_devices) {
+        log::error!("Refusing to start: {e}");
+        std::process::exit(1);
+    }
 
     let mut monitored_devices_final = MonitoredDevices::default();
 
-    let mut monitor_tasks = tokio::task::JoinSet::new();
+    for base_dir in config
+        .read()
+        .expect("Failed to get config read lock for base dirs")
+        .get_base_dirs()
+    {
+        if let Ok(usage) = VolumeUsage::new(&base_dir)
+            && usage.is_near_full(VOLUME_NEAR_FULL_FREE_RATIO)
+        {
+            println!(
+                "Warning: base dir {base_dir} is near full ({} bytes available), see imonitor rebalance",
+                usage.available_bytes
+            );
+        }
+    }
+
+    let mut monitor_tasks = JoinSet::new();
+    // Unhealthy devices are monitored again from these
+    let mut restartable_devices = HashMap::new();
+
+    let reachability_timeout = config
+        .read()
+        .expect("Failed to get config read lock for reachability timeout")
+        .settings
+        .reachability_timeout;
+
+    let device_filter = config
+        .read()
+        .expect("Failed to get config read lock for device filter")
+        .device_filter
+        .clone();
+
+    // Shared by all devices to bound simultaneous service connections, by priority
+    let connection_limiter = config
+        .read()
+        .expect("Failed to get config read lock for connection limit")
+        .settings
+        .max_global_concurrent_connections
+        .map(|limit| Arc::new(ConnectionManager::new(limit)));
+
+    // Shared by all devices to bound simultaneous heartbeat connections
+    let heartbeat_limiter = config
+        .read()
+        .expect("Failed to get config read lock for heartbeat connection limit")
+        .settings
+        .max_concurrent_heartbeat_connects
+        .map(|limit| Arc::new(Semaphore::new(limit.get())));
+
+    // Shared by all devices to bound the total download rate
+    let download_limiter = config
+        .read()
+        .expect("Failed to get config read lock for download limit")
+        .settings
+        .max_download_bytes_per_sec
+        .map(|limit| Arc::new(BandwidthLimiter::new(limit)));
+
+    // Shared by all devices, crash files are sent to it instead of written locally
+    let crashes_config = config
+        .read()
+        .expect("Failed to get config read lock for crash store")
+        .crashes
+        .clone();
+    let crash_backend = match (crashes_config.store, &crashes_config.remote) {
+        (CrashStore::Remote, Some(remote)) => match CrashBackend::from_config(remote).await {
+            Ok(backend) => Some(Arc::new(backend)),
+            Err(e) => {
+                log::error!("Failed to set up the crash store: {e}");
+                std::process::exit(1);
+            }
+        },
+        _ => None,
+    };
+
+    // Shared by all devices, rewritten on each heartbeat of any of them
+    let liveness = config
+        .read()
+        .expect("Failed to get config read lock for liveness file")
+        .settings
+        .liveness_file
+        .clone()
+        .map(|path| Arc::new(LivenessFile::new(path)));
+
+    // Shared by all devices, written to from its own thread
+    let audit_log = config
+        .read()
+        .expect("Failed to get config read lock for audit log")
+        .audit_log
+        .clone();
+    let audit_log: Option<Arc<dyn MonitorObserver>> = match audit_log {
+        Some(audit_log) => match AuditLog::open(Path::new(&audit_log.path), audit_log.hash_chain) {
+            Ok(audit) => Some(audit),
+            Err(e) => {
+                log::error!("Failed to open audit log {}: {e}", audit_log.path);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let state_format = config
+        .read()
+        .expect("Failed to get config read lock for state format")
+        .settings
+        .state_format;
+
+    let mut failed_devices = Vec::new();
+    let mut startup_report = StartupReport::default();
+    let mut control_devices = HashMap::new();
 
     for device_config in monitored_devices.devices {
-        // Get base path from config
-        let base_path;
+        // Get base path and repair setting from config
+        let base_dirs;
+        let auto_repair;
+        let proxy;
+        let state_dir;
         {
-            base_path = config
+            let config = config
                 .read()
-                .expect("Failed to get config read lock for base_path")
-                .get_base_dir();
+                .expect("Failed to get config read lock for base_dirs");
+            base_dirs = device_config.base_dirs(&config);
+            auto_repair = config.settings.auto_repair;
+            proxy = config.settings.proxy.clone();
+            state_dir = config.settings.state_dir;
         }
 
-        // Initialize device from monitored devices config
-        let mut device: Device = match device_config.clone().try_into_device(base_path) {
+        let mut device_report = DeviceReport {
+            udid: device_config.udid.clone(),
+            name: device_config
+                .name
+                .clone()
+                .unwrap_or_else(|| device_config.udid.clone()),
+            ..Default::default()
+        };
+
+        let mut device = match setup_device(
+            &device_config,
+            &base_dirs,
+            auto_repair,
+            proxy,
+            state_dir,
+            &mut device_report,
+        )
+        .await
+        {
             Ok(device) => device,
             Err(e) => {
-                println!("Failed to create device from config: {e}");
-                return;
+                log::error!("Failed to set up device {}: {e}", device_config.udid);
+                device_report.error = Some(e.to_string());
+                startup_report.devices.push(device_report);
+                if strict {
+                    if report_json {
+                        startup_report.print();
+                    }
+                    std::process::exit(1);
+                }
+                failed_devices.push(device_config.udid.clone());
+                // Keep the device in the monitored devices file as is
+                monitored_devices_final.devices.push(device_config);
+                continue;
             }
         };
 
-        // Create device dirs on fs
-        if let Err(e) = device.create_dirs() {
-            println!("Failed to create dirs for device {}: {e}", device.info.udid);
-            return;
-            //continue;
+        // Add device to vec of succeded devices to monitor, pointing to the pairing file
+        // final destination. This will be used to update devices.toml file content
+        let mut device_config_final = device_config.clone();
+        device_config_final.pairing_file_path = device.get_pairing_file_path();
+        monitored_devices_final.devices.push(device_config_final);
+
+        device.connection_limiter = connection_limiter.clone();
+        device.heartbeat_limiter = heartbeat_limiter.clone();
+        device.download_limiter = download_limiter.clone();
+        device.crash_backend = crash_backend.clone();
+        device.liveness = liveness.clone();
+        device.state_format = state_format;
+        if let Some(audit_log) = &audit_log {
+            device.observer = audit_log.clone();
+        }
+
+        if let Some(reason) = filtered_out(&device, &device_filter).await {
+            println!("Device {} skipped: {reason}", device.display_name());
+            device_report.skipped = Some(reason);
+            startup_report.devices.push(device_report);
+            continue;
         }
 
-        // Load activity coverage from fs
-        if let Err(e) = device.load_activity_coverage().await {
+        // Clones share their state with the monitored device
+        control_devices.insert(device.info.udid.clone(), device.clone());
+
+        device_report.reachable = device.probe_reachable(reachability_timeout).await;
+        if !device_report.reachable {
             println!(
-                "Failed to load activity coverage for device {}: {e}",
+                "Device {} is unreachable, starting its services once it answers",
                 device.info.udid
             );
-            // If it fails, we loose the recorded activity
-            continue;
+        } else if let Err(e) = device.device_timezone().await {
+            // Cached in the info dir for the reports, which fall back to UTC only
+            log::warn!("Device {}: timezone unknown: {e}", device.display_name());
         }
+        restartable_devices.insert(device.info.udid.clone(), device.clone());
 
-        // Add device to vec of succeded devices to monitor and
-        // write pairing file to the final destination
-        // This will be used to update devices.toml file content
-        let mut device_config_final = device_config.clone();
-        device_config_final.pairing_file_path = device.get_pairing_file_path();
-        monitored_devices_final.devices.push(device_config_final);
-        monitored_devices_final
-            .write_to_file(&MONITORED_DEVICES_FILE_PATH.into())
-            .expect("Failed to write to monitored devices");
+        // Add device monitor task to queue. Will be awaited
+        spawn_monitor(
+            &mut monitor_tasks,
+            device,
+            config.clone(),
+            device_report.reachable,
+            reachability_timeout,
+        );
+        device_report.monitoring_started = true;
+        startup_report.devices.push(device_report);
+    }
 
-        if let Err(e) = device
-            .write_pairing_file(&device_config.clone().pairing_file_path)
-            .await
-        {
+    monitored_devices_final
+        .write_to_file(&devices_file_path.to_path_buf())
+        .expect("Failed to write to monitored devices");
+
+    if !failed_devices.is_empty() {
+        println!(
+            "{} device(s) failed to be set up and are not monitored: {}",
+            failed_devices.len(),
+            failed_devices.join(", ")
+        );
+    }
+
+    if report_json {
+        startup_report.print();
+    }
+
+    let control_socket_path = config
+        .read()
+        .expect("Failed to get config read lock for control socket")
+        .settings
+        .control_socket
+        .clone();
+
+    let control_socket =
+        control_socket_path.and_then(|path| match ControlSocket::bind(Path::new(&path)) {
+            Ok(socket) => Some(socket),
+            Err(e) => {
+                log::error!("Failed to bind control socket {path}: {e}");
+                None
+            }
+        });
+    let control_socket_path = control_socket
+        .as_ref()
+        .map(|socket| socket.path().to_path_buf());
+    if let Some(socket) = control_socket {
+        tokio::spawn(socket.serve(Arc::new(control_devices)));
+    }
+
+    // Await all monitored devices tasks, until a shutdown signal
+    tokio::select! {
+        _ = async {
+            while let Some(res) = monitor_tasks.join_next().await {
+                match res {
+                    Err(e) => {
+                        log::error!("Device monitoring task error: {e}");
+                    }
+                    Ok((_, name, MonitorOutcome::ShutdownRequested)) => {
+                        println!("Device {name} monitoring finished");
+                    }
+                    // Needs a config or pairing fix, restarting would fail the same way
+                    Ok((_, name, MonitorOutcome::Fatal(e))) => {
+                        log::error!("Device {name} monitoring failed, not restarted: {e}");
+                    }
+                    Ok((udid, name, MonitorOutcome::Unhealthy { consecutive_failures })) => {
+                        log::warn!(
+                            "Device {name} unhealthy after {consecutive_failures} heartbeat failures, monitoring it again once reachable"
+                        );
+                        if let Some(device) = restartable_devices.get(&udid) {
+                            spawn_monitor(
+                                &mut monitor_tasks,
+                                device.clone(),
+                                config.clone(),
+                                false,
+                                reachability_timeout,
+                            );
+                        }
+                    }
+                }
+            }
+        } => {}
+        _ = shutdown_signal() => {
+            println!("Shutting down");
+        }
+    }
+
+    if let Some(path) = control_socket_path {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+/// Why the device filter skips the device, None if it is monitored. A device whose model and
+/// version are unknown, neither queried nor cached, is monitored.
+async fn filtered_out(device: &Device, device_filter: &DeviceFilterConfig) -> Option<String> {
+    if device_filter.is_empty() {
+        return None;
+    }
+
+    match device.product_info().await {
+        Ok(product_info) => device_filter.skip_reason(
+            product_info.product_type.as_deref(),
+            product_info.product_version.as_deref(),
+        ),
+        Err(e) => {
+            log::warn!(
+                "Device {}: model and iOS version unknown, device_filter not applied: {e}",
+                device.info.udid
+            );
+            None
+        }
+    }
+}
+
+/// Spawns the monitoring of a device, deferred until it is reachable if it is not.
+fn spawn_monitor(
+    monitor_tasks: &mut JoinSet<(String, String, MonitorOutcome)>,
+    mut device: Device,
+    config: Arc<RwLock<Config>>,
+    reachable: bool,
+    reachability_timeout: Duration,
+) {
+    monitor_tasks.spawn(async move {
+        if !reachable {
+            wait_reachable(&device, reachability_timeout).await;
+        }
+        let outcome = device.monitor(config).await;
+        (device.info.udid.clone(), device.display_name(), outcome)
+    });
+}
+
+/// Probes the device until it is reachable, instead of running its services in vain.
+async fn wait_reachable(device: &Device, reachability_timeout: Duration) {
+    loop {
+        tokio::time::sleep(Duration::from_secs(UNREACHABLE_PROBE_INTERVAL_SECS)).await;
+        if device.probe_reachable(reachability_timeout).await {
             println!(
-                "Failed to write pairing file for device {}: {e}",
+                "Device {} is reachable, starting its services",
                 device.info.udid
             );
             return;
         }
+    }
+}
 
-        if let Err(e) = device.init_logger() {
-            println!("Failed to init logger for device {}: {e}", device.info.udid);
+/// Waits for SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    let mut sigterm = match signal(SignalKind::terminate()) {
+        Ok(sigterm) => sigterm,
+        Err(e) => {
+            println!("Failed to listen to SIGTERM: {e}");
+            let _ = tokio::signal::ctrl_c().await;
             return;
         }
+    };
 
-        let config_clone = config.clone();
-        // Add device monitor task to queue. Will be awaited
-        monitor_tasks.spawn(async move { device.monitor(config_clone).await });
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = sigterm.recv() => {}
     }
+}
 
-    // Await all monitored devices tasks
-    while let Some(res) = monitor_tasks.join_next().await {
-        match res {
-            Err(e) => {
-                println!("Device monitoring task error: {e}");
-            }
-            Ok(Err(e)) => {
-                println!("Device monitoring error: {e}");
-            }
-            Ok(Ok(_)) => {
-                println!("Task finished");
+/// Builds the device and prepares its files on disk.
+async fn setup_device(
+    device_config: &DeviceConfig,
+    base_dirs: &[String],
+    auto_repair: bool,
+    proxy: Option<ProxyConfig>,
+    state_dir: bool,
+    report: &mut DeviceReport,
+) -> Result<Device, Box<dyn Error>> {
+    // Initialize device from monitored devices config
+    let mut device: Device = match device_config.clone().try_into_device(base_dirs) {
+        Ok(device) => device,
+        Err(e) if auto_repair => {
+            println!(
+                "Failed to create device {} from config ({e}), pairing again",
+                device_config.udid
+            );
+            let pairing_file = repair_pairing(device_config).await?;
+            let mut device = Device::new(
+                &device_config.udid,
+                &pairing_file,
+                &device_config.ip,
+                &device_config.connection_label,
+                base_dirs,
+            );
+            device.config_overrides = device_config.overrides.clone().unwrap_or_default();
+            if let Some(name) = &device_config.name {
+                device.info.name = name.clone();
             }
+            device
         }
+        Err(e) => return Err(format!("Failed to create device from config: {e}").into()),
+    };
+    device.proxy = proxy;
+    device.state_dir = state_dir;
+
+    // Create device dirs on fs
+    device
+        .create_dirs()
+        .map_err(|e| format!("Failed to create dirs: {e}"))?;
+    report.dirs_created = true;
+
+    // Two processes on the same base dir would corrupt each other's state files
+    if let Some(stale_pid) = device.acquire_instance_lock()? {
+        println!(
+            "Reclaimed the instance lock of device {} left by dead process {stale_pid}",
+            device.info.udid
+        );
     }
+
+    // Files left in the other layout, e.g. after state_dir was changed
+    let moved = device
+        .migrate_state_files()
+        .map_err(|e| format!("Failed to move state files: {e}"))?;
+    if moved > 0 {
+        println!(
+            "Moved {moved} state file(s) of device {} to the configured layout",
+            device.info.udid
+        );
+    }
+
+    // Only a rejected session means the pairing is invalid, the device may just be offline
+    if auto_repair
+        && let Err(EnrollError::StartSession(e)) = check_pairing(
+            &*device.get_provider("pairing_check"),
+            &device.connection.pairing_file,
+        )
+        .await
+    {
+        println!(
+            "Invalid pairing file for device {} ({e}), pairing again",
+            device.info.udid
+        );
+        device.connection.pairing_file = repair_pairing(device_config).await?;
+    }
+
+    // Load activity coverage from fs
+    // If it fails, we loose the recorded activity
+    device
+        .load_activity_coverage()
+        .await
+        .map_err(|e| format!("Failed to load activity coverage: {e}"))?;
+    report.coverage_loaded = true;
+
+    device
+        .write_pairing_file(&device_config.pairing_file_path)
+        .await
+        .map_err(|e| format!("Failed to write pairing file: {e}"))?;
+    report.pairing_written = true;
+
+    // Logs fall back to stderr, the device is still monitored
+    match device.init_logger() {
+        Ok(_) => report.logger_initialized = true,
+        Err(e) => println!("Device {}: {e}, logging to stderr", device_config.udid),
+    }
+
+    Ok(device)
+}
+
+/// Pairs again with the device over USB. The pairing file is written by `setup_device`.
+async fn repair_pairing(device_config: &DeviceConfig) -> Result<PairingFile, Box<dyn Error>> {
+    let pairing_file = enroll_usb_device(&device_config.udid, &device_config.connection_label)
+        .await
+        .map_err(|e| format!("Failed to pair again: {e}"))?;
+    println!("Device {} paired again", device_config.udid);
+    Ok(pairing_file)
 }
@@ -1,10 +1,12 @@
 use idevice::pairing_file::PairingFile;
+use imonitor_lib::config::{Config, ConfigOverrides, device_base_dir};
 use imonitor_lib::device::Device;
 use imonitor_lib::device::errors::DeviceError;
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::error::Error;
 use std::fs::File;
-use std::fs::read_to_string;
+use std::fs::{read_to_string, rename};
 use std::io::{BufWriter, Write};
 use std::path::{Path, PathBuf};
 
@@ -13,15 +15,27 @@ fn default_connection_label() -> String {
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct DeviceConfig {
     pub udid: String,
     pub pairing_file_path: String,
     pub ip: std::net::IpAddr,
     #[serde(default = "default_connection_label")]
     pub connection_label: String,
+    /// Readable name shown with the UDID in logs and reports, such as "QA iPhone 12".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Values shadowing the global config for this device only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub overrides: Option<ConfigOverrides>,
+    /// Base dir of this device, instead of the one assigned from the config base dirs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_dir_override: Option<String>,
 }
 
+/// Unknown keys are rejected, like in the config file.
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct MonitoredDevices {
     pub devices: Vec<DeviceConfig>,
 }
@@ -30,36 +44,239 @@ impl MonitoredDevices {
     /// Parses the config file and returns the values.
     pub fn parse(path: &Path) -> Result<MonitoredDevices, Box<dyn Error>> {
         let devices_str = read_to_string(path)?;
-        let devices: MonitoredDevices = toml::from_str(&devices_str)?;
+        let devices: MonitoredDevices =
+            toml::from_str(&devices_str).map_err(|e| format!("{}: {e}", path.display()))?;
         Ok(devices)
     }
 
+    /// Writes the device list to a temporary file, then renames it over `path` so that
+    /// readers never see a partially written file.
     pub fn write_to_file(&self, path: &PathBuf) -> Result<(), Box<dyn Error>> {
         let monitored_devices = toml::to_string(&self)?;
 
-        let file_h = File::create(path)?;
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let file_h = File::create(&tmp_path)?;
 
         let mut writer = BufWriter::new(file_h);
 
         writer.write_all(&monitored_devices.into_bytes())?;
 
         writer.flush()?;
+        writer.get_ref().sync_all()?;
+
+        rename(&tmp_path, path)?;
 
         Ok(())
     }
+
+    /// Checks the device list consistency. Returns the problems found, empty if valid.
+    /// Pairing files are not checked, see [`MonitoredDevices::missing_pairing_files`].
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut udids = HashSet::new();
+        let mut labels = HashSet::new();
+
+        for device in &self.devices {
+            if !udids.insert(&device.udid) {
+                problems.push(format!("Device {} is listed more than once", device.udid));
+            }
+            if !labels.insert(&device.connection_label) {
+                problems.push(format!(
+                    "Connection label {} of device {} is already used",
+                    device.connection_label, device.udid
+                ));
+            }
+        }
+
+        problems
+    }
+
+    /// Devices whose pairing file does not exist. The monitor repairs or skips them one by
+    /// one, only the self-test reports them upfront.
+    pub fn missing_pairing_files(&self) -> Vec<String> {
+        self.devices
+            .iter()
+            .filter(|device| !Path::new(&device.pairing_file_path).is_file())
+            .map(|device| {
+                format!(
+                    "Pairing file {} of device {} does not exist",
+                    device.pairing_file_path, device.udid
+                )
+            })
+            .collect()
+    }
+
+    /// Fails if more devices are listed than `max_devices`.
+    pub fn check_count(&self, max_devices: Option<usize>) -> Result<(), String> {
+        match max_devices {
+            Some(max_devices) if self.devices.len() > max_devices => Err(format!(
+                "{} devices are configured, more than max_devices ({max_devices})",
+                self.devices.len()
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Adds a device to the list. Fails if a device with the same UDID is already monitored.
+    pub fn add(&mut self, device_config: DeviceConfig) -> Result<(), Box<dyn Error>> {
+        if self.devices.iter().any(|d| d.udid == device_config.udid) {
+            return Err(format!("Device {} is already monitored", device_config.udid).into());
+        }
+
+        self.devices.push(device_config);
+        Ok(())
+    }
+
+    /// Removes the device with the given UDID. Returns false if it was not monitored.
+    pub fn remove(&mut self, udid: &str) -> bool {
+        let len = self.devices.len();
+        self.devices.retain(|d| d.udid != udid);
+        self.devices.len() != len
+    }
 }
 
 impl DeviceConfig {
-    pub fn try_into_device(self, base_dir: impl AsRef<Path>) -> Result<Device, DeviceError> {
+    /// Base dirs the device dir may be in: its override, else the configured ones.
+    pub fn base_dirs(&self, config: &Config) -> Vec<String> {
+        match &self.base_dir_override {
+            Some(base_dir) => vec![base_dir.clone()],
+            None => config.get_base_dirs(),
+        }
+    }
+
+    /// Base dir holding the device dir, see [`device_base_dir`].
+    pub fn base_dir(&self, config: &Config) -> String {
+        device_base_dir(&self.base_dirs(config), &self.udid)
+    }
+
+    pub fn try_into_device(self, base_dirs: &[String]) -> Result<Device, DeviceError> {
         let pairing_file = PairingFile::read_from_file(&self.pairing_file_path)
             .map_err(DeviceError::ReadPairingFile)?;
 
-        Ok(Device::new(
+        let mut device = Device::new(
             &self.udid,
             &pairing_file,
             &self.ip,
             &self.connection_label,
-            base_dir.as_ref(),
-        ))
+            base_dirs,
+        );
+        device.config_overrides = self.overrides.unwrap_or_default();
+        if let Some(name) = self.name {
+            device.info.name = name;
+        }
+
+        Ok(device)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device_config(udid: &str) -> DeviceConfig {
+        DeviceConfig {
+            udid: udid.to_string(),
+            pairing_file_path: format!("/var/lib/imonitor/{udid}.plist"),
+            ip: "10.0.0.1".parse().unwrap(),
+            connection_label: default_connection_label(),
+            name: None,
+            overrides: None,
+            base_dir_override: None,
+        }
+    }
+
+    #[test]
+    fn adding_a_monitored_device_again_fails() {
+        let mut monitored_devices = MonitoredDevices::default();
+        monitored_devices.add(device_config("udid-1")).unwrap();
+
+        let error = monitored_devices
+            .add(device_config("udid-1"))
+            .unwrap_err()
+            .to_string();
+        assert_eq!(error, "Device udid-1 is already monitored");
+        assert_eq!(monitored_devices.devices.len(), 1);
+    }
+
+    #[test]
+    fn removing_an_unknown_device_changes_nothing() {
+        let mut monitored_devices = MonitoredDevices::default();
+        monitored_devices.add(device_config("udid-1")).unwrap();
+
+        assert!(!monitored_devices.remove("udid-2"));
+        assert_eq!(monitored_devices.devices.len(), 1);
+        assert!(monitored_devices.remove("udid-1"));
+        assert!(monitored_devices.devices.is_empty());
+    }
+
+    #[test]
+    fn written_devices_parse_back() {
+        let path = std::env::temp_dir().join(format!("devices-{}.toml", uuid::Uuid::new_v4()));
+        let mut monitored_devices = MonitoredDevices::default();
+        monitored_devices.add(device_config("udid-1")).unwrap();
+        monitored_devices.add(device_config("udid-2")).unwrap();
+
+        monitored_devices.write_to_file(&path).unwrap();
+        let parsed = MonitoredDevices::parse(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let udids = parsed
+            .devices
+            .iter()
+            .map(|device| device.udid.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(udids, ["udid-1", "udid-2"]);
+        assert!(parsed.validate().is_empty());
+    }
+
+    #[test]
+    fn device_count_is_checked_against_max_devices() {
+        let mut monitored_devices = MonitoredDevices::default();
+        monitored_devices.add(device_config("udid-1")).unwrap();
+        monitored_devices.add(device_config("udid-2")).unwrap();
+
+        assert!(monitored_devices.check_count(None).is_ok());
+        assert!(monitored_devices.check_count(Some(2)).is_ok());
+        assert_eq!(
+            monitored_devices.check_count(Some(1)).unwrap_err(),
+            "2 devices are configured, more than max_devices (1)"
+        );
+    }
+
+    #[test]
+    fn device_name_is_optional() {
+        let path = std::env::temp_dir().join(format!("devices-{}.toml", uuid::Uuid::new_v4()));
+        let mut monitored_devices = MonitoredDevices::default();
+        monitored_devices
+            .add(DeviceConfig {
+                name: Some("QA iPhone 12".to_string()),
+                ..device_config("udid-1")
+            })
+            .unwrap();
+        monitored_devices.add(device_config("udid-2")).unwrap();
+
+        monitored_devices.write_to_file(&path).unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        let parsed = MonitoredDevices::parse(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(written.matches("name = ").count(), 1, "{written}");
+        assert_eq!(parsed.devices[0].name.as_deref(), Some("QA iPhone 12"));
+        assert_eq!(parsed.devices[1].name, None);
+    }
+
+    #[test]
+    fn duplicate_devices_are_reported() {
+        let mut monitored_devices = MonitoredDevices::default();
+        monitored_devices.devices.push(device_config("udid-1"));
+        monitored_devices.devices.push(device_config("udid-1"));
+
+        assert_eq!(
+            monitored_devices.validate(),
+            ["Device udid-1 is listed more than once"]
+        );
     }
 }
@@ -0,0 +1,187 @@
+use serde::Serialize;
+use std::net::IpAddr;
+
+/// Setup steps reached by a device at startup.
+#[derive(Debug, Default, Serialize)]
+pub struct DeviceReport {
+    pub udid: String,
+    pub name: String,
+    pub dirs_created: bool,
+    pub coverage_loaded: bool,
+    pub pairing_written: bool,
+    pub logger_initialized: bool,
+    /// Reason the device is not monitored though set up, such as the device filter
+    pub skipped: Option<String>,
+    /// Lockdown port answered the startup probe. Unreachable devices are degraded: their
+    /// services wait for a later probe to succeed
+    pub reachable: bool,
+    /// Monitoring task (heartbeat and services) spawned
+    pub monitoring_started: bool,
+    pub error: Option<String>,
+}
+
+/// Startup report printed as a single JSON line with `--report-json`.
+#[derive(Debug, Default, Serialize)]
+pub struct StartupReport {
+    pub success: bool,
+    pub devices: Vec<DeviceReport>,
+}
+
+impl StartupReport {
+    pub fn print(&mut self) {
+        self.success = self.devices.iter().all(|device| device.error.is_none());
+        match serde_json::to_string(self) {
+            Ok(report) => println!("{report}"),
+            Err(e) => eprintln!("Failed to serialize startup report: {e}"),
+        }
+    }
+}
+
+/// Result of one self-test check.
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub name: String,
+    pub passed: bool,
+    pub problems: Vec<String>,
+}
+
+/// Report of `imonitor selftest`.
+#[derive(Debug, Default, Serialize)]
+pub struct SelfTestReport {
+    pub success: bool,
+    pub checks: Vec<Check>,
+}
+
+impl SelfTestReport {
+    /// Records a check, passed if no problems were found. Returns whether it passed.
+    pub fn add(&mut self, name: impl Into<String>, problems: Vec<String>) -> bool {
+        let passed = problems.is_empty();
+        self.checks.push(Check {
+            name: name.into(),
+            passed,
+            problems,
+        });
+        passed
+    }
+
+    pub fn print(&mut self) {
+        self.success = self.checks.iter().all(|check| check.passed);
+        match serde_json::to_string_pretty(self) {
+            Ok(report) => println!("{report}"),
+            Err(e) => eprintln!("Failed to serialize self-test report: {e}"),
+        }
+    }
+}
+
+/// A device of the `list-devices` listing.
+#[derive(Debug, Serialize)]
+pub struct ListedDevice {
+    pub udid: String,
+    /// Address in the monitored devices file, if configured
+    pub ip: Option<IpAddr>,
+    /// "usb" or "network" as seen by usbmuxd, if present
+    pub connection: Option<String>,
+}
+
+/// Monitored devices file reconciled with the devices usbmuxd sees.
+#[derive(Debug, Default, Serialize)]
+pub struct DeviceListing {
+    pub configured_present: Vec<ListedDevice>,
+    /// Only expected over TCP, or unplugged
+    pub configured_missing: Vec<ListedDevice>,
+    pub present_unconfigured: Vec<ListedDevice>,
+}
+
+impl DeviceListing {
+    /// `configured` holds the UDID and address of each monitored device, `present` the UDID
+    /// and connection type of each device seen by usbmuxd.
+    pub fn new(configured: &[(String, IpAddr)], present: &[(String, String)]) -> DeviceListing {
+        let mut listing = DeviceListing::default();
+        for (udid, ip) in configured {
+            let connection = present
+                .iter()
+                .find(|(present_udid, _)| present_udid == udid)
+                .map(|(_, connection)| connection.clone());
+            let device = ListedDevice {
+                udid: udid.clone(),
+                ip: Some(*ip),
+                connection: connection.clone(),
+            };
+            match connection {
+                Some(_) => listing.configured_present.push(device),
+                None => listing.configured_missing.push(device),
+            }
+        }
+        for (udid, connection) in present {
+            if !configured
+                .iter()
+                .any(|(configured_udid, _)| configured_udid == udid)
+            {
+                listing.present_unconfigured.push(ListedDevice {
+                    udid: udid.clone(),
+                    ip: None,
+                    connection: Some(connection.clone()),
+                });
+            }
+        }
+        listing
+    }
+
+    pub fn print(&self, json: bool) {
+        if json {
+            match serde_json::to_string_pretty(self) {
+                Ok(listing) => println!("{listing}"),
+                Err(e) => eprintln!("Failed to serialize device listing: {e}"),
+            }
+            return;
+        }
+
+        let sections = [
+            ("Configured and present", &self.configured_present),
+            ("Configured, missing", &self.configured_missing),
+            ("Present, not configured", &self.present_unconfigured),
+        ];
+        for (title, devices) in sections {
+            println!("{title} ({}):", devices.len());
+            for device in devices {
+                let ip = device.ip.map(|ip| ip.to_string()).unwrap_or_default();
+                let connection = device.connection.as_deref().unwrap_or("-");
+                println!("  {:<40} {ip:<39} {connection}", device.udid);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn devices_are_listed_by_presence_and_configuration() {
+        let ip = |last: u8| IpAddr::from([192, 168, 1, last]);
+        let configured = [("a".to_string(), ip(1)), ("b".to_string(), ip(2))];
+        let present = [
+            ("b".to_string(), "usb".to_string()),
+            ("c".to_string(), "network".to_string()),
+        ];
+
+        let listing = DeviceListing::new(&configured, &present);
+
+        let udids = |devices: &[ListedDevice]| {
+            devices
+                .iter()
+                .map(|device| device.udid.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(udids(&listing.configured_present), ["b"]);
+        assert_eq!(listing.configured_present[0].ip, Some(ip(2)));
+        assert_eq!(
+            listing.configured_present[0].connection.as_deref(),
+            Some("usb")
+        );
+        assert_eq!(udids(&listing.configured_missing), ["a"]);
+        assert_eq!(listing.configured_missing[0].connection, None);
+        assert_eq!(udids(&listing.present_unconfigured), ["c"]);
+        assert_eq!(listing.present_unconfigured[0].ip, None);
+    }
+}
@@ -1,8 +1,15 @@
+use std::io::Write;
+use std::path::Path;
 use tracing::dispatcher::Dispatch;
 use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::RollingFileAppender;
 use tracing_subscriber::prelude::__tracing_subscriber_SubscriberExt;
 use tracing_subscriber::{filter::EnvFilter, filter::LevelFilter, fmt::format, prelude::*};
 
+mod truncate_lock;
+
+pub use truncate_lock::{LockedAppender, TruncateGuard, TruncateLock, truncate_lock_path};
+
 #[derive(Debug)]
 pub struct Logger {
     pub file_path: String,
@@ -11,9 +18,25 @@ pub struct Logger {
 }
 
 impl Logger {
+    /// Panics if the log file cannot be opened, see [`Logger::try_new`].
     pub fn new(dir: &str, file_name: &str) -> Self {
-        let file_appender = tracing_appender::rolling::never(dir, file_name);
-        let (non_blocking, guard) = tracing_appender::non_blocking(file_appender);
+        Self::try_new(dir, file_name).expect("Failed to open log file")
+    }
+
+    /// Logger appending to `<dir>/<file_name>`, coordinated with imonitor-send truncating
+    /// it through a [`TruncateLock`].
+    pub fn try_new(dir: &str, file_name: &str) -> std::io::Result<Self> {
+        let appender = LockedAppender::open(&Path::new(dir).join(file_name))?;
+        Ok(Self::from_writer(appender, file_name))
+    }
+
+    /// Logger writing to stderr, used when the log file cannot be written to.
+    pub fn stderr() -> Self {
+        Self::from_writer(std::io::stderr(), "<stderr>")
+    }
+
+    fn from_writer<W: Write + Send + 'static>(writer: W, file_path: &str) -> Self {
+        let (non_blocking, guard) = tracing_appender::non_blocking(writer);
         let filter = EnvFilter::from_default_env().add_directive(LevelFilter::INFO.into());
 
         let fmt_layer = tracing_subscriber::fmt::Layer::default()
@@ -29,13 +52,79 @@ impl Logger {
         let dispatch = Dispatch::new(subscriber);
 
         Self {
-            file_path: file_name.to_string(),
+            file_path: file_path.to_string(),
             dispatch,
             _guard: guard,
         }
     }
 }
 
+/// Rotation period of a rolling log file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Rotation {
+    Never,
+    Hourly,
+    #[default]
+    Daily,
+}
+
+/// Appender writing to `path`, with the rotation date appended to the file name.
+/// Only the `max_files` most recent files are kept if set.
+pub fn rolling_file(
+    path: &Path,
+    rotation: Rotation,
+    max_files: Option<usize>,
+) -> std::io::Result<RollingFileAppender> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| std::io::Error::other(format!("{} has no file name", path.display())))?
+        .to_string_lossy()
+        .to_string();
+
+    let rotation = match rotation {
+        Rotation::Never => tracing_appender::rolling::Rotation::NEVER,
+        Rotation::Hourly => tracing_appender::rolling::Rotation::HOURLY,
+        Rotation::Daily => tracing_appender::rolling::Rotation::DAILY,
+    };
+
+    let mut builder = RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix(file_name);
+    if let Some(max_files) = max_files {
+        builder = builder.max_log_files(max_files);
+    }
+    builder.build(dir).map_err(std::io::Error::other)
+}
+
+/// Writes everything to stderr and to another writer, such as a [`rolling_file`].
+pub struct StderrTee<W> {
+    inner: W,
+}
+
+impl<W: Write> StderrTee<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner }
+    }
+}
+
+impl<W: Write> Write for StderrTee<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // Losing the file copy is worse than losing the stderr one
+        let _ = std::io::stderr().write_all(buf);
+        self.inner.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        let _ = std::io::stderr().flush();
+        self.inner.flush()
+    }
+}
+
 pub trait HasLogger {
     fn logger(&self) -> Option<&Logger>;
 }
@@ -91,6 +180,23 @@ macro_rules! debug {
     }};
 }
 
+#[macro_export]
+macro_rules! warn {
+    ($self:expr, $($arg:tt)+) => {{
+        match $self.logger() {
+            Some(logger) => {
+                let dispatch = logger.dispatch.clone();
+                tracing::dispatcher::with_default(&dispatch, || {
+                    tracing::warn!($($arg)*);
+                });
+            },
+            None => {
+                eprintln!("Logger not set");
+            }
+        }
+    }};
+}
+
 #[macro_export]
 macro_rules! error {
     ($self:expr, $($arg:tt)+) => {{
@@ -0,0 +1,108 @@
+//! Coordination of the writers of a log file with imonitor-send, which cuts uploaded
+//! chunks off its head.
+//!
+//! Protocol, on the advisory lock file `<log>.lock`:
+//! - Writers append to the log in append mode and hold a shared lock during each write,
+//!   flushes included. Append mode writes at the current end of file, so no writer offset
+//!   goes stale after a truncate and the log never needs reopening.
+//! - imonitor-send holds an exclusive lock from reading the tail it keeps to rewriting it,
+//!   so that no line is appended in between and lost.
+//!
+//! The lock is advisory: a writer not taking it is not blocked.
+
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// Polling interval of async waiters, the exclusive lock is held for a few milliseconds
+const SHARED_LOCK_POLL_MS: u64 = 5;
+
+/// Lock file of a log, `<log>.lock`.
+pub fn truncate_lock_path(log_path: &Path) -> PathBuf {
+    let mut path = log_path.as_os_str().to_owned();
+    path.push(".lock");
+    PathBuf::from(path)
+}
+
+/// Lock coordinating the writers of a log with its truncation.
+#[derive(Debug)]
+pub struct TruncateLock {
+    file: File,
+}
+
+/// Released on drop.
+#[derive(Debug)]
+pub struct TruncateGuard<'a> {
+    file: &'a File,
+}
+
+impl Drop for TruncateGuard<'_> {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+    }
+}
+
+impl TruncateLock {
+    /// Opens the lock file of the log, creating it if missing.
+    pub fn open(log_path: &Path) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(truncate_lock_path(log_path))?;
+        Ok(Self { file })
+    }
+
+    /// Held by writers. Blocks while the log is truncated.
+    pub fn shared(&self) -> std::io::Result<TruncateGuard<'_>> {
+        self.file.lock_shared()?;
+        Ok(TruncateGuard { file: &self.file })
+    }
+
+    /// Same as [`TruncateLock::shared`], without blocking the runtime thread.
+    pub async fn shared_async(&self) -> std::io::Result<TruncateGuard<'_>> {
+        loop {
+            match self.file.try_lock_shared() {
+                Ok(()) => return Ok(TruncateGuard { file: &self.file }),
+                Err(TryLockError::WouldBlock) => {
+                    tokio::time::sleep(Duration::from_millis(SHARED_LOCK_POLL_MS)).await;
+                }
+                Err(TryLockError::Error(e)) => return Err(e),
+            }
+        }
+    }
+
+    /// Held while truncating the log. Blocks while a write is in progress.
+    pub fn exclusive(&self) -> std::io::Result<TruncateGuard<'_>> {
+        self.file.lock()?;
+        Ok(TruncateGuard { file: &self.file })
+    }
+}
+
+/// Log file appender taking the [`TruncateLock`] for each write.
+pub struct LockedAppender {
+    file: File,
+    lock: TruncateLock,
+}
+
+impl LockedAppender {
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let lock = TruncateLock::open(path)?;
+        Ok(Self { file, lock })
+    }
+}
+
+impl Write for LockedAppender {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let _guard = self.lock.shared()?;
+        // A whole event per write, never split around a truncate
+        self.file.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
@@ -0,0 +1,100 @@
+//! Truncating a log the way imonitor-send does while writers keep appending to it.
+
+use logger::{LockedAppender, TruncateLock};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const WRITERS: usize = 4;
+const LINES_PER_WRITER: usize = 2000;
+
+fn test_log_path() -> PathBuf {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    std::env::temp_dir().join(format!(
+        "logger-truncate-{}-{nanos}.log",
+        std::process::id()
+    ))
+}
+
+/// Cuts the complete lines off the head of the log and returns them, keeping the tail, like
+/// imonitor-send once a chunk is uploaded.
+fn cut_head(log_path: &Path, lock: &TruncateLock) -> Vec<u8> {
+    let _guard = lock.exclusive().unwrap();
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(log_path)
+        .unwrap();
+
+    let mut content = Vec::new();
+    file.read_to_end(&mut content).unwrap();
+    let offset = content
+        .iter()
+        .rposition(|byte| *byte == b'\n')
+        .map_or(0, |newline| newline + 1);
+
+    file.set_len(0).unwrap();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.write_all(&content[offset..]).unwrap();
+    file.flush().unwrap();
+
+    content.truncate(offset);
+    content
+}
+
+#[test]
+fn truncating_during_writes_loses_and_corrupts_nothing() {
+    let log_path = test_log_path();
+    File::create(&log_path).unwrap();
+
+    let writers = (0..WRITERS)
+        .map(|writer| {
+            let log_path = log_path.clone();
+            thread::spawn(move || {
+                let mut appender = LockedAppender::open(&log_path).unwrap();
+                for line in 0..LINES_PER_WRITER {
+                    appender
+                        .write_all(format!("writer {writer} line {line}\n").as_bytes())
+                        .unwrap();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let lock = TruncateLock::open(&log_path).unwrap();
+    let mut uploaded = Vec::new();
+    while !writers.iter().all(|writer| writer.is_finished()) {
+        uploaded.extend(cut_head(&log_path, &lock));
+    }
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    uploaded.extend(cut_head(&log_path, &lock));
+
+    assert_eq!(std::fs::read(&log_path).unwrap(), b"");
+    std::fs::remove_file(&log_path).unwrap();
+    std::fs::remove_file(logger::truncate_lock_path(&log_path)).unwrap();
+
+    // Every line exactly once and whole, in the order each writer wrote them
+    let uploaded = String::from_utf8(uploaded).unwrap();
+    let mut next_line = [0; WRITERS];
+    for line in uploaded.lines() {
+        let (writer, number) = line
+            .strip_prefix("writer ")
+            .and_then(|line| line.split_once(" line "))
+            .unwrap_or_else(|| panic!("corrupted line: {line:?}"));
+        let writer = writer.parse::<usize>().unwrap();
+        assert_eq!(
+            number.parse::<usize>().unwrap(),
+            next_line[writer],
+            "{line}"
+        );
+        next_line[writer] += 1;
+    }
+    assert_eq!(next_line, [LINES_PER_WRITER; WRITERS]);
+}